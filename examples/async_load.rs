@@ -0,0 +1,56 @@
+// Loads a video_metrics database in the background while the window keeps rendering, printing the frame
+// time every second so stalls during the load would show up
+//
+//     cargo run --example async_load -- metrics.db
+
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use winit::event::{Event, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::platform::run_return::EventLoopExtRunReturn;
+use winit::window::WindowBuilder;
+use zeta_dom::vulkano_renderer::{RendererOptions, VulkanoRenderer};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let db_path = std::env::args().nth(1).unwrap_or_else(|| "metrics.db".to_string());
+
+    let mut event_loop = EventLoop::new();
+    let window = WindowBuilder::new().with_title("zeta-DOM").build(&event_loop)?;
+    let mut renderer = VulkanoRenderer::create(window, RendererOptions::default())?;
+
+    let load = renderer.load_vertex_data_async(&db_path, |result| match result {
+        Ok(report) => println!("loaded {}", report),
+        Err(e) => println!("load failed: {}", e),
+    });
+
+    let mut result = Ok(());
+    let (mut frames, mut slowest, mut since) = (0u32, Duration::ZERO, Instant::now());
+    event_loop.run_return(|event, _, control_flow| {
+        *control_flow = ControlFlow::Poll;
+        match event {
+            Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => *control_flow = ControlFlow::Exit,
+            Event::MainEventsCleared => {
+                let start = Instant::now();
+                if let Err(e) = renderer.render_once() {
+                    result = Err(e);
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+                frames += 1;
+                slowest = slowest.max(start.elapsed());
+                if since.elapsed() >= Duration::from_secs(1) {
+                    let state = if load.is_finished() { "done" } else { "loading" };
+                    println!("{}: {} frames, slowest {:.1} ms", state, frames, slowest.as_secs_f64() * 1000.0);
+                    frames = 0;
+                    slowest = Duration::ZERO;
+                    since = Instant::now();
+                }
+            }
+            _ => {}
+        }
+    });
+
+    renderer.wait_idle();
+    Ok(result?)
+}
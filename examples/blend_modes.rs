@@ -0,0 +1,39 @@
+// Draws an opaque quad and two half-transparent ones overlapping it in the same frame. Opaque blocks are
+// drawn first, blended ones after them, so the overlap shows both colors mixed whatever the upload order.
+//
+//     cargo run --example blend_modes
+
+use std::error::Error;
+
+use winit::event_loop::EventLoop;
+use winit::window::WindowBuilder;
+use zeta_dom::db_ingestor::{BlendMode, PipelineKey, ShaderBlock, Vertex};
+use zeta_dom::vulkano_renderer::{RendererOptions, VulkanoRenderer, DEFAULT_MAX_INFLIGHT_UPLOADS};
+
+// Two triangles covering x0..x1, y0..y1 at depth z
+fn quad(x0: f32, y0: f32, x1: f32, y1: f32, z: f32, color: [f32; 4], blend: BlendMode) -> ShaderBlock {
+    let corners = [[x0, y0], [x1, y0], [x0, y1], [x1, y0], [x1, y1], [x0, y1]];
+    ShaderBlock {
+        vertices: corners.iter().map(|&[x, y]| Vertex { position: [x, y, z], ..Vertex::default() }).collect(),
+        material_data: color.to_vec(),
+        pipeline: PipelineKey { blend, ..PipelineKey::default() },
+        ..ShaderBlock::default()
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let event_loop = EventLoop::new();
+    let window = WindowBuilder::new().with_title("zeta-DOM").build(&event_loop)?;
+    let renderer = VulkanoRenderer::create(window, RendererOptions::default())?;
+
+    // The transparent quads come first and are nearer, they still blend over the opaque one behind them
+    let blocks = vec![
+        quad(-0.8, -0.8, 0.2, 0.2, 0.2, [1.0, 0.0, 0.0, 0.5], BlendMode::AlphaBlend),
+        quad(-0.2, -0.2, 0.8, 0.8, 0.3, [0.0, 1.0, 0.0, 0.5], BlendMode::AlphaBlend),
+        quad(-0.5, -0.5, 0.5, 0.5, 0.6, [0.0, 0.0, 1.0, 1.0], BlendMode::Opaque),
+    ];
+    renderer.stream_blocks(blocks, DEFAULT_MAX_INFLIGHT_UPLOADS, |_| {})?;
+
+    renderer.run(event_loop)?;
+    Ok(())
+}
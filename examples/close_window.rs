@@ -0,0 +1,33 @@
+// Opens a window that renders until it is closed, or until the given number of seconds has passed and
+// another thread stops it through the RenderControl handle. Either way run waits for the frames in
+// flight before returning, so the renderer and window are dropped without validation errors.
+//
+//     cargo run --example close_window -- 5
+
+use std::error::Error;
+use std::time::Duration;
+
+use winit::event_loop::EventLoop;
+use winit::window::WindowBuilder;
+use zeta_dom::vulkano_renderer::{RendererOptions, VulkanoRenderer};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let timeout = std::env::args().nth(1).map(|secs| secs.parse::<u64>()).transpose()?;
+
+    let event_loop = EventLoop::new();
+    let window = WindowBuilder::new().with_title("zeta-DOM").build(&event_loop)?;
+    let renderer = VulkanoRenderer::create(window, RendererOptions::default())?;
+    renderer.apply_vertex_data(&[0.0, -0.5, 0.5, 0.5, 0.5, 0.5, -0.5, 0.5, 0.5], &[1.0, 0.5, 0.0, 1.0])?;
+
+    if let Some(secs) = timeout {
+        let control = renderer.control();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(secs));
+            control.stop();
+        });
+    }
+
+    renderer.run(event_loop)?;
+    println!("closed cleanly");
+    Ok(())
+}
@@ -0,0 +1,40 @@
+// Renders the same offscreen scene with one, two and three frames in flight and prints the frame rate
+// of each, so the gain from letting the CPU record ahead of the GPU can be compared
+//
+//     cargo run --release --example frames_in_flight
+
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use zeta_dom::vulkano_renderer::{RendererOptions, VulkanoRenderer};
+
+const RUN_FOR: Duration = Duration::from_secs(3);
+
+fn main() -> Result<(), Box<dyn Error>> {
+    for frames in 1..=3 {
+        // Offscreen frames take the images in turn, so there have to be as many images as frames in flight
+        let options = RendererOptions { desired_image_count: Some(3), ..RendererOptions::default() };
+        let mut renderer = VulkanoRenderer::create_headless([1920, 1080], options)?.frames_in_flight(frames);
+
+        // Enough small triangles that recording and drawing a frame both take a while
+        for row in 0..100 {
+            let vertices: Vec<f32> = (0..200)
+                .flat_map(|column| {
+                    let (x, y) = (column as f32 / 100.0 - 1.0, row as f32 / 50.0 - 1.0);
+                    [x, y, 0.5, x + 0.01, y, 0.5, x, y + 0.02, 0.5]
+                })
+                .collect();
+            renderer.apply_vertex_data(&vertices, &[0.2, 0.6, 1.0, 1.0])?;
+        }
+
+        let start = Instant::now();
+        let mut rendered = 0u32;
+        renderer.render_loop(|| {
+            rendered += 1;
+            start.elapsed() < RUN_FOR
+        })?;
+        let fps = (rendered - 1) as f64 / start.elapsed().as_secs_f64();
+        println!("{} frame(s) in flight: {:.0} fps", frames, fps);
+    }
+    Ok(())
+}
@@ -0,0 +1,24 @@
+// Loads the frames of a video_metrics database and renders them in a window
+//
+//     cargo run --example load_metrics -- metrics.db
+
+use std::error::Error;
+
+use winit::event_loop::EventLoop;
+use winit::window::WindowBuilder;
+use zeta_dom::vulkano_renderer::{RendererOptions, VulkanoRenderer};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let db_path = std::env::args().nth(1).unwrap_or_else(|| "metrics.db".to_string());
+
+    let event_loop = EventLoop::new();
+    let window = WindowBuilder::new().with_title("zeta-DOM").build(&event_loop)?;
+    let renderer = VulkanoRenderer::create(window, RendererOptions::default())?;
+
+    // A missing database, one without frames and running out of GPU memory are reported as errors
+    let report = renderer.load_vertex_data(&db_path)?;
+    println!("loaded {}", report);
+
+    renderer.run(event_loop)?;
+    Ok(())
+}
@@ -0,0 +1,87 @@
+// Draws a grid of quads and tints the one under the cursor yellow on every click, restoring the color of
+// the one tinted before
+//
+//     cargo run --example pick
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use winit::event::{ElementState, Event, MouseButton, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::platform::run_return::EventLoopExtRunReturn;
+use winit::window::WindowBuilder;
+use zeta_dom::db_ingestor::{ShaderBlock, Vertex};
+use zeta_dom::vulkano_renderer::{BlockId, RendererOptions, VulkanoRenderer, DEFAULT_MAX_INFLIGHT_UPLOADS};
+
+const TINT: [f32; 4] = [1.0, 1.0, 0.0, 1.0];
+
+// One quad per cell of a size x size grid, with a gap between cells and a color of its own
+fn grid(size: usize) -> Vec<ShaderBlock> {
+    let cell = 2.0 / size as f32;
+    (0..size * size)
+        .map(|i| {
+            let (column, row) = (i % size, i / size);
+            let (x0, y0) = (column as f32 * cell - 1.0, row as f32 * cell - 1.0);
+            let (x1, y1) = (x0 + cell * 0.9, y0 + cell * 0.9);
+            let corners = [[x0, y0], [x1, y0], [x0, y1], [x1, y0], [x1, y1], [x0, y1]];
+            ShaderBlock {
+                vertices: corners.iter().map(|&[x, y]| Vertex { position: [x, y, 0.5], ..Vertex::default() }).collect(),
+                material_data: vec![column as f32 / size as f32, 0.3, row as f32 / size as f32, 1.0],
+                ..ShaderBlock::default()
+            }
+        })
+        .collect()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut event_loop = EventLoop::new();
+    let window = WindowBuilder::new().with_title("zeta-DOM").build(&event_loop)?;
+    let mut renderer = VulkanoRenderer::create(window, RendererOptions::default())?;
+
+    // Block ids are slots that removed blocks give back, so materials are looked up by id, not position
+    let blocks = grid(8);
+    let block_ids = renderer.stream_blocks(blocks.clone(), DEFAULT_MAX_INFLIGHT_UPLOADS, |_| {})?;
+    let materials: HashMap<BlockId, Vec<f32>> =
+        block_ids.into_iter().zip(blocks.into_iter().map(|block| block.material_data)).collect();
+
+    let mut cursor = (0.0, 0.0);
+    let mut tinted: Option<BlockId> = None;
+    let mut result = Ok(());
+    event_loop.run_return(|event, _, control_flow| {
+        *control_flow = ControlFlow::Poll;
+        let outcome = match event {
+            Event::WindowEvent { event: WindowEvent::CursorMoved { position, .. }, .. } => {
+                cursor = (position.x as f32, position.y as f32);
+                Ok(())
+            }
+            Event::WindowEvent { event: WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Left, .. }, .. } => {
+                let picked = renderer.pick(cursor);
+                println!("picked {:?}", picked);
+                let restored = match tinted.take() {
+                    Some(id) => renderer.update_block_materials(id, &materials[&id]),
+                    None => Ok(()),
+                };
+                restored.and_then(|()| match picked {
+                    Some(id) => {
+                        tinted = Some(id);
+                        renderer.update_block_materials(id, &TINT)
+                    }
+                    None => Ok(()),
+                })
+            }
+            Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => {
+                *control_flow = ControlFlow::Exit;
+                Ok(())
+            }
+            Event::MainEventsCleared => renderer.render_once(),
+            _ => Ok(()),
+        };
+        if let Err(e) = outcome {
+            result = Err(e);
+            *control_flow = ControlFlow::Exit;
+        }
+    });
+
+    renderer.wait_idle();
+    Ok(result?)
+}
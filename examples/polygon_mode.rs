@@ -0,0 +1,58 @@
+// Renders a database and cycles between filled, wireframe and point rendering with the space bar, e.g. to
+// see how the frames were partitioned into triangles
+//
+//     cargo run --example polygon_mode -- metrics.db
+
+use std::error::Error;
+
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::platform::run_return::EventLoopExtRunReturn;
+use winit::window::WindowBuilder;
+use zeta_dom::vulkano_renderer::{PolygonMode, RendererOptions, VulkanoRenderer};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let db_path = std::env::args().nth(1).unwrap_or_else(|| "metrics.db".to_string());
+
+    let mut event_loop = EventLoop::new();
+    let window = WindowBuilder::new().with_title("zeta-DOM").build(&event_loop)?;
+    let mut renderer = VulkanoRenderer::create(window, RendererOptions::default())?;
+    println!("loaded {}", renderer.load_vertex_data(&db_path)?);
+    println!("press space to switch between filled, wireframe and point rendering");
+
+    let mut result = Ok(());
+    event_loop.run_return(|event, _, control_flow| {
+        *control_flow = ControlFlow::Poll;
+        match event {
+            Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => *control_flow = ControlFlow::Exit,
+            Event::WindowEvent {
+                event: WindowEvent::KeyboardInput {
+                    input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::Space), .. },
+                    ..
+                },
+                ..
+            } => {
+                let next = match renderer.polygon_mode() {
+                    PolygonMode::Fill => PolygonMode::Line,
+                    PolygonMode::Line => PolygonMode::Point,
+                    PolygonMode::Point => PolygonMode::Fill,
+                };
+                // Line and Point need the fillModeNonSolid feature, the mode stays as it was without it
+                match renderer.set_polygon_mode(next) {
+                    Ok(()) => println!("{:?}", next),
+                    Err(e) => println!("cannot switch to {:?}: {}", next, e),
+                }
+            }
+            Event::MainEventsCleared => {
+                if let Err(e) = renderer.render_once() {
+                    result = Err(e);
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+            _ => {}
+        }
+    });
+
+    renderer.wait_idle();
+    Ok(result?)
+}
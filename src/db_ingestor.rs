@@ -1,149 +1,2249 @@
-use rusqlite::{Connection, Result};
+use rusqlite::types::{Type, Value};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Result, Row, TransactionBehavior};
+use serde::ser::{SerializeMap, SerializeSeq, Serializer};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::str::FromStr;
 
+use crate::shader_partition_compressor;
+
+// Layout of a single vertex as consumed by the vertex shader, also read and written as eight floats by the preprocess pass
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Vertex {
+    pub position: [f32; 3],
+    #[serde(default)]
+    pub normal: [f32; 3], // Zero when unknown, the lit shader then shades the face flat
+    #[serde(default)]
+    pub uv: [f32; 2], // Texture coordinates, (0, 0) is the top-left texel
+}
+
+impl Vertex {
+    // Groups flat floats into vertices, fields missing from the layout are zero
+    pub fn from_raw_f32(data: &[f32], layout: VertexLayout) -> Result<Vec<Vertex>, VertexLayoutError> {
+        let stride = layout.stride();
+        if data.len() % stride != 0 {
+            return Err(VertexLayoutError { len: data.len(), stride });
+        }
+        let vertices = data.chunks_exact(stride).map(|v| match layout {
+            VertexLayout::Position => Vertex { position: [v[0], v[1], v[2]], ..Vertex::default() },
+            VertexLayout::PositionUv => Vertex { position: [v[0], v[1], v[2]], uv: [v[3], v[4]], ..Vertex::default() },
+            VertexLayout::PositionNormalUv => Vertex { position: [v[0], v[1], v[2]], normal: [v[3], v[4], v[5]], uv: [v[6], v[7]] },
+        });
+        Ok(vertices.collect())
+    }
+
+    // Flattens vertices into the floats from_raw_f32 reads back, dropping fields the layout lacks
+    pub fn to_raw_f32(vertices: &[Vertex], layout: VertexLayout) -> Vec<f32> {
+        let mut data = Vec::with_capacity(vertices.len() * layout.stride());
+        for vertex in vertices {
+            data.extend_from_slice(&vertex.position);
+            if layout == VertexLayout::PositionNormalUv {
+                data.extend_from_slice(&vertex.normal);
+            }
+            if layout != VertexLayout::Position {
+                data.extend_from_slice(&vertex.uv);
+            }
+        }
+        data
+    }
+}
+
+// How vertices are laid out in flat float arrays, such as the vertex_data column of the video_metrics table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VertexLayout {
+    Position,         // x, y, z, what every database written before normals and UVs holds
+    PositionUv,       // x, y, z, u, v
+    PositionNormalUv, // Every Vertex field in declaration order
+}
+
+// Position-only databases keep ingesting as they always have, normals and UVs are opt-in
+impl Default for VertexLayout {
+    fn default() -> Self {
+        VertexLayout::Position
+    }
+}
+
+impl VertexLayout {
+    // Floats per vertex
+    pub fn stride(self) -> usize {
+        match self {
+            VertexLayout::Position => 3,
+            VertexLayout::PositionUv => 5,
+            VertexLayout::PositionNormalUv => 8,
+        }
+    }
+
+    // Name recorded in the schema_meta table
+    fn name(self) -> &'static str {
+        match self {
+            VertexLayout::Position => "position",
+            VertexLayout::PositionUv => "position_uv",
+            VertexLayout::PositionNormalUv => "position_normal_uv",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [VertexLayout::Position, VertexLayout::PositionUv, VertexLayout::PositionNormalUv].iter().copied().find(|layout| layout.name() == name)
+    }
+}
+
+// A flat float array that does not hold a whole number of vertices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VertexLayoutError {
+    pub len: usize,    // Number of floats
+    pub stride: usize, // Floats per vertex in the expected layout
+}
+
+impl fmt::Display for VertexLayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} floats are not a whole number of {}-float vertices", self.len, self.stride)
+    }
+}
+
+impl Error for VertexLayoutError {}
+
+// Column-major 4x4 model matrix applied to a block's vertices
+pub type Transform = [[f32; 4]; 4];
+
+pub const IDENTITY_TRANSFORM: Transform = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+// How a block's fragments are combined with what is already in the color attachment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BlendMode {
+    Opaque,     // Overwrites the color, drawn before blended blocks
+    AlphaBlend, // Mixes by the fragment's alpha, tested against but not written to the depth buffer
+}
+
+// Which triangle faces are discarded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CullMode {
+    None,
+    Back,
+    Front,
+}
+
+// Shader pair a block is drawn with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ShaderKind {
+    Unlit, // Material color times texture
+    Lit,   // Unlit color shaded by a fixed directional light
+}
+
+// Selects the graphics pipeline a block is drawn with, pipelines are created on first use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PipelineKey {
+    pub blend: BlendMode,
+    pub cull: CullMode,
+    pub shaders: ShaderKind,
+}
+
+impl Default for PipelineKey {
+    // The pipeline every renderer is built with
+    fn default() -> Self {
+        Self { blend: BlendMode::Opaque, cull: CullMode::None, shaders: ShaderKind::Unlit }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PartitionedData {
     pub blocks: Vec<ShaderBlock>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// A block of geometry uploaded to the renderer as one vertex buffer and one material buffer
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ShaderBlock {
-    pub vertex_data: Vec<f32>,
-    pub material_data: Vec<f32>,
+    pub vertices: Vec<Vertex>,   // Position, normal and texture coordinates of every vertex
+    pub material_data: Vec<f32>, // Material shared by every vertex: base color (r, g, b, a), roughness, metallic
+    #[serde(default)]
+    pub indices: Vec<u32>,       // Optional triangle indices into vertices, empty draws vertices in order
+    #[serde(default)]
+    pub texture_id: Option<u32>, // Texture loaded by the renderer, None or an unknown id samples plain white
+    #[serde(default)]
+    pub pipeline: PipelineKey,   // Blend, cull and shader selection, opaque and unlit unless given
+    #[serde(default)]
+    pub instances: Vec<Transform>, // Model transform of every copy drawn in one instanced draw, empty draws one
 }
 
 // Define the structure to hold frame metrics
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FrameData {
     pub frame_number: u32,
-    pub vertex_data: Vec<f32>,  // Vertex data to be passed to shaders
+    pub vertices: Vec<Vertex>,  // Vertex data to be passed to shaders
     pub material_data: Vec<f32>, // Material properties (e.g., colors)
 }
 
+impl FrameData {
+    // Blend of self towards other by s in 0..1, numbered after the nearer frame. Frames whose vertex or
+    // material lengths differ do not line up, the nearer one is returned unblended.
+    pub fn lerp(&self, other: &FrameData, s: f32) -> FrameData {
+        let nearer = if s < 0.5 { self } else { other };
+        if self.vertices.len() != other.vertices.len() || self.material_data.len() != other.material_data.len() {
+            return nearer.clone();
+        }
+        FrameData {
+            frame_number: nearer.frame_number,
+            vertices: blend(&self.vertices, &other.vertices, s, lerp_vertex),
+            material_data: blend(&self.material_data, &other.material_data, s, lerp),
+        }
+    }
+}
+
 // Define the structure for video metrics
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VideoMetrics {
     pub frame_data: Vec<FrameData>,
 }
 
+impl VideoMetrics {
+    // Frame at t, measured in frame numbers, blended linearly from the nearest frames before and after it,
+    // e.g. t = 10.5 halfway between frames 10 and 11 to play a 30fps capture at 60Hz. Frames need not be
+    // sorted. None when t lies outside the captured frame numbers.
+    //
+    // When the two frames differ in length, the result takes the length of the frame nearer to t and
+    // copies the elements only that frame has without blending. The blended frame is numbered after the
+    // nearer frame too.
+    pub fn interpolated_frame(&self, t: f32) -> Option<FrameData> {
+        if !t.is_finite() {
+            return None;
+        }
+        let before = self.frame_data.iter().filter(|f| f.frame_number as f32 <= t).max_by_key(|f| f.frame_number)?;
+        let after = self.frame_data.iter().filter(|f| f.frame_number as f32 >= t).min_by_key(|f| f.frame_number)?;
+        if before.frame_number == after.frame_number {
+            return Some(before.clone());
+        }
+
+        let s = (t - before.frame_number as f32) / (after.frame_number - before.frame_number) as f32;
+        let nearer = if s < 0.5 { before } else { after };
+        Some(FrameData {
+            frame_number: nearer.frame_number,
+            vertices: blend(&before.vertices, &after.vertices, s, lerp_vertex),
+            material_data: blend(&before.material_data, &after.material_data, s, lerp),
+        })
+    }
+
+    // Pretty-printed JSON in the same shape serde reads back
+    pub fn write_json<W: Write>(&self, w: W) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(w, self)?;
+        Ok(())
+    }
+
+    // One row per frame: frame_number, then position, normal and uv of every vertex (v0_px .. v0_v, v1_px ..)
+    // and the material floats (m0, m1 ..). The header covers the frame with the most vertices and the one
+    // with the most material floats, shorter frames leave the remaining cells empty.
+    pub fn write_csv<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        const VERTEX_COLUMNS: [&str; 8] = ["px", "py", "pz", "nx", "ny", "nz", "u", "v"];
+        let max_vertices = self.frame_data.iter().map(|f| f.vertices.len()).max().unwrap_or(0);
+        let max_material = self.frame_data.iter().map(|f| f.material_data.len()).max().unwrap_or(0);
+
+        let mut header = vec!["frame_number".to_string()];
+        for i in 0..max_vertices {
+            header.extend(VERTEX_COLUMNS.iter().map(|c| format!("v{}_{}", i, c)));
+        }
+        header.extend((0..max_material).map(|i| format!("m{}", i)));
+        writeln!(w, "{}", header.join(","))?;
+
+        for frame in &self.frame_data {
+            let mut row = vec![frame.frame_number.to_string()];
+            for i in 0..max_vertices {
+                match frame.vertices.get(i) {
+                    Some(v) => row.extend(v.position.iter().chain(&v.normal).chain(&v.uv).map(f32::to_string)),
+                    None => row.extend(std::iter::repeat(String::new()).take(VERTEX_COLUMNS.len())),
+                }
+            }
+            row.extend((0..max_material).map(|i| frame.material_data.get(i).map_or(String::new(), f32::to_string)));
+            writeln!(w, "{}", row.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+fn lerp(a: f32, b: f32, s: f32) -> f32 {
+    a + (b - a) * s
+}
+
+fn lerp_vertex(a: Vertex, b: Vertex, s: f32) -> Vertex {
+    let mut normal = [0.0; 3];
+    for i in 0..3 {
+        normal[i] = lerp(a.normal[i], b.normal[i], s);
+    }
+    // Blending opposite normals can cancel out, a zero normal is left for the shader to ignore
+    let len = normal.iter().map(|n| n * n).sum::<f32>().sqrt();
+    if len > 0.0 {
+        normal.iter_mut().for_each(|n| *n /= len);
+    }
+    Vertex {
+        position: [lerp(a.position[0], b.position[0], s), lerp(a.position[1], b.position[1], s), lerp(a.position[2], b.position[2], s)],
+        normal,
+        uv: [lerp(a.uv[0], b.uv[0], s), lerp(a.uv[1], b.uv[1], s)],
+    }
+}
+
+// Blends a towards b by s, with the length of the nearer one, see VideoMetrics::interpolated_frame
+fn blend<T: Copy>(a: &[T], b: &[T], s: f32, lerp: impl Fn(T, T, f32) -> T) -> Vec<T> {
+    let nearer = if s < 0.5 { a } else { b };
+    (0..nearer.len())
+        .map(|i| match (a.get(i), b.get(i)) {
+            (Some(&a), Some(&b)) => lerp(a, b, s),
+            _ => nearer[i],
+        })
+        .collect()
+}
+
+// Measured cost of rendering a single frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTiming {
+    pub frame_number: u32,
+    pub gpu_micros: u64, // Time the GPU spent executing the frame's commands
+    pub cpu_micros: u64, // Time the CPU spent recording and submitting the frame
+}
+
+// How vertex and material floats are stored in the video_metrics table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageFormat {
+    Csv,  // Comma-separated text, readable by hand but large and lossy for malformed values
+    Blob, // Little-endian f32s, about 4x smaller and lossless
+    Compressed, // Each frame as a block from shader_partition_compressor::compress_block in vertex_data, material_data left empty
+}
+
+impl Default for StorageFormat {
+    fn default() -> Self {
+        StorageFormat::Csv // Existing databases were written as CSV
+    }
+}
+
+impl StorageFormat {
+    // Name recorded in the schema_meta table
+    fn name(self) -> &'static str {
+        match self {
+            StorageFormat::Csv => "csv",
+            StorageFormat::Blob => "blob",
+            StorageFormat::Compressed => "compressed",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [StorageFormat::Csv, StorageFormat::Blob, StorageFormat::Compressed].iter().copied().find(|format| format.name() == name)
+    }
+
+    // SQLite column type used when this format creates the video_metrics table
+    fn column_type(self) -> &'static str {
+        match self {
+            StorageFormat::Csv => "TEXT",
+            StorageFormat::Blob | StorageFormat::Compressed => "BLOB",
+        }
+    }
+
+    fn encode(self, data: &[f32]) -> Value {
+        match self {
+            StorageFormat::Csv => Value::Text(to_csv(data)),
+            StorageFormat::Blob | StorageFormat::Compressed => Value::Blob(to_blob(data)),
+        }
+    }
+
+    // vertex_data and material_data values of frame, raw vertices are written in layout
+    fn encode_frame(self, frame: &FrameData, layout: VertexLayout) -> (Value, Value) {
+        match self {
+            // Whole vertices are kept, so normals and UVs survive whatever the layout
+            StorageFormat::Compressed => {
+                let block = ShaderBlock { vertices: frame.vertices.clone(), material_data: frame.material_data.clone(), ..ShaderBlock::default() };
+                (Value::Blob(shader_partition_compressor::compress_block(&block)), Value::Blob(Vec::new()))
+            }
+            _ => (self.encode(&Vertex::to_raw_f32(&frame.vertices, layout)), self.encode(&frame.material_data)),
+        }
+    }
+
+    // Type reported in conversion errors of values in this format
+    fn value_type(self) -> Type {
+        match self {
+            StorageFormat::Csv => Type::Text,
+            StorageFormat::Blob | StorageFormat::Compressed => Type::Blob,
+        }
+    }
+
+    fn decode(self, row: &Row, column: usize) -> Result<Vec<f32>> {
+        match self {
+            StorageFormat::Csv => parse_csv_checked(&row.get::<_, String>(column)?)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(column, Type::Text, Box::new(e))),
+            StorageFormat::Blob | StorageFormat::Compressed => Ok(parse_blob(&row.get::<_, Vec<u8>>(column)?)),
+        }
+    }
+}
+
+// Pipeline stage a SPIR-V module in the shaders table is written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Compute,
+}
+
+impl ShaderStage {
+    // Suffix of the row names of this stage, the same extensions glslc uses for the sources
+    fn suffix(self) -> &'static str {
+        match self {
+            ShaderStage::Vertex => "vert",
+            ShaderStage::Fragment => "frag",
+            ShaderStage::Compute => "comp",
+        }
+    }
+
+    // Row name of the module of this stage in shader name, e.g. basic.vert
+    fn row_name(self, name: &str) -> String {
+        format!("{}.{}", name, self.suffix())
+    }
+}
+
+// A shaders table blob that is not a SPIR-V module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSpirvError {
+    pub name: String, // Row name of the module
+    pub len: usize,   // Length of the blob in bytes
+}
+
+impl fmt::Display for InvalidSpirvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "shader {} is not a SPIR-V module ({} bytes)", self.name, self.len)
+    }
+}
+
+impl Error for InvalidSpirvError {}
+
+// SPIR-V modules are a whole number of words starting with the magic number, in either byte order
+pub(crate) fn is_spirv(bytes: &[u8]) -> bool {
+    const MAGIC: u32 = 0x0723_0203;
+    if bytes.len() < 20 || bytes.len() % 4 != 0 {
+        return false; // Shorter than the module header
+    }
+    let first = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    u32::from_le_bytes(first) == MAGIC || u32::from_be_bytes(first) == MAGIC
+}
+
+// Fails with InvalidSpirvError unless spirv is a whole number of words starting with the SPIR-V magic number
+fn check_spirv(name: String, spirv: Vec<u8>) -> Result<Vec<u8>> {
+    if is_spirv(&spirv) {
+        Ok(spirv)
+    } else {
+        let e = InvalidSpirvError { name, len: spirv.len() };
+        Err(rusqlite::Error::FromSqlConversionFailure(0, Type::Blob, Box::new(e)))
+    }
+}
+
+// Connection settings applied by DatabaseManager::open_with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DbOptions {
+    pub wal: bool, // Write-ahead logging, lets readers continue while a write is in progress. Ignored for :memory:
+    pub busy_timeout: Duration, // How long to retry on a locked database before failing with SQLITE_BUSY
+    pub foreign_keys: bool, // Enforce FOREIGN KEY constraints, off by default in SQLite
+}
+
+impl Default for DbOptions {
+    // What new has always opened with, rusqlite's connections retry for 5 seconds on a locked database
+    fn default() -> Self {
+        Self { wal: false, busy_timeout: Duration::from_secs(5), foreign_keys: false }
+    }
+}
+
 // Define a struct for managing database connections and caching
 pub struct DatabaseManager {
     conn: Mutex<Connection>, // Mutex for exclusive access to the connection
     schema_cache: Arc<Mutex<SQLiteAttributeCache>>, // Shared schema cache
+    storage_format: StorageFormat, // Format of the vertex_data and material_data columns
+    vertex_layout: VertexLayout, // Floats per vertex in the vertex_data column
 }
 
 impl DatabaseManager {
     // Create a new DatabaseManager
     pub fn new(db_path: &str) -> Result<Self> {
-        let conn = Connection::open(db_path)?;
+        Self::open_with(db_path, DbOptions::default())
+    }
+
+    // Create a DatabaseManager whose connection is configured with options, e.g. WAL and a busy timeout
+    // for ingesting while another process reads
+    pub fn open_with(db_path: &str, options: DbOptions) -> Result<Self> {
+        Self::configure(Connection::open(db_path)?, options)
+    }
+
+    // Like new, but fails instead of creating an empty database when there is none at db_path. Frames are
+    // read in the format and vertex layout the database was written with, see stored_storage.
+    pub fn open_existing(db_path: &str) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            db_path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        let db = Self::configure(conn, DbOptions::default())?;
+        let (format, layout) = db.stored_storage()?;
+        Ok(db.with_storage_format(format).with_vertex_layout(layout))
+    }
+
+    // Format and vertex layout video_metrics was written with, as recorded in schema_meta. Databases from
+    // before they were recorded hold positions only, their format is told by the declared type of the
+    // vertex_data column. Both are the defaults without a video_metrics table.
+    fn stored_storage(&self) -> Result<(StorageFormat, VertexLayout)> {
+        let conn = self.conn.lock().unwrap();
+        let mut schema_cache = self.schema_cache.lock().unwrap();
+        let (mut format, mut layout) = (None, None);
+        if !schema_cache.get_columns(&conn, "schema_meta")?.is_empty() {
+            if let Some(name) = read_meta(&conn, "storage_format")? {
+                format = Some(StorageFormat::from_name(&name)
+                    .ok_or_else(|| schema_error(format!("video_metrics is stored as {}, which is not a known format", name)))?);
+            }
+            if let Some(name) = read_meta(&conn, "vertex_layout")? {
+                layout = Some(VertexLayout::from_name(&name)
+                    .ok_or_else(|| schema_error(format!("video_metrics holds {} vertices, which is not a known layout", name)))?);
+            }
+        }
+        let format = match format {
+            Some(format) => format,
+            None => {
+                let columns = schema_cache.get_columns(&conn, "video_metrics")?;
+                match columns.iter().find(|c| c.name == "vertex_data") {
+                    Some(c) if c.decl_type.eq_ignore_ascii_case("BLOB") => StorageFormat::Blob,
+                    _ => StorageFormat::default(),
+                }
+            }
+        };
+        Ok((format, layout.unwrap_or_default()))
+    }
+
+    fn configure(conn: Connection, options: DbOptions) -> Result<Self> {
+        if options.wal {
+            // Returns the mode in effect, which stays "memory" for in-memory databases
+            conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get::<_, String>(0))?;
+        }
+        conn.busy_timeout(options.busy_timeout)?;
+        conn.pragma_update(None, "foreign_keys", &options.foreign_keys)?;
         let schema_cache = Arc::new(Mutex::new(SQLiteAttributeCache::new()));
         
-        Ok(Self { conn: Mutex::new(conn), schema_cache })
+        Ok(Self { conn: Mutex::new(conn), schema_cache, storage_format: StorageFormat::default(), vertex_layout: VertexLayout::default() })
+    }
+
+    // Read and write vertex and material floats in the given format
+    pub fn with_storage_format(mut self, format: StorageFormat) -> Self {
+        self.storage_format = format;
+        self
+    }
+
+    // Read and write vertex_data with the given floats per vertex, e.g. VertexLayout::PositionNormalUv
+    // to keep normals and UVs, the default stores positions only
+    pub fn with_vertex_layout(mut self, layout: VertexLayout) -> Self {
+        self.vertex_layout = layout;
+        self
     }
 
     // Ingest video metrics in a thread-safe manner
     pub fn ingest_video_metrics(&self) -> Result<VideoMetrics> {
         let conn = self.conn.lock().unwrap(); // Lock the connection for exclusive access
-        
-        let mut stmt = conn.prepare("SELECT frame_number, vertex_data, material_data FROM video_metrics")?;
-        
-        let metrics_iter = stmt.query_map([], |row| {
-            let vertex_data: String = row.get(1)?; // Assuming vertex_data is stored as a CSV string
-            let material_data: String = row.get(2)?; // Assuming material_data is stored as a CSV string
-
-            Ok(FrameData {
-                frame_number: row.get(0)?,
-                vertex_data: parse_csv(&vertex_data),  // Parse CSV to Vec<f32>
-                material_data: parse_csv(&material_data), // Parse CSV to Vec<f32>
-            })
-        })?;
+        require_columns(&self.schema_cache, &conn, "video_metrics", &VIDEO_METRICS_COLUMNS)?;
+
+        read_video_metrics(&conn, self.storage_format, self.vertex_layout)
+    }
+
+    // Like ingest_video_metrics, but yields frames as they are read instead of collecting them
+    // all first, for captures too long to hold in memory at once
+    pub fn ingest_video_metrics_iter(&self) -> VideoMetricsIter<'_> {
+        VideoMetricsIter { db: self, last_rowid: None, batch: VecDeque::new(), done: false }
+    }
+
+    // ingest_video_metrics_iter as a Stream. Each batch is still read synchronously when polled,
+    // so poll it from a blocking-friendly task if the database is slow.
+    #[cfg(feature = "futures")]
+    pub fn ingest_video_metrics_stream(&self) -> impl futures::Stream<Item = Result<FrameData>> + '_ {
+        futures::stream::iter(self.ingest_video_metrics_iter())
+    }
+
+    // Store every frame of metrics in one transaction, creating the video_metrics table if missing.
+    // Returns the number of rows written.
+    pub fn store_video_metrics(&self, metrics: &VideoMetrics) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap(); // Lock the connection for exclusive access
+        write_video_metrics(&mut conn, metrics, self.storage_format, self.vertex_layout)
+    }
+
+    // Record the measured timing of a frame, creating the frame_timings table if missing. Whether it exists
+    // is looked up in schema_cache, so only the first write to a database runs the CREATE TABLE.
+    pub fn record_frame_timing(&self, frame_number: u32, gpu_micros: u64, cpu_micros: u64) -> Result<()> {
+        let conn = self.conn.lock().unwrap(); // Lock the connection for exclusive access
+
+        if self.schema_cache.lock().unwrap().get_columns(&conn, "frame_timings")?.is_empty() {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS frame_timings (
+                    frame_number INTEGER NOT NULL,
+                    gpu_micros INTEGER NOT NULL,
+                    cpu_micros INTEGER NOT NULL
+                )",
+                [],
+            )?;
+        }
+        conn.prepare_cached("INSERT INTO frame_timings (frame_number, gpu_micros, cpu_micros) VALUES (?1, ?2, ?3)")?
+            .execute(params![frame_number, gpu_micros as i64, cpu_micros as i64])?;
+        Ok(())
+    }
+
+    // Drop every cached prepared statement and table schema, call after altering tables outside this
+    // manager so the next queries are built against the new schema
+    pub fn clear_statement_cache(&self) {
+        self.conn.lock().unwrap().flush_prepared_statement_cache();
+        self.schema_cache.lock().unwrap().clear();
+    }
 
-        let frame_data: Vec<FrameData> = metrics_iter.collect::<Result<Vec<_>, _>>()?;
-        Ok(VideoMetrics { frame_data })
+    // Create the tables this manager uses if they are missing and bring older databases up to
+    // SCHEMA_VERSION. Fails with SQLITE_SCHEMA and a description when an existing table cannot be used,
+    // e.g. video_metrics written in another StorageFormat, or when the database is newer than this code.
+    pub fn ensure_schema(&self) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap(); // Lock the connection for exclusive access
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)", [])?;
+        let version: Option<usize> = tx
+            .query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get::<_, Option<i64>>(0))?
+            .map(|v| v as usize);
+        let version = version.unwrap_or(0);
+        if version > SCHEMA_VERSION {
+            return Err(schema_error(format!(
+                "database schema version {} is newer than the supported version {}",
+                version, SCHEMA_VERSION
+            )));
+        }
+        for migration in &MIGRATIONS[version..] {
+            migration(&tx, self.storage_format, self.vertex_layout)?;
+        }
+        if version < SCHEMA_VERSION {
+            tx.execute("DELETE FROM schema_version", [])?;
+            tx.execute("INSERT INTO schema_version (version) VALUES (?1)", params![SCHEMA_VERSION as i64])?;
+        }
+
+        // Tables that existed before the first migration may have any shape
+        let mut schema_cache = self.schema_cache.lock().unwrap();
+        let data_type = self.storage_format.column_type();
+        for (table, expected) in &[
+            ("video_metrics", [("frame_number", "INTEGER"), ("vertex_data", data_type), ("material_data", data_type)]),
+            ("frame_timings", [("frame_number", "INTEGER"), ("gpu_micros", "INTEGER"), ("cpu_micros", "INTEGER")]),
+        ] {
+            schema_cache.invalidate(table);
+            let columns = schema_cache.get_columns(&tx, table)?;
+            for (name, decl_type) in expected {
+                match columns.iter().find(|c| c.name == *name) {
+                    None => return Err(schema_error(format!("{} has no {} column", table, name))),
+                    Some(c) if !c.decl_type.eq_ignore_ascii_case(decl_type) => {
+                        return Err(schema_error(format!("{}.{} is {} but {} is expected", table, name, c.decl_type, decl_type)));
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        check_storage(&tx, self.storage_format, self.vertex_layout)?;
+        schema_cache.invalidate("shaders");
+        drop(schema_cache);
+        tx.commit()
+    }
+
+    // Spawn a thread that writes timings sent on the returned channel, so the render loop never waits on SQLite.
+    // The thread exits once every sender has been dropped.
+    pub fn spawn_timing_writer(self: Arc<Self>) -> (Sender<FrameTiming>, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel::<FrameTiming>();
+        let handle = thread::spawn(move || {
+            for timing in receiver {
+                if let Err(e) = self.record_frame_timing(timing.frame_number, timing.gpu_micros, timing.cpu_micros) {
+                    log::warn!("Failed to record frame timing: {}", e);
+                }
+            }
+        });
+        (sender, handle)
+    }
+
+    // Read a SPIR-V module stored as a blob in the shaders table, which holds
+    // (name TEXT PRIMARY KEY, spirv BLOB NOT NULL) rows. Rows are named after the shader and its stage,
+    // see get_shader.
+    pub fn load_shader_spirv(&self, name: &str) -> Result<Vec<u8>> {
+        let conn = self.conn.lock().unwrap(); // Lock the connection for exclusive access
+        conn.query_row("SELECT spirv FROM shaders WHERE name = ?1", params![name], |row| row.get(0))
+    }
+
+    // Read the module of one stage of a shader, stored in the row "<name>.<vert|frag|comp>".
+    // Fails if the blob is not a SPIR-V module, so a corrupt row never reaches the driver.
+    pub fn get_shader(&self, name: &str, stage: ShaderStage) -> Result<Vec<u8>> {
+        let row_name = stage.row_name(name);
+        let spirv = self.load_shader_spirv(&row_name)?;
+        check_spirv(row_name, spirv)
+    }
+
+    // Store the module of one stage of a shader, replacing any previous one and creating the shaders table if missing
+    pub fn put_shader(&self, name: &str, stage: ShaderStage, spirv: &[u8]) -> Result<()> {
+        let row_name = stage.row_name(name);
+        let spirv = check_spirv(row_name.clone(), spirv.to_vec())?;
+        let conn = self.conn.lock().unwrap(); // Lock the connection for exclusive access
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS shaders (
+                name TEXT PRIMARY KEY,
+                spirv BLOB NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute("INSERT OR REPLACE INTO shaders (name, spirv) VALUES (?1, ?2)", params![row_name, spirv])?;
+        Ok(())
     }
 
     // Additional methods for writing data can be added here, ensuring exclusive access when needed.
 }
 
-// Helper function to parse CSV string into Vec<f32>
-fn parse_csv(data: &str) -> Vec<f32> {
-    data.split(',')
-        .filter_map(|s| s.trim().parse::<f32>().ok()) // Filter and parse to f32
-        .collect()
+// Version ensure_schema brings databases to, the number of MIGRATIONS
+pub const SCHEMA_VERSION: usize = 2;
+
+// MIGRATIONS[n] takes a database from version n to n + 1. Version 0 is a database that ensure_schema
+// has never seen, possibly with tables written by store_video_metrics or record_frame_timing.
+const MIGRATIONS: [Migration; SCHEMA_VERSION] = [create_tables, record_storage];
+
+// Gets the format and vertex layout new tables are created for
+type Migration = fn(&Connection, StorageFormat, VertexLayout) -> Result<()>;
+
+fn create_tables(conn: &Connection, format: StorageFormat, _layout: VertexLayout) -> Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS video_metrics (
+            frame_number INTEGER NOT NULL,
+            vertex_data {0} NOT NULL,
+            material_data {0} NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS frame_timings (
+            frame_number INTEGER NOT NULL,
+            gpu_micros INTEGER NOT NULL,
+            cpu_micros INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS shaders (
+            name TEXT PRIMARY KEY,
+            spirv BLOB NOT NULL
+        );",
+        format.column_type()
+    ))
 }
 
-// Placeholder for SQLiteAttributeCache
-#[derive(Debug)]
-pub struct SQLiteAttributeCache {
-    // Store attribute data
+// Records the format and vertex layout video_metrics is stored with, unless schema_meta already names them.
+// Blob and compressed tables have the same column types, and the layout cannot be told from the floats,
+// so only these rows tell them apart.
+fn record_storage(conn: &Connection, format: StorageFormat, layout: VertexLayout) -> Result<()> {
+    conn.execute("CREATE TABLE IF NOT EXISTS schema_meta (key TEXT PRIMARY KEY, value TEXT NOT NULL)", [])?;
+    conn.execute("INSERT OR IGNORE INTO schema_meta (key, value) VALUES ('storage_format', ?1)", params![format.name()])?;
+    conn.execute("INSERT OR IGNORE INTO schema_meta (key, value) VALUES ('vertex_layout', ?1)", params![layout.name()])?;
+    Ok(())
 }
 
-impl SQLiteAttributeCache {
-    pub fn new() -> Self {
-        Self {
-            // Initialize cache here
+// Fails with SQLITE_SCHEMA when schema_meta records another format or layout, rows written with them
+// could not be read back with the others
+fn check_storage(conn: &Connection, format: StorageFormat, layout: VertexLayout) -> Result<()> {
+    for (key, expected) in &[("storage_format", format.name()), ("vertex_layout", layout.name())] {
+        match read_meta(conn, key)? {
+            Some(name) if name != *expected => {
+                return Err(schema_error(format!("video_metrics {} is {} but {} is expected", key, name, expected)));
+            }
+            _ => {}
         }
     }
+    Ok(())
+}
 
-    // Methods for cache management can be added here
+// Value of a schema_meta row, None when there is no such row
+fn read_meta(conn: &Connection, key: &str) -> Result<Option<String>> {
+    conn.query_row("SELECT value FROM schema_meta WHERE key = ?1", params![key], |row| row.get(0)).optional()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn schema_error(message: String) -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_SCHEMA), Some(message))
+}
 
-    #[test]
-    fn test_parse_csv() {
-        let csv_data = "1.0,2.0,3.0,4.0";
-        let parsed = parse_csv(csv_data);
-        assert_eq!(parsed, vec![1.0, 2.0, 3.0, 4.0]);
+// Reads every row of video_metrics through conn
+fn read_video_metrics(conn: &Connection, format: StorageFormat, layout: VertexLayout) -> Result<VideoMetrics> {
+    // Compiled once and kept in the connection's statement cache, keyed by the SQL text
+    let mut stmt = conn.prepare_cached("SELECT frame_number, vertex_data, material_data FROM video_metrics")?;
+
+    let metrics_iter = stmt.query_map([], |row| frame_from_row(row, format, layout))?;
+
+    let frame_data: Vec<FrameData> = metrics_iter.collect::<Result<Vec<_>, _>>()?;
+    Ok(VideoMetrics { frame_data })
+}
+
+// Decodes frame_number, vertex_data and material_data from the first three columns of row. With
+// StorageFormat::Compressed vertex_data holds the whole frame and material_data is ignored.
+fn frame_from_row(row: &Row, format: StorageFormat, layout: VertexLayout) -> Result<FrameData> {
+    if format == StorageFormat::Compressed {
+        let block = shader_partition_compressor::decompress_block(&row.get::<_, Vec<u8>>(1)?)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, Type::Blob, Box::new(e)))?;
+        return Ok(FrameData { frame_number: row.get(0)?, vertices: block.vertices, material_data: block.material_data });
     }
+    Ok(FrameData {
+        frame_number: row.get(0)?,
+        vertices: Vertex::from_raw_f32(&format.decode(row, 1)?, layout)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, format.value_type(), Box::new(e)))?,
+        material_data: format.decode(row, 2)?,
+    })
 }
 
+// Rows read from video_metrics per lock of the connection by VideoMetricsIter
+const FRAME_BATCH: usize = 64;
 
+// Lazily reads video_metrics in rowid order, FRAME_BATCH rows at a time. The connection is only
+// locked while a batch is read, so the manager stays usable between calls to next, and rows
+// inserted behind the last one read are still seen.
+pub struct VideoMetricsIter<'a> {
+    db: &'a DatabaseManager,
+    last_rowid: Option<i64>, // None until the columns have been checked
+    batch: VecDeque<Result<FrameData>>,
+    done: bool,
+}
 
+impl VideoMetricsIter<'_> {
+    fn fetch(&mut self) -> Result<()> {
+        let conn = self.db.conn.lock().unwrap();
+        let last_rowid = match self.last_rowid {
+            Some(rowid) => rowid,
+            None => {
+                require_columns(&self.db.schema_cache, &conn, "video_metrics", &VIDEO_METRICS_COLUMNS)?;
+                i64::MIN
+            }
+        };
+        let mut stmt = conn.prepare_cached(
+            "SELECT frame_number, vertex_data, material_data, rowid FROM video_metrics
+             WHERE rowid > ?1 ORDER BY rowid LIMIT ?2",
+        )?;
+        let mut rows = stmt.query(params![last_rowid, FRAME_BATCH as i64])?;
+        let mut next_rowid = last_rowid;
+        while let Some(row) = rows.next()? {
+            next_rowid = row.get(3)?;
+            self.batch.push_back(frame_from_row(row, self.db.storage_format, self.db.vertex_layout));
+        }
+        self.last_rowid = Some(next_rowid);
+        self.done = self.batch.len() < FRAME_BATCH;
+        Ok(())
+    }
+}
 
-#[derive(Debug)]
-// Represents a single node in the conic tree
-pub struct ConicNode {
-    pub name: String,
-    pub value: Option<String>, // Holds specific values (if any)
-    pub children: Vec<ConicNode>, // Child nodes
+impl Iterator for VideoMetricsIter<'_> {
+    type Item = Result<FrameData>;
+
+    fn next(&mut self) -> Option<Result<FrameData>> {
+        if self.batch.is_empty() && !self.done {
+            if let Err(e) = self.fetch() {
+                // Nothing after a failed read can be trusted to follow on from it
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.batch.pop_front()
+    }
 }
 
-impl ConicNode {
-    pub fn new(name: &str, value: Option<&str>) -> Self {
-        Self {
-            name: name.to_string(),
-            value: value.map(|v| v.to_string()),
-            children: Vec::new(),
+// Writes every frame of metrics in one transaction, creating the video_metrics table if missing. Fails
+// when the table holds frames in another format.
+fn write_video_metrics(conn: &mut Connection, metrics: &VideoMetrics, format: StorageFormat, layout: VertexLayout) -> Result<usize> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    tx.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS video_metrics (
+                frame_number INTEGER NOT NULL,
+                vertex_data {0} NOT NULL,
+                material_data {0} NOT NULL
+            )",
+            format.column_type()
+        ),
+        [],
+    )?;
+    record_storage(&tx, format, layout)?;
+    check_storage(&tx, format, layout)?;
+
+    let mut written = 0;
+    {
+        let mut stmt = tx.prepare("INSERT INTO video_metrics (frame_number, vertex_data, material_data) VALUES (?1, ?2, ?3)")?;
+        for frame in &metrics.frame_data {
+            let (vertex_data, material_data) = format.encode_frame(frame, layout); // Same layout read_video_metrics reads back
+            written += stmt.execute(params![frame.frame_number, vertex_data, material_data])?;
         }
     }
+    tx.commit()?;
+    Ok(written)
+}
 
-    pub fn add_child(&mut self, child: ConicNode) {
-        self.children.push(child);
+// Columns video_metrics ingests read
+const VIDEO_METRICS_COLUMNS: [&str; 3] = ["frame_number", "vertex_data", "material_data"];
+
+// Fails with InvalidColumnName for the first column table lacks, checked against schema_cache
+fn require_columns(schema_cache: &Mutex<SQLiteAttributeCache>, conn: &Connection, table: &str, required: &[&str]) -> Result<()> {
+    let mut schema_cache = schema_cache.lock().unwrap();
+    let columns = schema_cache.get_columns(conn, table)?;
+    if columns.is_empty() {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+            Some(format!("no such table: {}", table)),
+        ));
+    }
+    match required.iter().find(|name| !columns.iter().any(|c| c.name == **name)) {
+        Some(missing) => Err(rusqlite::Error::InvalidColumnName(missing.to_string())),
+        None => Ok(()),
     }
 }
 
-// Represents a conic tree
-pub struct ConicTree {
-    pub root: ConicNode,
+// Like DatabaseManager, but reads go through a pool of read-only connections so independent ingests
+// run in parallel. Writes still go through a single writer connection, as SQLite allows one writer at
+// a time. Needs a database file, every connection to :memory: would see its own empty database.
+pub struct DatabaseManagerPool {
+    writer: Mutex<Connection>,
+    readers: Mutex<Vec<Connection>>, // Idle read-only connections
+    reader_returned: Condvar, // Signalled whenever a PooledConnection is dropped
+    schema_cache: Mutex<SQLiteAttributeCache>, // Columns ingests check, like DatabaseManager's
+    storage_format: StorageFormat,
+    vertex_layout: VertexLayout,
 }
 
-impl ConicTree {
-    pub fn new(root: ConicNode) -> Self {
-        Self { root }
+impl DatabaseManagerPool {
+    // Open a writer and size read-only connections to db_path, creating the database if missing.
+    // The writer switches it to WAL so readers never wait for a write to finish.
+    pub fn new(db_path: &str, size: usize) -> Result<Self> {
+        let options = DbOptions { wal: true, busy_timeout: Duration::from_secs(5), ..DbOptions::default() };
+        let writer = DatabaseManager::open_with(db_path, options)?.conn.into_inner().unwrap();
+        let readers = (0..size.max(1))
+            .map(|_| {
+                let conn = Connection::open_with_flags(
+                    db_path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
+                )?;
+                conn.busy_timeout(options.busy_timeout)?;
+                Ok(conn)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            writer: Mutex::new(writer),
+            readers: Mutex::new(readers),
+            reader_returned: Condvar::new(),
+            schema_cache: Mutex::new(SQLiteAttributeCache::new()),
+            storage_format: StorageFormat::default(),
+            vertex_layout: VertexLayout::default(),
+        })
     }
 
-    pub fn add_child(&mut self, child: ConicNode) {
-        self.root.children.push(child);
+    pub fn with_storage_format(mut self, format: StorageFormat) -> Self {
+        self.storage_format = format;
+        self
+    }
+
+    pub fn with_vertex_layout(mut self, layout: VertexLayout) -> Self {
+        self.vertex_layout = layout;
+        self
+    }
+
+    // Take an idle read-only connection, blocking until one is returned if all are in use
+    pub fn get(&self) -> PooledConnection<'_> {
+        let mut readers = self.readers.lock().unwrap();
+        loop {
+            if let Some(conn) = readers.pop() {
+                return PooledConnection { conn: Some(conn), pool: self };
+            }
+            readers = self.reader_returned.wait(readers).unwrap();
+        }
+    }
+
+    // The single connection writes go through, held exclusively until the guard is dropped
+    pub fn writer(&self) -> MutexGuard<'_, Connection> {
+        self.writer.lock().unwrap()
+    }
+
+    // Checks the columns of video_metrics the same way DatabaseManager::ingest_video_metrics does
+    pub fn ingest_video_metrics(&self) -> Result<VideoMetrics> {
+        let conn = self.get();
+        require_columns(&self.schema_cache, &conn, "video_metrics", &VIDEO_METRICS_COLUMNS)?;
+        read_video_metrics(&conn, self.storage_format, self.vertex_layout)
+    }
+
+    pub fn store_video_metrics(&self, metrics: &VideoMetrics) -> Result<usize> {
+        write_video_metrics(&mut self.writer(), metrics, self.storage_format, self.vertex_layout)
     }
 }
 
-// Function to build a conic tree from a JSON string
+// A read-only connection borrowed from a DatabaseManagerPool, returned to it when dropped
+pub struct PooledConnection<'a> {
+    conn: Option<Connection>, // Only None while being returned
+    pool: &'a DatabaseManagerPool,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.readers.lock().unwrap().push(conn);
+            self.pool.reader_returned.notify_one();
+        }
+    }
+}
+
+// A CSV value that is not a valid f32
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvParseError {
+    pub column: usize, // Zero-based index of the value within the CSV string
+    pub token: String, // The raw value as stored, before trimming
+}
+
+impl fmt::Display for CsvParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid float {:?} in CSV column {}", self.token, self.column)
+    }
+}
+
+impl Error for CsvParseError {}
+
+// Parse a CSV string into Vec<f32>, failing on the first value that is not a float.
+// An empty string is an empty vector, but empty values such as a trailing comma are errors.
+pub fn parse_csv_checked(data: &str) -> std::result::Result<Vec<f32>, CsvParseError> {
+    if data.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    data.split(',')
+        .enumerate()
+        .map(|(column, token)| {
+            token.trim().parse::<f32>().map_err(|_| CsvParseError { column, token: token.to_string() })
+        })
+        .collect()
+}
+
+// Helper function to parse CSV string into Vec<f32>, silently skipping values that fail to parse.
+// Prefer parse_csv_checked, skipped values shift every later value to the wrong slot.
+pub fn parse_csv(data: &str) -> Vec<f32> {
+    data.split(',')
+        .filter_map(|s| s.trim().parse::<f32>().ok()) // Filter and parse to f32
+        .collect()
+}
+
+// Helper function to write Vec<f32> as the CSV string parse_csv expects
+fn to_csv(data: &[f32]) -> String {
+    data.iter()
+        .map(|v| v.to_string()) // Shortest representation that parses back to the same f32
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Helper function to parse a little-endian f32 blob into Vec<f32>, ignoring a trailing partial value
+fn parse_blob(data: &[u8]) -> Vec<f32> {
+    data.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+// Helper function to write Vec<f32> as the blob parse_blob expects
+fn to_blob(data: &[f32]) -> Vec<u8> {
+    data.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+// A column as reported by PRAGMA table_info
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnInfo {
+    pub name: String,
+    pub decl_type: String, // Declared type, empty when the column was declared without one
+    pub not_null: bool,
+    pub primary_key: bool,
+}
+
+// Caches the columns of tables, so queries can be checked against the schema without asking SQLite every time.
+// Must be invalidated when a table is altered or dropped.
+#[derive(Debug, Default)]
+pub struct SQLiteAttributeCache {
+    tables: HashMap<String, Vec<ColumnInfo>>,
+    pragma_queries: usize, // Times the database was asked for a table's columns
+}
+
+impl SQLiteAttributeCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Columns of table in declaration order, read from the database on the first lookup. A missing table
+    // has no columns and is not cached, so it is found once created.
+    pub fn get_columns(&mut self, conn: &Connection, table: &str) -> Result<&[ColumnInfo]> {
+        if !self.tables.contains_key(table) {
+            self.pragma_queries += 1;
+            let mut stmt = conn.prepare_cached("SELECT name, type, \"notnull\", pk FROM pragma_table_info(?1)")?;
+            let columns = stmt
+                .query_map(params![table], |row| {
+                    Ok(ColumnInfo {
+                        name: row.get(0)?,
+                        decl_type: row.get(1)?,
+                        not_null: row.get(2)?,
+                        primary_key: row.get::<_, i64>(3)? > 0,
+                    })
+                })?
+                .collect::<Result<Vec<_>>>()?;
+            if columns.is_empty() {
+                return Ok(&[]);
+            }
+            self.tables.insert(table.to_string(), columns);
+        }
+        Ok(&self.tables[table])
+    }
+
+    // Forget the columns of one table, call after altering or dropping it
+    pub fn invalidate(&mut self, table: &str) {
+        self.tables.remove(table);
+    }
+
+    pub fn clear(&mut self) {
+        self.tables.clear();
+    }
+
+    pub fn pragma_queries(&self) -> usize {
+        self.pragma_queries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let csv_data = "1.0,2.0,3.0,4.0";
+        let parsed = parse_csv(csv_data);
+        assert_eq!(parsed, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_store_video_metrics_round_trip() {
+        let db = DatabaseManager::new(":memory:").unwrap().with_vertex_layout(VertexLayout::PositionNormalUv);
+        let metrics = VideoMetrics {
+            frame_data: vec![
+                FrameData {
+                    frame_number: 0,
+                    vertices: vec![Vertex { position: [0.1, -0.5, 3.25], normal: [0.0, 0.0, 1.0], uv: [0.5, 1.0] }],
+                    material_data: vec![1.0, 0.0, 0.0, 1.0],
+                },
+                FrameData { frame_number: 1, vertices: vec![Vertex { position: [1e-7, 123456.79, 0.0], ..Vertex::default() }], material_data: vec![] },
+            ],
+        };
+
+        assert_eq!(db.store_video_metrics(&metrics).unwrap(), 2);
+        assert_eq!(db.ingest_video_metrics().unwrap(), metrics);
+    }
+
+    #[test]
+    fn test_ingest_iter_reads_lazily() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        let metrics = VideoMetrics {
+            frame_data: (0..FRAME_BATCH as u32 * 3 + 5)
+                .map(|i| FrameData { frame_number: i, vertices: vec![Vertex::default()], material_data: vec![i as f32] })
+                .collect(),
+        };
+        db.store_video_metrics(&metrics).unwrap();
+
+        let mut frames = db.ingest_video_metrics_iter();
+        let first: Vec<FrameData> = frames.by_ref().take(3).map(|f| f.unwrap()).collect();
+        assert_eq!(first, metrics.frame_data[..3]);
+        // Only the first batch has been read, the rest of the table is untouched
+        assert_eq!(frames.batch.len(), FRAME_BATCH - 3);
+
+        // The connection is not held between batches
+        assert_eq!(db.ingest_video_metrics().unwrap(), metrics);
+
+        let rest: Vec<FrameData> = frames.map(|f| f.unwrap()).collect();
+        assert_eq!(rest, metrics.frame_data[3..]);
+    }
+
+    #[test]
+    fn test_ingest_iter_checks_required_columns() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        db.conn.lock().unwrap().execute("CREATE TABLE video_metrics (frame_number INTEGER)", []).unwrap();
+
+        let mut frames = db.ingest_video_metrics_iter();
+        assert!(frames.next().unwrap().is_err());
+        assert!(frames.next().is_none());
+        assert_eq!(db.ingest_video_metrics_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_ingest_after_clearing_statement_cache() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        let metrics = VideoMetrics {
+            frame_data: vec![FrameData { frame_number: 3, vertices: vec![Vertex::default()], material_data: vec![0.5] }],
+        };
+        db.store_video_metrics(&metrics).unwrap();
+        assert_eq!(db.ingest_video_metrics().unwrap(), metrics);
+
+        // A column added behind the cached statement's back must not break the next ingest
+        db.conn.lock().unwrap().execute("ALTER TABLE video_metrics ADD COLUMN note TEXT", []).unwrap();
+        db.clear_statement_cache();
+        assert_eq!(db.ingest_video_metrics().unwrap(), metrics);
+    }
+
+    // Compares repeated ingests with and without the statement cache, run with
+    // cargo test --release -- --ignored --nocapture bench_cached_ingest
+    #[test]
+    #[ignore]
+    fn bench_cached_ingest() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        let metrics = VideoMetrics {
+            frame_data: (0..8).map(|i| FrameData { frame_number: i, vertices: vec![Vertex::default(); 3], material_data: vec![1.0; 4] }).collect(),
+        };
+        db.store_video_metrics(&metrics).unwrap();
+        let iterations = 20_000;
+
+        let start = std::time::Instant::now();
+        for _ in 0..iterations {
+            db.clear_statement_cache();
+            assert_eq!(db.ingest_video_metrics().unwrap().frame_data.len(), 8);
+        }
+        let uncached = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for _ in 0..iterations {
+            assert_eq!(db.ingest_video_metrics().unwrap().frame_data.len(), 8);
+        }
+        let cached = start.elapsed();
+
+        println!("{} ingests: uncached {:?}, cached {:?}", iterations, uncached, cached);
+    }
+
+    #[test]
+    fn test_attribute_cache_reads_schema_once() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE shaders (name TEXT PRIMARY KEY, spirv BLOB NOT NULL)", []).unwrap();
+        let mut cache = SQLiteAttributeCache::new();
+
+        let columns = cache.get_columns(&conn, "shaders").unwrap().to_vec();
+        assert_eq!(columns.len(), 2);
+        assert_eq!(columns[0], ColumnInfo { name: "name".to_string(), decl_type: "TEXT".to_string(), not_null: false, primary_key: true });
+        assert!(columns[1].not_null && columns[1].decl_type == "BLOB");
+
+        // Served from the cache, the table is gone from the database
+        conn.execute("DROP TABLE shaders", []).unwrap();
+        assert_eq!(cache.get_columns(&conn, "shaders").unwrap(), &columns[..]);
+        assert_eq!(cache.pragma_queries(), 1);
+
+        cache.invalidate("shaders");
+        assert!(cache.get_columns(&conn, "shaders").unwrap().is_empty());
+        assert_eq!(cache.pragma_queries(), 2);
+    }
+
+    #[test]
+    fn test_ingest_checks_required_columns() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        assert!(db.ingest_video_metrics().is_err());
+
+        db.conn.lock().unwrap().execute("CREATE TABLE video_metrics (frame_number INTEGER, vertex_data TEXT)", []).unwrap();
+        assert!(matches!(db.ingest_video_metrics(), Err(rusqlite::Error::InvalidColumnName(name)) if name == "material_data"));
+
+        // Repeated ingests reuse the cached schema
+        db.ingest_video_metrics().unwrap_err();
+        assert_eq!(db.schema_cache.lock().unwrap().pragma_queries(), 2);
+    }
+
+    #[test]
+    fn test_open_with_enables_wal() {
+        let path = std::env::temp_dir().join(format!("zeta_dom_wal_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let options = DbOptions { wal: true, busy_timeout: Duration::from_millis(250), foreign_keys: true };
+        {
+            let db = DatabaseManager::open_with(path_str, options).unwrap();
+            let conn = db.conn.lock().unwrap();
+            let journal_mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+            assert_eq!(journal_mode, "wal");
+            let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0)).unwrap();
+            assert!(foreign_keys);
+        }
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path_str, suffix));
+        }
+
+        let db = DatabaseManager::new(":memory:").unwrap();
+        let journal_mode: String = db.conn.lock().unwrap().query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+        assert_eq!(journal_mode, "memory");
+        // Callers of new keep waiting on a locked database as before
+        let busy_timeout: i64 = db.conn.lock().unwrap().query_row("PRAGMA busy_timeout", [], |row| row.get(0)).unwrap();
+        assert_eq!(busy_timeout, 5000);
+    }
+
+    #[test]
+    fn test_open_existing_does_not_create() {
+        let path = std::env::temp_dir().join(format!("zeta_dom_existing_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        assert!(DatabaseManager::open_existing(path_str).is_err());
+        assert!(!path.exists());
+
+        DatabaseManager::new(path_str).unwrap().ensure_schema().unwrap();
+        assert!(DatabaseManager::open_existing(path_str).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_open_existing_reads_the_stored_format() {
+        let metrics = VideoMetrics {
+            frame_data: vec![FrameData { frame_number: 0, vertices: vec![Vertex { position: [0.5, f32::NAN, -2.0], ..Vertex::default() }], material_data: vec![1.0] }],
+        };
+        for format in [StorageFormat::Csv, StorageFormat::Blob, StorageFormat::Compressed] {
+            let path = std::env::temp_dir().join(format!("zeta_dom_format_{:?}_{}.db", format, std::process::id()));
+            let path_str = path.to_str().unwrap();
+            let _ = std::fs::remove_file(&path);
+            DatabaseManager::new(path_str).unwrap().with_storage_format(format).store_video_metrics(&metrics).unwrap();
+
+            let db = DatabaseManager::open_existing(path_str).unwrap();
+            assert_eq!(db.storage_format, format);
+            let frame = &db.ingest_video_metrics().unwrap().frame_data[0];
+            assert!(frame.vertices[0].position[1].is_nan());
+            assert_eq!(frame.material_data, vec![1.0]);
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn test_open_existing_reads_the_stored_vertex_layout() {
+        let path = std::env::temp_dir().join(format!("zeta_dom_layout_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let vertices = vec![Vertex { position: [1.0, 2.0, 3.0], normal: [0.0, 1.0, 0.0], uv: [0.25, 0.75] }; 2];
+        let metrics = VideoMetrics { frame_data: vec![FrameData { frame_number: 0, vertices, material_data: vec![] }] };
+        let db = DatabaseManager::new(path_str).unwrap().with_vertex_layout(VertexLayout::PositionNormalUv);
+        db.store_video_metrics(&metrics).unwrap();
+        // Rows in another layout would not be whole vertices in this one
+        let positions = db.with_vertex_layout(VertexLayout::Position);
+        assert!(positions.store_video_metrics(&metrics).is_err());
+        assert!(positions.ensure_schema().is_err());
+        drop(positions);
+
+        let db = DatabaseManager::open_existing(path_str).unwrap();
+        assert_eq!(db.vertex_layout, VertexLayout::PositionNormalUv);
+        assert_eq!(db.ingest_video_metrics().unwrap(), metrics);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_pool_concurrent_ingest() {
+        let path = std::env::temp_dir().join(format!("zeta_dom_pool_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let metrics = VideoMetrics {
+            frame_data: (0..16)
+                .map(|i| FrameData { frame_number: i, vertices: vec![Vertex::default(); 3], material_data: vec![i as f32] })
+                .collect(),
+        };
+        {
+            let pool = DatabaseManagerPool::new(path_str, 3).unwrap();
+            assert_eq!(pool.store_video_metrics(&metrics).unwrap(), 16);
+
+            // More threads than connections, so some have to wait for a connection to come back
+            thread::scope(|scope| {
+                let workers: Vec<_> = (0..8)
+                    .map(|_| scope.spawn(|| (0..20).map(|_| pool.ingest_video_metrics().unwrap()).collect::<Vec<_>>()))
+                    .collect();
+                for worker in workers {
+                    assert!(worker.join().unwrap().iter().all(|ingested| *ingested == metrics));
+                }
+            });
+            assert_eq!(pool.readers.lock().unwrap().len(), 3);
+
+            // Readers cannot write
+            assert!(pool.get().execute("DELETE FROM video_metrics", []).is_err());
+        }
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path_str, suffix));
+        }
+    }
+
+    #[test]
+    fn test_pool_ingest_checks_required_columns() {
+        let path = std::env::temp_dir().join(format!("zeta_dom_pool_columns_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        {
+            let pool = DatabaseManagerPool::new(path_str, 1).unwrap();
+            assert!(pool.ingest_video_metrics().is_err());
+
+            pool.writer().execute("CREATE TABLE video_metrics (frame_number INTEGER, vertex_data TEXT)", []).unwrap();
+            assert!(matches!(pool.ingest_video_metrics(), Err(rusqlite::Error::InvalidColumnName(name)) if name == "material_data"));
+            pool.ingest_video_metrics().unwrap_err();
+            assert_eq!(pool.schema_cache.lock().unwrap().pragma_queries(), 2);
+        }
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path_str, suffix));
+        }
+    }
+
+    #[test]
+    fn test_ensure_schema_bootstraps_fresh_database() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        db.ensure_schema().unwrap();
+        db.ensure_schema().unwrap(); // Nothing left to migrate
+        assert_eq!(db.ingest_video_metrics().unwrap(), VideoMetrics { frame_data: vec![] });
+
+        let version: i64 = db.conn.lock().unwrap().query_row("SELECT version FROM schema_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, SCHEMA_VERSION as i64);
+        db.record_frame_timing(1, 10, 20).unwrap();
+        db.put_shader("basic", ShaderStage::Vertex, &spirv_header()).unwrap();
+    }
+
+    #[test]
+    fn test_ensure_schema_rejects_incompatible_tables() {
+        let message = |db: &DatabaseManager| match db.ensure_schema() {
+            Err(rusqlite::Error::SqliteFailure(_, Some(message))) => message,
+            other => panic!("unexpected result {:?}", other),
+        };
+
+        // Written as CSV, opened as blobs
+        let db = DatabaseManager::new(":memory:").unwrap();
+        db.store_video_metrics(&VideoMetrics { frame_data: vec![] }).unwrap();
+        let db = db.with_storage_format(StorageFormat::Blob);
+        assert_eq!(message(&db), "video_metrics.vertex_data is TEXT but BLOB is expected");
+
+        let db = DatabaseManager::new(":memory:").unwrap();
+        db.conn.lock().unwrap().execute("CREATE TABLE frame_timings (frame_number INTEGER)", []).unwrap();
+        assert_eq!(message(&db), "frame_timings has no gpu_micros column");
+
+        // Compressed and blob tables only differ in what schema_meta records
+        let db = DatabaseManager::new(":memory:").unwrap().with_storage_format(StorageFormat::Compressed);
+        db.store_video_metrics(&VideoMetrics { frame_data: vec![] }).unwrap();
+        let db = db.with_storage_format(StorageFormat::Blob);
+        assert_eq!(message(&db), "video_metrics storage_format is compressed but blob is expected");
+        assert!(db.store_video_metrics(&VideoMetrics { frame_data: vec![] }).is_err());
+
+        let db = DatabaseManager::new(":memory:").unwrap();
+        db.ensure_schema().unwrap();
+        db.conn.lock().unwrap().execute("UPDATE schema_version SET version = 99", []).unwrap();
+        assert!(message(&db).contains("newer"));
+    }
+
+    fn interpolation_metrics() -> VideoMetrics {
+        let vertex = |x: f32| Vertex { position: [x, 0.0, 0.0], normal: [0.0, 0.0, 1.0], uv: [x, 0.0] };
+        VideoMetrics {
+            frame_data: vec![
+                FrameData { frame_number: 12, vertices: vec![vertex(4.0), vertex(8.0)], material_data: vec![1.0, 1.0, 0.0] },
+                FrameData { frame_number: 10, vertices: vec![vertex(0.0)], material_data: vec![0.0, 0.0] },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_write_json_round_trip() {
+        let metrics = interpolation_metrics();
+        let mut json = Vec::new();
+        metrics.write_json(&mut json).unwrap();
+        let parsed: VideoMetrics = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed, metrics);
+    }
+
+    #[test]
+    fn test_write_csv_pads_shorter_frames() {
+        let mut csv = Vec::new();
+        interpolation_metrics().write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("frame_number,v0_px,v0_py,v0_pz,v0_nx,v0_ny,v0_nz,v0_u,v0_v,v1_px,"));
+        assert!(lines[0].ends_with(",v1_v,m0,m1,m2"));
+        assert_eq!(lines[1], "12,4,0,0,0,0,1,4,0,8,0,0,0,0,1,8,0,1,1,0");
+        assert_eq!(lines[2], "10,0,0,0,0,0,1,0,0,,,,,,,,,0,0,");
+        assert!(lines.iter().all(|line| line.split(',').count() == 20));
+    }
+
+    #[test]
+    fn test_interpolated_frame_exact() {
+        let metrics = interpolation_metrics();
+        assert_eq!(metrics.interpolated_frame(10.0).as_ref(), Some(&metrics.frame_data[1]));
+        assert_eq!(metrics.interpolated_frame(12.0).as_ref(), Some(&metrics.frame_data[0]));
+    }
+
+    #[test]
+    fn test_interpolated_frame_midpoint() {
+        let frame = interpolation_metrics().interpolated_frame(11.0).unwrap();
+        assert_eq!(frame.frame_number, 12);
+        assert_eq!(frame.material_data, vec![0.5, 0.5, 0.0]); // Length of the nearer frame, extra value copied
+        assert_eq!(frame.vertices.len(), 2);
+        assert_eq!(frame.vertices[0].position, [2.0, 0.0, 0.0]);
+        assert_eq!(frame.vertices[0].normal, [0.0, 0.0, 1.0]);
+        assert_eq!(frame.vertices[1].position, [8.0, 0.0, 0.0]);
+
+        let frame = interpolation_metrics().interpolated_frame(10.5).unwrap();
+        assert_eq!((frame.frame_number, frame.material_data), (10, vec![0.25, 0.25]));
+        assert_eq!(frame.vertices.len(), 1);
+    }
+
+    #[test]
+    fn test_frame_lerp() {
+        let vertex = |x: f32| Vertex { position: [x, 0.0, 0.0], ..Vertex::default() };
+        let a = FrameData { frame_number: 3, vertices: vec![vertex(0.0)], material_data: vec![0.0, 1.0] };
+        let b = FrameData { frame_number: 4, vertices: vec![vertex(4.0)], material_data: vec![1.0, 0.0] };
+        let mid = a.lerp(&b, 0.5);
+        assert_eq!(mid.vertices[0].position, [2.0, 0.0, 0.0]);
+        assert_eq!(mid.material_data, vec![0.5, 0.5]);
+        assert_eq!(mid.frame_number, 4);
+        assert_eq!(a.lerp(&b, 0.25).material_data, vec![0.25, 0.75]);
+
+        // Mismatched lengths fall back to the nearer frame
+        let longer = FrameData { vertices: vec![vertex(4.0), vertex(8.0)], ..b.clone() };
+        assert_eq!(a.lerp(&longer, 0.4), a);
+        assert_eq!(a.lerp(&longer, 0.5), longer);
+    }
+
+    #[test]
+    fn test_interpolated_frame_out_of_range() {
+        let metrics = interpolation_metrics();
+        assert_eq!(metrics.interpolated_frame(9.99), None);
+        assert_eq!(metrics.interpolated_frame(12.01), None);
+        assert_eq!(metrics.interpolated_frame(f32::NAN), None);
+        assert_eq!(VideoMetrics { frame_data: vec![] }.interpolated_frame(0.0), None);
+    }
+
+    #[test]
+    fn test_parse_csv_checked() {
+        assert_eq!(parse_csv_checked(" 1.0, 2.5 ,3").unwrap(), vec![1.0, 2.5, 3.0]);
+        assert_eq!(parse_csv_checked("").unwrap(), Vec::<f32>::new());
+        assert_eq!(
+            parse_csv_checked("1.0,foo,3.0").unwrap_err(),
+            CsvParseError { column: 1, token: "foo".to_string() }
+        );
+        assert_eq!(parse_csv_checked("1.0,2.0,").unwrap_err().column, 2); // Trailing comma
+        assert_eq!(parse_csv("1.0,foo,3.0"), vec![1.0, 3.0]); // Lenient variant still skips
+    }
+
+    #[test]
+    fn test_ingest_reports_malformed_csv() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        db.store_video_metrics(&VideoMetrics { frame_data: vec![] }).unwrap(); // Creates the table
+        db.conn.lock().unwrap()
+            .execute("INSERT INTO video_metrics VALUES (0, '1.0,foo,3.0', '1.0')", [])
+            .unwrap();
+
+        match db.ingest_video_metrics() {
+            Err(rusqlite::Error::FromSqlConversionFailure(1, Type::Text, e)) => {
+                assert_eq!(e.to_string(), "invalid float \"foo\" in CSV column 1");
+            }
+            other => panic!("expected a conversion error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ingest_checks_vertex_layout() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        db.store_video_metrics(&VideoMetrics { frame_data: vec![] }).unwrap(); // Creates the table
+        db.conn.lock().unwrap()
+            .execute("INSERT INTO video_metrics VALUES (0, '1,2,3,4,5,6', '1.0')", [])
+            .unwrap();
+
+        // Position-only rows are two vertices under the default layout
+        let frames = db.ingest_video_metrics().unwrap().frame_data;
+        assert_eq!(frames[0].vertices.len(), 2);
+        assert_eq!(frames[0].vertices[1].position, [4.0, 5.0, 6.0]);
+
+        // Six floats are not whole vertices with normals and UVs
+        let full = db.with_vertex_layout(VertexLayout::PositionNormalUv);
+        match full.ingest_video_metrics() {
+            Err(rusqlite::Error::FromSqlConversionFailure(1, Type::Text, e)) => {
+                assert_eq!(e.to_string(), "6 floats are not a whole number of 8-float vertices");
+            }
+            other => panic!("expected a conversion error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_conic_tree_from_json() {
+        let tree = ConicTree::from_json(r#"{"frame": {"number": 3, "shaders": ["basic", {"name": "pbr"}]}, "label": "intro"}"#).unwrap();
+        let root = &tree.root;
+        assert_eq!(root.name, "root");
+        assert_eq!(root.children.len(), 2);
+
+        let frame = root.children.iter().find(|n| n.name == "frame").unwrap();
+        let number = frame.children.iter().find(|n| n.name == "number").unwrap();
+        assert_eq!(number.value.as_deref(), Some("3"));
+
+        let shaders = frame.children.iter().find(|n| n.name == "shaders").unwrap();
+        assert_eq!(shaders.value, None);
+        assert_eq!(shaders.children[0].name, "0");
+        assert_eq!(shaders.children[0].value.as_deref(), Some("basic"));
+        assert_eq!(shaders.children[1].children[0].name, "name");
+        assert_eq!(shaders.children[1].children[0].value.as_deref(), Some("pbr"));
+
+        let label = root.children.iter().find(|n| n.name == "label").unwrap();
+        assert_eq!(label.value.as_deref(), Some("intro"));
+        assert!(label.children.is_empty());
+    }
+
+    #[test]
+    fn test_conic_tree_from_json_keeps_key_order() {
+        let tree = ConicTree::from_json(r#"{"zeta": 1, "alpha": 2, "mid": {"y": 3, "b": 4}}"#).unwrap();
+        let names = |node: &ConicNode| node.children.iter().map(|n| n.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&tree.root), vec!["zeta", "alpha", "mid"]);
+        assert_eq!(names(tree.get("root/mid").unwrap()), vec!["y", "b"]);
+        assert_eq!(tree.to_json(), r#"{"zeta":1,"alpha":2,"mid":{"y":3,"b":4}}"#);
+    }
+
+    #[test]
+    fn test_conic_tree_json_round_trip() {
+        let json = r#"{"frame":{"number":3,"scale":-0.5,"visible":true,"shaders":["basic",{"name":"pbr","id":"007"}],"parent":null},"label":"intro"}"#;
+        let tree = ConicTree::from_json(json).unwrap();
+
+        let written: serde_json::Value = serde_json::from_str(&tree.to_json()).unwrap();
+        assert_eq!(written, serde_json::from_str::<serde_json::Value>(json).unwrap());
+
+        let reparsed = ConicTree::from_json(&tree.to_json_pretty()).unwrap();
+        assert_eq!(reparsed.root, tree.root);
+
+        // Empty objects and arrays read like null and are written back as null, the tree is unchanged
+        let empty = ConicTree::from_json(r#"{"object":{},"array":[],"null":null}"#).unwrap();
+        let written: serde_json::Value = serde_json::from_str(&empty.to_json()).unwrap();
+        assert_eq!(written, serde_json::json!({"object": null, "array": null, "null": null}));
+        assert_eq!(ConicTree::from_json(&empty.to_json()).unwrap().root, empty.root);
+    }
+
+    #[test]
+    fn test_conic_tree_json_keeps_strings_that_look_like_scalars() {
+        let json = r#"{"quoted":["1e5","true","-0"],"number":1e5,"bool":true}"#;
+        let tree = ConicTree::from_json(json).unwrap();
+        assert_eq!(tree.get("root/quoted/0").unwrap().kind, ConicValueKind::String);
+        assert_eq!(tree.get("root/number").unwrap().kind, ConicValueKind::Number);
+
+        let written: serde_json::Value = serde_json::from_str(&tree.to_json()).unwrap();
+        assert_eq!(written, serde_json::from_str::<serde_json::Value>(json).unwrap());
+        assert_eq!(written["quoted"][0], serde_json::json!("1e5"));
+        assert_eq!(written["quoted"][1], serde_json::json!("true"));
+        assert_eq!(ConicTree::from_json(&tree.to_json()).unwrap().root, tree.root);
+
+        // Nodes built in code hold strings unless told otherwise
+        let mut built = ConicTree::new(ConicNode::new("root", None));
+        built.add_child(ConicNode::new("text", Some("1e5")));
+        built.add_child(ConicNode::new("count", Some("3")).with_kind(ConicValueKind::Number));
+        assert_eq!(built.to_json(), r#"{"text":"1e5","count":3}"#);
+    }
+
+    #[test]
+    fn test_conic_tree_traversal_order() {
+        //        root
+        //       /    \
+        //      a      b
+        //     / \     |
+        //    c   d    e
+        let mut a = ConicNode::new("a", None);
+        a.add_child(ConicNode::new("c", None));
+        a.add_child(ConicNode::new("d", None));
+        let mut b = ConicNode::new("b", None);
+        b.add_child(ConicNode::new("e", None));
+        let mut tree = ConicTree::new(ConicNode::new("root", None));
+        tree.add_child(a);
+        tree.add_child(b);
+
+        let dfs: Vec<&str> = tree.iter_dfs().map(|n| n.name.as_str()).collect();
+        let bfs: Vec<&str> = tree.iter_bfs().map(|n| n.name.as_str()).collect();
+        assert_eq!(dfs, vec!["root", "a", "c", "d", "b", "e"]);
+        assert_eq!(bfs, vec!["root", "a", "b", "c", "d", "e"]);
+
+        let json_tree = ConicTree::from_json(r#"{"x": [1, 2, {"y": 3}], "z": null}"#).unwrap();
+        assert_eq!(json_tree.iter_dfs().count(), 7);
+        assert_eq!(json_tree.iter_bfs().count(), 7);
+    }
+
+    #[test]
+    fn test_conic_tree_path_lookup() {
+        let mut materials = ConicNode::new("materials", None);
+        materials.add_child(ConicNode::new("diffuse", Some("0.8")));
+        materials.add_child(ConicNode::new("diffuse", Some("0.2"))); // Ambiguous sibling, the first one wins
+        let mut tree = ConicTree::new(ConicNode::new("root", None));
+        tree.add_child(materials);
+
+        assert_eq!(tree.get_value("root/materials/diffuse"), Some("0.8"));
+        assert_eq!(tree.get_value("root/materials/diffuse/"), Some("0.8"));
+        assert_eq!(tree.get("root/materials").map(|n| n.children.len()), Some(2));
+        assert_eq!(tree.get("root").map(|n| n.name.as_str()), Some("root"));
+        assert_eq!(tree.get_value("root/materials"), None); // Found, but has no value
+
+        assert!(tree.get("root/materials/specular").is_none());
+        assert!(tree.get("materials/diffuse").is_none()); // Paths start at the root
+        assert!(tree.get("root//diffuse").is_none());
+        assert!(tree.get("").is_none());
+    }
+
+    #[test]
+    fn test_conic_tree_to_partitioned_data() {
+        let tree = ConicTree::from_json(r#"{
+            "scene": {
+                "block": {"vertex_data": "0.0,0.5,0.0, -0.5,-0.5,0.0, 0.5,-0.5,0.0", "material_data": [1.0, 0.0, 0.0, 1.0]},
+                "block_2": {"vertex_data": [0, 0, 0], "material_data": "1,1,1,1", "indices": [0, 0, 0], "texture_id": 4}
+            },
+            "blocker": {"vertex_data": "not a block"}
+        }"#).unwrap();
+
+        let data = tree.to_partitioned_data().unwrap();
+        assert_eq!(data.blocks.len(), 2);
+        assert_eq!(data.blocks[0].vertices.len(), 3);
+        assert_eq!(data.blocks[0].vertices[1].position, [-0.5, -0.5, 0.0]);
+        assert_eq!(data.blocks[0].material_data, vec![1.0, 0.0, 0.0, 1.0]);
+        assert!(data.blocks[0].indices.is_empty());
+        assert_eq!(data.blocks[0].texture_id, None);
+        assert_eq!(data.blocks[1].indices, vec![0, 0, 0]);
+        assert_eq!(data.blocks[1].texture_id, Some(4));
+
+        // Blocks come in document order, not sorted by name
+        let tree = ConicTree::from_json(r#"{
+            "block_2": {"vertex_data": [0, 0, 0], "material_data": [], "texture_id": 2},
+            "block_10": {"vertex_data": [0, 0, 0], "material_data": [], "texture_id": 10},
+            "block_1": {"vertex_data": [0, 0, 0], "material_data": [], "texture_id": 1}
+        }"#).unwrap();
+        let textures: Vec<_> = tree.to_partitioned_data().unwrap().blocks.iter().map(|b| b.texture_id).collect();
+        assert_eq!(textures, vec![Some(2), Some(10), Some(1)]);
+    }
+
+    #[test]
+    fn test_conic_tree_to_partitioned_data_errors() {
+        let missing = ConicTree::from_json(r#"{"block": {"vertex_data": "0,0,0"}}"#).unwrap();
+        assert_eq!(
+            missing.to_partitioned_data().unwrap_err(),
+            ConvertError::MissingChild { block: "block".to_string(), child: "material_data" }
+        );
+
+        let malformed = ConicTree::from_json(r#"{"block": {"vertex_data": "0,x,0", "material_data": ""}}"#).unwrap();
+        assert_eq!(
+            malformed.to_partitioned_data().unwrap_err(),
+            ConvertError::MalformedNumber { block: "block".to_string(), child: "vertex_data", column: 1, token: "x".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_conic_tree_rejects_malformed_json() {
+        assert!(matches!(ConicTree::from_json(r#"{"frame": [1, 2"#), Err(ConicParseError::Json(_))));
+        assert!(ConicTree::from_json("").is_err());
+    }
+
+    #[test]
+    fn test_blob_codec() {
+        let values = [0.0, -1.5, f32::MAX, f32::MIN_POSITIVE];
+        assert_eq!(to_blob(&values).len(), 16);
+        assert_eq!(parse_blob(&to_blob(&values)), values);
+        assert_eq!(parse_blob(&[0, 0, 128, 63, 1]), vec![1.0]); // Trailing partial value is dropped
+    }
+
+    #[test]
+    fn test_compressed_frames_are_decompressed_on_ingest() {
+        let vertices = vec![Vertex { position: [0.5, -0.25, 1.0], normal: [0.0, 0.0, 1.0], uv: [0.75, 0.5] }; 3];
+        let frame = FrameData { frame_number: 4, vertices: vertices.clone(), material_data: vec![1.0, 0.5, 0.0, 1.0] };
+        let db = DatabaseManager::new(":memory:").unwrap().with_storage_format(StorageFormat::Compressed);
+        db.store_video_metrics(&VideoMetrics { frame_data: vec![frame.clone()] }).unwrap();
+
+        // Normals and UVs survive even though the default layout stores positions only
+        assert_eq!(db.ingest_video_metrics().unwrap().frame_data, vec![frame.clone()]);
+        assert_eq!(db.ingest_video_metrics_iter().next().unwrap().unwrap(), frame);
+
+        // A truncated block is an error rather than a frame of garbage floats
+        let compressed = shader_partition_compressor::compress_block(&ShaderBlock { vertices, ..ShaderBlock::default() });
+        db.conn.lock().unwrap().execute(
+            "INSERT INTO video_metrics (frame_number, vertex_data, material_data) VALUES (5, ?1, x'')",
+            params![&compressed[..compressed.len() / 2]],
+        ).unwrap();
+        assert!(db.ingest_video_metrics().is_err());
+    }
+
+    #[test]
+    fn test_raw_blobs_that_look_compressed_are_not_decompressed() {
+        // The first float's bytes read "ZSB", the header of a compressed block
+        let x = f32::from_le_bytes([b'Z', b'S', b'B', 0x3f]);
+        let frame = FrameData { frame_number: 0, vertices: vec![Vertex { position: [x, 1.0, 2.0], ..Vertex::default() }], material_data: vec![x] };
+        let db = DatabaseManager::new(":memory:").unwrap().with_storage_format(StorageFormat::Blob);
+        db.store_video_metrics(&VideoMetrics { frame_data: vec![frame.clone()] }).unwrap();
+        assert_eq!(db.ingest_video_metrics().unwrap().frame_data, vec![frame]);
+    }
+
+    #[test]
+    fn test_special_values_round_trip_in_both_formats() {
+        for format in [StorageFormat::Csv, StorageFormat::Blob] {
+            let db = DatabaseManager::new(":memory:").unwrap().with_storage_format(format);
+            let metrics = VideoMetrics {
+                frame_data: vec![
+                    FrameData {
+                        frame_number: 0,
+                        vertices: vec![Vertex { position: [f32::NAN, f32::INFINITY, f32::NEG_INFINITY], ..Vertex::default() }],
+                        material_data: vec![],
+                    },
+                    FrameData { frame_number: 1, vertices: vec![], material_data: vec![0.25] },
+                ],
+            };
+            db.store_video_metrics(&metrics).unwrap();
+
+            let loaded = db.ingest_video_metrics().unwrap();
+            let special = &loaded.frame_data[0].vertices[0].position;
+            assert!(special[0].is_nan(), "{:?}", format);
+            assert_eq!(&special[1..], &[f32::INFINITY, f32::NEG_INFINITY]);
+            assert!(loaded.frame_data[0].material_data.is_empty());
+            assert!(loaded.frame_data[1].vertices.is_empty());
+            assert_eq!(loaded.frame_data[1].material_data, vec![0.25]);
+        }
+    }
+
+    #[test]
+    fn test_record_frame_timing() {
+        let db = Arc::new(DatabaseManager::new(":memory:").unwrap());
+        db.record_frame_timing(1, 1200, 300).unwrap();
+
+        let (sender, handle) = db.clone().spawn_timing_writer();
+        sender.send(FrameTiming { frame_number: 2, gpu_micros: 900, cpu_micros: 250 }).unwrap();
+        drop(sender);
+        handle.join().unwrap();
+
+        let conn = db.conn.lock().unwrap();
+        let rows: Vec<(u32, i64)> = conn
+            .prepare("SELECT frame_number, gpu_micros FROM frame_timings ORDER BY frame_number").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<Result<_>>().unwrap();
+        assert_eq!(rows, vec![(1, 1200), (2, 900)]);
+    }
+
+    #[test]
+    fn test_load_shader_spirv() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        {
+            let conn = db.conn.lock().unwrap();
+            conn.execute("CREATE TABLE shaders (name TEXT PRIMARY KEY, spirv BLOB NOT NULL)", []).unwrap();
+            conn.execute("INSERT INTO shaders (name, spirv) VALUES ('basic.vert', ?1)", params![vec![3u8, 2, 35, 7]]).unwrap();
+        }
+        assert_eq!(db.load_shader_spirv("basic.vert").unwrap(), vec![3, 2, 35, 7]);
+        assert!(matches!(db.load_shader_spirv("missing.frag"), Err(rusqlite::Error::QueryReturnedNoRows)));
+    }
+
+    // Header of an empty SPIR-V 1.0 module
+    fn spirv_header() -> Vec<u8> {
+        [0x0723_0203u32, 0x0001_0000, 0, 1, 0].iter().flat_map(|word| word.to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_put_and_get_shader() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        let module = spirv_header();
+        db.put_shader("basic", ShaderStage::Vertex, &module).unwrap();
+
+        assert_eq!(db.get_shader("basic", ShaderStage::Vertex).unwrap(), module);
+        assert_eq!(db.load_shader_spirv("basic.vert").unwrap(), module);
+        assert!(matches!(db.get_shader("basic", ShaderStage::Fragment), Err(rusqlite::Error::QueryReturnedNoRows)));
+
+        // Replaced, not duplicated
+        let mut updated = module.clone();
+        updated.extend_from_slice(&[0; 4]);
+        db.put_shader("basic", ShaderStage::Vertex, &updated).unwrap();
+        assert_eq!(db.get_shader("basic", ShaderStage::Vertex).unwrap(), updated);
+    }
+
+    #[test]
+    fn test_get_shader_rejects_invalid_blobs() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        assert!(db.put_shader("basic", ShaderStage::Vertex, b"#version 450").is_err());
+
+        db.put_shader("basic", ShaderStage::Fragment, &spirv_header()).unwrap();
+        let mut truncated = spirv_header();
+        truncated.pop();
+        db.conn.lock().unwrap()
+            .execute("UPDATE shaders SET spirv = ?1 WHERE name = 'basic.frag'", params![truncated]).unwrap();
+        match db.get_shader("basic", ShaderStage::Fragment) {
+            Err(rusqlite::Error::FromSqlConversionFailure(_, Type::Blob, e)) => {
+                assert_eq!(e.to_string(), "shader basic.frag is not a SPIR-V module (19 bytes)");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_shader_block_round_trip() {
+        let block = ShaderBlock {
+            vertices: vertices_from_floats(&[0.0, 0.5, 0.0, -0.5, -0.5, 0.0, 0.5, -0.5, 0.0], &[0.5, 0.0, 0.0, 1.0, 1.0, 1.0]).collect(),
+            material_data: vec![1.0, 0.0, 0.0, 1.0],
+            indices: vec![0, 1, 2],
+            texture_id: Some(3),
+            pipeline: PipelineKey::default(),
+            instances: Vec::new(),
+        };
+        let json = serde_json::to_string(&block).unwrap();
+        let decoded: ShaderBlock = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.vertices, block.vertices);
+        assert_eq!(decoded.material_data, block.material_data);
+        assert_eq!(decoded.indices, block.indices);
+        assert_eq!(decoded.texture_id, Some(3));
+    }
+
+    #[test]
+    fn test_is_spirv_checks_header() {
+        let mut module = vec![0u8; 20];
+        module[..4].copy_from_slice(&0x0723_0203u32.to_le_bytes());
+        assert!(is_spirv(&module));
+        module[..4].copy_from_slice(&0x0723_0203u32.to_be_bytes());
+        assert!(is_spirv(&module));
+
+        assert!(!is_spirv(b"#version 450\nvoid main() {}\n"));
+        assert!(!is_spirv(&module[..16])); // Truncated header
+        module.push(0);
+        assert!(!is_spirv(&module)); // Not a whole number of words
+    }
+
+    #[test]
+    fn test_vertex_raw_layouts() {
+        let raw = [1.0, 2.0, 3.0, 0.0, 1.0, 0.0, 0.25, 0.75];
+        let vertices = Vertex::from_raw_f32(&raw, VertexLayout::PositionNormalUv).unwrap();
+        assert_eq!(vertices, vec![Vertex { position: [1.0, 2.0, 3.0], normal: [0.0, 1.0, 0.0], uv: [0.25, 0.75] }]);
+        assert_eq!(Vertex::to_raw_f32(&vertices, VertexLayout::PositionNormalUv), raw.to_vec());
+        assert_eq!(Vertex::to_raw_f32(&vertices, VertexLayout::PositionUv), vec![1.0, 2.0, 3.0, 0.25, 0.75]);
+
+        // Legacy position-only data
+        let legacy = Vertex::from_raw_f32(&raw[..6], VertexLayout::Position).unwrap();
+        assert_eq!(legacy[1], Vertex { position: [0.0, 1.0, 0.0], ..Vertex::default() });
+
+        assert_eq!(
+            Vertex::from_raw_f32(&raw[..7], VertexLayout::PositionNormalUv).unwrap_err(),
+            VertexLayoutError { len: 7, stride: 8 }
+        );
+        assert_eq!(std::mem::size_of::<Vertex>(), VertexLayout::PositionNormalUv.stride() * 4); // No padding
+    }
+
+    #[test]
+    fn test_vertices_pair_uvs_by_index() {
+        let positions = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let vertices: Vec<Vertex> = vertices_from_floats(&positions, &[0.0, 1.0, 1.0, 1.0, 0.5]).collect();
+        assert_eq!(vertices[0].uv, [0.0, 1.0]);
+        assert_eq!(vertices[1].uv, [1.0, 1.0]);
+        assert_eq!(vertices[2].uv, [0.0, 0.0]); // Incomplete trailing pair falls back to (0, 0)
+    }
+
+    #[test]
+    fn test_pipeline_key_defaults_to_opaque() {
+        let json = r#"{"vertices":[],"material_data":[]}"#;
+        let block: ShaderBlock = serde_json::from_str(json).unwrap();
+        assert_eq!(block.pipeline, PipelineKey::default());
+        assert_eq!(block.pipeline.blend, BlendMode::Opaque);
+
+        let json = r#"{"vertices":[],"material_data":[],"pipeline":{"blend":"AlphaBlend","cull":"Back","shaders":"Lit"}}"#;
+        let block: ShaderBlock = serde_json::from_str(json).unwrap();
+        assert_eq!(block.pipeline, PipelineKey { blend: BlendMode::AlphaBlend, cull: CullMode::Back, shaders: ShaderKind::Lit });
+    }
+}
+
+
+
+
+#[derive(Debug, Clone, PartialEq)]
+// Represents a single node in the conic tree
+pub struct ConicNode {
+    pub name: String,
+    pub value: Option<String>, // Holds specific values (if any)
+    pub kind: ConicValueKind, // JSON type of value, so "1e5" and 1e5 are written back differently
+    pub children: Vec<ConicNode>, // Child nodes
+}
+
+// JSON type a node's value was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConicValueKind {
+    String,
+    Number,
+    Bool,
+}
+
+impl ConicNode {
+    // A node whose value, if any, is a string
+    pub fn new(name: &str, value: Option<&str>) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.map(|v| v.to_string()),
+            kind: ConicValueKind::String,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_kind(mut self, kind: ConicValueKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn add_child(&mut self, child: ConicNode) {
+        self.children.push(child);
+    }
+}
+
+// Represents a conic tree
+pub struct ConicTree {
+    pub root: ConicNode,
+}
+
+impl ConicTree {
+    pub fn new(root: ConicNode) -> Self {
+        Self { root }
+    }
+
+    pub fn add_child(&mut self, child: ConicNode) {
+        self.root.children.push(child);
+    }
+
+    // Resolves a slash-separated path of node names starting at the root, e.g. "root/materials/diffuse".
+    // The first child with a matching name is followed, and a trailing slash is ignored.
+    pub fn get(&self, path: &str) -> Option<&ConicNode> {
+        let path = path.strip_suffix('/').unwrap_or(path);
+        let mut segments = path.split('/');
+        if segments.next()? != self.root.name {
+            return None;
+        }
+        segments.try_fold(&self.root, |node, segment| {
+            node.children.iter().find(|child| child.name == segment)
+        })
+    }
+
+    // Value of the node at path, None when the node is missing or has no value
+    pub fn get_value(&self, path: &str) -> Option<&str> {
+        self.get(path)?.value.as_deref()
+    }
+
+    // Visits every node depth-first, root first and children in insertion order
+    pub fn iter_dfs(&self) -> impl Iterator<Item = &ConicNode> {
+        ConicDfs { stack: vec![&self.root] }
+    }
+
+    // Visits every node level by level, root first and children in insertion order
+    pub fn iter_bfs(&self) -> impl Iterator<Item = &ConicNode> {
+        ConicBfs { queue: VecDeque::from(vec![&self.root]) }
+    }
+}
+
+// Pending nodes are kept on a stack, so memory grows with depth times fan-out rather than tree size
+struct ConicDfs<'a> {
+    stack: Vec<&'a ConicNode>,
+}
+
+impl<'a> Iterator for ConicDfs<'a> {
+    type Item = &'a ConicNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.stack.extend(node.children.iter().rev()); // Reversed so the first child is popped next
+        Some(node)
+    }
+}
+
+// Pending nodes are kept in a queue holding at most about two levels of the tree
+struct ConicBfs<'a> {
+    queue: VecDeque<&'a ConicNode>,
+}
+
+impl<'a> Iterator for ConicBfs<'a> {
+    type Item = &'a ConicNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.queue.pop_front()?;
+        self.queue.extend(node.children.iter());
+        Some(node)
+    }
+}
+
+// Errors that can occur while building a conic tree from JSON
+#[derive(Debug)]
+pub enum ConicParseError {
+    Json(serde_json::Error), // The input is not valid JSON
+}
+
+impl fmt::Display for ConicParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConicParseError::Json(e) => write!(f, "invalid conic tree JSON: {}", e),
+        }
+    }
+}
+
+impl Error for ConicParseError {}
+
+impl From<serde_json::Error> for ConicParseError {
+    fn from(e: serde_json::Error) -> Self {
+        ConicParseError::Json(e)
+    }
+}
+
+// Function to build a conic tree from a JSON string
+impl ConicTree {
+    // Object keys become child nodes, array elements become children named by their index,
+    // and strings, numbers and booleans become the value of the node holding them. null, {} and []
+    // all become a node without value or children. Children follow the key order of the document,
+    // serde_json keeps it with the preserve_order feature.
+    pub fn from_json(s: &str) -> std::result::Result<ConicTree, ConicParseError> {
+        let json: serde_json::Value = serde_json::from_str(s)?;
+        Ok(ConicTree::new(conic_node_from_json("root", &json)))
+    }
+}
+
+// Serializes a node in the shape from_json reads: children named 0..n in order become an array,
+// other children an object keyed by name, and leaves their value. Values are written unquoted only
+// when their kind is Number or Bool and they parse as one, so strings such as "1e5" or "true" stay
+// strings. A leaf without a value is null, so empty children are never
+// written as an empty array or object: {} and [] read by from_json come back as null. The tree
+// survives the round trip, that JSON text does not. A node's value is dropped when it also has children.
+impl Serialize for ConicNode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if !self.children.is_empty() {
+            let indexed = self.children.iter().enumerate().all(|(i, child)| child.name == i.to_string());
+            if indexed {
+                let mut seq = serializer.serialize_seq(Some(self.children.len()))?;
+                for child in &self.children {
+                    seq.serialize_element(child)?;
+                }
+                return seq.end();
+            }
+            let mut map = serializer.serialize_map(Some(self.children.len()))?;
+            for child in &self.children {
+                map.serialize_entry(&child.name, child)?;
+            }
+            return map.end();
+        }
+        let value = match &self.value {
+            Some(value) => value,
+            None => return serializer.serialize_none(),
+        };
+        match (self.kind, serde_json::from_str::<serde_json::Value>(value)) {
+            (ConicValueKind::Number, Ok(number @ serde_json::Value::Number(_))) => number.serialize(serializer),
+            (ConicValueKind::Bool, Ok(boolean @ serde_json::Value::Bool(_))) => boolean.serialize(serializer),
+            _ => serializer.serialize_str(value),
+        }
+    }
+}
+
+impl ConicTree {
+    // Serializes the tree as compact JSON that from_json turns back into the same tree
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.root).expect("conic nodes only have string keys")
+    }
+
+    // Same as to_json, indented for reading
+    pub fn to_json_pretty(&self) -> String {
+        serde_json::to_string_pretty(&self.root).expect("conic nodes only have string keys")
+    }
+}
+
+// Errors that can occur while turning a conic tree into shader blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConvertError {
+    MissingChild { block: String, child: &'static str }, // A block node lacks a required child
+    MalformedNumber { block: String, child: &'static str, column: usize, token: String }, // A payload value is not a number
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::MissingChild { block, child } => write!(f, "block {:?} has no {} child", block, child),
+            ConvertError::MalformedNumber { block, child, column, token } => {
+                write!(f, "invalid number {:?} at index {} of {} in block {:?}", token, column, child, block)
+            }
+        }
+    }
+}
+
+impl Error for ConvertError {}
+
+impl ConicTree {
+    // Collects every node named "block" (or "block" followed by a digit, '_' or '-'), depth-first, into a shader block.
+    // vertex_data (x, y, z per vertex) and material_data children are required, uv_data (u, v per vertex),
+    // indices and texture_id are optional.
+    // Lists are either a CSV value or children holding one number each, e.g. a JSON array.
+    pub fn to_partitioned_data(&self) -> std::result::Result<PartitionedData, ConvertError> {
+        let blocks = self.iter_dfs()
+            .filter(|node| is_block_name(&node.name))
+            .map(shader_block_from_node)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(PartitionedData { blocks })
+    }
+}
+
+fn is_block_name(name: &str) -> bool {
+    match name.strip_prefix("block") {
+        Some(rest) => rest.is_empty() || rest.starts_with(|c: char| c.is_ascii_digit() || c == '_' || c == '-'),
+        None => false,
+    }
+}
+
+// Groups separate position and UV arrays into vertices without normals, ignoring a trailing partial vertex.
+// UVs are paired by vertex index, vertices without a full UV pair get (0, 0).
+pub(crate) fn vertices_from_floats<'a>(positions: &'a [f32], uvs: &'a [f32]) -> impl ExactSizeIterator<Item = Vertex> + 'a {
+    positions.chunks_exact(3).enumerate().map(move |(i, p)| Vertex {
+        position: [p[0], p[1], p[2]],
+        normal: [0.0; 3],
+        uv: match uvs.get(i * 2..i * 2 + 2) {
+            Some(uv) => [uv[0], uv[1]],
+            None => [0.0, 0.0],
+        },
+    })
+}
+
+fn shader_block_from_node(block: &ConicNode) -> std::result::Result<ShaderBlock, ConvertError> {
+    let child = |name: &str| block.children.iter().find(|c| c.name == name);
+    let optional = |name: &'static str| child(name).map(|node| (name, node));
+    let required = |name: &'static str| {
+        optional(name).ok_or_else(|| ConvertError::MissingChild { block: block.name.clone(), child: name })
+    };
+
+    let positions: Vec<f32> = conic_numbers(block, Some(required("vertex_data")?))?;
+    let uvs: Vec<f32> = conic_numbers(block, optional("uv_data"))?;
+    Ok(ShaderBlock {
+        vertices: vertices_from_floats(&positions, &uvs).collect(),
+        material_data: conic_numbers(block, Some(required("material_data")?))?,
+        indices: conic_numbers(block, optional("indices"))?,
+        texture_id: conic_numbers::<u32>(block, optional("texture_id"))?.first().copied(),
+        pipeline: PipelineKey::default(),
+        instances: Vec::new(),
+    })
+}
+
+// Reads a list of numbers from a CSV value or from one value per child, a missing child is an empty list
+fn conic_numbers<T: FromStr>(block: &ConicNode, child: Option<(&'static str, &ConicNode)>) -> std::result::Result<Vec<T>, ConvertError> {
+    let (name, node) = match child {
+        Some(child) => child,
+        None => return Ok(Vec::new()),
+    };
+    let tokens: Vec<&str> = if node.children.is_empty() {
+        match node.value.as_deref().map(str::trim) {
+            Some(csv) if !csv.is_empty() => csv.split(',').collect(),
+            _ => Vec::new(),
+        }
+    } else {
+        node.children.iter().map(|c| c.value.as_deref().unwrap_or("")).collect()
+    };
+    tokens.iter()
+        .enumerate()
+        .map(|(column, token)| {
+            token.trim().parse::<T>().map_err(|_| ConvertError::MalformedNumber {
+                block: block.name.clone(),
+                child: name,
+                column,
+                token: token.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn conic_node_from_json(name: &str, json: &serde_json::Value) -> ConicNode {
+    use serde_json::Value as Json;
+
+    match json {
+        Json::Object(map) => {
+            let mut node = ConicNode::new(name, None);
+            for (key, child) in map {
+                node.add_child(conic_node_from_json(key, child));
+            }
+            node
+        }
+        Json::Array(items) => {
+            let mut node = ConicNode::new(name, None);
+            for (index, child) in items.iter().enumerate() {
+                node.add_child(conic_node_from_json(&index.to_string(), child));
+            }
+            node
+        }
+        Json::String(s) => ConicNode::new(name, Some(s)),
+        Json::Number(n) => ConicNode::new(name, Some(&n.to_string())).with_kind(ConicValueKind::Number),
+        Json::Bool(b) => ConicNode::new(name, Some(&b.to_string())).with_kind(ConicValueKind::Bool),
+        Json::Null => ConicNode::new(name, None),
+    }
+}
@@ -0,0 +1,4 @@
+// Ingests video metrics into SQLite, partitions them into shader blocks and renders those with Vulkan
+pub mod db_ingestor;
+pub mod shader_partition_compressor;
+pub mod vulkano_renderer;
@@ -0,0 +1,732 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::db_ingestor::{
+    vertices_from_floats, BlendMode, CullMode, DatabaseManager, FrameData, PartitionedData, PipelineKey, ShaderBlock, ShaderKind,
+    Vertex, VertexLayout, VideoMetricsIter,
+};
+
+// Number of consecutive frame numbers grouped into one block unless configured otherwise
+pub const DEFAULT_FRAMES_PER_BLOCK: u32 = 1;
+
+// Errors that can occur while reading and partitioning frame data
+#[derive(Debug)]
+pub enum PartitionError {
+    Open(rusqlite::Error),       // No database could be opened at the given path
+    Database(rusqlite::Error),   // The video_metrics rows could not be read
+    Decompress(DecompressError), // A compressed block could not be decoded
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionError::Open(e) => write!(f, "failed to open database: {}", e),
+            PartitionError::Database(e) => write!(f, "failed to read frame data: {}", e),
+            PartitionError::Decompress(e) => write!(f, "failed to decompress block: {}", e),
+        }
+    }
+}
+
+impl Error for PartitionError {}
+
+impl From<rusqlite::Error> for PartitionError {
+    fn from(e: rusqlite::Error) -> Self {
+        PartitionError::Database(e)
+    }
+}
+
+// Decides which frames end up in which shader block
+pub trait PartitionStrategy {
+    fn partition(&self, frames: Vec<FrameData>) -> Vec<ShaderBlock>;
+}
+
+// Groups frames by frame_number range: frames n * frames_per_block up to (n + 1) * frames_per_block - 1
+// form block n. Vertex data of a group is concatenated in frame order and the first frame's material is used.
+// Empty ranges produce no block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRange {
+    pub frames_per_block: u32,
+}
+
+impl Default for FrameRange {
+    fn default() -> Self {
+        Self { frames_per_block: DEFAULT_FRAMES_PER_BLOCK }
+    }
+}
+
+impl PartitionStrategy for FrameRange {
+    fn partition(&self, frames: Vec<FrameData>) -> Vec<ShaderBlock> {
+        let frames_per_block = self.frames_per_block.max(1);
+
+        let mut ranges: BTreeMap<u32, Vec<FrameData>> = BTreeMap::new();
+        for frame in frames {
+            ranges.entry(frame.frame_number / frames_per_block).or_default().push(frame);
+        }
+
+        ranges.into_values()
+            .map(|mut frames| {
+                frames.sort_by_key(|frame| frame.frame_number); // Stable, rows keep their order within a frame
+                let material_data = frames[0].material_data.clone();
+                ShaderBlock {
+                    vertices: frames.into_iter().flat_map(|frame| frame.vertices).collect(),
+                    material_data,
+                    indices: Vec::new(),
+                    texture_id: None,
+                    pipeline: PipelineKey::default(),
+                    instances: Vec::new(),
+                }
+            })
+            .collect()
+    }
+}
+
+// Reads every frame from the database at db_path and partitions it one block per frame. A missing
+// database is an error rather than an empty one created in its place.
+pub fn partition_data(db_path: &str) -> Result<PartitionedData, PartitionError> {
+    partition_data_with(db_path, &FrameRange::default())
+}
+
+// Same as partition_data, with a custom strategy
+pub fn partition_data_with<S: PartitionStrategy + ?Sized>(db_path: &str, strategy: &S) -> Result<PartitionedData, PartitionError> {
+    let db = DatabaseManager::open_existing(db_path).map_err(PartitionError::Open)?;
+    partition_metrics(&db, strategy)
+}
+
+// Partitions the frames of an already open database
+pub fn partition_metrics<S: PartitionStrategy + ?Sized>(db: &DatabaseManager, strategy: &S) -> Result<PartitionedData, PartitionError> {
+    let metrics = db.ingest_video_metrics()?;
+    Ok(PartitionedData { blocks: strategy.partition(metrics.frame_data) })
+}
+
+// Like partition_metrics with a FrameRange, but reads the frames in batches and yields each block once the
+// first frame of the next range is read, so the whole capture is never in memory. Frames of one range
+// have to be stored next to each other, as captures write them; a range that shows up again later
+// becomes a second block.
+pub fn partition_metrics_iter(db: &DatabaseManager, strategy: FrameRange) -> FrameRangeBlocks<'_> {
+    FrameRangeBlocks { frames: db.ingest_video_metrics_iter(), strategy, pending: Vec::new() }
+}
+
+// Blocks of partition_metrics_iter in the order their frames are stored
+pub struct FrameRangeBlocks<'a> {
+    frames: VideoMetricsIter<'a>,
+    strategy: FrameRange,
+    pending: Vec<FrameData>, // Frames of the range being read
+}
+
+impl FrameRangeBlocks<'_> {
+    fn range(&self, frame: &FrameData) -> u32 {
+        frame.frame_number / self.strategy.frames_per_block.max(1)
+    }
+
+    // The block of the pending frames, None when there are none
+    fn take_block(&mut self) -> Option<ShaderBlock> {
+        self.strategy.partition(std::mem::take(&mut self.pending)).pop()
+    }
+}
+
+impl Iterator for FrameRangeBlocks<'_> {
+    type Item = Result<ShaderBlock, PartitionError>;
+
+    fn next(&mut self) -> Option<Result<ShaderBlock, PartitionError>> {
+        loop {
+            match self.frames.next() {
+                Some(Ok(frame)) => {
+                    let next_range = self.pending.first().map_or(false, |first| self.range(first) != self.range(&frame));
+                    let block = if next_range { self.take_block() } else { None };
+                    self.pending.push(frame);
+                    if block.is_some() {
+                        return block.map(Ok);
+                    }
+                }
+                Some(Err(e)) => {
+                    // The frames iterator stops after an error, a partial block would be wrong
+                    self.pending.clear();
+                    return Some(Err(e.into()));
+                }
+                None => return self.take_block().map(Ok),
+            }
+        }
+    }
+}
+
+// Header written before every compressed block, followed by the layout version
+const BLOCK_MAGIC: &[u8; 3] = b"ZSB";
+
+// Layout versions, ASCII digits so headers read "ZSB1" and "ZSB2". Version 1 stores positions and UVs as
+// separate arrays, optionally followed by a pipeline key, and is migrated on decompression. Version 2 stores
+// whole vertices, the pipeline key and optional instances.
+const BLOCK_VERSION_SPLIT_UVS: u8 = b'1';
+const BLOCK_VERSION: u8 = b'2';
+
+// How the float arrays of a block are encoded by compress_block_with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,           // Raw little-endian values, only the framing is added
+    Lossless,       // Each float is XORed with the same component of the previous vertex and varint encoded
+    Quantized(f32), // Floats are rounded to multiples of the step and delta encoded, smallest output but lossy
+}                   // The step must be finite and positive, see Compression::quantized
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Lossless
+    }
+}
+
+impl Compression {
+    // Quantized with the given step, None unless the step is finite and positive. Other steps would
+    // divide every float into an infinity or NaN and decode it as 0.
+    pub fn quantized(step: f32) -> Option<Self> {
+        if step.is_finite() && step > 0.0 {
+            Some(Compression::Quantized(step))
+        } else {
+            None
+        }
+    }
+
+    fn mode(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lossless => 1,
+            Compression::Quantized(_) => 2,
+        }
+    }
+}
+
+// Errors that can occur while decoding a compressed block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecompressError {
+    BadMagic,        // The data does not start with a compressed block header
+    UnknownVersion(u8), // The header names a layout this version cannot decode, e.g. written by a newer one
+    UnknownMode(u8), // The header names a compression this version cannot decode
+    Truncated,       // The data ends in the middle of a value
+    UnknownPipeline([u8; 3]), // The pipeline key bytes name a blend, cull or shader mode this version lacks
+    PartialVertex(usize), // The vertex array holds this many floats, which is not a whole number of vertices
+    InvalidStep(u32), // The bits of a quantization step that is not finite and positive
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecompressError::BadMagic => write!(f, "not a compressed shader block"),
+            DecompressError::UnknownVersion(version) => write!(f, "unknown compressed block version {:?}", *version as char),
+            DecompressError::UnknownMode(mode) => write!(f, "unknown compression mode {}", mode),
+            DecompressError::Truncated => write!(f, "compressed shader block is truncated"),
+            DecompressError::UnknownPipeline(bytes) => write!(f, "unknown pipeline key {:?}", bytes),
+            DecompressError::PartialVertex(len) => write!(f, "{} vertex floats do not form whole vertices", len),
+            DecompressError::InvalidStep(bits) => write!(f, "invalid quantization step {}", f32::from_bits(*bits)),
+        }
+    }
+}
+
+impl Error for DecompressError {}
+
+impl From<DecompressError> for PartitionError {
+    fn from(e: DecompressError) -> Self {
+        PartitionError::Decompress(e)
+    }
+}
+
+// Compresses a block with the default lossless compression
+pub fn compress_block(block: &ShaderBlock) -> Vec<u8> {
+    compress_block_with(block, Compression::default())
+}
+
+// Serializes a block as the header, vertex and material arrays, indices, texture id, pipeline key and instances.
+// The output only depends on the block and compression, so equal blocks compress to equal bytes.
+// Panics on a Quantized step that Compression::quantized would reject.
+pub fn compress_block_with(block: &ShaderBlock, compression: Compression) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16 + block.vertices.len() * std::mem::size_of::<Vertex>());
+    out.extend_from_slice(BLOCK_MAGIC);
+    out.push(BLOCK_VERSION);
+    out.push(compression.mode());
+    if let Compression::Quantized(step) = compression {
+        assert!(Compression::quantized(step).is_some(), "invalid quantization step {}", step);
+        out.extend_from_slice(&step.to_le_bytes());
+    }
+
+    let layout = VertexLayout::PositionNormalUv;
+    encode_floats(&mut out, &Vertex::to_raw_f32(&block.vertices, layout), layout.stride(), compression);
+    encode_floats(&mut out, &block.material_data, 1, compression);
+
+    // Indices are delta encoded against the same corner of the previous triangle
+    write_varint(&mut out, block.indices.len() as u64);
+    for (i, &index) in block.indices.iter().enumerate() {
+        let previous = if i >= 3 { block.indices[i - 3] as i64 } else { 0 };
+        write_varint(&mut out, zigzag(index as i64 - previous));
+    }
+
+    match block.texture_id {
+        Some(id) => {
+            out.push(1);
+            write_varint(&mut out, id as u64);
+        }
+        None => out.push(0),
+    }
+    out.extend_from_slice(&pipeline_key_bytes(block.pipeline));
+
+    // Optional trailing section, so blocks without instances keep their old encoding
+    if !block.instances.is_empty() {
+        let floats: Vec<f32> = block.instances.iter().flat_map(|m| m.iter().flatten().copied()).collect();
+        encode_floats(&mut out, &floats, 16, compression);
+    }
+    out
+}
+
+// One byte each for the blend mode, cull mode and shader kind
+fn pipeline_key_bytes(key: PipelineKey) -> [u8; 3] {
+    let blend = match key.blend {
+        BlendMode::Opaque => 0,
+        BlendMode::AlphaBlend => 1,
+    };
+    let cull = match key.cull {
+        CullMode::None => 0,
+        CullMode::Back => 1,
+        CullMode::Front => 2,
+    };
+    let shaders = match key.shaders {
+        ShaderKind::Unlit => 0,
+        ShaderKind::Lit => 1,
+    };
+    [blend, cull, shaders]
+}
+
+fn pipeline_key_from_bytes(bytes: [u8; 3]) -> Result<PipelineKey, DecompressError> {
+    let blend = match bytes[0] {
+        0 => BlendMode::Opaque,
+        1 => BlendMode::AlphaBlend,
+        _ => return Err(DecompressError::UnknownPipeline(bytes)),
+    };
+    let cull = match bytes[1] {
+        0 => CullMode::None,
+        1 => CullMode::Back,
+        2 => CullMode::Front,
+        _ => return Err(DecompressError::UnknownPipeline(bytes)),
+    };
+    let shaders = match bytes[2] {
+        0 => ShaderKind::Unlit,
+        1 => ShaderKind::Lit,
+        _ => return Err(DecompressError::UnknownPipeline(bytes)),
+    };
+    Ok(PipelineKey { blend, cull, shaders })
+}
+
+// Decodes a block written by compress_block or compress_block_with, by this or an earlier version
+pub fn decompress_block(data: &[u8]) -> Result<ShaderBlock, DecompressError> {
+    let mut reader = ByteReader { data, pos: 0 };
+    if reader.take(3)? != BLOCK_MAGIC {
+        return Err(DecompressError::BadMagic);
+    }
+    let version = reader.byte()?;
+    if version != BLOCK_VERSION && version != BLOCK_VERSION_SPLIT_UVS {
+        return Err(DecompressError::UnknownVersion(version));
+    }
+    let compression = match reader.byte()? {
+        0 => Compression::None,
+        1 => Compression::Lossless,
+        2 => {
+            let step = f32::from_le_bytes(reader.array()?);
+            Compression::quantized(step).ok_or(DecompressError::InvalidStep(step.to_bits()))?
+        }
+        mode => return Err(DecompressError::UnknownMode(mode)),
+    };
+
+    let vertices = if version == BLOCK_VERSION_SPLIT_UVS {
+        let positions = decode_floats(&mut reader, 3, compression)?;
+        if positions.len() % 3 != 0 {
+            return Err(DecompressError::PartialVertex(positions.len()));
+        }
+        let uvs = decode_floats(&mut reader, 2, compression)?;
+        vertices_from_floats(&positions, &uvs).collect()
+    } else {
+        let layout = VertexLayout::PositionNormalUv;
+        let raw = decode_floats(&mut reader, layout.stride(), compression)?;
+        Vertex::from_raw_f32(&raw, layout).map_err(|e| DecompressError::PartialVertex(e.len))?
+    };
+    let material_data = decode_floats(&mut reader, 1, compression)?;
+
+    let len = reader.varint()? as usize;
+    let mut indices: Vec<u32> = Vec::with_capacity(len.min(data.len()));
+    for i in 0..len {
+        let previous = if i >= 3 { indices[i - 3] as i64 } else { 0 };
+        indices.push(previous.wrapping_add(unzigzag(reader.varint()?)) as u32);
+    }
+
+    let texture_id = match reader.byte()? {
+        0 => None,
+        _ => Some(reader.varint()? as u32),
+    };
+    // Version 1 blocks written before pipeline keys end here
+    let pipeline = if version == BLOCK_VERSION_SPLIT_UVS && reader.pos == data.len() {
+        PipelineKey::default()
+    } else {
+        let key = reader.take(3)?;
+        pipeline_key_from_bytes([key[0], key[1], key[2]])?
+    };
+
+    let instances = if version != BLOCK_VERSION_SPLIT_UVS && reader.pos < data.len() {
+        let floats = decode_floats(&mut reader, 16, compression)?;
+        if floats.len() % 16 != 0 {
+            return Err(DecompressError::Truncated);
+        }
+        floats.chunks_exact(16).map(|m| {
+            let mut transform = [[0.0; 4]; 4];
+            for (i, value) in m.iter().enumerate() {
+                transform[i / 4][i % 4] = *value;
+            }
+            transform
+        }).collect()
+    } else {
+        Vec::new()
+    };
+    Ok(ShaderBlock { vertices, material_data, indices, texture_id, pipeline, instances })
+}
+
+// Deltas are taken against the value stride places back, i.e. the same component of the previous vertex
+fn encode_floats(out: &mut Vec<u8>, values: &[f32], stride: usize, compression: Compression) {
+    write_varint(out, values.len() as u64);
+    match compression {
+        Compression::None => {
+            for value in values {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        Compression::Lossless => {
+            for (i, value) in values.iter().enumerate() {
+                let previous = if i >= stride { values[i - stride].to_bits() } else { 0 };
+                write_varint(out, (value.to_bits() ^ previous) as u64);
+            }
+        }
+        Compression::Quantized(step) => {
+            let quantized: Vec<i64> = values.iter().map(|value| (value / step).round() as i64).collect();
+            for (i, q) in quantized.iter().enumerate() {
+                let previous = if i >= stride { quantized[i - stride] } else { 0 };
+                write_varint(out, zigzag(q.wrapping_sub(previous)));
+            }
+        }
+    }
+}
+
+fn decode_floats(reader: &mut ByteReader, stride: usize, compression: Compression) -> Result<Vec<f32>, DecompressError> {
+    let len = reader.varint()? as usize;
+    let mut values: Vec<f32> = Vec::with_capacity(len.min(reader.data.len()));
+    match compression {
+        Compression::None => {
+            for _ in 0..len {
+                values.push(f32::from_le_bytes(reader.array()?));
+            }
+        }
+        Compression::Lossless => {
+            for i in 0..len {
+                let previous = if i >= stride { values[i - stride].to_bits() } else { 0 };
+                values.push(f32::from_bits(reader.varint()? as u32 ^ previous));
+            }
+        }
+        Compression::Quantized(step) => {
+            let mut quantized: Vec<i64> = Vec::with_capacity(values.capacity());
+            for i in 0..len {
+                let previous = if i >= stride { quantized[i - stride] } else { 0 };
+                let q = previous.wrapping_add(unzigzag(reader.varint()?));
+                quantized.push(q);
+                values.push(q as f32 * step);
+            }
+        }
+    }
+    Ok(values)
+}
+
+// Cursor over compressed bytes that fails with Truncated instead of panicking
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecompressError> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(DecompressError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecompressError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array(&mut self) -> Result<[u8; 4], DecompressError> {
+        let bytes = self.take(4)?;
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn varint(&mut self) -> Result<u64, DecompressError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecompressError::Truncated)
+    }
+}
+
+// LEB128, seven bits per byte, low bits first
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Maps small negative and positive deltas to small unsigned values
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_ingestor::{StorageFormat, VideoMetrics, IDENTITY_TRANSFORM};
+
+    fn frame(frame_number: u32, vertex: f32, red: f32) -> FrameData {
+        let vertices = vec![Vertex { position: [vertex; 3], ..Vertex::default() }];
+        FrameData { frame_number, vertices, material_data: vec![red, 0.0, 0.0, 1.0] }
+    }
+
+    #[test]
+    fn test_partition_metrics_by_frame_range() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        let frames = vec![frame(3, 3.0, 0.3), frame(0, 0.0, 0.0), frame(1, 1.0, 0.1), frame(5, 5.0, 0.5)];
+        db.store_video_metrics(&VideoMetrics { frame_data: frames }).unwrap();
+
+        let per_frame = partition_metrics(&db, &FrameRange::default()).unwrap();
+        assert_eq!(per_frame.blocks.len(), 4);
+        assert_eq!(per_frame.blocks[0].vertices[0].position, [0.0; 3]);
+        assert_eq!(per_frame.blocks[3].vertices[0].position, [5.0; 3]);
+
+        // Frames 0-1, 2-3 and 4-5 are grouped, in frame order
+        let pairs = partition_metrics(&db, &FrameRange { frames_per_block: 2 }).unwrap();
+        assert_eq!(pairs.blocks.len(), 3);
+        let positions: Vec<[f32; 3]> = pairs.blocks[0].vertices.iter().map(|v| v.position).collect();
+        assert_eq!(positions, vec![[0.0; 3], [1.0; 3]]);
+        assert_eq!(pairs.blocks[0].material_data, vec![0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(pairs.blocks[1].material_data, vec![0.3, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_partition_metrics_iter_streams_ranges() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        let frames = vec![frame(0, 0.0, 0.0), frame(1, 1.0, 0.1), frame(2, 2.0, 0.2), frame(5, 5.0, 0.5), frame(1, 1.5, 0.1)];
+        db.store_video_metrics(&VideoMetrics { frame_data: frames }).unwrap();
+
+        let blocks: Vec<ShaderBlock> = partition_metrics_iter(&db, FrameRange { frames_per_block: 2 })
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let positions: Vec<Vec<f32>> = blocks.iter()
+            .map(|block| block.vertices.iter().map(|v| v.position[0]).collect())
+            .collect();
+        // Frame 1 stored after frame 5 starts a second block for its range
+        assert_eq!(positions, vec![vec![0.0, 1.0], vec![2.0], vec![5.0], vec![1.5]]);
+        assert_eq!(blocks[1].material_data, vec![0.2, 0.0, 0.0, 1.0]);
+
+        let empty = DatabaseManager::new(":memory:").unwrap();
+        assert!(matches!(partition_metrics_iter(&empty, FrameRange::default()).next(), Some(Err(PartitionError::Database(_)))));
+    }
+
+    #[test]
+    fn test_custom_strategy() {
+        // Everything in one block
+        struct Single;
+        impl PartitionStrategy for Single {
+            fn partition(&self, frames: Vec<FrameData>) -> Vec<ShaderBlock> {
+                FrameRange { frames_per_block: u32::MAX }.partition(frames)
+            }
+        }
+
+        let db = DatabaseManager::new(":memory:").unwrap();
+        db.store_video_metrics(&VideoMetrics { frame_data: vec![frame(0, 0.0, 0.0), frame(9, 9.0, 0.9)] }).unwrap();
+        let data = partition_metrics(&db, &Single).unwrap();
+        assert_eq!(data.blocks.len(), 1);
+        assert_eq!(data.blocks[0].vertices.len(), 2);
+    }
+
+    // A 100x100 grid of vertices over [-1, 1], the shape of a typical terrain or plane mesh
+    fn grid_block() -> ShaderBlock {
+        let mut vertices = Vec::new();
+        for row in 0..100 {
+            for column in 0..100 {
+                let (u, v) = (column as f32 / 99.0, row as f32 / 99.0);
+                vertices.push(Vertex {
+                    position: [u * 2.0 - 1.0, (u * 6.0).sin() * 0.1, v * 2.0 - 1.0],
+                    normal: [0.0, 1.0, 0.0],
+                    uv: [u, v],
+                });
+            }
+        }
+        let indices = (0..99u32).flat_map(|row| (0..99u32).flat_map(move |column| {
+            let i = row * 100 + column;
+            vec![i, i + 1, i + 100, i + 1, i + 101, i + 100]
+        })).collect();
+        ShaderBlock {
+            vertices,
+            material_data: vec![0.8, 0.8, 0.8, 1.0, 0.5, 0.0],
+            indices,
+            texture_id: Some(2),
+            pipeline: PipelineKey { blend: BlendMode::AlphaBlend, cull: CullMode::Back, shaders: ShaderKind::Lit },
+            instances: Vec::new(),
+        }
+    }
+
+    fn raw_size(block: &ShaderBlock) -> usize {
+        block.vertices.len() * std::mem::size_of::<Vertex>() + 4 * (block.material_data.len() + block.indices.len())
+    }
+
+    #[test]
+    fn test_compress_block_round_trip() {
+        let block = grid_block();
+        for compression in [Compression::None, Compression::Lossless] {
+            let compressed = compress_block_with(&block, compression);
+            let decoded = decompress_block(&compressed).unwrap();
+            assert_eq!(decoded.vertices, block.vertices);
+            assert_eq!(decoded.material_data, block.material_data);
+            assert_eq!(decoded.indices, block.indices);
+            assert_eq!(decoded.texture_id, block.texture_id);
+            assert_eq!(decoded.pipeline, block.pipeline);
+        }
+        assert_eq!(compress_block(&block), compress_block(&block)); // Deterministic
+    }
+
+    #[test]
+    fn test_compress_block_instances() {
+        let plain = grid_block();
+        let mut instanced = grid_block();
+        let mut moved = IDENTITY_TRANSFORM;
+        moved[3] = [2.0, 0.0, -1.5, 1.0];
+        instanced.instances = vec![IDENTITY_TRANSFORM, moved];
+        for compression in [Compression::None, Compression::Lossless] {
+            let decoded = decompress_block(&compress_block_with(&instanced, compression)).unwrap();
+            assert_eq!(decoded.instances, instanced.instances);
+        }
+
+        // Blocks without instances keep the encoding from before instancing
+        let compressed = compress_block(&plain);
+        assert!(compress_block(&instanced).starts_with(&compressed));
+        assert!(decompress_block(&compressed).unwrap().instances.is_empty());
+    }
+
+    #[test]
+    fn test_lossless_compression_ratio() {
+        let block = grid_block();
+        let compressed = compress_block(&block);
+        let ratio = raw_size(&block) as f32 / compressed.len() as f32;
+        assert!(ratio > 2.0, "compression ratio {:.2}", ratio); // About 2.4 for this grid
+    }
+
+    #[test]
+    fn test_quantized_compression_ratio() {
+        let block = grid_block();
+        let step = 1.0 / 4096.0;
+        let compressed = compress_block_with(&block, Compression::quantized(step).unwrap());
+        let ratio = raw_size(&block) as f32 / compressed.len() as f32;
+        assert!(ratio > 2.5, "compression ratio {:.2}", ratio);
+
+        let decoded = decompress_block(&compressed).unwrap();
+        assert_eq!(decoded.indices, block.indices);
+        for (decoded, original) in decoded.vertices.iter().zip(&block.vertices) {
+            for (d, o) in decoded.position.iter().zip(&original.position) {
+                assert!((d - o).abs() <= step / 2.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantized_step_must_be_finite_and_positive() {
+        for step in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert_eq!(Compression::quantized(step), None);
+        }
+        assert!(std::panic::catch_unwind(|| compress_block_with(&grid_block(), Compression::Quantized(0.0))).is_err());
+
+        // Blocks written with a zero step would decode every float as 0
+        let mut compressed = compress_block_with(&grid_block(), Compression::quantized(0.5).unwrap());
+        compressed[5..9].copy_from_slice(&0.0f32.to_le_bytes());
+        assert_eq!(decompress_block(&compressed).unwrap_err(), DecompressError::InvalidStep(0));
+    }
+
+    #[test]
+    fn test_decompress_rejects_bad_input() {
+        let compressed = compress_block(&grid_block());
+        assert_eq!(decompress_block(b"nope").unwrap_err(), DecompressError::BadMagic);
+        assert_eq!(decompress_block(&compressed[..compressed.len() / 2]).unwrap_err(), DecompressError::Truncated);
+        assert_eq!(decompress_block(b"ZSB2\x09").unwrap_err(), DecompressError::UnknownMode(9));
+        assert_eq!(decompress_block(b"ZSB9\x01").unwrap_err(), DecompressError::UnknownVersion(b'9'));
+        assert!(compressed.starts_with(b"ZSB2"));
+    }
+
+    #[test]
+    fn test_decompress_migrates_split_uv_blocks() {
+        // Version 1: positions, UVs and material as separate arrays, indices and the texture id
+        let mut legacy = b"ZSB1".to_vec();
+        legacy.push(Compression::Lossless.mode());
+        encode_floats(&mut legacy, &[0.0, 0.5, 0.0, -0.5, -0.5, 0.0], 3, Compression::Lossless);
+        encode_floats(&mut legacy, &[0.5, 0.0, 1.0, 1.0], 2, Compression::Lossless);
+        encode_floats(&mut legacy, &[1.0, 0.0, 0.0, 1.0], 1, Compression::Lossless);
+        write_varint(&mut legacy, 0);
+        legacy.push(0);
+
+        let block = decompress_block(&legacy).unwrap();
+        assert_eq!(block.vertices.len(), 2);
+        assert_eq!(block.vertices[1], Vertex { position: [-0.5, -0.5, 0.0], uv: [1.0, 1.0], ..Vertex::default() });
+        assert_eq!(block.material_data, vec![1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(block.pipeline, PipelineKey::default());
+
+        // The same layout with the pipeline key appended
+        legacy.extend_from_slice(&[1, 1, 1]);
+        let block = decompress_block(&legacy).unwrap();
+        assert_eq!(block.pipeline, PipelineKey { blend: BlendMode::AlphaBlend, cull: CullMode::Back, shaders: ShaderKind::Lit });
+        assert!(block.instances.is_empty());
+    }
+
+    #[test]
+    fn test_zigzag() {
+        for value in [0i64, 1, -1, 63, -64, i64::MAX, i64::MIN] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+    }
+
+    #[test]
+    fn test_partition_data_reads_compressed_databases() {
+        let path = std::env::temp_dir().join(format!("zeta_dom_compressed_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let frames = vec![frame(0, 0.0, 0.0), frame(1, 1.0, 0.1)];
+        let db = DatabaseManager::new(path_str).unwrap().with_storage_format(StorageFormat::Compressed);
+        db.store_video_metrics(&VideoMetrics { frame_data: frames.clone() }).unwrap();
+        drop(db);
+
+        // Opened the way the renderer's loaders open it, without being told the format
+        let data = partition_data(path_str).unwrap();
+        assert_eq!(data.blocks.len(), 2);
+        assert_eq!(data.blocks[1].vertices, frames[1].vertices);
+        assert_eq!(data.blocks[1].material_data, frames[1].material_data);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_partition_reports_missing_table() {
+        let db = DatabaseManager::new(":memory:").unwrap();
+        assert!(matches!(partition_metrics(&db, &FrameRange::default()), Err(PartitionError::Database(_))));
+
+        // Partitioning a path with no database must not leave an empty one behind
+        let path = std::env::temp_dir().join(format!("zeta_dom_missing_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(partition_data(path.to_str().unwrap()), Err(PartitionError::Open(_))));
+        assert!(!path.exists());
+    }
+}
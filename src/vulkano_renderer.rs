@@ -10,185 +10,9236 @@
 
 #![allow(dead_code)]
 
-use std::collections::BTreeMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::time::{Duration, Instant};
 
 use rusqlite;
 use rusqlite::TransactionBehavior;
 
-use vulkano::device::{Device, Queue};
-use vulkano::pipeline::{GraphicsPipeline, viewport::Viewport};
-use vulkano::buffer::{CpuAccessibleBuffer};
+use vulkano::device::{Device, DeviceCreationError, DeviceOwned, Features, Queue};
+use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineCreationError, viewport::{Scissor, Viewport}};
+use vulkano::pipeline::{ComputePipeline, ComputePipelineCreationError};
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::vertex::BuffersDefinition;
+use vulkano::buffer::{BufferAccess, BufferSlice, BufferUsage, CpuAccessibleBuffer, CpuBufferPool, DeviceLocalBuffer, TypedBufferAccess};
+use vulkano::buffer::cpu_pool::CpuBufferPoolChunk;
 use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+use vulkano::command_buffer::{AutoCommandBufferBuilderContextError, BeginRenderPassError, BuildError};
+use vulkano::command_buffer::{CommandBufferExecError, CopyBufferError, CopyImageToBufferError, DispatchError, DrawError};
+use vulkano::command_buffer::{ExecuteCommandsError, PrimaryAutoCommandBuffer, SecondaryAutoCommandBuffer};
+use vulkano::command_buffer::{ResetQueryPoolError, WriteTimestampError};
+use vulkano::query::{QueryPool, QueryResultFlags, QueryType};
+use vulkano::sync::{AccessFlags, PipelineStage, PipelineStages};
 use vulkano::framebuffer::{Framebuffer, Subpass, RenderPass, FramebufferAbstract};
-use vulkano::image::{SwapchainImage, ImageUsage};
-use vulkano::swapchain::{Swapchain, Surface, PresentMode, SwapchainCreationError};
-use vulkano::sync::{self, GpuFuture};
-use vulkano::instance::{Instance, PhysicalDevice};
+use vulkano::framebuffer::{FramebufferCreationError, RenderPassCreationError};
+use vulkano::framebuffer::{AttachmentDesc, LoadOp, RenderPassDesc, StoreOp, SubpassDependencyDesc, SubpassDesc};
+use vulkano::format::{ClearValue, Format};
+use vulkano::image::{AttachmentImage, ImageAccess, ImageCreationError, ImageViewAccess, SwapchainImage, ImageUsage};
+use vulkano::image::{ImageDimensions, ImageLayout, ImmutableImage, MipmapsCount};
+use vulkano::sampler::{Filter, MipmapMode, Sampler, SamplerAddressMode, SamplerCreationError};
+use vulkano::swapchain::{Swapchain, Surface, PresentMode, SwapchainCreationError, AcquireError};
+use vulkano::swapchain::{CapabilitiesError, ColorSpace, SwapchainAcquireFuture};
+use vulkano::sync::{self, GpuFuture, FlushError, FenceSignalFuture};
+use vulkano::memory::DeviceMemoryAllocError;
+use vulkano::memory::pool::StdMemoryPool;
+use vulkano::{OomError, VulkanObject};
+use vulkano::instance::{Instance, InstanceCreationError, InstanceExtensions, PhysicalDevice, PhysicalDeviceType, QueueFamily};
+use vulkano::instance::{ApplicationInfo, layers_list};
+use vulkano::instance::debug::{DebugCallback, Message, MessageSeverity, MessageType};
+use vulkano::Version;
 use vulkano::device::DeviceExtensions;
-use vulkano::pipeline::shader::ShaderModule;
-use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::pipeline::shader::{ComputeEntryPoint, GraphicsEntryPoint, ShaderInterface, ShaderModule, SpecializationConstants};
+use vulkano::descriptor::descriptor_set::{DescriptorSet, PersistentDescriptorSet};
+use vulkano::descriptor::descriptor_set::{PersistentDescriptorSetBuildError, PersistentDescriptorSetError};
+use vulkano::descriptor::descriptor_set::{DescriptorSetDesc, UnsafeDescriptorSetLayout};
+use vulkano::descriptor::descriptor::DescriptorDescTy;
+use vulkano::buffer::cpu_access::{ReadLockError, WriteLockError};
+use vulkano::pipeline::PipelineBindPoint;
+
+use winit::event::{ElementState, Event, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::platform::run_return::EventLoopExtRunReturn;
+use winit::window::Window;
+
+use crate::db_ingestor::{ConicNode, ConicTree, DatabaseManager, FrameData, FrameTiming, PartitionedData, ShaderBlock, VideoMetrics};
+use crate::db_ingestor::{is_spirv, BlendMode, CullMode, PipelineKey, ShaderKind, Transform, Vertex, VertexLayout, VertexLayoutError, IDENTITY_TRANSFORM};
+use crate::shader_partition_compressor::{self, FrameRange, PartitionError};
+
+// Vertex is stored by db_ingestor, its fields are the vertex shader's per-vertex inputs
+vulkano::impl_vertex!(Vertex, position, normal, uv);
+
+// Per-instance input of the vertex shader, one per entry of ShaderBlock::instances or a single identity
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct InstanceData {
+    pub instance_transform: Transform,
+}
+
+vulkano::impl_vertex!(InstanceData, instance_transform);
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        src: "
+            #version 450
+
+            layout(location = 0) in vec3 position;
+            layout(location = 1) in vec2 uv;
+            layout(location = 2) in vec3 normal;
+            layout(location = 3) in mat4 instance_transform; // Per instance, identity for blocks without instances
+
+            layout(location = 0) out vec2 v_uv;
+            layout(location = 1) out vec3 v_position; // World space, for shading
+            layout(location = 2) out vec3 v_normal;   // World space, zero when the block has no normals
+
+            layout(set = 0, binding = 0) uniform Camera {
+                mat4 view;
+                mat4 proj;
+            } camera;
+
+            // Used instead of the push constant when the device cannot fit a mat4 in push constants
+            layout(set = 2, binding = 0) uniform Model {
+                mat4 transform;
+            } model;
+
+            layout(push_constant) uniform PushConstants {
+                mat4 transform;
+            } push;
+
+            void main() {
+                mat4 transform = push.transform * model.transform * instance_transform;
+                vec4 world = transform * vec4(position, 1.0);
+                gl_Position = camera.proj * camera.view * world;
+                v_uv = uv;
+                v_position = world.xyz;
+                v_normal = mat3(transform) * normal;
+            }
+        "
+    }
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        src: "
+            #version 450
+
+            layout(location = 0) in vec2 v_uv;
+            layout(location = 2) in vec3 v_normal;
+
+            layout(location = 0) out vec4 f_color;
+            layout(location = 1) out vec4 f_normal; // Discarded in subpasses with a single color attachment
+
+            layout(set = 1, binding = 0) uniform Material {
+                vec4 base_color;
+                float roughness;
+                float metallic;
+            } material;
+
+            // White when the block has no texture, so the base color is used as is
+            layout(set = 1, binding = 1) uniform sampler2D tex;
+
+            // Set when the color target is a UNORM format, which stores shader output without
+            // the sRGB encoding an _SRGB format would apply
+            layout(constant_id = 0) const bool manual_gamma = false;
+
+            // Set for draw subpasses with more than one color attachment, the second receives the normal
+            layout(constant_id = 1) const bool write_normals = false;
+
+            vec3 srgb_encode(vec3 c) {
+                return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(0.0031308, c));
+            }
+
+            void main() {
+                f_color = material.base_color * texture(tex, v_uv);
+                if (manual_gamma) {
+                    f_color.rgb = srgb_encode(f_color.rgb);
+                }
+                if (write_normals) {
+                    f_normal = vec4(v_normal * 0.5 + 0.5, 1.0);
+                }
+            }
+        "
+    }
+}
+
+// Same material inputs as fs, lit by a fixed directional light
+mod fs_lit {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        src: "
+            #version 450
+
+            layout(location = 0) in vec2 v_uv;
+            layout(location = 1) in vec3 v_position;
+            layout(location = 2) in vec3 v_normal;
+
+            layout(location = 0) out vec4 f_color;
+            layout(location = 1) out vec4 f_normal;
+
+            layout(set = 1, binding = 0) uniform Material {
+                vec4 base_color;
+                float roughness;
+                float metallic;
+            } material;
+
+            layout(set = 1, binding = 1) uniform sampler2D tex;
+
+            const vec3 LIGHT_DIR = vec3(0.267, 0.802, 0.535);
+            const float AMBIENT = 0.2;
+
+            // Same flags as fs
+            layout(constant_id = 0) const bool manual_gamma = false;
+            layout(constant_id = 1) const bool write_normals = false;
+
+            vec3 srgb_encode(vec3 c) {
+                return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(0.0031308, c));
+            }
+
+            void main() {
+                // Faces of blocks without normals are shaded flat from the screen-space derivatives
+                vec3 normal = length(v_normal) > 0.0
+                    ? normalize(v_normal)
+                    : normalize(cross(dFdx(v_position), dFdy(v_position)));
+                float diffuse = abs(dot(normal, LIGHT_DIR));
+                vec4 color = material.base_color * texture(tex, v_uv);
+                f_color = vec4(color.rgb * (AMBIENT + (1.0 - AMBIENT) * diffuse), color.a);
+                if (manual_gamma) {
+                    f_color.rgb = srgb_encode(f_color.rgb);
+                }
+                if (write_normals) {
+                    f_normal = vec4(normal * 0.5 + 0.5, 1.0);
+                }
+            }
+        "
+    }
+}
+
+// Default preprocess pass, copies the vertices of a block unchanged. Shaders passed to
+// set_preprocess_shader must keep its bindings, push constants and workgroup size constant.
+mod cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        src: "
+            #version 450
+
+            layout(constant_id = 0) const uint workgroup_size = 64;
+            layout(local_size_x_id = 0) in;
+
+            layout(set = 0, binding = 0) readonly buffer Raw {
+                float raw[]; // Eight floats per vertex, as in ShaderBlock::vertices
+            };
+
+            layout(set = 0, binding = 1) writeonly buffer Vertices {
+                float vertices[]; // Eight floats per Vertex, position, normal then uv
+            };
+
+            layout(push_constant) uniform Counts {
+                uint vertex_count;
+            } counts;
+
+            void main() {
+                uint i = gl_GlobalInvocationID.x;
+                if (i >= counts.vertex_count) {
+                    return;
+                }
+                for (uint k = 0; k < 8; k++) {
+                    vertices[i * 8 + k] = raw[i * 8 + k];
+                }
+            }
+        "
+    }
+}
+
+// Full-screen triangle of the post-processing pass, drawn without vertex buffers
+mod post_vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        src: "
+            #version 450
+
+            layout(location = 0) out vec2 v_uv;
+
+            void main() {
+                v_uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+                gl_Position = vec4(v_uv * 2.0 - 1.0, 0.0, 1.0);
+            }
+        "
+    }
+}
+
+// Default post-processing effect, copies the scene unchanged. Shaders passed to set_post_shader
+// must keep its input and binding.
+mod post_fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        src: "
+            #version 450
+
+            layout(location = 0) in vec2 v_uv;
+
+            layout(location = 0) out vec4 f_color;
+
+            layout(set = 0, binding = 0) uniform sampler2D scene;
+
+            void main() {
+                f_color = texture(scene, v_uv);
+            }
+        "
+    }
+}
+
+// Errors that can occur while uploading data or rendering a frame
+#[derive(Debug)]
+pub enum RendererError {
+    Acquire(AcquireError),                      // Failed to acquire the next swapchain image
+    CommandBufferBuild(Box<dyn Error + Send + Sync>), // Failed to record or build a command buffer
+    Execute(CommandBufferExecError),            // Failed to submit a command buffer to the queue
+    Flush(FlushError),                          // Failed to flush or wait on the GPU future
+    BufferAllocation(DeviceMemoryAllocError),   // Failed to allocate a vertex or material buffer
+    UnsupportedSampleCount(u32),                // Requested MSAA sample count is not 1, 2, 4 or 8
+    DescriptorSet(Box<dyn Error + Send + Sync>), // Failed to build a descriptor set for a block
+    UnknownBlock(BlockId),                      // No uploaded block has this id
+    BufferWrite(WriteLockError),                // A buffer is still in use by the GPU and cannot be written
+    BufferRead(ReadLockError),                  // A buffer is still in use by the GPU and cannot be read
+    NoFrameRendered,                            // Pixels were requested before any frame was rendered
+    NotOffscreen,                               // The operation needs a headless renderer
+    Texture(Box<dyn Error + Send + Sync>),      // Failed to create a texture image or sampler
+    Reconfigure(Box<dyn Error + Send + Sync>),  // Failed to rebuild the render pass, pipeline or framebuffers
+    SwapchainRecreation(SwapchainCreationError), // The swapchain could not be recreated for the new surface size
+    Partition(PartitionError),                  // Frame data could not be loaded from the database
+    DatabaseOpen(rusqlite::Error),              // No database could be opened at the given path
+    EmptyPartition,                             // The database holds no frames to load
+    ShaderCompile(String),                      // A ShaderSource could not be turned into shader modules, with the compiler's diagnostics
+    ShaderInterface(ShaderInterfaceError),      // A custom shader does not declare the interface of the built-in shader it replaces
+    BudgetExceeded { requested: u64, available: u64 }, // A block does not fit in the memory budget, in bytes
+    BlockEvicted(BlockId),                      // The block was evicted to stay within the memory budget
+    BlockRemoved(BlockId),                      // The block was removed by remove_block and its id not reused yet
+    UnsupportedFeature(&'static str),           // The device does not support or did not enable this Vulkan feature
+    InvalidFrameRate(f32),                      // Playback rates must be positive and finite
+    MalformedGeometry(String),                  // Vertex data or indices do not describe whole, in-range vertices
+    UpdateOutOfRange { end: usize, len: usize }, // A partial update reaches past the end of the block's buffer
+    InvalidRenderPassConfig(String),            // A RenderPassConfig names attachments or subpasses that do not exist
+    UnknownAttachment(usize),                   // The scene render pass has no color attachment with this index
+    UnknownSceneNode(String),                   // The scene graph has no node at this path, or there is no scene graph
+}
+
+impl RendererError {
+    // Returns true when the swapchain no longer matches the surface and must be recreated
+    pub fn is_out_of_date(&self) -> bool {
+        matches!(
+            self,
+            RendererError::Acquire(AcquireError::OutOfDate) | RendererError::Flush(FlushError::OutOfDate)
+        )
+    }
+
+    // Returns true when the render loop can carry on with the next frame
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            RendererError::Acquire(AcquireError::DeviceLost)
+            | RendererError::Flush(FlushError::DeviceLost)
+            | RendererError::BufferAllocation(_)
+            | RendererError::UnsupportedSampleCount(_)
+            | RendererError::Reconfigure(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RendererError::Acquire(e) => write!(f, "failed to acquire swapchain image: {}", e),
+            RendererError::CommandBufferBuild(e) => write!(f, "failed to build command buffer: {}", e),
+            RendererError::Execute(e) => write!(f, "failed to execute command buffer: {}", e),
+            RendererError::Flush(e) => write!(f, "failed to flush GPU future: {}", e),
+            RendererError::BufferAllocation(e) => write!(f, "failed to allocate buffer: {}", e),
+            RendererError::UnsupportedSampleCount(n) => write!(f, "unsupported sample count {}, expected 1, 2, 4 or 8", n),
+            RendererError::DescriptorSet(e) => write!(f, "failed to build descriptor set: {}", e),
+            RendererError::UnknownBlock(id) => write!(f, "no shader block with id {}", id),
+            RendererError::BufferWrite(e) => write!(f, "failed to write buffer: {}", e),
+            RendererError::BufferRead(e) => write!(f, "failed to read buffer: {}", e),
+            RendererError::NoFrameRendered => write!(f, "no frame has been rendered yet"),
+            RendererError::NotOffscreen => write!(f, "the renderer is not rendering offscreen"),
+            RendererError::Texture(e) => write!(f, "failed to create texture: {}", e),
+            RendererError::Reconfigure(e) => write!(f, "failed to reconfigure renderer: {}", e),
+            RendererError::SwapchainRecreation(e) => write!(f, "failed to recreate swapchain: {}", e),
+            RendererError::Partition(e) => write!(f, "failed to load vertex data: {}", e),
+            RendererError::DatabaseOpen(e) => write!(f, "failed to open database: {}", e),
+            RendererError::EmptyPartition => write!(f, "the database holds no frames to load"),
+            RendererError::ShaderCompile(diagnostics) => write!(f, "failed to compile shaders: {}", diagnostics),
+            RendererError::ShaderInterface(e) => write!(f, "{}", e),
+            RendererError::BudgetExceeded { requested, available } => {
+                write!(f, "block needs {} bytes but only {} fit in the memory budget", requested, available)
+            }
+            RendererError::BlockEvicted(id) => write!(f, "shader block {} was evicted from GPU memory", id),
+            RendererError::BlockRemoved(id) => write!(f, "shader block {} was removed", id),
+            RendererError::UnsupportedFeature(feature) => write!(f, "the device does not support {}", feature),
+            RendererError::MalformedGeometry(reason) => write!(f, "malformed geometry: {}", reason),
+            RendererError::InvalidRenderPassConfig(reason) => write!(f, "invalid render pass config: {}", reason),
+            RendererError::UnknownAttachment(index) => write!(f, "the render pass has no color attachment {}", index),
+            RendererError::UnknownSceneNode(path) => write!(f, "no scene graph node at {}", path),
+            RendererError::UpdateOutOfRange { end, len } => {
+                write!(f, "update ends at element {} but the buffer holds {}", end, len)
+            }
+            RendererError::InvalidFrameRate(fps) => write!(f, "invalid playback rate {} fps", fps),
+        }
+    }
+}
+
+impl Error for RendererError {}
+
+impl From<VertexLayoutError> for RendererError {
+    fn from(e: VertexLayoutError) -> Self {
+        RendererError::MalformedGeometry(e.to_string())
+    }
+}
+
+impl From<ShaderInterfaceError> for RendererError {
+    fn from(e: ShaderInterfaceError) -> Self {
+        RendererError::ShaderInterface(e)
+    }
+}
+
+impl From<AcquireError> for RendererError {
+    fn from(e: AcquireError) -> Self {
+        RendererError::Acquire(e)
+    }
+}
+
+impl From<CommandBufferExecError> for RendererError {
+    fn from(e: CommandBufferExecError) -> Self {
+        RendererError::Execute(e)
+    }
+}
+
+impl From<FlushError> for RendererError {
+    fn from(e: FlushError) -> Self {
+        RendererError::Flush(e)
+    }
+}
+
+impl From<PartitionError> for RendererError {
+    fn from(e: PartitionError) -> Self {
+        RendererError::Partition(e)
+    }
+}
+
+impl From<SwapchainCreationError> for RendererError {
+    fn from(e: SwapchainCreationError) -> Self {
+        RendererError::SwapchainRecreation(e)
+    }
+}
+
+impl From<DeviceMemoryAllocError> for RendererError {
+    fn from(e: DeviceMemoryAllocError) -> Self {
+        RendererError::BufferAllocation(e)
+    }
+}
+
+// Every error raised while recording a command buffer maps onto CommandBufferBuild
+macro_rules! command_buffer_error {
+    ($($ty:ty),*) => {
+        $(
+            impl From<$ty> for RendererError {
+                fn from(e: $ty) -> Self {
+                    RendererError::CommandBufferBuild(Box::new(e))
+                }
+            }
+        )*
+    };
+}
+
+command_buffer_error!(
+    OomError,
+    BuildError,
+    BeginRenderPassError,
+    DrawError,
+    AutoCommandBufferBuilderContextError,
+    CopyImageToBufferError,
+    CopyBufferError,
+    DispatchError,
+    ExecuteCommandsError,
+    ResetQueryPoolError,
+    WriteTimestampError
+);
+
+impl From<WriteLockError> for RendererError {
+    fn from(e: WriteLockError) -> Self {
+        RendererError::BufferWrite(e)
+    }
+}
+
+impl From<ReadLockError> for RendererError {
+    fn from(e: ReadLockError) -> Self {
+        RendererError::BufferRead(e)
+    }
+}
+
+impl From<ImageCreationError> for RendererError {
+    fn from(e: ImageCreationError) -> Self {
+        RendererError::Texture(Box::new(e))
+    }
+}
+
+impl From<SamplerCreationError> for RendererError {
+    fn from(e: SamplerCreationError) -> Self {
+        RendererError::Texture(Box::new(e))
+    }
+}
+
+impl From<PersistentDescriptorSetError> for RendererError {
+    fn from(e: PersistentDescriptorSetError) -> Self {
+        RendererError::DescriptorSet(Box::new(e))
+    }
+}
+
+impl From<PersistentDescriptorSetBuildError> for RendererError {
+    fn from(e: PersistentDescriptorSetBuildError) -> Self {
+        RendererError::DescriptorSet(Box::new(e))
+    }
+}
+
+// Errors that can occur while building a renderer from a surface
+#[derive(Debug)]
+pub enum RendererInitError {
+    InstanceCreation(InstanceCreationError),           // The Vulkan instance could not be created
+    SurfaceCreation(vulkano_win::CreationError),       // The window could not be turned into a surface
+    NoSuitableDevice,                                  // No physical device can render and present to the surface
+    DeviceCreation(DeviceCreationError),               // The logical device could not be created
+    SurfaceCapabilities(CapabilitiesError),            // Querying the surface capabilities failed
+    SwapchainCreation(SwapchainCreationError),         // The swapchain could not be created
+    RenderPassCreation(RenderPassCreationError),       // The render pass could not be created
+    ShaderLoad(OomError),                              // A shader module could not be loaded
+    PipelineCreation(GraphicsPipelineCreationError),   // The graphics pipeline could not be created
+    FramebufferCreation(FramebufferCreationError),     // A framebuffer could not be created
+    DepthImageCreation(ImageCreationError),            // A depth attachment could not be created
+    UnsupportedDepthFormat(Format),                    // The requested depth format cannot be used as an attachment
+    InvalidOptions(RendererError),                     // The renderer options were rejected
+    DeviceNotFound { name: String, available: Vec<String> }, // No usable device has the requested name
+}
+
+impl fmt::Display for RendererInitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RendererInitError::InstanceCreation(e) => write!(f, "failed to create Vulkan instance: {}", e),
+            RendererInitError::SurfaceCreation(e) => write!(f, "failed to create surface: {}", e),
+            RendererInitError::NoSuitableDevice => write!(f, "no physical device supports graphics and presentation to this surface"),
+            RendererInitError::DeviceCreation(e) => write!(f, "failed to create device: {}", e),
+            RendererInitError::SurfaceCapabilities(e) => write!(f, "failed to query surface capabilities: {}", e),
+            RendererInitError::SwapchainCreation(e) => write!(f, "failed to create swapchain: {}", e),
+            RendererInitError::RenderPassCreation(e) => write!(f, "failed to create render pass: {}", e),
+            RendererInitError::ShaderLoad(e) => write!(f, "failed to load shader module: {}", e),
+            RendererInitError::PipelineCreation(e) => write!(f, "failed to create graphics pipeline: {}", e),
+            RendererInitError::FramebufferCreation(e) => write!(f, "failed to create framebuffer: {}", e),
+            RendererInitError::DepthImageCreation(e) => write!(f, "failed to create depth image: {}", e),
+            RendererInitError::UnsupportedDepthFormat(format) => write!(f, "depth format {:?} is not supported", format),
+            RendererInitError::InvalidOptions(e) => write!(f, "invalid renderer options: {}", e),
+            RendererInitError::DeviceNotFound { name, available } => {
+                write!(f, "no usable device named {:?}, available devices: {}", name, available.join(", "))
+            }
+        }
+    }
+}
+
+impl Error for RendererInitError {}
+
+impl From<InstanceCreationError> for RendererInitError {
+    fn from(e: InstanceCreationError) -> Self {
+        RendererInitError::InstanceCreation(e)
+    }
+}
+
+impl From<vulkano_win::CreationError> for RendererInitError {
+    fn from(e: vulkano_win::CreationError) -> Self {
+        RendererInitError::SurfaceCreation(e)
+    }
+}
+
+impl From<DeviceCreationError> for RendererInitError {
+    fn from(e: DeviceCreationError) -> Self {
+        RendererInitError::DeviceCreation(e)
+    }
+}
+
+impl From<CapabilitiesError> for RendererInitError {
+    fn from(e: CapabilitiesError) -> Self {
+        RendererInitError::SurfaceCapabilities(e)
+    }
+}
+
+impl From<SwapchainCreationError> for RendererInitError {
+    fn from(e: SwapchainCreationError) -> Self {
+        RendererInitError::SwapchainCreation(e)
+    }
+}
+
+impl From<RenderPassCreationError> for RendererInitError {
+    fn from(e: RenderPassCreationError) -> Self {
+        RendererInitError::RenderPassCreation(e)
+    }
+}
+
+impl From<GraphicsPipelineCreationError> for RendererInitError {
+    fn from(e: GraphicsPipelineCreationError) -> Self {
+        RendererInitError::PipelineCreation(e)
+    }
+}
+
+impl From<FramebufferCreationError> for RendererInitError {
+    fn from(e: FramebufferCreationError) -> Self {
+        RendererInitError::FramebufferCreation(e)
+    }
+}
+
+impl From<ImageCreationError> for RendererInitError {
+    fn from(e: ImageCreationError) -> Self {
+        RendererInitError::DepthImageCreation(e)
+    }
+}
+
+// Options applied when the renderer builds its own device, swapchain and pipeline
+#[derive(Debug, Clone)]
+pub struct RendererOptions {
+    pub depth: bool, // Attach a depth buffer and enable depth testing, disable for 2D-only workloads
+    pub depth_format: Option<Format>, // Depth format to use (e.g. D16Unorm or D32Sfloat), None picks the best supported
+    pub samples: u32, // MSAA sample count (1, 2, 4 or 8), clamped to what the device supports
+    pub buffer_strategy: BufferStrategy, // Where vertex and index buffers are stored
+    pub preferred_present_modes: Vec<PresentMode>, // Tried in order, FIFO is used when none is supported
+    pub shaders: ShaderSource, // Vertex shader and unlit fragment shader the pipelines are built with
+    pub device: DeviceSelector, // Physical device to render with when several are suitable
+    pub instance: InstanceConfig, // Used by create and create_headless, ignored when the caller brings its own instance
+    pub desired_image_count: Option<u32>, // Swapchain images, e.g. 2 for double or 3 for triple buffering, clamped to what the surface allows. None is the surface minimum. Offscreen it is also the number of frames in flight, never fewer than DEFAULT_FRAMES_IN_FLIGHT.
+}
+
+impl Default for RendererOptions {
+    fn default() -> Self {
+        Self {
+            depth: true,
+            depth_format: None,
+            samples: 1,
+            buffer_strategy: BufferStrategy::default(),
+            preferred_present_modes: Vec::new(),
+            shaders: ShaderSource::default(),
+            device: DeviceSelector::default(),
+            instance: InstanceConfig::default(),
+            desired_image_count: None,
+        }
+    }
+}
+
+pub const VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
+
+// How create_instance sets up the Vulkan instance. Validation is on by default in debug builds only,
+// messages of the layer are forwarded to the log crate under the "vulkan" target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceConfig {
+    pub validation: bool, // Enable VALIDATION_LAYER and debug-utils, skipped with a warning when the layer is not installed
+    pub app_name: String, // Reported to the driver and shown by tools like RenderDoc
+}
+
+impl Default for InstanceConfig {
+    fn default() -> Self {
+        Self {
+            validation: cfg!(debug_assertions),
+            app_name: String::from("zeta-DOM"),
+        }
+    }
+}
+
+// Creates an instance with the given extensions plus whatever config asks for. The returned callback
+// must be kept alive for as long as validation messages should be reported.
+//
+//     let (instance, _callback) = create_instance(&InstanceConfig { validation: true, ..Default::default() },
+//                                                 vulkano_win::required_extensions())?;
+pub fn create_instance(
+    config: &InstanceConfig,
+    mut extensions: InstanceExtensions,
+) -> Result<(Arc<Instance>, Option<DebugCallback>), RendererInitError> {
+    let validation = config.validation && validation_layer_available();
+    if config.validation && !validation {
+        // Once per process, renderers created in a loop would repeat it otherwise
+        static MISSING_LAYER: Once = Once::new();
+        MISSING_LAYER.call_once(|| {
+            log::warn!("{} is not installed, creating instances without validation", VALIDATION_LAYER);
+        });
+    }
+    let layers: Vec<&str> = if validation { vec![VALIDATION_LAYER] } else { Vec::new() };
+    extensions.ext_debug_utils |= validation;
+
+    let app_info = ApplicationInfo {
+        application_name: Some(config.app_name.as_str().into()),
+        application_version: None,
+        engine_name: Some("zeta-DOM".into()),
+        engine_version: None,
+    };
+    let instance = Instance::new(Some(&app_info), Version::V1_1, &extensions, layers.iter().copied())?;
+    if !validation {
+        return Ok((instance, None));
+    }
+
+    let severity = MessageSeverity { error: true, warning: true, information: true, verbose: false };
+    match DebugCallback::new(&instance, severity, MessageType::all(), log_validation_message) {
+        Ok(callback) => Ok((instance, Some(callback))),
+        Err(e) => {
+            log::warn!("failed to install the validation message callback: {}", e);
+            Ok((instance, None))
+        }
+    }
+}
+
+fn validation_layer_available() -> bool {
+    match layers_list() {
+        Ok(mut layers) => layers.any(|l| l.name() == VALIDATION_LAYER),
+        Err(_) => false,
+    }
+}
+
+fn log_validation_message(msg: &Message) {
+    let level = validation_log_level(msg.severity);
+    let prefix = msg.layer_prefix.unwrap_or("vulkan");
+    log::log!(target: "vulkan", level, "[{}] {}", prefix, msg.description);
+}
+
+// Errors and warnings of the layer keep their level, information is only useful when tracing
+fn validation_log_level(severity: MessageSeverity) -> log::Level {
+    if severity.error {
+        log::Level::Error
+    } else if severity.warning {
+        log::Level::Warn
+    } else if severity.information {
+        log::Level::Debug
+    } else {
+        log::Level::Trace
+    }
+}
+
+// Chooses among the physical devices that can render. A device requested by name that is missing or
+// cannot render is an error listing the usable ones, a missing index falls back to PreferDiscrete with a
+// warning since indices change when devices are added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    PreferDiscrete,   // Discrete GPUs first, then integrated, virtual and CPU devices
+    PreferIntegrated, // Integrated GPUs first to save power on laptops, then the PreferDiscrete order
+    ByIndex(usize),   // Position in PhysicalDevice::enumerate, counting devices that cannot render
+    ByName(String),   // Exact properties().device_name, e.g. as listed by vulkaninfo
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        DeviceSelector::PreferDiscrete
+    }
+}
+
+// The physical device a renderer ended up on
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub device_type: PhysicalDeviceType,
+    pub index: usize,          // Position in PhysicalDevice::enumerate
+    pub vendor_id: u32,
+    pub driver_version: u32,   // Vendor-specific encoding, see driver_version_string
+    pub api_version: Version,  // Highest Vulkan version the driver supports
+}
+
+impl DeviceInfo {
+    // NVIDIA packs 10.8.8.6 bits and Intel on Windows 18.14, everything else follows the Vulkan layout
+    pub fn driver_version_string(&self) -> String {
+        let v = self.driver_version;
+        match self.vendor_id {
+            0x10de => format!("{}.{}.{}.{}", v >> 22, (v >> 14) & 0xff, (v >> 6) & 0xff, v & 0x3f),
+            0x8086 if cfg!(windows) => format!("{}.{}", v >> 14, v & 0x3fff),
+            _ => format!("{}.{}.{}", v >> 22, (v >> 12) & 0x3ff, v & 0xfff),
+        }
+    }
+}
+
+// What select_device needs to know about a suitable physical device
+#[derive(Debug, Clone)]
+struct DeviceCandidate {
+    index: usize, // Position in PhysicalDevice::enumerate
+    name: String,
+    device_type: PhysicalDeviceType,
+}
+
+// Where the vertex shader and the unlit fragment shader come from. Replacements must keep the
+// interface of the built-in shaders.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShaderSource {
+    Builtin,                                        // Compiled into the crate
+    Spirv { vertex: Vec<u8>, fragment: Vec<u8> },   // Precompiled SPIR-V modules
+    #[cfg(feature = "glsl")]
+    Glsl { vertex: String, fragment: String },      // Compiled with shaderc when the pipeline is built
+}
+
+impl Default for ShaderSource {
+    fn default() -> Self {
+        ShaderSource::Builtin
+    }
+}
+
+// Depth formats in order of preference
+const DEPTH_FORMATS: [Format; 4] = [
+    Format::D32Sfloat,
+    Format::D32Sfloat_S8Uint,
+    Format::D24Unorm_S8Uint,
+    Format::D16Unorm,
+];
+
+// Index of an uploaded shader block, in upload order
+pub type BlockId = usize;
+
+// Attribute children of scene graph nodes, every other child is a node of its own
+const SCENE_TRANSFORM: &str = "transform";
+const SCENE_BLOCK: &str = "block_id";
+const SCENE_VISIBLE: &str = "visible";
+
+// Hierarchy of uploaded blocks read from a ConicTree. A node's "transform" child holds its column-major
+// matrix relative to its parent, 16 numbers as CSV or one per child (identity when missing), and its
+// "block_id" child the block drawn with the node's world matrix. A "visible" child of false (or 0) hides
+// the node along with everything below it. Nodes are addressed by the slash-separated
+// paths of ConicTree::get, e.g. "root/car/wheel".
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneGraph {
+    nodes: Vec<SceneNode>, // Depth-first, so parents come before their children
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct SceneNode {
+    path: String,
+    parent: Option<usize>, // Index into SceneGraph::nodes
+    transform: Transform,  // Relative to the parent
+    block: Option<BlockId>,
+    visible: bool,
+}
+
+impl SceneGraph {
+    pub fn from_tree(tree: &ConicTree) -> Result<Self, SceneGraphError> {
+        let mut nodes = Vec::new();
+        let mut pending = vec![(&tree.root, tree.root.name.clone(), None)];
+        while let Some((node, path, parent)) = pending.pop() {
+            let index = nodes.len();
+            let children = node.children.iter().filter(|child| ![SCENE_TRANSFORM, SCENE_BLOCK, SCENE_VISIBLE].contains(&child.name.as_str()));
+            // Reversed so the first child is visited next
+            pending.extend(children.rev().map(|child| (child, format!("{}/{}", path, child.name), Some(index))));
+            nodes.push(SceneNode {
+                transform: scene_transform(node, &path)?,
+                block: scene_block(node, &path)?,
+                visible: scene_visible(node, &path)?,
+                path,
+                parent,
+            });
+        }
+        Ok(Self { nodes })
+    }
+
+    // Transform of the node at path relative to its parent
+    pub fn transform(&self, path: &str) -> Option<Transform> {
+        self.find(path).map(|index| self.nodes[index].transform)
+    }
+
+    // Replaces the transform of the node at path, false when there is no such node
+    pub fn set_transform(&mut self, path: &str, transform: Transform) -> bool {
+        match self.find(path) {
+            Some(index) => {
+                self.nodes[index].transform = transform;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Shows or hides the node at path and everything below it, false when there is no such node
+    pub fn set_visible(&mut self, path: &str, visible: bool) -> bool {
+        match self.find(path) {
+            Some(index) => {
+                self.nodes[index].visible = visible;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Referenced blocks of visible nodes with the world matrices of their nodes, depth-first
+    pub fn draw_list(&self) -> Vec<(BlockId, Transform)> {
+        self.resolve().0
+    }
+
+    // The draw list, and the blocks referenced by hidden nodes or nodes below them
+    fn resolve(&self) -> (Vec<(BlockId, Transform)>, Vec<BlockId>) {
+        // World matrix of every node, None below a hidden node
+        let mut world: Vec<Option<Transform>> = Vec::with_capacity(self.nodes.len());
+        let mut draws = Vec::new();
+        let mut hidden = Vec::new();
+        for node in &self.nodes {
+            let transform = if !node.visible {
+                None
+            } else {
+                match node.parent {
+                    Some(parent) => world[parent].map(|parent| mat4_mul(&parent, &node.transform)),
+                    None => Some(node.transform),
+                }
+            };
+            match (node.block, transform) {
+                (Some(block), Some(transform)) => draws.push((block, transform)),
+                (Some(block), None) => hidden.push(block),
+                (None, _) => {}
+            }
+            world.push(transform);
+        }
+        (draws, hidden)
+    }
+
+    // Same node ConicTree::get finds, the first in depth-first order
+    fn find(&self, path: &str) -> Option<usize> {
+        let path = path.strip_suffix('/').unwrap_or(path);
+        self.nodes.iter().position(|node| node.path == path)
+    }
+}
+
+// Errors that can occur while reading a scene graph from a conic tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SceneGraphError {
+    MalformedTransform(String),  // Path of a node whose transform is not 16 numbers
+    MalformedBlockId(String),    // Path of a node whose block_id is not a block index
+    MalformedVisibility(String), // Path of a node whose visible is not true, false, 1 or 0
+}
+
+impl fmt::Display for SceneGraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneGraphError::MalformedTransform(path) => write!(f, "the transform of scene node {} is not 16 numbers", path),
+            SceneGraphError::MalformedBlockId(path) => write!(f, "the block_id of scene node {} is not a block index", path),
+            SceneGraphError::MalformedVisibility(path) => write!(f, "the visible flag of scene node {} is not a boolean", path),
+        }
+    }
+}
+
+impl Error for SceneGraphError {}
+
+fn scene_attribute<'a>(node: &'a ConicNode, name: &str) -> Option<&'a ConicNode> {
+    node.children.iter().find(|child| child.name == name)
+}
+
+// Values of an attribute, from a CSV value or one per child
+fn scene_values(attribute: &ConicNode) -> Vec<&str> {
+    if attribute.children.is_empty() {
+        attribute.value.as_deref().map_or(Vec::new(), |csv| csv.split(',').map(str::trim).collect())
+    } else {
+        attribute.children.iter().map(|child| child.value.as_deref().unwrap_or("").trim()).collect()
+    }
+}
+
+fn scene_transform(node: &ConicNode, path: &str) -> Result<Transform, SceneGraphError> {
+    let attribute = match scene_attribute(node, SCENE_TRANSFORM) {
+        Some(attribute) => attribute,
+        None => return Ok(IDENTITY_TRANSFORM),
+    };
+    let malformed = || SceneGraphError::MalformedTransform(path.to_string());
+    let values = scene_values(attribute)
+        .into_iter()
+        .map(|value| value.parse::<f32>().map_err(|_| malformed()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.len() != 16 {
+        return Err(malformed());
+    }
+    let mut transform = IDENTITY_TRANSFORM;
+    for (i, value) in values.into_iter().enumerate() {
+        transform[i / 4][i % 4] = value;
+    }
+    Ok(transform)
+}
+
+fn scene_block(node: &ConicNode, path: &str) -> Result<Option<BlockId>, SceneGraphError> {
+    match scene_attribute(node, SCENE_BLOCK).map(scene_values).as_deref() {
+        None => Ok(None),
+        Some([value]) => value.parse().map(Some).map_err(|_| SceneGraphError::MalformedBlockId(path.to_string())),
+        Some(_) => Err(SceneGraphError::MalformedBlockId(path.to_string())),
+    }
+}
+
+fn scene_visible(node: &ConicNode, path: &str) -> Result<bool, SceneGraphError> {
+    match scene_attribute(node, SCENE_VISIBLE).map(scene_values).as_deref() {
+        None => Ok(true),
+        Some(["true"]) | Some(["1"]) => Ok(true),
+        Some(["false"]) | Some(["0"]) => Ok(false),
+        Some(_) => Err(SceneGraphError::MalformedVisibility(path.to_string())),
+    }
+}
+
+// How a camera maps view space to clip space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Perspective { fov_y: f32 },   // Vertical field of view in radians
+    Orthographic { height: f32 }, // World units visible vertically, the width follows the aspect ratio
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Projection::Perspective { fov_y: std::f32::consts::FRAC_PI_3 }
+    }
+}
+
+// Camera looking from position towards target
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    pub position: [f32; 3],
+    pub target: [f32; 3],
+    pub up: [f32; 3],
+    pub projection: Projection,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            position: [0.0, 0.0, 2.0],
+            target: [0.0, 0.0, 0.0],
+            up: [0.0, 1.0, 0.0],
+            projection: Projection::default(),
+            near: 0.1,
+            far: 100.0,
+        }
+    }
+}
+
+impl Camera {
+    // World to view space
+    pub fn view(&self) -> Transform {
+        look_at(self.position, self.target, self.up)
+    }
+
+    // View space to Vulkan clip space (y down, depth 0..1)
+    pub fn projection(&self, aspect_ratio: f32) -> Transform {
+        match self.projection {
+            Projection::Perspective { fov_y } => perspective(fov_y, aspect_ratio, self.near, self.far),
+            Projection::Orthographic { height } => orthographic(height, aspect_ratio, self.near, self.far),
+        }
+    }
+
+    // Combined view and projection matrix
+    pub fn view_projection(&self, aspect_ratio: f32) -> Transform {
+        mat4_mul(&self.projection(aspect_ratio), &self.view())
+    }
+
+    // View and projection matrices as uploaded to the camera uniform
+    pub fn matrices(&self, aspect_ratio: f32) -> CameraMatrices {
+        CameraMatrices { view: self.view(), proj: self.projection(aspect_ratio) }
+    }
+}
+
+// Keeps pitch short of straight up or down, where the view would flip around the up axis
+const MAX_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+
+// Turns pointer and keyboard input into camera updates. Input either comes from run, which forwards
+// window events and calls update and apply before every frame, or is fed in by the caller.
+pub trait CameraController {
+    // Returns whether the event was used
+    fn handle_event(&mut self, event: &WindowEvent) -> bool;
+
+    // Advances movement that depends on time, dt is in seconds
+    fn update(&mut self, _dt: f32) {}
+
+    // Writes position, target and up, the projection is left as it is
+    fn apply(&self, camera: &mut Camera);
+}
+
+// Circles around target. Dragging with the left button rotates, the wheel zooms and dragging with the
+// right button pans. Distance never drops below min_distance so the camera can't enter the target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrbitController {
+    pub target: [f32; 3],
+    pub distance: f32,
+    pub yaw: f32,   // Radians around +y, 0 places the camera on +z looking down -z
+    pub pitch: f32, // Radians above the horizontal plane, clamped to +-MAX_PITCH
+    pub min_distance: f32,
+    pub max_distance: f32,
+    pub rotate_speed: f32, // Radians per pixel of pointer movement
+    pub zoom_speed: f32,   // Fraction of the distance covered per wheel line
+    dragging: Option<MouseButton>, // Button held since the last press
+    cursor: Option<[f32; 2]>,      // Last cursor position, None until the first move
+}
+
+impl OrbitController {
+    pub fn new(target: [f32; 3], distance: f32) -> Self {
+        let mut controller = Self {
+            target,
+            distance,
+            yaw: 0.0,
+            pitch: 0.0,
+            min_distance: 0.01,
+            max_distance: f32::INFINITY,
+            rotate_speed: 0.005,
+            zoom_speed: 0.1,
+            dragging: None,
+            cursor: None,
+        };
+        controller.zoom(0.0);
+        controller
+    }
+
+    // Matches position and target of the camera, so switching controllers does not jump the view
+    pub fn from_camera(camera: &Camera) -> Self {
+        let offset = sub3(camera.position, camera.target);
+        let distance = dot3(offset, offset).sqrt();
+        let mut controller = Self::new(camera.target, distance);
+        if distance > 0.0 {
+            controller.yaw = offset[0].atan2(offset[2]);
+            controller.pitch = (offset[1] / distance).asin().clamp(-MAX_PITCH, MAX_PITCH);
+        }
+        controller
+    }
+
+    // Pointer movement in pixels, moving right turns the camera left around the target
+    pub fn rotate(&mut self, dx: f32, dy: f32) {
+        self.yaw -= dx * self.rotate_speed;
+        self.pitch = (self.pitch + dy * self.rotate_speed).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    // Positive lines move closer, each one covers zoom_speed of the remaining distance
+    pub fn zoom(&mut self, lines: f32) {
+        let scale = (1.0 - self.zoom_speed).powf(lines);
+        self.distance = (self.distance * scale).clamp(self.min_distance, self.max_distance.max(self.min_distance));
+    }
+
+    // Pointer movement in pixels, moves the target in the view plane proportionally to the distance
+    pub fn pan(&mut self, dx: f32, dy: f32) {
+        let forward = normalize3(sub3(self.target, self.position()));
+        let right = normalize3(cross3(forward, [0.0, 1.0, 0.0]));
+        let up = cross3(right, forward);
+        let scale = self.distance * self.rotate_speed;
+        for i in 0..3 {
+            self.target[i] += (up[i] * dy - right[i] * dx) * scale;
+        }
+    }
+
+    pub fn position(&self) -> [f32; 3] {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        [
+            self.target[0] + self.distance * cos_pitch * sin_yaw,
+            self.target[1] + self.distance * sin_pitch,
+            self.target[2] + self.distance * cos_pitch * cos_yaw,
+        ]
+    }
+}
+
+impl CameraController for OrbitController {
+    fn handle_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::MouseInput { state, button, .. } => {
+                match (state, button) {
+                    (ElementState::Pressed, MouseButton::Left) | (ElementState::Pressed, MouseButton::Right) => {
+                        self.dragging = Some(*button);
+                    }
+                    (ElementState::Released, _) if self.dragging == Some(*button) => self.dragging = None,
+                    _ => return false,
+                }
+                true
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                let cursor = [position.x as f32, position.y as f32];
+                let last = self.cursor.replace(cursor);
+                match (self.dragging, last) {
+                    (Some(MouseButton::Left), Some(last)) => self.rotate(cursor[0] - last[0], cursor[1] - last[1]),
+                    (Some(_), Some(last)) => self.pan(cursor[0] - last[0], cursor[1] - last[1]),
+                    _ => return false,
+                }
+                true
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.zoom(scroll_lines(delta));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn apply(&self, camera: &mut Camera) {
+        camera.position = self.position();
+        camera.target = self.target;
+        camera.up = [0.0, 1.0, 0.0];
+    }
+}
+
+// First-person camera. WASD moves, Space and LShift move up and down, dragging with the left button looks around.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlyController {
+    pub position: [f32; 3],
+    pub yaw: f32,   // Radians around +y, 0 looks down -z
+    pub pitch: f32, // Radians above the horizontal plane, clamped to +-MAX_PITCH
+    pub move_speed: f32, // World units per second
+    pub look_speed: f32, // Radians per pixel of pointer movement
+    movement: [f32; 3], // Held keys as forward, right and up axes in -1..1
+    looking: bool,
+    cursor: Option<[f32; 2]>,
+}
+
+impl FlyController {
+    pub fn new(position: [f32; 3]) -> Self {
+        Self {
+            position,
+            yaw: 0.0,
+            pitch: 0.0,
+            move_speed: 1.0,
+            look_speed: 0.003,
+            movement: [0.0; 3],
+            looking: false,
+            cursor: None,
+        }
+    }
+
+    // Keeps the camera where it is, looking in the same direction
+    pub fn from_camera(camera: &Camera) -> Self {
+        let forward = normalize3(sub3(camera.target, camera.position));
+        let mut controller = Self::new(camera.position);
+        controller.yaw = (-forward[0]).atan2(-forward[2]);
+        controller.pitch = forward[1].clamp(-1.0, 1.0).asin().clamp(-MAX_PITCH, MAX_PITCH);
+        controller
+    }
+
+    // Pointer movement in pixels, moving down looks down
+    pub fn look(&mut self, dx: f32, dy: f32) {
+        self.yaw -= dx * self.look_speed;
+        self.pitch = (self.pitch - dy * self.look_speed).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    // Moves along the view direction, its horizontal right vector and world up, each axis in -1..1
+    pub fn translate(&mut self, forward: f32, right: f32, up: f32, dt: f32) {
+        let f = self.forward();
+        let r = normalize3(cross3(f, [0.0, 1.0, 0.0]));
+        let step = self.move_speed * dt;
+        for i in 0..3 {
+            self.position[i] += (f[i] * forward + r[i] * right) * step;
+        }
+        self.position[1] += up * step;
+    }
+
+    // Unit vector the camera looks along
+    pub fn forward(&self) -> [f32; 3] {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        [-cos_pitch * sin_yaw, sin_pitch, -cos_pitch * cos_yaw]
+    }
+}
+
+impl CameraController for FlyController {
+    fn handle_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::KeyboardInput { input, .. } => {
+                let amount = if input.state == ElementState::Pressed { 1.0 } else { 0.0 };
+                let (axis, sign) = match input.virtual_keycode {
+                    Some(VirtualKeyCode::W) => (0, 1.0),
+                    Some(VirtualKeyCode::S) => (0, -1.0),
+                    Some(VirtualKeyCode::D) => (1, 1.0),
+                    Some(VirtualKeyCode::A) => (1, -1.0),
+                    Some(VirtualKeyCode::Space) => (2, 1.0),
+                    Some(VirtualKeyCode::LShift) => (2, -1.0),
+                    _ => return false,
+                };
+                // Releasing a key only stops the direction it started, so opposite keys don't cancel out for good
+                if amount > 0.0 || self.movement[axis] == sign {
+                    self.movement[axis] = sign * amount;
+                }
+                true
+            }
+            WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => {
+                self.looking = *state == ElementState::Pressed;
+                true
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                let cursor = [position.x as f32, position.y as f32];
+                match self.cursor.replace(cursor) {
+                    Some(last) if self.looking => self.look(cursor[0] - last[0], cursor[1] - last[1]),
+                    _ => return false,
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn update(&mut self, dt: f32) {
+        let [forward, right, up] = self.movement;
+        self.translate(forward, right, up, dt);
+    }
+
+    fn apply(&self, camera: &mut Camera) {
+        let forward = self.forward();
+        camera.position = self.position;
+        camera.target = [
+            self.position[0] + forward[0],
+            self.position[1] + forward[1],
+            self.position[2] + forward[2],
+        ];
+        camera.up = [0.0, 1.0, 0.0];
+    }
+}
+
+// Wheel movement in lines, pixel deltas from touchpads count as one line per 20 pixels
+fn scroll_lines(delta: &MouseScrollDelta) -> f32 {
+    match delta {
+        MouseScrollDelta::LineDelta(_, y) => *y,
+        MouseScrollDelta::PixelDelta(position) => position.y as f32 / 20.0,
+    }
+}
+
+// View and projection matrices shared by every block in a frame, column-major like Transform
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraMatrices {
+    pub view: Transform,
+    pub proj: Transform,
+}
+
+impl Default for CameraMatrices {
+    // Keeps vertices in clip space
+    fn default() -> Self {
+        Self { view: IDENTITY_TRANSFORM, proj: IDENTITY_TRANSFORM }
+    }
+}
+
+// Where the camera uniform's matrices come from
+#[derive(Debug, Clone, Copy)]
+enum CameraSource {
+    Clip,                       // Identity matrices
+    LookAt(Camera),             // Projection follows the aspect ratio of the target
+    Matrices(CameraMatrices),   // Used exactly as given
+}
+
+// The camera and the uniform its matrices were last uploaded to
+struct CameraState {
+    source: CameraSource,
+    uploaded: Option<(CameraMatrices, Arc<dyn DescriptorSet + Send + Sync>)>, // Reused while the matrices stay the same
+}
+
+// How attachments are initialised at the start of every frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameSettings {
+    pub clear_color: [f32; 4], // RGBA the color attachment is cleared to
+    pub load_previous: bool,   // Keep the image's contents instead of clearing, see set_load_previous for when those are the previous frame
+    pub clear_depth: f32,      // Value the depth attachment is cleared to, 1.0 is the far plane
+}
+
+impl Default for FrameSettings {
+    fn default() -> Self {
+        Self {
+            clear_color: [0.0, 0.0, 0.0, 1.0],
+            load_previous: false,
+            clear_depth: 1.0,
+        }
+    }
+}
+
+// Sub-rectangle of the surface frames are drawn into, in pixels from the top left corner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewportRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ViewportRect {
+    // Covers a whole image of the given dimensions
+    pub fn full(dimensions: [u32; 2]) -> Self {
+        Self { x: 0, y: 0, width: dimensions[0], height: dimensions[1] }
+    }
+
+    // The part of the rect inside an image of the given dimensions, at least one pixel so the viewport stays valid
+    fn clamped(self, dimensions: [u32; 2]) -> Self {
+        let x = self.x.min(dimensions[0].saturating_sub(1));
+        let y = self.y.min(dimensions[1].saturating_sub(1));
+        Self {
+            x,
+            y,
+            width: self.width.min(dimensions[0].saturating_sub(x)).max(1),
+            height: self.height.min(dimensions[1].saturating_sub(y)).max(1),
+        }
+    }
+
+    fn viewport(&self) -> Viewport {
+        Viewport {
+            origin: [self.x as f32, self.y as f32],
+            dimensions: [self.width as f32, self.height as f32],
+            depth_range: 0.0..1.0,
+        }
+    }
+
+    // Keeps draws from spilling out of the rect, e.g. wide lines or a clear-colored background
+    fn scissor(&self) -> Scissor {
+        Scissor { origin: [self.x as i32, self.y as i32], dimensions: [self.width, self.height] }
+    }
+}
+
+// Descriptor set index of the per-frame camera uniform
+pub const CAMERA_SET: usize = 0;
+
+// Descriptor set index of the per-block model uniform used when transforms do not fit in push constants
+pub const MODEL_SET: usize = 2;
+
+// Descriptor set index the material uniform is bound to unless configured otherwise
+pub const DEFAULT_MATERIAL_SET: usize = 1;
+
+// Material uniform layout, one float per slot in ShaderBlock::material_data order:
+// [0..4] base color (r, g, b, a), [4] roughness, [5] metallic
+const DEFAULT_MATERIAL: [f32; 6] = [1.0, 1.0, 1.0, 1.0, 1.0, 0.0];
+
+// Number of frames the CPU may record ahead of the GPU unless configured otherwise
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
+// Fence signalled once the GPU has finished with a frame's resources
+type FrameFence = Arc<FenceSignalFuture<Box<dyn GpuFuture>>>;
+
+// Where block geometry is stored on the GPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferStrategy {
+    HostVisible, // Written directly by the CPU, suits small or frequently changing data
+    DeviceLocal, // Copied through a staging buffer into GPU memory, suits large static meshes
+}
+
+impl Default for BufferStrategy {
+    fn default() -> Self {
+        BufferStrategy::HostVisible
+    }
+}
+
+// A buffer of T stored according to the renderer's BufferStrategy
+type GpuArray<T> = Arc<dyn TypedBufferAccess<Content = [T]> + Send + Sync>;
+
+// A slice of a CpuBufferPool, the memory returns to the pool when it is dropped
+type PoolChunk<T> = CpuBufferPoolChunk<T, Arc<StdMemoryPool>>;
+
+// A CpuBufferPool that counts the chunks it hands out and the buffers it had to allocate for them
+struct CountingPool<T> {
+    pool: CpuBufferPool<T>,
+    chunks: AtomicUsize,      // Chunks handed out so far
+    allocations: AtomicUsize, // Times the pool grew into a new buffer
+}
+
+impl<T: Send + Sync + 'static> CountingPool<T> {
+    fn new(device: Arc<Device>, usage: BufferUsage) -> Self {
+        Self { pool: CpuBufferPool::new(device, usage), chunks: AtomicUsize::new(0), allocations: AtomicUsize::new(0) }
+    }
+
+    fn chunk<I>(&self, data: I) -> Result<PoolChunk<T>, DeviceMemoryAllocError>
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let capacity = self.pool.capacity();
+        let chunk = self.pool.chunk(data)?;
+        if self.pool.capacity() != capacity {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        self.chunks.fetch_add(1, Ordering::Relaxed);
+        Ok(chunk)
+    }
+
+    fn reserved_bytes(&self) -> u64 {
+        (self.pool.capacity() * std::mem::size_of::<T>()) as u64
+    }
+
+    // Chunks handed out, bytes reserved and allocations, the same for pools of any element type
+    fn stats(&self) -> (usize, u64, usize) {
+        (self.chunks.load(Ordering::Relaxed), self.reserved_bytes(), self.allocations.load(Ordering::Relaxed))
+    }
+}
+
+// Pools one kind of block array is allocated from
+struct ArrayPools<T> {
+    host: CountingPool<T>,    // Host-visible arrays the GPU draws from directly
+    staging: CountingPool<T>, // Sources of copies into device-local arrays, reused once the copies finish
+}
+
+impl<T: Send + Sync + 'static> ArrayPools<T> {
+    fn new(device: &Arc<Device>, usage: BufferUsage) -> Self {
+        Self {
+            // Written in place by update_block_vertices
+            host: CountingPool::new(device.clone(), BufferUsage { transfer_destination: true, ..usage }),
+            staging: CountingPool::new(device.clone(), BufferUsage::transfer_source()),
+        }
+    }
+
+    // Chunk holding data, drawn from directly with host-visible storage and copied from otherwise
+    fn fill<I>(&self, strategy: BufferStrategy, data: I) -> Result<PoolChunk<T>, RendererError>
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let pool = match strategy {
+            BufferStrategy::HostVisible => &self.host,
+            BufferStrategy::DeviceLocal => &self.staging,
+        };
+        Ok(pool.chunk(data)?)
+    }
+}
+
+// Pools the arrays of every block are allocated from, shared with the threads of background loads
+#[derive(Clone)]
+struct BlockPools {
+    vertices: Arc<ArrayPools<Vertex>>, // Shared instead of allocating a buffer per block
+    indices: Arc<ArrayPools<u32>>,
+    instances: Arc<ArrayPools<InstanceData>>,
+}
+
+impl BlockPools {
+    fn new(device: &Arc<Device>) -> Self {
+        Self {
+            vertices: Arc::new(ArrayPools::new(device, BufferUsage::vertex_buffer())),
+            indices: Arc::new(ArrayPools::new(device, BufferUsage::index_buffer())),
+            instances: Arc::new(ArrayPools::new(device, BufferUsage::vertex_buffer())),
+        }
+    }
+
+    // Checks block and writes its arrays into chunks for strategy. Needs no queue, so background loads
+    // do it on their own thread and leave the rendering thread only the copies to record.
+    fn stage(&self, strategy: BufferStrategy, block: ShaderBlock) -> Result<StagedBlock, RendererError> {
+        validate_indices(&block)?;
+        let vertices = self.vertices.fill(strategy, block.vertices.iter().copied())?;
+        let indices = if block.indices.is_empty() {
+            None
+        } else {
+            Some(self.indices.fill(strategy, block.indices.iter().copied())?)
+        };
+        let instances = if block.instances.is_empty() {
+            None
+        } else {
+            Some(self.instances.fill(strategy, block.instances.iter().map(|&instance_transform| InstanceData { instance_transform }))?)
+        };
+        Ok(StagedBlock { block, strategy, vertices, indices, instances })
+    }
+}
+
+// A block whose arrays have been written into pool chunks by BlockPools::stage
+struct StagedBlock {
+    block: ShaderBlock, // Its vertices are still needed for the bounds and the preprocess pass
+    strategy: BufferStrategy, // Which pools the chunks come from
+    vertices: PoolChunk<Vertex>,
+    indices: Option<PoolChunk<u32>>,
+    instances: Option<PoolChunk<InstanceData>>, // None draws the shared identity
+}
+
+// What happens when a new block does not fit in the memory budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetPolicy {
+    EvictLeastRecentlyUsed, // Drops the blocks drawn, uploaded or moved longest ago until the new one fits
+    Reject,                 // Fails the upload with RendererError::BudgetExceeded
+}
+
+impl Default for BudgetPolicy {
+    fn default() -> Self {
+        BudgetPolicy::EvictLeastRecentlyUsed
+    }
+}
+
+// Bytes of GPU memory taken by resident blocks, against a limit
+struct MemoryBudget {
+    limit: u64,
+    used: u64, // Resident blocks plus reservations of uploads in progress
+    policy: BudgetPolicy,
+    clock: u64, // Advanced on every use, orders blocks from least to most recently used
+    resident: HashMap<BlockId, (u64, u64)>, // Last use and size of every block that has not been evicted
+}
+
+impl MemoryBudget {
+    fn new(limit: u64) -> Self {
+        Self { limit, used: 0, policy: BudgetPolicy::default(), clock: 0, resident: HashMap::new() }
+    }
+
+    // Makes room for bytes, returning the blocks that have to be evicted for it. Nothing is evicted
+    // when the block cannot fit.
+    fn reserve(&mut self, bytes: u64) -> Result<Vec<BlockId>, RendererError> {
+        let free = self.limit.saturating_sub(self.used);
+        if bytes <= free {
+            self.used += bytes;
+            return Ok(Vec::new());
+        }
+        let reclaimable: u64 = self.resident.values().map(|&(_, size)| size).sum();
+        if self.policy == BudgetPolicy::Reject || bytes > free + reclaimable {
+            let available = if self.policy == BudgetPolicy::Reject { free } else { free + reclaimable };
+            return Err(RendererError::BudgetExceeded { requested: bytes, available });
+        }
+
+        let mut by_age: Vec<(BlockId, u64, u64)> = self.resident.iter().map(|(&id, &(used, size))| (id, used, size)).collect();
+        by_age.sort_by_key(|&(id, used, _)| (used, id));
+        let mut evicted = Vec::new();
+        let mut freed = free;
+        for (id, _, size) in by_age {
+            if freed >= bytes {
+                break;
+            }
+            self.resident.remove(&id);
+            self.used -= size;
+            freed += size;
+            evicted.push(id);
+        }
+        self.used += bytes;
+        Ok(evicted)
+    }
+
+    // Gives back a reservation whose upload failed
+    fn cancel(&mut self, bytes: u64) {
+        self.used = self.used.saturating_sub(bytes);
+    }
+
+    // Gives back the memory of a block that was removed
+    fn release(&mut self, block_id: BlockId) {
+        if let Some((_, size)) = self.resident.remove(&block_id) {
+            self.used = self.used.saturating_sub(size);
+        }
+    }
+
+    // Records the block a reservation was used for
+    fn commit(&mut self, block_id: BlockId, bytes: u64) {
+        self.clock += 1;
+        self.resident.insert(block_id, (self.clock, bytes));
+    }
+
+    // Marks a block as the most recently used one
+    fn touch(&mut self, block_id: BlockId) {
+        self.touch_all(&[block_id]);
+    }
+
+    // Marks blocks as used together, e.g. by the same frame
+    fn touch_all(&mut self, block_ids: &[BlockId]) {
+        self.clock += 1;
+        for block_id in block_ids {
+            if let Some(entry) = self.resident.get_mut(block_id) {
+                entry.0 = self.clock;
+            }
+        }
+    }
+}
+
+// Memory held by the pools block buffers are allocated from, see buffer_pool_stats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BufferPoolStats {
+    pub chunks: usize,         // Host-visible block buffers allocated from the pools, staging chunks are not counted
+    pub staging_chunks: usize, // Sources of device-local uploads, recycled once their copies finish
+    pub bytes_reserved: u64,   // Size of the buffers currently backing the pools, staging included
+    pub allocations: usize,    // Times a pool had to allocate a larger buffer
+}
+
+impl BufferPoolStats {
+    // Fraction of chunks served from memory the pools already held, 0 before the first chunk
+    pub fn reuse_rate(&self) -> f32 {
+        let requests = self.chunks + self.staging_chunks;
+        if requests == 0 {
+            0.0
+        } else {
+            1.0 - self.allocations.min(requests) as f32 / requests as f32
+        }
+    }
+}
+
+// Command buffer recording staging copies for one batch of uploads
+struct UploadBuilder {
+    commands: AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    timestamps: Option<Arc<QueryPool>>, // Brackets the copies when profiling
+    copies: usize, // Recorded so far, there is nothing to submit without any
+}
+
+// Queue uploads are recorded for, and how finished copies are handed to the graphics queue
+struct UploadContext {
+    queue: Arc<Queue>,          // A dedicated transfer queue when the device has one, the graphics queue otherwise
+    graphics_queue: Arc<Queue>, // Draws the uploaded buffers
+}
+
+impl UploadContext {
+    fn new(graphics_queue: Arc<Queue>, transfer_queue: Option<Arc<Queue>>) -> Self {
+        Self { queue: transfer_queue.unwrap_or_else(|| graphics_queue.clone()), graphics_queue }
+    }
+
+    fn dedicated(&self) -> bool {
+        self.queue.family().id() != self.graphics_queue.family().id()
+    }
+
+    // Families device-local buffers are shared by. Buffers shared by both families are concurrent, so the
+    // semaphore in submit is the whole hand-over and no ownership barriers are needed.
+    fn queue_families(&self) -> Vec<QueueFamily> {
+        if self.dedicated() {
+            vec![self.graphics_queue.family(), self.queue.family()]
+        } else {
+            vec![self.graphics_queue.family()]
+        }
+    }
+
+    // Submits recorded copies. Off the graphics queue, the graphics queue waits for them on a semaphore
+    // and the fence only signals once it has, so blocks are safe to draw after waiting on it.
+    fn submit(&self, device: &Arc<Device>, commands: PrimaryAutoCommandBuffer) -> Result<FenceSignalFuture<Box<dyn GpuFuture>>, RendererError> {
+        let executed = sync::now(device.clone()).then_execute(self.queue.clone(), commands)?;
+        if !self.dedicated() {
+            return Ok(executed.boxed().then_signal_fence_and_flush()?);
+        }
+        let acquire = AutoCommandBufferBuilder::primary(
+            device.clone(),
+            self.graphics_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?.build()?;
+        Ok(executed
+            .then_signal_semaphore_and_flush()?
+            .then_execute(self.graphics_queue.clone(), acquire)?
+            .boxed()
+            .then_signal_fence_and_flush()?)
+    }
+}
+
+// A submitted batch of uploads, its staging chunks return to the pools once the fence is dropped
+struct PendingUpload {
+    fence: FenceSignalFuture<Box<dyn GpuFuture>>,
+    timestamps: Option<Arc<QueryPool>>,
+}
+
+// What load_vertex_data uploaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    pub blocks: usize,   // Shader blocks uploaded
+    pub vertices: usize, // Vertices across all of them
+    pub bytes: u64,      // Device-local memory taken by their buffers, as counted against the memory budget
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} blocks, {} vertices, {} bytes", self.blocks, self.vertices, self.bytes)
+    }
+}
+
+// Progress of stream_blocks, reported every time an upload has finished on the GPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub uploaded: usize,       // Blocks whose buffers are ready to draw
+    pub total: Option<usize>,  // Number of blocks being loaded, None when the source cannot tell
+}
+
+impl LoadProgress {
+    // Share of the blocks uploaded so far, between 0 and 1
+    pub fn fraction(&self) -> Option<f32> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(total) => Some(self.uploaded as f32 / total as f32),
+            None => None,
+        }
+    }
+}
+
+// Uploads stream_blocks keeps in flight when asked for none
+pub const DEFAULT_MAX_INFLIGHT_UPLOADS: usize = 4;
+
+// Blocks of a background load staged ahead of the renderer, bounding the staging memory they hold while waiting
+pub const ASYNC_LOAD_CHANNEL_BOUND: usize = 16;
+
+// Blocks of a background load whose copies are submitted between two frames
+pub const ASYNC_LOAD_BLOCKS_PER_FRAME: usize = 4;
+
+// Returned by load_vertex_data_async. Dropping it aborts the load, blocks already uploaded stay.
+pub struct LoadHandle {
+    cancelled: Option<Arc<AtomicBool>>, // None once detached
+    finished: Arc<AtomicBool>,
+}
+
+impl LoadHandle {
+    // True once the completion callback has been called
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    // Lets the load run to completion without keeping the handle around
+    pub fn detach(mut self) {
+        self.cancelled = None;
+    }
+}
+
+impl Drop for LoadHandle {
+    fn drop(&mut self) {
+        if let Some(cancelled) = &self.cancelled {
+            cancelled.store(true, Ordering::Release);
+        }
+    }
+}
+
+// A load_vertex_data_async in progress, drained between frames
+struct BackgroundLoad {
+    db_path: String,
+    blocks: Receiver<Result<StagedBlock, RendererError>>,
+    cancelled: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    pending: Option<(PendingUpload, Vec<(UploadedBlock, u64)>)>, // Copies submitted and not yet known to be done
+    report: LoadReport, // Blocks registered so far
+    on_complete: Box<dyn FnOnce(Result<LoadReport, RendererError>) + Send>,
+}
+
+// GPU buffers of a shader block that has been uploaded and is drawn every frame
+struct UploadedBlock {
+    vertex_buffer: GpuArray<Vertex>,
+    material_buffer: Arc<PoolChunk<f32>>,
+    index_buffer: Option<GpuArray<u32>>, // Present when the block shares vertices between triangles
+    instance_buffer: GpuArray<InstanceData>, // Drawn once per entry, blocks without instances share one identity
+    material_set: Arc<dyn DescriptorSet + Send + Sync>, // Binds material_buffer as the material uniform
+    transform: Transform, // Pushed as a push constant on every draw, so moving a block needs no upload
+    pipeline: PipelineKey,
+    preprocess: Option<PreprocessInput>, // Present when uploaded while the preprocess pass was enabled
+    bounds: Option<Bounds>, // Of the vertex positions before the transform, None when there are no vertices
+    instances: Vec<Transform>, // Contents of instance_buffer, empty for the shared identity, kept to update bounds
+    visible: bool, // Cleared by set_block_visible, hidden blocks keep their buffers but are not drawn
+}
+
+// How triangles are rasterized, for every block at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PolygonMode {
+    Fill,  // Solid triangles
+    Line,  // Triangle edges only
+    Point, // Triangle vertices only
+}
+
+// Index of a texture loaded with load_texture, referenced by ShaderBlock::texture_id
+pub type TextureId = u32;
+
+// An RGBA8 image sampled by the fragment shader
+#[derive(Clone)]
+pub struct Texture {
+    image: Arc<ImmutableImage>,
+    sampler: Arc<Sampler>,
+}
+
+// Number of recent frames the frame time statistics are computed over unless configured otherwise
+pub const DEFAULT_STATS_WINDOW: usize = 240;
+
+// Frame statistics and loaded data of a renderer. Frame fields are updated at the end of every rendered
+// frame, block fields on every upload and eviction. Entries are free-form and never touched by the renderer.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    frames_rendered: u64,
+    last_frame_time: Option<Duration>,
+    frame_times: VecDeque<Duration>, // Intervals between the ends of the most recent frames, oldest first
+    window: usize, // Capacity of frame_times
+    last_frame_end: Option<Instant>, // None after a pause in rendering, so the gap is not counted as a frame
+    gpu_timings: VecDeque<GpuTiming>, // Most recent profiled passes, oldest first, at most window entries
+    culling: CullingStats, // Blocks drawn and culled in the most recently recorded frame
+    last_present: Option<Instant>, // When the most recent frame was submitted, kept across pauses
+    extent: [u32; 2], // Size of the image the most recent frame was rendered into
+    active_pipelines: Vec<PipelineKey>, // Pipelines the most recently recorded frame drew with, in draw order
+    source: Option<String>, // Database the most recent load_vertex_data or stream_vertex_data read from
+    loaded_blocks: usize, // Blocks currently uploaded, evicted ones excluded
+    vertex_count: usize, // Vertices across the loaded blocks
+    entries: BTreeMap<String, String>, // User-defined key/value pairs
+    buffer_pools: BufferPoolStats, // As of the most recent block upload
+    image_count: usize, // Target images the renderer cycles through, as granted by the surface
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Self::with_window(DEFAULT_STATS_WINDOW)
+    }
+}
+
+impl Metadata {
+    // Keeps statistics over the last window frames, at least one
+    pub fn with_window(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            frames_rendered: 0,
+            last_frame_time: None,
+            frame_times: VecDeque::with_capacity(window),
+            window,
+            last_frame_end: None,
+            gpu_timings: VecDeque::new(),
+            culling: CullingStats::default(),
+            last_present: None,
+            extent: [0, 0],
+            active_pipelines: Vec::new(),
+            source: None,
+            loaded_blocks: 0,
+            vertex_count: 0,
+            entries: BTreeMap::new(),
+            buffer_pools: BufferPoolStats::default(),
+            image_count: 0,
+        }
+    }
+
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    pub fn with_entry(mut self, key: &str, value: &str) -> Self {
+        self.set_entry(key, value);
+        self
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn loaded_blocks(&self) -> usize {
+        self.loaded_blocks
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.vertex_count
+    }
+
+    pub fn entry(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub fn entries(&self) -> &BTreeMap<String, String> {
+        &self.entries
+    }
+
+    // Returns the previous value
+    pub fn set_entry(&mut self, key: &str, value: &str) -> Option<String> {
+        self.entries.insert(key.to_string(), value.to_string())
+    }
+
+    pub fn remove_entry(&mut self, key: &str) -> Option<String> {
+        self.entries.remove(key)
+    }
+
+    pub fn buffer_pools(&self) -> BufferPoolStats {
+        self.buffer_pools
+    }
+
+    pub fn record_buffer_pools(&mut self, stats: BufferPoolStats) {
+        self.buffer_pools = stats;
+    }
+
+    pub fn record_source(&mut self, source: &str) {
+        self.source = Some(source.to_string());
+    }
+
+    pub fn record_block_loaded(&mut self, vertices: usize) {
+        self.loaded_blocks += 1;
+        self.vertex_count += vertices;
+    }
+
+    pub fn record_block_evicted(&mut self, vertices: usize) {
+        self.loaded_blocks = self.loaded_blocks.saturating_sub(1);
+        self.vertex_count = self.vertex_count.saturating_sub(vertices);
+    }
+
+    pub fn frames_rendered(&self) -> u64 {
+        self.frames_rendered
+    }
+
+    pub fn last_present(&self) -> Option<Instant> {
+        self.last_present
+    }
+
+    pub fn extent(&self) -> [u32; 2] {
+        self.extent
+    }
+
+    pub fn image_count(&self) -> usize {
+        self.image_count
+    }
+
+    pub fn active_pipelines(&self) -> &[PipelineKey] {
+        &self.active_pipelines
+    }
+
+    pub fn record_pipelines(&mut self, pipelines: Vec<PipelineKey>) {
+        self.active_pipelines = pipelines;
+    }
+
+    pub fn record_culling(&mut self, culling: CullingStats) {
+        self.culling = culling;
+    }
+
+    pub fn record_gpu_timing(&mut self, timing: GpuTiming) {
+        if self.gpu_timings.len() == self.window {
+            self.gpu_timings.pop_front();
+        }
+        self.gpu_timings.push_back(timing);
+    }
+
+    // Profiled GPU passes, oldest first
+    pub fn gpu_timings(&self) -> Vec<GpuTiming> {
+        self.gpu_timings.iter().copied().collect()
+    }
+
+    // Records a frame that finished at end, its frame time is the interval since the previous one
+    pub fn record_frame(&mut self, end: Instant) {
+        self.frames_rendered += 1;
+        self.last_present = Some(end);
+        if let Some(previous) = self.last_frame_end {
+            self.record_frame_time(end.saturating_duration_since(previous));
+        }
+        self.last_frame_end = Some(end);
+    }
+
+    fn record_frame_time(&mut self, frame_time: Duration) {
+        if self.frame_times.len() == self.window {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(frame_time);
+        self.last_frame_time = Some(frame_time);
+    }
+
+    // Forgets when the last frame ended, called when rendering stops for a while
+    // (minimized window, swapchain recreation) so the gap does not skew the statistics
+    pub fn break_interval(&mut self) {
+        self.last_frame_end = None;
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let mut sorted: Vec<Duration> = self.frame_times.iter().copied().collect();
+        sorted.sort();
+        let total: Duration = sorted.iter().sum();
+        FrameStats {
+            frames_rendered: self.frames_rendered,
+            last_frame_time: self.last_frame_time,
+            average_fps: if total.is_zero() { 0.0 } else { sorted.len() as f32 / total.as_secs_f32() },
+            p95_frame_time: percentile(&sorted, 0.95),
+            p99_frame_time: percentile(&sorted, 0.99),
+            culling: self.culling,
+        }
+    }
+}
+
+// Work measured with GPU timestamps while profiling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuPass {
+    RenderPass, // From the start to the end of a frame's render pass
+    Upload,     // Staging copies of one batch of device-local block uploads
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpuTiming {
+    pub pass: GpuPass,
+    pub frame_number: Option<u32>, // Frame the render pass belongs to, None for uploads
+    pub millis: f64,
+}
+
+// Snapshot of a renderer's Metadata
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub frames_rendered: u64,
+    pub last_frame_time: Option<Duration>, // None until two frames have been rendered
+    pub average_fps: f32,                  // Over the statistics window, 0 when unknown
+    pub p95_frame_time: Option<Duration>,
+    pub p99_frame_time: Option<Duration>,
+    pub culling: CullingStats, // Of the most recently recorded frame
+}
+
+// How many blocks of a frame were drawn, how many were skipped for lying outside the camera frustum and
+// how many were hidden with set_block_visible or by a hidden scene graph node. Blocks without vertices are
+// never drawn and count as none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CullingStats {
+    pub drawn: usize,
+    pub culled: usize,
+    pub hidden: usize,
+}
+
+// Handle that lets another thread stop, pause or resume a running renderer
+#[derive(Debug, Clone, Default)]
+pub struct RenderControl {
+    stop: Arc<AtomicBool>,
+    paused: Arc<(Mutex<bool>, Condvar)>, // Render loops park on the condvar until resumed or stopped
+}
+
+impl RenderControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Requests the event loop to exit after the current frame
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Taking the lock orders the store before any waiter's next check
+        let _paused = self.paused.0.lock().unwrap();
+        self.paused.1.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    // Stops rendering new frames until resume is called
+    pub fn pause(&self) {
+        *self.paused.0.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.paused.0.lock().unwrap() = false;
+        self.paused.1.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.0.lock().unwrap()
+    }
+
+    // Blocks the calling thread while paused, returns early once stopped
+    pub fn wait_while_paused(&self) {
+        let mut paused = self.paused.0.lock().unwrap();
+        while *paused && !self.is_stopped() {
+            paused = self.paused.1.wait(paused).unwrap();
+        }
+    }
+}
+
+// Handle that lets another thread read or update the Metadata of a renderer while it renders
+#[derive(Debug, Clone)]
+pub struct MetadataHandle(Arc<Mutex<Metadata>>);
+
+impl MetadataHandle {
+    // Copy of the Metadata, the lock is only held for the clone
+    pub fn snapshot(&self) -> Metadata {
+        self.0.lock().unwrap().clone()
+    }
+
+    // Runs f with the Metadata locked, see VulkanoRenderer::metadata_mut
+    pub fn update<R>(&self, f: impl FnOnce(&mut Metadata) -> R) -> R {
+        f(&mut self.0.lock().unwrap())
+    }
+}
+
+// How play shows the time between two recorded frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpolationMode {
+    Nearest, // Each frame stays on screen until the next one is due
+    Linear,  // Vertices and materials are blended between adjacent frames on every rendered frame
+}
+
+impl Default for InterpolationMode {
+    fn default() -> Self {
+        InterpolationMode::Nearest
+    }
+}
+
+// Handle that lets another thread seek or loop a running play, pausing goes through RenderControl
+#[derive(Debug, Clone, Default)]
+pub struct PlaybackControl {
+    state: Arc<Mutex<PlaybackState>>,
+}
+
+#[derive(Debug, Default)]
+struct PlaybackState {
+    seek: Option<u32>, // Frame number to jump to, taken by the next playback step
+    looping: bool,
+    interpolation: InterpolationMode,
+    current: Option<u32>, // Frame number on screen, None before the first one is uploaded
+}
+
+impl PlaybackControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Jumps to the first frame numbered frame_number or later, the last frame when there is none
+    pub fn seek(&self, frame_number: u32) {
+        self.state.lock().unwrap().seek = Some(frame_number);
+    }
+
+    // Starts over from the first frame after the last one instead of returning
+    pub fn set_looping(&self, looping: bool) {
+        self.state.lock().unwrap().looping = looping;
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.state.lock().unwrap().looping
+    }
+
+    // Takes effect on the next rendered frame
+    pub fn set_interpolation(&self, mode: InterpolationMode) {
+        self.state.lock().unwrap().interpolation = mode;
+    }
+
+    pub fn interpolation(&self) -> InterpolationMode {
+        self.state.lock().unwrap().interpolation
+    }
+
+    pub fn current_frame(&self) -> Option<u32> {
+        self.state.lock().unwrap().current
+    }
+
+    fn take_seek(&self) -> Option<u32> {
+        self.state.lock().unwrap().seek.take()
+    }
+
+    fn set_current(&self, frame_number: Option<u32>) {
+        self.state.lock().unwrap().current = frame_number;
+    }
+}
+
+// Frames of metrics in frame_number order, keeping the first of every duplicate number
+fn playback_frames(mut frames: Vec<FrameData>) -> Vec<FrameData> {
+    let sorted = frames.windows(2).all(|pair| pair[0].frame_number < pair[1].frame_number);
+    if !sorted {
+        log::warn!("Playback frames are out of order or repeat frame numbers, sorting them and dropping duplicates");
+        frames.sort_by_key(|frame| frame.frame_number); // Stable, the first duplicate stays in front
+        frames.dedup_by_key(|frame| frame.frame_number);
+    }
+    frames
+}
+
+// Time of the frame at index into a play at fps
+fn playback_offset(index: usize, fps: f32) -> Duration {
+    Duration::from_secs_f64(index as f64 / fps as f64)
+}
+
+// How recent acquisitions went with respect to VK_SUBOPTIMAL_KHR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Suboptimal {
+    No,
+    Recreated,  // The swapchain is being recreated because an acquisition was suboptimal
+    Persistent, // Still suboptimal after recreating, the driver reports it regardless of the swapchain
+}
+
+impl Suboptimal {
+    // State after an acquisition, and whether the swapchain should be recreated before the next frame. The
+    // frame is presented either way. Some drivers report every image as suboptimal, recreating for them again
+    // and again would only stall each frame, so that stops until an acquisition is optimal.
+    fn after_acquire(self, suboptimal: bool) -> (Suboptimal, bool) {
+        match (self, suboptimal) {
+            (_, false) => (Suboptimal::No, false),
+            (Suboptimal::No, true) => (Suboptimal::Recreated, true),
+            (Suboptimal::Recreated, true) | (Suboptimal::Persistent, true) => (Suboptimal::Persistent, false),
+        }
+    }
+}
+
+// Attempts at acquiring a swapchain image per frame, every out of date one recreates the swapchain first.
+// During a resize storm the surface can change again before the new swapchain is used.
+const MAX_ACQUIRE_ATTEMPTS: usize = 3;
+
+// How long a frame waits for the presentation engine to release an image, timing out is recoverable
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Runs acquire until it succeeds or fails with anything but OutOfDate, calling recreate in between.
+// The last attempt's OutOfDate is returned so the caller can recreate before the next frame.
+fn acquire_with_retries<S, T>(
+    state: &mut S,
+    mut acquire: impl FnMut(&mut S) -> Result<T, AcquireError>,
+    mut recreate: impl FnMut(&mut S) -> Result<(), RendererError>,
+) -> Result<T, RendererError> {
+    for _ in 1..MAX_ACQUIRE_ATTEMPTS {
+        match acquire(state) {
+            Err(AcquireError::OutOfDate) => recreate(state)?,
+            result => return result.map_err(RendererError::from),
+        }
+    }
+    Ok(acquire(state)?)
+}
+
+// What the render loop does after a frame: Ok(true) when the swapchain has to be recreated before the next
+// one, Ok(false) to carry on, logging recoverable errors. Errors it cannot recover from are returned.
+fn recovery(frame: Result<(), RendererError>) -> Result<bool, RendererError> {
+    match frame {
+        Ok(()) => Ok(false),
+        Err(e) if e.is_out_of_date() => Ok(true),
+        Err(e) if e.is_recoverable() => {
+            log::warn!("Render error: {}", e);
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// How often a paused event loop wakes up to notice resume or stop from another thread
+const PAUSED_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Time between frames of a play blended with InterpolationMode::Linear, about 60 Hz
+const LINEAR_PLAYBACK_INTERVAL: Duration = Duration::from_micros(16_667);
+
+// Errors that can occur while capturing a rendered frame
+#[derive(Debug)]
+pub enum CaptureError {
+    Render(RendererError),      // Rendering or copying the frame failed
+    UnsupportedFormat(Format),  // The color format cannot be converted to RGBA8
+    Unsupported,                // The target images are not known or cannot be copied from
+    Encode(Box<dyn Error + Send + Sync>), // The captured pixels could not be written as an image file
+}
+
+impl CaptureError {
+    // Returns true when capturing again later may succeed, e.g. once a pending resize has been handled
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, CaptureError::Render(e) if e.is_out_of_date())
+    }
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaptureError::Render(e) => write!(f, "failed to capture frame: {}", e),
+            CaptureError::UnsupportedFormat(format) => write!(f, "cannot convert {:?} to RGBA8", format),
+            CaptureError::Unsupported => write!(f, "the render target does not support capturing"),
+            CaptureError::Encode(e) => write!(f, "failed to encode captured frame: {}", e),
+        }
+    }
+}
+
+impl Error for CaptureError {}
+
+impl From<RendererError> for CaptureError {
+    fn from(e: RendererError) -> Self {
+        CaptureError::Render(e)
+    }
+}
+
+// Errors that can occur while reloading shaders from SPIR-V files, the previous pipeline stays in use
+#[derive(Debug)]
+pub enum ShaderReloadError {
+    Io(PathBuf, io::Error),          // A shader file could not be read
+    InvalidSpirv(PathBuf),           // A shader file does not hold a SPIR-V module
+    NotSpirv(&'static str),          // The bytes given for this stage are not a SPIR-V module
+    ShaderLoad(OomError),            // The shader module could not be created
+    Interface(ShaderInterfaceError), // A module does not declare the interface of the built-in shader it replaces
+    Pipeline(RendererInitError),     // The pipeline was rejected
+    #[cfg(feature = "notify")]
+    Watch(notify::Error),            // The shader files could not be watched
+}
+
+impl fmt::Display for ShaderReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderReloadError::Io(path, e) => write!(f, "failed to read shader {}: {}", path.display(), e),
+            ShaderReloadError::InvalidSpirv(path) => write!(f, "{} is not a SPIR-V module", path.display()),
+            ShaderReloadError::NotSpirv(stage) => write!(f, "the {} shader is not a SPIR-V module", stage),
+            ShaderReloadError::ShaderLoad(e) => write!(f, "failed to load shader module: {}", e),
+            ShaderReloadError::Interface(e) => write!(f, "{}", e),
+            ShaderReloadError::Pipeline(e) => write!(f, "failed to rebuild pipeline: {}", e),
+            #[cfg(feature = "notify")]
+            ShaderReloadError::Watch(e) => write!(f, "failed to watch shaders: {}", e),
+        }
+    }
+}
+
+impl Error for ShaderReloadError {}
+
+impl From<RendererInitError> for ShaderReloadError {
+    fn from(e: RendererInitError) -> Self {
+        ShaderReloadError::Pipeline(e)
+    }
+}
+
+impl From<ShaderInterfaceError> for ShaderReloadError {
+    fn from(e: ShaderInterfaceError) -> Self {
+        ShaderReloadError::Interface(e)
+    }
+}
+
+// A custom shader module that cannot stand in for the built-in shader it replaces. Pipelines for custom
+// modules are built from the built-in shader's reflection data, so the two must agree.
+#[derive(Debug)]
+pub enum ShaderInterfaceError {
+    Unreadable(&'static str, String), // The module's "main" entry point could not be reflected, with the reason
+    Mismatch(&'static str, String),   // The module declares something the built-in shader does not, with what
+    BuiltinLoad(OomError),            // The built-in shader to compare against could not be loaded
+}
+
+impl fmt::Display for ShaderInterfaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderInterfaceError::Unreadable(stage, reason) => write!(f, "the {} shader cannot be reflected: {}", stage, reason),
+            ShaderInterfaceError::Mismatch(stage, reason) => {
+                write!(f, "the {} shader does not match the built-in one: {}", stage, reason)
+            }
+            ShaderInterfaceError::BuiltinLoad(e) => write!(f, "failed to load the built-in shader: {}", e),
+        }
+    }
+}
+
+impl Error for ShaderInterfaceError {}
+
+#[cfg(feature = "notify")]
+impl From<notify::Error> for ShaderReloadError {
+    fn from(e: notify::Error) -> Self {
+        ShaderReloadError::Watch(e)
+    }
+}
+
+// Vertex and fragment modules loaded from disk, replacing vs and the fragment shader of ShaderKind::Unlit.
+// They must keep the interface of the built-in shaders, whose reflection data describes them.
+#[derive(Clone)]
+struct ShaderModules {
+    vertex: Arc<ShaderModule>,
+    fragment: Arc<ShaderModule>,
+}
+
+// SPIR-V compiled from GLSL at runtime, keyed by a hash of the stage and source, so rebuilding a
+// pipeline from the same source does not compile it again
+#[derive(Default)]
+struct ShaderCache {
+    #[cfg(feature = "glsl")]
+    compiled: HashMap<u64, Vec<u32>>,
+}
+
+// Compute pass run over the raw floats of blocks before they are drawn
+struct Preprocess {
+    module: Option<Arc<ShaderModule>>, // Set by set_preprocess_shader, None runs the default cs shader
+    pipeline: Arc<ComputePipeline>,
+    workgroup_size: u32,
+}
+
+// Local workgroup size of the preprocess pass unless configured otherwise
+pub const DEFAULT_PREPROCESS_WORKGROUP_SIZE: u32 = 64;
+
+// Attachments and subpasses of the scene render pass when frames write more than the target image, e.g. the
+// G-buffer of deferred shading. Color attachment 0 is always the target image, the ones listed here follow it
+// and the depth attachment, if any, comes last. Blocks are drawn in draw_subpass, whose first color attachment
+// must be the target. The built-in shaders write the world-space normal, mapped to 0..1, to its second. The
+// other subpasses only order the attachment writes and reads of the pass.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderPassConfig {
+    pub color_attachments: Vec<Format>, // Images after the target, created by the renderer and cleared to zero every frame
+    pub subpasses: Vec<SubpassConfig>,  // Empty is a single subpass writing every color attachment
+    pub dependencies: Vec<(u32, u32)>,  // Source and destination subpass, the destination reads what the source wrote
+    pub draw_subpass: u32,
+}
+
+impl RenderPassConfig {
+    // One subpass writing the target and an image of each format
+    pub fn with_color_attachments(formats: Vec<Format>) -> Self {
+        Self { color_attachments: formats, subpasses: Vec::new(), dependencies: Vec::new(), draw_subpass: 0 }
+    }
+
+    fn color_count(&self) -> usize {
+        1 + self.color_attachments.len()
+    }
+
+    // The subpasses of the pass, with the implicit one filled in
+    fn resolved_subpasses(&self) -> Vec<SubpassConfig> {
+        if self.subpasses.is_empty() {
+            vec![SubpassConfig { color: (0..self.color_count()).collect(), input: Vec::new(), depth: true }]
+        } else {
+            self.subpasses.clone()
+        }
+    }
+
+    fn check(&self) -> Result<(), RendererError> {
+        let invalid = |reason: String| Err(RendererError::InvalidRenderPassConfig(reason));
+        let subpasses = self.resolved_subpasses();
+        if self.draw_subpass as usize >= subpasses.len() {
+            return invalid(format!("draw subpass {} of {}", self.draw_subpass, subpasses.len()));
+        }
+        for (i, subpass) in subpasses.iter().enumerate() {
+            if let Some(&attachment) = subpass.color.iter().chain(&subpass.input).find(|&&a| a >= self.color_count()) {
+                return invalid(format!("subpass {} uses color attachment {} of {}", i, attachment, self.color_count()));
+            }
+            if let Some(attachment) = subpass.input.iter().find(|a| subpass.color.contains(a)) {
+                return invalid(format!("subpass {} reads and writes color attachment {}", i, attachment));
+            }
+        }
+        if subpasses[self.draw_subpass as usize].color.first() != Some(&0) {
+            return invalid("the draw subpass must write the target as its first color attachment".to_string());
+        }
+        for &(source, destination) in &self.dependencies {
+            if source >= destination || destination as usize >= subpasses.len() {
+                return invalid(format!("dependency of subpass {} on subpass {}", destination, source));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Color attachments a subpass of a RenderPassConfig writes and reads, by attachment index
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SubpassConfig {
+    pub color: Vec<usize>, // Written in fragment shader output order
+    pub input: Vec<usize>, // Read as input attachments, written by an earlier subpass
+    pub depth: bool,       // Uses the depth attachment, when the renderer has one
+}
+
+// Second render pass that samples the scene and draws a full-screen triangle into the target image
+struct PostProcess {
+    module: Option<Arc<ShaderModule>>, // Set by set_post_shader, None runs the default post_fs shader
+    render_pass: Arc<RenderPass>,
+    pipeline: Arc<GraphicsPipeline>,
+    sampler: Arc<Sampler>,
+    targets: Vec<PostTarget>, // One per target image, rebuilt along with the framebuffers
+}
+
+// Per target image resources of the post-processing pass
+struct PostTarget {
+    scene_framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>, // Scene render pass into the intermediate image
+    framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>, // Post render pass into the target image
+    set: Arc<dyn DescriptorSet + Send + Sync>, // Samples the intermediate image
+    attachments: Vec<Arc<AttachmentImage>>, // Extra color attachments of scene_framebuffer, see RenderPassConfig
+}
+
+// Inputs of the preprocess pass for one block, uploaded while preprocessing was enabled. The pass runs when
+// the block is uploaded or its vertices are updated and when the shader changes, not every frame.
+#[derive(Clone)]
+struct PreprocessInput {
+    raw: Arc<CpuAccessibleBuffer<[f32]>>, // The block's vertices as PositionNormalUv floats
+    vertices: Arc<DeviceLocalBuffer<[Vertex]>>, // Written by the pass and drawn instead of vertex_buffer
+    set: Arc<dyn DescriptorSet + Send + Sync>, // Binds raw and vertices
+}
+
+// Watches the files passed to watch_shaders, polled between frames
+#[cfg(feature = "notify")]
+struct ShaderWatch {
+    _watcher: notify::RecommendedWatcher, // Stops watching when dropped
+    events: std::sync::mpsc::Receiver<notify::DebouncedEvent>,
+    vertex_path: PathBuf,
+    fragment_path: PathBuf,
+    on_error: Box<dyn FnMut(ShaderReloadError)>,
+}
+
+// egui panels drawn over presented frames, see enable_overlay
+#[cfg(feature = "egui")]
+struct Overlay {
+    gui: egui_winit_vulkano::Gui, // Loads the presented image and draws on top of it
+    stats_panel: bool, // Show the built-in renderer panel
+    ui: Option<Box<dyn FnMut(&egui::Context) + Send>>, // Set by set_overlay_ui, called every frame
+}
+
+// How long the watcher waits for writes to a shader file to settle before reporting it
+#[cfg(feature = "notify")]
+const SHADER_WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
+
+// A captured frame as tightly packed RGBA8 rows, top row first
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+// Images a renderer draws into
+enum RenderTarget {
+    // Presented to a window surface, images are empty when the caller built the framebuffers
+    Swapchain(Arc<Swapchain<Window>>, Vec<Arc<SwapchainImage<Window>>>),
+    Offscreen(Vec<Arc<AttachmentImage>>), // Rendered without a surface and never presented
+}
+
+impl RenderTarget {
+    // Whether the framebuffers came with VulkanoRenderer::new, the renderer cannot rebuild them
+    fn caller_framebuffers(&self) -> bool {
+        matches!(self, RenderTarget::Swapchain(_, images) if images.is_empty())
+    }
+
+    fn dimensions(&self) -> [u32; 2] {
+        match self {
+            RenderTarget::Swapchain(swapchain, _) => swapchain.dimensions(),
+            RenderTarget::Offscreen(images) => images[0].dimensions(),
+        }
+    }
+
+    fn format(&self) -> Format {
+        match self {
+            RenderTarget::Swapchain(swapchain, _) => swapchain.format(),
+            RenderTarget::Offscreen(images) => images[0].format(),
+        }
+    }
+
+    // Offscreen images keep linear values for capture, only presented UNORM images are encoded
+    fn manual_gamma(&self) -> bool {
+        match self {
+            RenderTarget::Swapchain(swapchain, _) => needs_manual_gamma(swapchain.format()),
+            RenderTarget::Offscreen(_) => false,
+        }
+    }
+}
 
 pub struct VulkanoRenderer {
     device: Arc<Device>,
-    queue: Arc<Queue>,
-    pipeline: Arc<GraphicsPipeline>,
-    swapchain: Arc<Swapchain<Window>>,
-    framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
-    render_pass: Arc<RenderPass>,
-    metadata: Mutex<Metadata>, // Lock to manage concurrent access
+    queue: Arc<Queue>,
+    pipeline: Arc<GraphicsPipeline>, // Draws blocks with the default PipelineKey
+    pipelines: Mutex<HashMap<(PipelineKey, PolygonMode), Arc<GraphicsPipeline>>>, // Other pipelines, created on first use
+    polygon_mode: PolygonMode, // How the triangles of every block are rasterized
+    target: RenderTarget,
+    framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    render_pass: Arc<RenderPass>,
+    metadata: Arc<Mutex<Metadata>>, // Shared with the handles of metadata_handle
+    frame_fences: Vec<Option<FrameFence>>, // One synchronization slot per frame in flight
+    current_frame: usize, // Slot used by the next call to render_frame
+    previous_frame_end: Option<Box<dyn GpuFuture>>, // Future of the last submitted frame, chained into the next one
+    blocks: Mutex<Vec<Option<UploadedBlock>>>, // Buffers uploaded by load_vertex_data, None once evicted or removed
+    removed_blocks: Mutex<BTreeSet<BlockId>>, // Slots of removed blocks, reused by the next uploads. Lock after blocks.
+    scene_graph: Mutex<Option<SceneGraph>>, // When set, decides which blocks are drawn and with what transforms
+    missing_scene_blocks: Mutex<Vec<BlockId>>, // Last warned about, so a missing reference is not reported every frame
+    memory_budget: Mutex<MemoryBudget>, // Bytes of block buffers allowed on the GPU
+    control: RenderControl, // Shared stop flag checked by run
+    depth_format: Option<Format>, // Format of the per-image depth attachments, None when depth is disabled
+    samples: u32, // MSAA sample count, 1 renders straight into the swapchain image
+    material_set: usize, // Descriptor set index of the material uniform in the pipeline layout
+    camera: Mutex<CameraState>, // Bound once per frame, uploaded again only when its matrices change
+    aspect_ratio: f32, // Width over height of the viewport, refreshed on recreation and by set_viewport
+    camera_pool: CpuBufferPool<vs::ty::Camera>, // Per-frame camera uniforms
+    upload_context: UploadContext, // Queue choice and synchronization of buffer uploads
+    pools: BlockPools, // Vertex, index and instance arrays of all blocks
+    material_pool: CountingPool<f32>, // Material uniforms of all blocks
+    push_transforms: bool, // Whether a Transform fits within the device's push constant limit, always on conformant devices
+    viewport: Viewport, // Set as dynamic state every frame, covers viewport_rect of the current images
+    scissor: Scissor, // Set as dynamic state along with viewport
+    viewport_rect: Option<ViewportRect>, // Set by set_viewport, None draws into the whole surface
+    identity_model_set: Mutex<Option<Arc<dyn DescriptorSet + Send + Sync>>>, // Model uniform bound when push_transforms is set
+    model_pool: CpuBufferPool<Transform>, // Per-draw model uniforms when push_transforms is not set
+    identity_instances: Mutex<Option<GpuArray<InstanceData>>>, // Instance buffer of blocks without instances
+    last_image: Option<usize>, // Index of the image the most recent frame was rendered into
+    capture_buffer: Option<Arc<CpuAccessibleBuffer<[u8]>>>, // When set, the next frame is copied here before presenting
+    buffer_strategy: BufferStrategy, // Where vertex and index buffers of new blocks are stored
+    timestamp_pool: Option<Arc<QueryPool>>, // TIMESTAMPS_PER_SLOT queries per frame slot, None when the queue cannot write them
+    pending_timings: Vec<Option<(u32, u64, bool)>>, // Frame number, CPU micros and whether it was profiled, per slot
+    profiling: bool, // Time the render pass and uploads with GPU timestamps
+    timing_sink: Option<Sender<FrameTiming>>, // Receives the timing of every finished frame
+    last_gpu_frame_nanos: Option<u64>, // GPU time of the most recent frame whose fence has been waited on
+    frame_number: u32, // Number of frames submitted so far
+    textures: Mutex<Vec<Texture>>, // Textures loaded by load_texture, indexed by TextureId
+    white_texture: Mutex<Option<Texture>>, // 1x1 fallback for blocks without a usable texture, created on first use
+    frame_settings: Mutex<FrameSettings>, // Picked up at the start of the next frame
+    clears_color: bool, // Whether the current render pass clears the color attachment or loads it
+    render_pass_config: Option<RenderPassConfig>, // None is the single-attachment scene render pass
+    attachment_images: Vec<Vec<Arc<AttachmentImage>>>, // Extra color attachments of each framebuffer
+    present_modes: Vec<PresentMode>, // Negotiated again on every swapchain recreation, empty keeps the current mode
+    desired_image_count: Option<u32>, // Applied on every swapchain recreation, None is the surface minimum
+    needs_recreate: bool, // Set on resize or a suboptimal acquisition, the swapchain is recreated at the start of the next frame once the window has an area
+    suboptimal: Suboptimal, // Decides whether a suboptimal acquisition schedules a recreation
+    shader_modules: Option<ShaderModules>, // Shaders from RendererOptions::shaders or reload_shaders, None uses the built-in ones
+    shader_cache: ShaderCache,
+    preprocess: Option<Preprocess>, // Created when the preprocess pass is first enabled or given a shader
+    preprocess_enabled: bool, // False bypasses the pass and draws the vertices built on the CPU
+    post: Option<PostProcess>, // None renders the scene straight into the target image
+    vertex_layout: VertexLayout, // Floats per vertex expected by apply_vertex_data
+    playback: PlaybackControl, // Seek and loop requests for play
+    parallel_recording: bool, // Record draws into secondary command buffers on several threads
+    frustum_culling: bool, // Skip blocks whose bounds lie outside the camera frustum
+    debug_callback: Option<DebugCallback>, // Forwards validation messages while the renderer lives, see InstanceConfig
+    debug_names: bool, // Name buffers and images after their role so validation messages say which one is meant
+    camera_controller: Option<Box<dyn CameraController + Send>>, // Fed window events by run, applied before every frame
+    loads: Vec<BackgroundLoad>, // Started by load_vertex_data_async and not yet finished
+    #[cfg(feature = "notify")]
+    shader_watch: Option<ShaderWatch>, // Set by watch_shaders
+    #[cfg(feature = "egui")]
+    overlay: Option<Overlay>, // Set by enable_overlay
+}
+
+impl VulkanoRenderer {
+    // Constructor for the renderer
+    pub fn new(device: Arc<Device>, queue: Arc<Queue>, pipeline: Arc<GraphicsPipeline>,
+               swapchain: Arc<Swapchain<Window>>, framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+               render_pass: Arc<RenderPass>, metadata: Metadata) -> Self {
+        Self::with_target(device, queue, pipeline, RenderTarget::Swapchain(swapchain, Vec::new()), framebuffers, render_pass, metadata)
+    }
+
+    // Shared constructor for swapchain and offscreen renderers
+    fn with_target(device: Arc<Device>, queue: Arc<Queue>, pipeline: Arc<GraphicsPipeline>,
+                   target: RenderTarget, framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+                   render_pass: Arc<RenderPass>, mut metadata: Metadata) -> Self {
+        metadata.image_count = framebuffers.len();
+        let initial_aspect_ratio = aspect_ratio(target.dimensions());
+        let initial_rect = ViewportRect::full(target.dimensions());
+        let camera_pool = CpuBufferPool::new(device.clone(), BufferUsage::uniform_buffer());
+        let model_pool = CpuBufferPool::new(device.clone(), BufferUsage::uniform_buffer());
+        let pools = BlockPools::new(&device);
+        let material_pool = CountingPool::new(device.clone(), BufferUsage { transfer_destination: true, ..BufferUsage::uniform_buffer() });
+        let push_transforms = std::mem::size_of::<Transform>() as u32
+            <= device.physical_device().limits().max_push_constants_size();
+        let timestamp_pool = create_timestamp_pool(&device, &queue, DEFAULT_FRAMES_IN_FLIGHT);
+        let memory_budget = default_memory_budget(device.physical_device());
+        Self {
+            device,
+            queue,
+            pipeline,
+            pipelines: Mutex::new(HashMap::new()),
+            polygon_mode: PolygonMode::Fill,
+            target,
+            framebuffers,
+            render_pass,
+            metadata: Arc::new(Mutex::new(metadata)),
+            frame_fences: vec![None; DEFAULT_FRAMES_IN_FLIGHT],
+            current_frame: 0,
+            previous_frame_end: None,
+            blocks: Mutex::new(Vec::new()),
+            removed_blocks: Mutex::new(BTreeSet::new()),
+            scene_graph: Mutex::new(None),
+            missing_scene_blocks: Mutex::new(Vec::new()),
+            memory_budget: Mutex::new(MemoryBudget::new(memory_budget)),
+            control: RenderControl::new(),
+            depth_format: None,
+            samples: 1,
+            material_set: DEFAULT_MATERIAL_SET,
+            camera: Mutex::new(CameraState { source: CameraSource::Clip, uploaded: None }),
+            aspect_ratio: initial_aspect_ratio,
+            camera_pool,
+            upload_context: UploadContext::new(queue.clone(), None),
+            pools,
+            material_pool,
+            push_transforms,
+            viewport: initial_rect.viewport(),
+            scissor: initial_rect.scissor(),
+            viewport_rect: None,
+            identity_model_set: Mutex::new(None),
+            model_pool,
+            identity_instances: Mutex::new(None),
+            last_image: None,
+            capture_buffer: None,
+            buffer_strategy: BufferStrategy::default(),
+            timestamp_pool,
+            pending_timings: vec![None; DEFAULT_FRAMES_IN_FLIGHT],
+            profiling: false,
+            timing_sink: None,
+            last_gpu_frame_nanos: None,
+            frame_number: 0,
+            textures: Mutex::new(Vec::new()),
+            white_texture: Mutex::new(None),
+            frame_settings: Mutex::new(FrameSettings::default()),
+            clears_color: true,
+            render_pass_config: None,
+            attachment_images: Vec::new(),
+            present_modes: Vec::new(),
+            desired_image_count: None,
+            needs_recreate: false,
+            suboptimal: Suboptimal::No,
+            shader_modules: None,
+            shader_cache: ShaderCache::default(),
+            preprocess: None,
+            preprocess_enabled: false,
+            post: None,
+            vertex_layout: VertexLayout::default(),
+            playback: PlaybackControl::new(),
+            parallel_recording: false,
+            frustum_culling: true,
+            debug_callback: None,
+            debug_names: false,
+            camera_controller: None,
+            loads: Vec::new(),
+            #[cfg(feature = "notify")]
+            shader_watch: None,
+            #[cfg(feature = "egui")]
+            overlay: None,
+        }
+    }
+
+    // Sets how many frames may be in flight at once, must be at least 1. Offscreen frames take the images in
+    // turn, so there are never more frames in flight than offscreen images.
+    pub fn frames_in_flight(mut self, frames: usize) -> Self {
+        let mut frames = frames.max(1);
+        if let RenderTarget::Offscreen(images) = &self.target {
+            if frames > images.len() {
+                log::warn!("{} frames in flight requested, limited to the {} offscreen images", frames, images.len());
+                frames = images.len();
+            }
+        }
+        self.wait_idle();
+        self.frame_fences = vec![None; frames];
+        self.pending_timings = vec![None; frames];
+        self.timestamp_pool = create_timestamp_pool(&self.device, &self.queue, frames);
+        self.current_frame = 0;
+        self
+    }
+
+    // Limits the bytes of device-local block buffers, by default most of the largest device-local heap.
+    // Blocks uploaded already count against the new limit from the next upload on. Host-visible blocks
+    // live in shared pools and are never evicted for it.
+    pub fn with_memory_budget(self, bytes: u64) -> Self {
+        self.memory_budget.lock().unwrap().limit = bytes;
+        self
+    }
+
+    // Sets what happens to an upload that does not fit in the memory budget
+    pub fn with_budget_policy(self, policy: BudgetPolicy) -> Self {
+        self.memory_budget.lock().unwrap().policy = policy;
+        self
+    }
+
+    // Sends the timing of every finished frame to sink, e.g. a DatabaseManager::spawn_timing_writer channel
+    pub fn set_timing_sink(&mut self, sink: Sender<FrameTiming>) {
+        self.timing_sink = Some(sink);
+    }
+
+    // Reports the timing of the frame that last used this slot, once its fence has been waited on
+    fn collect_timing(&mut self, slot: usize) {
+        let (frame_number, cpu_micros, profiled) = match self.pending_timings[slot].take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let period = self.device.physical_device().properties().timestamp_period;
+        let first = slot as u32 * TIMESTAMPS_PER_SLOT;
+
+        if profiled {
+            if let Some(ticks) = self.timestamp_pool.as_ref().and_then(|pool| timestamp_delta(pool, first + 2)) {
+                self.metadata.lock().unwrap().record_gpu_timing(GpuTiming {
+                    pass: GpuPass::RenderPass,
+                    frame_number: Some(frame_number),
+                    millis: ticks_to_millis(ticks, period),
+                });
+            }
+        }
+
+        let frame_ticks = self.timestamp_pool.as_ref().and_then(|pool| timestamp_delta(pool, first));
+        if let Some(ticks) = frame_ticks {
+            self.last_gpu_frame_nanos = Some(ticks_to_nanos(ticks, period));
+        }
+
+        let sink = match &self.timing_sink {
+            Some(sink) => sink,
+            None => return,
+        };
+        let gpu_micros = frame_ticks.map_or(0, |ticks| ticks_to_micros(ticks, period));
+
+        // A dropped receiver only means nobody is listening anymore
+        let _ = sink.send(FrameTiming { frame_number, gpu_micros, cpu_micros });
+    }
+
+    // Nanoseconds the GPU spent on the most recent finished frame, from the timestamps bracketing its
+    // commands. None when the queue cannot write timestamps or no frame has finished yet. Frames finish
+    // when their slot comes round again or on wait_idle.
+    pub fn last_gpu_frame_nanos(&self) -> Option<u64> {
+        self.last_gpu_frame_nanos
+    }
+
+    // Times every render pass and device-local upload with GPU timestamps, read back through gpu_timings
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiling = enabled;
+    }
+
+    // Profiled GPU passes, oldest first, None when the device cannot write timestamps
+    pub fn gpu_timings(&self) -> Option<Vec<GpuTiming>> {
+        self.timestamp_pool.as_ref()?;
+        Some(self.metadata.lock().unwrap().gpu_timings())
+    }
+
+    // Sets where vertex and index buffers of blocks uploaded from now on are stored
+    pub fn set_buffer_strategy(&mut self, strategy: BufferStrategy) {
+        self.buffer_strategy = strategy;
+    }
+
+    // Splits the draws of large scenes across threads, each recording a secondary command buffer that the
+    // frame's primary command buffer executes. Scenes too small to be worth a thread are recorded inline.
+    pub fn set_parallel_recording(&mut self, enabled: bool) {
+        self.parallel_recording = enabled;
+    }
+
+    // Skips blocks outside the camera frustum, on by default. Bounds come from the vertices as uploaded, so
+    // blocks drawn from the preprocess output, which the shader may move anywhere, are never culled. Turn it
+    // off to check whether geometry goes missing because of it.
+    pub fn set_frustum_culling(&mut self, enabled: bool) {
+        self.frustum_culling = enabled;
+    }
+
+    // Sets the descriptor set index materials are bound to, for pipelines passed to new with a different layout
+    pub fn set_material_set(&mut self, set: usize) {
+        self.material_set = set;
+    }
+
+    // Largest push constant block the device accepts, in bytes
+    pub fn max_push_constant_bytes(&self) -> u32 {
+        self.device.physical_device().limits().max_push_constants_size()
+    }
+
+    // Pipeline drawing blocks with the given key, created and cached on first use
+    fn pipeline_for(&self, key: PipelineKey) -> Result<Arc<GraphicsPipeline>, RendererError> {
+        if key == PipelineKey::default() && self.polygon_mode == PolygonMode::Fill {
+            return Ok(self.pipeline.clone());
+        }
+        let mut pipelines = self.pipelines.lock().unwrap();
+        if let Some(pipeline) = pipelines.get(&(key, self.polygon_mode)) {
+            return Ok(pipeline.clone());
+        }
+        let pipeline = create_pipeline(&self.device, &self.render_pass, self.draw_subpass(), self.depth_format.is_some(), key, self.polygon_mode, self.shader_modules.as_ref(), self.target.manual_gamma())
+            .map_err(|e| RendererError::Reconfigure(Box::new(e)))?;
+        pipelines.insert((key, self.polygon_mode), pipeline.clone());
+        Ok(pipeline)
+    }
+
+    // Draws the next frames filled, as wireframes or as vertex points, e.g. to see the triangles of
+    // partitioned geometry. Line and Point need the fillModeNonSolid device feature.
+    pub fn set_polygon_mode(&mut self, mode: PolygonMode) -> Result<(), RendererError> {
+        if mode != PolygonMode::Fill && !self.device.enabled_features().fill_mode_non_solid {
+            return Err(RendererError::UnsupportedFeature("fillModeNonSolid"));
+        }
+        self.polygon_mode = mode; // Variants for the mode are created on first use and kept for switching back
+        Ok(())
+    }
+
+    pub fn polygon_mode(&self) -> PolygonMode {
+        self.polygon_mode
+    }
+
+    // Replaces the vertex shader and the unlit fragment shader with SPIR-V files, e.g. compiled with glslc.
+    // Nothing changes when a file cannot be loaded or the pipeline is rejected.
+    pub fn reload_shaders<P: AsRef<Path>>(&mut self, vert_path: P, frag_path: P) -> Result<(), ShaderReloadError> {
+        let read = |path: &Path| -> Result<Vec<u8>, ShaderReloadError> {
+            let spirv = std::fs::read(path).map_err(|e| ShaderReloadError::Io(path.to_path_buf(), e))?;
+            if !is_spirv(&spirv) {
+                return Err(ShaderReloadError::InvalidSpirv(path.to_path_buf()));
+            }
+            Ok(spirv)
+        };
+        let (vertex, fragment) = (read(vert_path.as_ref())?, read(frag_path.as_ref())?);
+        self.reload_shaders_from_spirv(&vertex, &fragment)
+    }
+
+    // Same as reload_shaders with modules already in memory, e.g. read with DatabaseManager::load_shader_spirv
+    pub fn reload_shaders_from_spirv(&mut self, vert_spv: &[u8], frag_spv: &[u8]) -> Result<(), ShaderReloadError> {
+        let load = |spirv: &[u8], builtin: BuiltinShader| -> Result<Arc<ShaderModule>, ShaderReloadError> {
+            if !is_spirv(spirv) {
+                return Err(ShaderReloadError::NotSpirv(builtin.name()));
+            }
+            check_custom_shader(&self.device, spirv, builtin)?;
+            unsafe { ShaderModule::new(self.device.clone(), spirv) }.map_err(ShaderReloadError::ShaderLoad)
+        };
+        let modules = ShaderModules { vertex: load(vert_spv, BuiltinShader::Vertex)?, fragment: load(frag_spv, BuiltinShader::Fragment)? };
+        Ok(self.install_shaders(Some(modules))?)
+    }
+
+    // Rebuilds the pipelines with shaders from source, compiling GLSL if needed. Nothing changes on error.
+    pub fn set_shader_source(&mut self, source: &ShaderSource) -> Result<(), RendererError> {
+        let modules = load_shader_source(&self.device, source, &mut self.shader_cache)?;
+        self.install_shaders(modules).map_err(|e| RendererError::Reconfigure(Box::new(e)))
+    }
+
+    // Swaps in pipelines built with modules, None goes back to the built-in shaders
+    fn install_shaders(&mut self, modules: Option<ShaderModules>) -> Result<(), RendererInitError> {
+        let pipeline = create_pipeline(&self.device, &self.render_pass, self.draw_subpass(), self.depth_format.is_some(), PipelineKey::default(), PolygonMode::Fill, modules.as_ref(), self.target.manual_gamma())?;
+
+        // Frames in flight keep the old pipelines alive through their command buffers, so they are
+        // only destroyed once the GPU has finished with them
+        self.pipeline = pipeline;
+        self.pipelines.lock().unwrap().clear();
+        self.shader_modules = modules;
+        Ok(())
+    }
+
+    // Reloads the shaders whenever either SPIR-V file changes on disk. Reloads happen between frames,
+    // errors are passed to on_error and leave the current pipeline in place.
+    #[cfg(feature = "notify")]
+    pub fn watch_shaders<P, F>(&mut self, vert_path: P, frag_path: P, on_error: F) -> Result<(), ShaderReloadError>
+    where
+        P: AsRef<Path>,
+        F: FnMut(ShaderReloadError) + 'static,
+    {
+        use notify::Watcher;
+
+        let (sender, events) = std::sync::mpsc::channel();
+        let mut watcher = notify::watcher(sender, SHADER_WATCH_DEBOUNCE)?;
+        let vertex_path = absolute_path(vert_path.as_ref());
+        let fragment_path = absolute_path(frag_path.as_ref());
+        // Watch the directories, editors often replace a file instead of writing to it
+        for path in &[&vertex_path, &fragment_path] {
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+        }
+        self.shader_watch = Some(ShaderWatch { _watcher: watcher, events, vertex_path, fragment_path, on_error: Box::new(on_error) });
+        Ok(())
+    }
+
+    // Reloads the watched shaders if either file changed since the last frame
+    #[cfg(feature = "notify")]
+    fn poll_shader_watch(&mut self) {
+        let watch = match self.shader_watch.as_mut() {
+            Some(watch) => watch,
+            None => return,
+        };
+        let mut changed = false;
+        while let Ok(event) = watch.events.try_recv() {
+            let path = match event {
+                notify::DebouncedEvent::Create(path)
+                | notify::DebouncedEvent::Write(path)
+                | notify::DebouncedEvent::Rename(_, path) => path,
+                _ => continue,
+            };
+            changed |= path == watch.vertex_path || path == watch.fragment_path;
+        }
+        if !changed {
+            return;
+        }
+        let (vertex_path, fragment_path) = (watch.vertex_path.clone(), watch.fragment_path.clone());
+        if let Err(e) = self.reload_shaders(&vertex_path, &fragment_path) {
+            if let Some(watch) = self.shader_watch.as_mut() {
+                (watch.on_error)(e);
+            }
+        }
+    }
+
+    // Runs spirv over the raw vertex floats of every block uploaded while preprocessing is enabled, writing
+    // the vertices that are drawn. The shader must keep the interface of the default cs shader.
+    pub fn set_preprocess_shader(&mut self, spirv: &[u8]) -> Result<(), RendererError> {
+        if !is_spirv(spirv) {
+            return Err(RendererError::ShaderCompile("the preprocess shader is not a SPIR-V module".to_string()));
+        }
+        check_custom_shader(&self.device, spirv, BuiltinShader::Preprocess)?;
+        let module = unsafe { ShaderModule::new(self.device.clone(), spirv) }
+            .map_err(|e| RendererError::Reconfigure(Box::new(e)))?;
+        let workgroup_size = self.preprocess.as_ref().map_or(DEFAULT_PREPROCESS_WORKGROUP_SIZE, |p| p.workgroup_size);
+        self.preprocess = Some(create_preprocess(&self.device, Some(module), workgroup_size)?);
+        self.preprocess_enabled = true;
+        self.rerun_preprocess()
+    }
+
+    // Sets the local workgroup size of the preprocess pass, rebuilding its pipeline if it exists
+    pub fn set_preprocess_workgroup_size(&mut self, size: u32) -> Result<(), RendererError> {
+        let module = self.preprocess.as_ref().and_then(|preprocess| preprocess.module.clone());
+        self.preprocess = Some(create_preprocess(&self.device, module, size.max(1))?);
+        self.rerun_preprocess()
+    }
+
+    // Enables or bypasses the preprocess pass. Only blocks uploaded while it is enabled go through it,
+    // the default shader is used until set_preprocess_shader is called.
+    pub fn set_preprocess_enabled(&mut self, enabled: bool) -> Result<(), RendererError> {
+        if enabled && self.preprocess.is_none() {
+            self.preprocess = Some(create_preprocess(&self.device, None, DEFAULT_PREPROCESS_WORKGROUP_SIZE)?);
+        }
+        self.preprocess_enabled = enabled;
+        self.rerun_preprocess()
+    }
+
+    // Runs the SPIR-V module spirv once over input in groups workgroups and waits for it, returning the
+    // buffer it wrote. The shader must keep the interface of the default cs shader: input at binding 0, an
+    // output of the same length at binding 1 and the float count as push constant. The output is usable as
+    // a vertex buffer; use set_preprocess_shader instead to run a shader over every block before it is drawn.
+    pub fn dispatch_compute(
+        &self,
+        spirv: &[u8],
+        input: Arc<CpuAccessibleBuffer<[f32]>>,
+        groups: [u32; 3],
+    ) -> Result<Arc<CpuAccessibleBuffer<[f32]>>, RendererError> {
+        if !is_spirv(spirv) {
+            return Err(RendererError::ShaderCompile("the compute shader is not a SPIR-V module".to_string()));
+        }
+        check_custom_shader(&self.device, spirv, BuiltinShader::Preprocess)?;
+        let shader = unsafe { ShaderModule::new(self.device.clone(), spirv) }
+            .map_err(|e| RendererError::Reconfigure(Box::new(e)))?;
+        let compute = create_preprocess(&self.device, Some(shader), DEFAULT_PREPROCESS_WORKGROUP_SIZE)?;
+        let len = input.len();
+        let output = CpuAccessibleBuffer::from_iter(
+            self.device.clone(),
+            BufferUsage { storage_buffer: true, vertex_buffer: true, transfer_source: true, ..BufferUsage::none() },
+            false,
+            (0..len).map(|_| 0.0f32),
+        )?;
+        let layout = compute.pipeline.layout().descriptor_set_layout(0)
+            .ok_or_else(|| RendererError::DescriptorSet("compute pipeline layout has no descriptor set 0".into()))?;
+        let set = Arc::new(
+            PersistentDescriptorSet::start(layout.clone())
+                .add_buffer(input)?
+                .add_buffer(output.clone())?
+                .build()?
+        );
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        builder
+            .bind_pipeline_compute(compute.pipeline.clone())
+            .bind_descriptor_sets(PipelineBindPoint::Compute, compute.pipeline.layout().clone(), 0, set)
+            .push_constants(compute.pipeline.layout().clone(), 0, cs::ty::Counts { vertex_count: len as u32 })
+            .dispatch(groups)?;
+        let command_buffer = builder.build()?;
+
+        // Waiting on the fence makes the writes visible to the host and to every later submission
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer)?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+        Ok(output)
+    }
+
+    // Renders the scene into an intermediate image and runs spirv over it in a full-screen pass that
+    // writes the target image. The shader must keep the interface of the default post_fs shader. The
+    // intermediate image has the target's format, so the effect sees the values that would otherwise
+    // be presented.
+    pub fn set_post_shader(&mut self, spirv: &[u8]) -> Result<(), RendererError> {
+        if !is_spirv(spirv) {
+            return Err(RendererError::ShaderCompile("the post shader is not a SPIR-V module".to_string()));
+        }
+        check_custom_shader(&self.device, spirv, BuiltinShader::Post)?;
+        let module = unsafe { ShaderModule::new(self.device.clone(), spirv) }
+            .map_err(|e| RendererError::Reconfigure(Box::new(e)))?;
+        self.install_post(Some(module))
+    }
+
+    // Enables the post-processing pass with the passthrough shader until set_post_shader is called, or
+    // removes the pass along with its intermediate images
+    pub fn set_post_enabled(&mut self, enabled: bool) -> Result<(), RendererError> {
+        match (enabled, &self.post) {
+            (true, Some(_)) => Ok(()),
+            (true, None) => self.install_post(None),
+            (false, _) => {
+                self.post = None; // Frames in flight keep the images they sample alive
+                Ok(())
+            }
+        }
+    }
+
+    // Whether frames go through the post-processing pass
+    pub fn post_enabled(&self) -> bool {
+        self.post.is_some()
+    }
+
+    fn install_post(&mut self, module: Option<Arc<ShaderModule>>) -> Result<(), RendererError> {
+        let reconfigure = |e: RendererInitError| RendererError::Reconfigure(Box::new(e));
+        let render_pass = create_post_render_pass(self.device.clone(), self.target.format())
+            .map_err(|e| reconfigure(e.into()))?;
+        let pipeline = create_post_pipeline(&self.device, &render_pass, module.as_ref()).map_err(reconfigure)?;
+        let sampler = Sampler::new(
+            self.device.clone(),
+            Filter::Linear,
+            Filter::Linear,
+            MipmapMode::Nearest,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            SamplerAddressMode::ClampToEdge,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        )?;
+        let mut post = PostProcess { module, render_pass, pipeline, sampler, targets: Vec::new() };
+        post.targets = self.post_targets(&post)?;
+        self.post = Some(post);
+        Ok(())
+    }
+
+    // Intermediate images, with framebuffers and descriptor sets, for every image of the current target
+    fn post_targets(&self, post: &PostProcess) -> Result<Vec<PostTarget>, RendererError> {
+        let reconfigure = |e: RendererInitError| RendererError::Reconfigure(Box::new(e));
+        let format = self.target.format();
+        let dimensions = self.target.dimensions();
+        let framebuffers = match &self.target {
+            RenderTarget::Swapchain(_, images) if images.is_empty() => {
+                return Err(RendererError::Reconfigure("the framebuffers were built by the caller and cannot be rebuilt".into()));
+            }
+            RenderTarget::Swapchain(_, images) => build_framebuffers(&self.device, &post.render_pass, images.clone(), dimensions, format, None, 1),
+            RenderTarget::Offscreen(images) => build_framebuffers(&self.device, &post.render_pass, images.clone(), dimensions, format, None, 1),
+        }
+        .map_err(reconfigure)?;
+        let layout = post.pipeline.layout().descriptor_set_layout(0)
+            .ok_or_else(|| RendererError::DescriptorSet("post pipeline layout has no descriptor set 0".into()))?;
+        let usage = ImageUsage { color_attachment: true, sampled: true, ..ImageUsage::none() };
+        framebuffers.into_iter().enumerate().map(|(i, framebuffer)| -> Result<PostTarget, RendererError> {
+            let image = AttachmentImage::with_usage(self.device.clone(), dimensions, format, usage)?;
+            self.set_debug_name(image.inner().image, &format!("post input {}", i));
+            let (mut scene_framebuffers, mut attachments) = self.scene_framebuffers(&self.render_pass, vec![image.clone()])
+                .map_err(reconfigure)?;
+            let (scene_framebuffer, attachments) = (scene_framebuffers.remove(0), attachments.remove(0));
+            let set = Arc::new(
+                PersistentDescriptorSet::start(layout.clone())
+                    .add_sampled_image(image, post.sampler.clone())?
+                    .build()?
+            );
+            Ok(PostTarget { scene_framebuffer, framebuffer, set, attachments })
+        }).collect()
+    }
+
+    // Follows the target images and the scene render pass, does nothing while the pass is disabled
+    fn rebuild_post_targets(&mut self) -> Result<(), RendererError> {
+        let mut post = match self.post.take() {
+            Some(post) => post,
+            None => return Ok(()),
+        };
+        // Without targets frames fail as out of date, so a failed rebuild is retried by the next recreation
+        let targets = self.post_targets(&post);
+        post.targets = Vec::new();
+        let result = targets.map(|targets| post.targets = targets);
+        self.post = Some(post);
+        result
+    }
+
+    // Uploads the vertices of a block as raw floats for the preprocess pass, None while it is bypassed
+    fn preprocess_input(&self, block_vertices: &[Vertex]) -> Result<Option<PreprocessInput>, RendererError> {
+        let preprocess = match &self.preprocess {
+            Some(preprocess) if self.preprocess_enabled => preprocess,
+            _ => return Ok(None),
+        };
+        if block_vertices.is_empty() {
+            return Ok(None);
+        }
+        let raw = CpuAccessibleBuffer::from_iter(
+            self.device.clone(),
+            BufferUsage::storage_buffer(),
+            false,
+            Vertex::to_raw_f32(block_vertices, VertexLayout::PositionNormalUv).into_iter(),
+        )?;
+        let vertices = DeviceLocalBuffer::<[Vertex]>::array(
+            self.device.clone(),
+            block_vertices.len(),
+            BufferUsage { storage_buffer: true, vertex_buffer: true, transfer_source: true, ..BufferUsage::none() },
+            std::iter::once(self.queue.family()),
+        )?;
+        let layout = preprocess.pipeline.layout().descriptor_set_layout(0)
+            .ok_or_else(|| RendererError::DescriptorSet("preprocess pipeline layout has no descriptor set 0".into()))?;
+        let set = Arc::new(
+            PersistentDescriptorSet::start(layout.clone())
+                .add_buffer(raw.clone())?
+                .add_buffer(vertices.clone())?
+                .build()?
+        );
+        let input = PreprocessInput { raw, vertices, set };
+        // Nothing draws the new vertices yet, so the pass can run without waiting for frames
+        self.dispatch_preprocess(&[&input])?;
+        Ok(Some(input))
+    }
+
+    // Runs the preprocess pass over inputs on the graphics queue and waits for it. Frames in flight may
+    // still draw the vertices it writes, so callers wait for them unless the inputs are new.
+    fn dispatch_preprocess(&self, inputs: &[&PreprocessInput]) -> Result<(), RendererError> {
+        let preprocess = match &self.preprocess {
+            Some(preprocess) if self.preprocess_enabled && !inputs.is_empty() => preprocess,
+            _ => return Ok(()),
+        };
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        for input in inputs {
+            let counts = cs::ty::Counts { vertex_count: input.vertices.len() as u32 };
+            builder
+                .bind_pipeline_compute(preprocess.pipeline.clone())
+                .bind_descriptor_sets(PipelineBindPoint::Compute, preprocess.pipeline.layout().clone(), 0, input.set.clone())
+                .push_constants(preprocess.pipeline.layout().clone(), 0, counts)
+                .dispatch([workgroup_count(counts.vertex_count, preprocess.workgroup_size), 1, 1])?;
+        }
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), builder.build()?)?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+        Ok(())
+    }
+
+    // Reruns the preprocess pass over every block uploaded while it was enabled, once the frames in flight
+    // are done drawing its previous output
+    fn rerun_preprocess(&mut self) -> Result<(), RendererError> {
+        if !self.preprocess_enabled || self.preprocess.is_none() {
+            return Ok(());
+        }
+        self.wait_idle();
+        let blocks = self.blocks.lock().unwrap();
+        let inputs: Vec<&PreprocessInput> = blocks.iter().flatten().filter_map(|block| block.preprocess.as_ref()).collect();
+        self.dispatch_preprocess(&inputs)
+    }
+
+    // Looks up a descriptor set layout of the graphics pipeline
+    fn set_layout(&self, set: usize) -> Result<&Arc<UnsafeDescriptorSetLayout>, RendererError> {
+        self.pipeline.layout().descriptor_set_layout(set)
+            .ok_or_else(|| RendererError::DescriptorSet(format!("pipeline layout has no descriptor set {}", set).into()))
+    }
+
+    // Descriptor set of an identity model uniform, created on first use and bound while transforms are pushed
+    fn identity_model_set(&self) -> Result<Arc<dyn DescriptorSet + Send + Sync>, RendererError> {
+        let mut identity = self.identity_model_set.lock().unwrap();
+        if let Some(set) = &*identity {
+            return Ok(set.clone());
+        }
+        let buffer = CpuAccessibleBuffer::from_data(self.device.clone(), BufferUsage::uniform_buffer(), false, IDENTITY_TRANSFORM)?;
+        let set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(
+            PersistentDescriptorSet::start(self.set_layout(MODEL_SET)?.clone())
+                .add_buffer(buffer)?
+                .build()?
+        );
+        *identity = Some(set.clone());
+        Ok(set)
+    }
+
+    // Uploads tightly packed RGBA8 pixels, top row first, and returns the id blocks use to reference them
+    pub fn load_texture(&self, width: u32, height: u32, rgba: &[u8]) -> Result<TextureId, RendererError> {
+        let texture = self.create_texture(width, height, rgba)?;
+        let mut textures = self.textures.lock().unwrap();
+        textures.push(texture);
+        Ok((textures.len() - 1) as TextureId)
+    }
+
+    // Decodes an image file with the image crate and uploads it as a texture
+    #[cfg(feature = "image")]
+    pub fn load_texture_file<P: AsRef<std::path::Path>>(&self, path: P) -> Result<TextureId, RendererError> {
+        let rgba = image::open(path).map_err(|e| RendererError::Texture(Box::new(e)))?.to_rgba8();
+        self.load_texture(rgba.width(), rgba.height(), rgba.as_raw())
+    }
+
+    // Creates a sampled image from RGBA8 pixels and waits for the upload to finish
+    fn create_texture(&self, width: u32, height: u32, rgba: &[u8]) -> Result<Texture, RendererError> {
+        let expected = width as usize * height as usize * 4;
+        if width == 0 || height == 0 || rgba.len() != expected {
+            return Err(RendererError::Texture(
+                format!("expected {} bytes for a {}x{} RGBA8 texture, got {}", expected, width, height, rgba.len()).into()
+            ));
+        }
+        let (image, upload) = ImmutableImage::from_iter(
+            rgba.iter().cloned(),
+            ImageDimensions::Dim2d { width, height, array_layers: 1 },
+            MipmapsCount::One,
+            Format::R8G8B8A8Srgb,
+            self.queue.clone(),
+        )?;
+        upload.then_signal_fence_and_flush()?.wait(None)?;
+        let sampler = Sampler::new(
+            self.device.clone(),
+            Filter::Linear,
+            Filter::Linear,
+            MipmapMode::Nearest,
+            SamplerAddressMode::Repeat,
+            SamplerAddressMode::Repeat,
+            SamplerAddressMode::Repeat,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+        )?;
+        Ok(Texture { image, sampler })
+    }
+
+    // Texture a block samples, the white fallback when it has none or references an unknown id
+    fn texture_for(&self, texture_id: Option<TextureId>) -> Result<Texture, RendererError> {
+        if let Some(texture) = texture_id.and_then(|id| self.textures.lock().unwrap().get(id as usize).cloned()) {
+            return Ok(texture);
+        }
+        let mut white = self.white_texture.lock().unwrap();
+        if white.is_none() {
+            *white = Some(self.create_texture(1, 1, &[255, 255, 255, 255])?);
+        }
+        Ok(white.clone().unwrap())
+    }
+
+    // Sets the color the next frames are cleared to
+    pub fn set_clear_color(&self, rgba: [f32; 4]) {
+        self.frame_settings.lock().unwrap().clear_color = rgba;
+    }
+
+    // Sets the value the depth attachment is cleared to on the next frames
+    pub fn set_clear_depth(&self, depth: f32) {
+        self.frame_settings.lock().unwrap().clear_depth = depth;
+    }
+
+    // Draws the next frames over the previous contents instead of clearing them.
+    // Switching rebuilds the render pass, so it is rejected when the caller built the framebuffers.
+    //
+    // The contents kept are those of the image a frame is drawn into, nothing is copied between images.
+    // They are the previous frame only offscreen with one frame in flight, where every frame draws into
+    // the same image, e.g. create_headless(extent, options)?.frames_in_flight(1). With a swapchain, or
+    // several frames in flight, a frame draws over the one rendered into its image images ago.
+    pub fn set_load_previous(&self, load_previous: bool) -> Result<(), RendererError> {
+        if load_previous == self.clears_color && self.target.caller_framebuffers() {
+            return Err(RendererError::Reconfigure("the framebuffers were built by the caller and cannot be rebuilt".into()));
+        }
+        self.frame_settings.lock().unwrap().load_previous = load_previous;
+        Ok(())
+    }
+
+    pub fn frame_settings(&self) -> FrameSettings {
+        *self.frame_settings.lock().unwrap()
+    }
+
+    // Draws the next frames into a sub-rectangle of the surface, e.g. one half of a split screen.
+    // The rect is clamped to the surface, and the camera's aspect ratio follows it.
+    pub fn set_viewport(&mut self, rect: ViewportRect) {
+        self.viewport_rect = Some(rect);
+        self.update_viewport();
+    }
+
+    // Draws the next frames into the whole surface again
+    pub fn reset_viewport(&mut self) {
+        self.viewport_rect = None;
+        self.update_viewport();
+    }
+
+    // Refreshes the dynamic viewport state for the current target and viewport_rect
+    fn update_viewport(&mut self) {
+        let dimensions = self.target.dimensions();
+        let rect = self.viewport_rect.map_or(ViewportRect::full(dimensions), |rect| rect.clamped(dimensions));
+        self.viewport = rect.viewport();
+        self.scissor = rect.scissor();
+        self.aspect_ratio = aspect_ratio([rect.width, rect.height]);
+    }
+
+    // Present mode of the swapchain, None when rendering offscreen
+    pub fn current_present_mode(&self) -> Option<PresentMode> {
+        self.swapchain().map(|swapchain| swapchain.present_mode())
+    }
+
+    // Format negotiated with the surface, None when rendering offscreen. Recreating the swapchain
+    // keeps it.
+    pub fn surface_format(&self) -> Option<Format> {
+        self.swapchain().map(|swapchain| swapchain.format())
+    }
+
+    // Whether the surface only offered UNORM formats, in which case the built-in fragment shaders
+    // encode their output to sRGB themselves. Custom fragment shaders can read the same flag from
+    // the bool specialization constant with constant_id 0.
+    pub fn manual_gamma(&self) -> bool {
+        self.target.manual_gamma()
+    }
+
+    // Sets the present modes to try, in order, when the swapchain is next recreated
+    pub fn set_preferred_present_modes(&mut self, modes: Vec<PresentMode>) {
+        self.present_modes = modes;
+    }
+
+    // Sets the number of swapchain images, see RendererOptions::desired_image_count. The swapchain is
+    // recreated with it before the next frame; offscreen renderers keep their images.
+    pub fn set_desired_image_count(&mut self, count: Option<u32>) {
+        self.desired_image_count = count;
+        self.needs_recreate |= self.swapchain().is_some();
+    }
+
+    // Sets the camera used from the next frame on
+    pub fn set_camera(&self, camera: Camera) {
+        self.camera.lock().unwrap().source = CameraSource::LookAt(camera);
+    }
+
+    // Camera used from the next frame on. Replaces matrices given to set_camera_matrices, or clip space,
+    // with the default camera first, logging a warning when matrices are dropped that way.
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        let state = self.camera.get_mut().unwrap();
+        if let CameraSource::Matrices(_) = state.source {
+            log::warn!("camera_mut replaces the matrices given to set_camera_matrices with the default camera");
+        }
+        if !matches!(state.source, CameraSource::LookAt(_)) {
+            state.source = CameraSource::LookAt(Camera::default());
+        }
+        match &mut state.source {
+            CameraSource::LookAt(camera) => camera,
+            _ => unreachable!(),
+        }
+    }
+
+    // Lets run feed window events to controller and apply it to the camera before every frame
+    pub fn set_camera_controller(&mut self, controller: Option<Box<dyn CameraController + Send>>) {
+        if let Some(controller) = &controller {
+            controller.apply(self.camera_mut());
+        }
+        self.camera_controller = controller;
+    }
+
+    // Advances the camera controller by dt seconds and applies it
+    fn update_camera_controller(&mut self, dt: f32) {
+        if let Some(mut controller) = self.camera_controller.take() {
+            controller.update(dt);
+            controller.apply(self.camera_mut());
+            self.camera_controller = Some(controller);
+        }
+    }
+
+    // Sets view and projection matrices used as given from the next frame on
+    pub fn set_camera_matrices(&self, matrices: CameraMatrices) {
+        self.camera.lock().unwrap().source = CameraSource::Matrices(matrices);
+    }
+
+    // View and projection matrices for the current camera and target size
+    fn camera_matrices(&self, source: &CameraSource) -> CameraMatrices {
+        match source {
+            CameraSource::Clip => CameraMatrices::default(),
+            CameraSource::LookAt(camera) => camera.matrices(self.aspect_ratio),
+            CameraSource::Matrices(matrices) => *matrices,
+        }
+    }
+
+    // Descriptor set binding the camera uniform, and the matrices it holds. A new buffer is only taken from
+    // the pool when the matrices change, frames in flight keep reading the one they were recorded with.
+    fn camera_set(&self) -> Result<(CameraMatrices, Arc<dyn DescriptorSet + Send + Sync>), RendererError> {
+        let mut camera = self.camera.lock().unwrap();
+        let matrices = self.camera_matrices(&camera.source);
+        if let Some((uploaded, set)) = &camera.uploaded {
+            if *uploaded == matrices {
+                return Ok((matrices, set.clone()));
+            }
+        }
+        let buffer = self.camera_pool.next(vs::ty::Camera { view: matrices.view, proj: matrices.proj })?;
+        let set: Arc<dyn DescriptorSet + Send + Sync> = Arc::new(
+            PersistentDescriptorSet::start(self.set_layout(CAMERA_SET)?.clone())
+                .add_buffer(buffer)?
+                .build()?
+        );
+        camera.uploaded = Some((matrices, set.clone()));
+        Ok((matrices, set))
+    }
+
+    // Blocks until every frame in flight has finished on the GPU
+    pub fn wait_idle(&mut self) {
+        self.previous_frame_end = None;
+        for slot in 0..self.frame_fences.len() {
+            if let Some(fence) = self.frame_fences[slot].take() {
+                let _ = fence.wait(None);
+            }
+            self.collect_timing(slot);
+        }
+    }
+
+    // Builds everything needed to draw into window, from the Vulkan instance up to the framebuffers.
+    // The window is owned by the surface afterwards and can be reached through window().
+    //
+    //     let event_loop = EventLoop::new();
+    //     let window = WindowBuilder::new().build(&event_loop)?;
+    //     let mut renderer = VulkanoRenderer::create(window, RendererOptions::default())?;
+    //     // Frames keep presenting while the database is read
+    //     let _load = renderer.load_vertex_data_async("metrics.db", |result| match result {
+    //         Ok(report) => println!("loaded {}", report),
+    //         Err(e) => println!("load failed: {}", e),
+    //     });
+    //     renderer.run(event_loop)?;
+    pub fn create(window: Window, options: RendererOptions) -> Result<Self, RendererInitError> {
+        let (instance, debug_callback) = create_instance(&options.instance, vulkano_win::required_extensions())?;
+        let surface = vulkano_win::create_vk_surface(window, instance.clone())?;
+        let mut renderer = Self::from_surface_with_options(surface, instance, options)?;
+        renderer.enable_debug_names(debug_callback);
+        Ok(renderer)
+    }
+
+    // Window the renderer presents to, None when rendering offscreen
+    pub fn window(&self) -> Option<&Window> {
+        match &self.target {
+            RenderTarget::Swapchain(swapchain, _) => Some(swapchain.surface().window()),
+            RenderTarget::Offscreen(_) => None,
+        }
+    }
+
+    // Builds the device, swapchain, render pass and pipeline for the given surface
+    pub fn from_surface(surface: Arc<Surface<Window>>, instance: Arc<Instance>) -> Result<Self, RendererInitError> {
+        Self::from_surface_with_options(surface, instance, RendererOptions::default())
+    }
+
+    // Same as from_surface, with control over optional attachments
+    pub fn from_surface_with_options(
+        surface: Arc<Surface<Window>>,
+        instance: Arc<Instance>,
+        options: RendererOptions,
+    ) -> Result<Self, RendererInitError> {
+        let device_extensions = DeviceExtensions {
+            khr_swapchain: true,
+            ..DeviceExtensions::none()
+        };
+
+        // Pick a device with a queue family that can both draw and present
+        let suitable = PhysicalDevice::enumerate(&instance)
+            .filter(|p| p.supported_extensions().is_superset_of(&device_extensions))
+            .filter_map(|p| {
+                p.queue_families()
+                    .find(|q| q.supports_graphics() && surface.is_supported(*q).unwrap_or(false))
+                    .map(|q| (p, q))
+            })
+            .collect();
+        let (physical, queue_family) = choose_device(suitable, &options.device)?;
+
+        let (device, mut queues) = Device::new(
+            physical,
+            &optional_features(physical),
+            &physical.required_extensions().union(&device_extensions),
+            queue_requests(physical, queue_family),
+        )?;
+        let queue = queues.next().ok_or(RendererInitError::NoSuitableDevice)?;
+        let transfer_queue = queues.next();
+
+        let caps = surface.capabilities(physical)?;
+        let dimensions: [u32; 2] = surface.window().inner_size().into();
+        let (format, color_space) = choose_surface_format(&caps.supported_formats)
+            .ok_or(RendererInitError::NoSuitableDevice)?;
+        let composite_alpha = caps.supported_composite_alpha.iter().next()
+            .ok_or(RendererInitError::NoSuitableDevice)?;
+        let present_mode = choose_present_mode(&options.preferred_present_modes, |mode| caps.present_modes.supports(mode));
+
+        let (swapchain, images) = Swapchain::start(device.clone(), surface.clone())
+            .num_images(clamp_image_count(options.desired_image_count, caps.min_image_count, caps.max_image_count))
+            .format(format)
+            .color_space(color_space)
+            .dimensions(dimensions)
+            .usage(swapchain_usage(caps.supported_usage_flags))
+            .sharing_mode(&queue)
+            .composite_alpha(composite_alpha)
+            .present_mode(present_mode)
+            .build()?;
+
+        let depth_format = resolve_depth_format(physical, &options)?;
+        let samples = resolve_sample_count(physical, &options)?;
+
+        let render_pass = create_render_pass(device.clone(), swapchain.format(), depth_format, samples, true)?;
+        let mut shader_cache = ShaderCache::default();
+        let modules = load_shader_source(&device, &options.shaders, &mut shader_cache).map_err(RendererInitError::InvalidOptions)?;
+        let pipeline = create_pipeline(&device, &render_pass, 0, depth_format.is_some(), PipelineKey::default(), PolygonMode::Fill, modules.as_ref(), needs_manual_gamma(format))?;
+        let framebuffers = build_framebuffers(&device, &render_pass, images.clone(), dimensions, swapchain.format(), depth_format, samples)?;
+
+        let target = RenderTarget::Swapchain(swapchain, images);
+        let mut renderer = Self::with_target(device, queue, pipeline, target, framebuffers, render_pass, Metadata::default());
+        renderer.depth_format = depth_format;
+        renderer.samples = samples;
+        renderer.buffer_strategy = options.buffer_strategy;
+        renderer.shader_modules = modules;
+        renderer.shader_cache = shader_cache;
+        renderer.present_modes = options.preferred_present_modes;
+        renderer.desired_image_count = options.desired_image_count;
+        renderer.upload_context = UploadContext::new(renderer.queue.clone(), transfer_queue);
+        Ok(renderer)
+    }
+
+    // Builds a renderer that draws into offscreen images instead of a swapchain, for CI and video export
+    pub fn new_headless(device: Arc<Device>, queue: Arc<Queue>, extent: [u32; 2], format: Format) -> Result<Self, RendererInitError> {
+        Self::new_headless_with_options(device, queue, extent, format, RendererOptions::default())
+    }
+
+    // Creates its own instance and device and renders RGBA8 frames of the given size without any window,
+    // so read_pixels works on machines without a display server
+    pub fn create_headless(extent: [u32; 2], options: RendererOptions) -> Result<Self, RendererInitError> {
+        let (instance, debug_callback) = create_instance(&options.instance, InstanceExtensions::none())?;
+
+        let suitable = PhysicalDevice::enumerate(&instance)
+            .filter_map(|p| p.queue_families().find(|q| q.supports_graphics()).map(|q| (p, q)))
+            .collect();
+        let (physical, queue_family) = choose_device(suitable, &options.device)?;
+
+        let (device, mut queues) = Device::new(
+            physical,
+            &optional_features(physical),
+            &physical.required_extensions(),
+            queue_requests(physical, queue_family),
+        )?;
+        let queue = queues.next().ok_or(RendererInitError::NoSuitableDevice)?;
+        let transfer_queue = queues.next();
+
+        let mut renderer = Self::new_headless_with_options(device, queue.clone(), extent, Format::R8G8B8A8Unorm, options)?;
+        renderer.upload_context = UploadContext::new(queue, transfer_queue);
+        renderer.enable_debug_names(debug_callback);
+        Ok(renderer)
+    }
+
+    // Same as new_headless, with control over optional attachments
+    pub fn new_headless_with_options(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        extent: [u32; 2],
+        format: Format,
+        options: RendererOptions,
+    ) -> Result<Self, RendererInitError> {
+        let physical = device.physical_device();
+        let depth_format = resolve_depth_format(physical, &options)?;
+        let samples = resolve_sample_count(physical, &options)?;
+
+        // At least one image per frame in flight, as nothing but the frame fences keeps a frame from drawing
+        // into an image still in use. Readable so frames can be copied back to the CPU.
+        let usage = ImageUsage {
+            color_attachment: true,
+            transfer_source: true,
+            ..ImageUsage::none()
+        };
+        let image_count = options.desired_image_count.map_or(DEFAULT_FRAMES_IN_FLIGHT, |count| count as usize).max(DEFAULT_FRAMES_IN_FLIGHT);
+        let images = (0..image_count)
+            .map(|_| AttachmentImage::with_usage(device.clone(), extent, format, usage))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let render_pass = create_render_pass(device.clone(), format, depth_format, samples, true)?;
+        let mut shader_cache = ShaderCache::default();
+        let modules = load_shader_source(&device, &options.shaders, &mut shader_cache).map_err(RendererInitError::InvalidOptions)?;
+        let pipeline = create_pipeline(&device, &render_pass, 0, depth_format.is_some(), PipelineKey::default(), PolygonMode::Fill, modules.as_ref(), false)?;
+        let framebuffers = build_framebuffers(&device, &render_pass, images.clone(), extent, format, depth_format, samples)?;
+
+        let mut renderer = Self::with_target(device, queue, pipeline, RenderTarget::Offscreen(images), framebuffers, render_pass, Metadata::default());
+        renderer.depth_format = depth_format;
+        renderer.samples = samples;
+        renderer.buffer_strategy = options.buffer_strategy;
+        renderer.shader_modules = modules;
+        renderer.shader_cache = shader_cache;
+        // Every image is in use by one frame in flight
+        Ok(renderer.frames_in_flight(image_count))
+    }
+
+    // Copies the most recently rendered offscreen image into CPU memory, in the image's own format
+    pub fn read_pixels(&mut self) -> Result<Vec<u8>, RendererError> {
+        let image = match &self.target {
+            RenderTarget::Offscreen(images) => images[self.last_image.ok_or(RendererError::NoFrameRendered)?].clone(),
+            RenderTarget::Swapchain(..) => return Err(RendererError::NotOffscreen),
+        };
+        let buffer = self.readback_buffer()?;
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        builder.copy_image_to_buffer(image, buffer.clone())?;
+        let command_buffer = builder.build()?;
+
+        // The frame must be finished before its image can be copied
+        self.wait_idle();
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer)?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+
+        let pixels = buffer.read()?.to_vec();
+        Ok(pixels)
+    }
+
+    // Renders the current scene and returns it as RGBA8; offscreen renderers return the last rendered frame
+    pub fn capture_frame(&mut self) -> Result<CapturedFrame, CaptureError> {
+        let [width, height] = self.target.dimensions();
+        let format = self.target.format();
+
+        let bytes = match &self.target {
+            RenderTarget::Offscreen(_) => self.read_pixels()?,
+            RenderTarget::Swapchain(_, images) if images.is_empty() => return Err(CaptureError::Unsupported),
+            // The swapchain images would be rendered at the old size, capture once the resize is handled
+            RenderTarget::Swapchain(..) if self.resize_pending() => {
+                return Err(RendererError::Acquire(AcquireError::OutOfDate).into());
+            }
+            RenderTarget::Swapchain(..) => {
+                // Presented images cannot be read back, so the copy is recorded into the frame itself
+                let buffer = self.readback_buffer()?;
+                self.capture_buffer = Some(buffer.clone());
+                let rendered = self.render_frame();
+                self.capture_buffer = None;
+                rendered?;
+                self.wait_idle();
+                let bytes = buffer.read().map_err(RendererError::from)?.to_vec();
+                bytes
+            }
+        };
+
+        Ok(CapturedFrame { width, height, pixels: to_rgba8(format, bytes)? })
+    }
+
+    // Renders the current scene and saves it as a PNG at path
+    #[cfg(feature = "image")]
+    pub fn capture_frame_png<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<(), CaptureError> {
+        let frame = self.capture_frame()?;
+        image::save_buffer(path, &frame.pixels, frame.width, frame.height, image::ColorType::Rgba8)
+            .map_err(|e| CaptureError::Encode(Box::new(e)))
+    }
+
+    // Returns true when the window has been resized but the swapchain not yet recreated
+    fn resize_pending(&self) -> bool {
+        match (self.swapchain(), self.surface_extent()) {
+            (Some(swapchain), Some(extent)) => self.needs_recreate || extent != swapchain.dimensions(),
+            _ => false,
+        }
+    }
+
+    // Host-visible buffer large enough for one render target image
+    fn readback_buffer(&self) -> Result<Arc<CpuAccessibleBuffer<[u8]>>, RendererError> {
+        let [width, height] = self.target.dimensions();
+        let bytes_per_pixel = self.target.format().size().unwrap_or(4);
+        let len = width as usize * height as usize * bytes_per_pixel;
+        Ok(CpuAccessibleBuffer::from_iter(
+            self.device.clone(),
+            BufferUsage::transfer_destination(),
+            false,
+            (0..len).map(|_| 0u8)
+        )?)
+    }
+
+    // Load vertex data from the database at db_path, which must exist and hold at least one frame
+    pub fn load_vertex_data(&self, db_path: &str) -> Result<LoadReport, RendererError> {
+        self.load_vertex_data_with_progress(db_path, |_, _| {})
+    }
+
+    // Like load_vertex_data, calling on_progress(loaded, total) as blocks are recorded. The call with
+    // loaded == total comes once the upload has finished and every block is ready to draw.
+    pub fn load_vertex_data_with_progress<F>(&self, db_path: &str, on_progress: F) -> Result<LoadReport, RendererError>
+    where
+        F: FnMut(usize, usize),
+    {
+        let partitioned_data = partition_database(db_path)?;
+        let report = self.apply_partitions(partitioned_data, on_progress)?;
+        self.metadata.lock().unwrap().record_source(db_path);
+        Ok(report)
+    }
+
+    // Decompresses blocks written by shader_partition_compressor::compress_block and uploads them together
+    pub fn load_compressed_blocks<B: AsRef<[u8]>>(&self, compressed: &[B]) -> Result<LoadReport, RendererError> {
+        let blocks = compressed.iter()
+            .map(|data| shader_partition_compressor::decompress_block(data.as_ref()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(PartitionError::from)?;
+        self.apply_partitions(PartitionedData { blocks }, |_, _| {})
+    }
+
+    // Like load_vertex_data, but streams the blocks with at most max_inflight uploads in flight
+    pub fn stream_vertex_data<F>(&self, db_path: &str, max_inflight: usize, progress: F) -> Result<Vec<BlockId>, RendererError>
+    where
+        F: FnMut(LoadProgress),
+    {
+        let partitioned_data = partition_database(db_path)?;
+        let block_ids = self.stream_blocks(partitioned_data.blocks, max_inflight, progress)?;
+        self.metadata.lock().unwrap().record_source(db_path);
+        Ok(block_ids)
+    }
+
+    // Like load_vertex_data, but reads, partitions and stages the database on a background thread so frames
+    // keep being rendered meanwhile. Staged blocks wait in a bounded channel, between frames a few of them
+    // have their copies submitted and are registered once a later frame finds the copies done, so the
+    // rendering thread never waits for an upload. on_complete is called from the rendering thread with the
+    // report or the first error, and not at all when the returned handle is dropped before then.
+    //
+    //     let load = renderer.load_vertex_data_async("metrics.db", |result| match result {
+    //         Ok(report) => println!("loaded {}", report),
+    //         Err(e) => println!("load failed: {}", e),
+    //     });
+    //     load.detach();
+    pub fn load_vertex_data_async<F>(&mut self, db_path: &str, on_complete: F) -> LoadHandle
+    where
+        F: FnOnce(Result<LoadReport, RendererError>) + Send + 'static,
+    {
+        let (sender, blocks) = std::sync::mpsc::sync_channel(ASYNC_LOAD_CHANNEL_BOUND);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        let path = db_path.to_string();
+        let flag = cancelled.clone();
+        let (pools, strategy) = (self.pools.clone(), self.buffer_strategy);
+        std::thread::spawn(move || partition_in_background(&path, sender, &flag, |block| pools.stage(strategy, block)));
+
+        self.loads.push(BackgroundLoad {
+            db_path: db_path.to_string(),
+            blocks,
+            cancelled: cancelled.clone(),
+            finished: finished.clone(),
+            pending: None,
+            report: LoadReport::default(),
+            on_complete: Box::new(on_complete),
+        });
+        LoadHandle { cancelled: Some(cancelled), finished }
+    }
+
+    // Moves every background load on by one step, see advance_load, and completes the loads that are done
+    fn poll_loads(&mut self) {
+        for mut load in std::mem::take(&mut self.loads) {
+            if load.cancelled.load(Ordering::Acquire) {
+                self.abandon_load(&mut load);
+                continue;
+            }
+            match self.advance_load(&mut load) {
+                Ok(false) => self.loads.push(load),
+                Ok(true) => {
+                    self.metadata.lock().unwrap().record_source(&load.db_path);
+                    load.finished.store(true, Ordering::Release);
+                    (load.on_complete)(Ok(load.report));
+                }
+                Err(e) => {
+                    load.cancelled.store(true, Ordering::Release); // Stops the thread at its next block
+                    self.abandon_load(&mut load);
+                    load.finished.store(true, Ordering::Release);
+                    (load.on_complete)(Err(e));
+                }
+            }
+        }
+    }
+
+    // One step of a background load between frames, never waiting for the GPU. Once the copies submitted
+    // by an earlier step are done their blocks are registered, then the copies of up to
+    // ASYNC_LOAD_BLOCKS_PER_FRAME staged blocks are submitted. Returns true once every block is registered.
+    fn advance_load(&self, load: &mut BackgroundLoad) -> Result<bool, RendererError> {
+        if let Some((pending, _)) = &load.pending {
+            if !pending.fence.is_signaled()? {
+                return Ok(false);
+            }
+            let (pending, created) = load.pending.take().unwrap();
+            // The fence is signalled, this only records the GPU time
+            if let Err(e) = self.finish_upload(pending) {
+                self.cancel_blocks(created);
+                return Err(e);
+            }
+            self.register_loaded(load, created);
+        }
+
+        let mut ready = Vec::new();
+        let mut done = false;
+        while ready.len() < ASYNC_LOAD_BLOCKS_PER_FRAME {
+            match load.blocks.try_recv() {
+                Ok(staged) => ready.push(staged?),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    done = true;
+                    break;
+                }
+            }
+        }
+        if !ready.is_empty() {
+            let mut uploads = self.upload_builder()?;
+            let mut created = Vec::new();
+            for staged in ready {
+                match self.create_staged_block(staged, &mut uploads) {
+                    Ok(block) => created.push(block),
+                    Err(e) => {
+                        self.cancel_blocks(created);
+                        return Err(e);
+                    }
+                }
+            }
+            match self.flush_uploads(uploads) {
+                Ok(Some(pending)) => load.pending = Some((pending, created)),
+                Ok(None) => self.register_loaded(load, created), // Host-visible blocks have nothing to copy
+                Err(e) => {
+                    self.cancel_blocks(created);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(done && load.pending.is_none())
+    }
+
+    // Registers blocks of a background load whose copies are done, counting them in its report
+    fn register_loaded(&self, load: &mut BackgroundLoad, created: Vec<(UploadedBlock, u64)>) {
+        for (uploaded, bytes) in created {
+            load.report.blocks += 1;
+            load.report.vertices += uploaded.vertex_buffer.len() as usize;
+            load.report.bytes += bytes;
+            self.register_block(uploaded, bytes);
+        }
+    }
+
+    // Waits for the copies a load that stops early still has in flight and gives back their reservations
+    fn abandon_load(&self, load: &mut BackgroundLoad) {
+        if let Some((pending, created)) = load.pending.take() {
+            let _ = pending.fence.wait(None);
+            self.cancel_blocks(created);
+        }
+    }
+
+    // Uploads blocks one submission each, keeping up to max_inflight of them in flight and only waiting
+    // for the oldest one when that limit is reached. Staging memory is recycled as uploads finish, so it
+    // stays bounded however many blocks there are. Every block is ready to draw when this returns.
+    pub fn stream_blocks<I, F>(&self, blocks: I, max_inflight: usize, mut progress: F) -> Result<Vec<BlockId>, RendererError>
+    where
+        I: IntoIterator<Item = ShaderBlock>,
+        F: FnMut(LoadProgress),
+    {
+        let blocks = blocks.into_iter();
+        let total = match blocks.size_hint() {
+            (lower, Some(upper)) if lower == upper => Some(lower),
+            _ => None,
+        };
+        let max_inflight = if max_inflight == 0 { DEFAULT_MAX_INFLIGHT_UPLOADS } else { max_inflight };
+
+        // Blocks are registered as their uploads finish, so none is drawn before its copies are done
+        let mut inflight = VecDeque::with_capacity(max_inflight);
+        let mut block_ids = Vec::with_capacity(total.unwrap_or(0));
+        let mut uploaded = 0;
+        for block in blocks {
+            if inflight.len() >= max_inflight {
+                let (pending, created) = inflight.pop_front().unwrap();
+                let block_id = self.register_uploaded(pending, created).map_err(|e| self.abandon_uploads(&mut inflight, e))?;
+                block_ids.push(block_id);
+                uploaded += 1;
+                progress(LoadProgress { uploaded, total });
+            }
+            let mut uploads = self.upload_builder().map_err(|e| self.abandon_uploads(&mut inflight, e))?;
+            let created = self.create_budgeted_block(block, &mut uploads).map_err(|e| self.abandon_uploads(&mut inflight, e))?;
+            match self.flush_uploads(uploads) {
+                Ok(Some(pending)) => inflight.push_back((pending, created)),
+                Ok(None) => {
+                    block_ids.push(self.register_block(created.0, created.1));
+                    uploaded += 1;
+                    progress(LoadProgress { uploaded, total });
+                }
+                Err(e) => {
+                    self.cancel_blocks(vec![created]);
+                    return Err(self.abandon_uploads(&mut inflight, e));
+                }
+            }
+        }
+        while let Some((pending, created)) = inflight.pop_front() {
+            let block_id = self.register_uploaded(pending, created).map_err(|e| self.abandon_uploads(&mut inflight, e))?;
+            block_ids.push(block_id);
+            uploaded += 1;
+            progress(LoadProgress { uploaded, total });
+        }
+        Ok(block_ids)
+    }
+
+    // Waits for the copies of a block created by create_budgeted_block and registers it, giving back its
+    // reservation when they failed
+    fn register_uploaded(&self, pending: PendingUpload, (uploaded, bytes): (UploadedBlock, u64)) -> Result<BlockId, RendererError> {
+        match self.finish_upload(pending) {
+            Ok(()) => Ok(self.register_block(uploaded, bytes)),
+            Err(e) => {
+                self.cancel_blocks(vec![(uploaded, bytes)]);
+                Err(e)
+            }
+        }
+    }
+
+    // Drops the uploads still in flight when stream_blocks fails with e, once the GPU is done copying
+    // into their buffers, and gives back their reservations
+    fn abandon_uploads(&self, inflight: &mut VecDeque<(PendingUpload, (UploadedBlock, u64))>, e: RendererError) -> RendererError {
+        for (pending, created) in inflight.drain(..) {
+            let _ = pending.fence.wait(None);
+            self.cancel_blocks(vec![created]);
+        }
+        e
+    }
+
+    // Applies partitioned shader data to the vertex pipeline
+    // Nothing is drawn or presented while loading, render_frame picks the blocks up afterwards.
+    fn apply_partitions<F>(&self, data: PartitionedData, mut on_progress: F) -> Result<LoadReport, RendererError>
+    where
+        F: FnMut(usize, usize),
+    {
+        // Staging copies for every block go out in a single submission
+        let total = data.blocks.len();
+        let mut report = LoadReport::default();
+        self.upload_blocks(data.blocks.into_iter().enumerate().map(|(i, block)| {
+            report.blocks += 1;
+            report.vertices += block.vertices.len();
+            report.bytes += self.block_bytes(&block);
+            if i > 0 {
+                on_progress(i, total); // The blocks before this one are recorded
+            }
+            block
+        }))?;
+        on_progress(total, total);
+        Ok(report)
+    }
+
+    // Sets how apply_vertex_data splits its floats into vertices
+    pub fn set_vertex_layout(&mut self, layout: VertexLayout) {
+        self.vertex_layout = layout;
+    }
+
+    pub fn vertex_layout(&self) -> VertexLayout {
+        self.vertex_layout
+    }
+
+    // Uploads flat vertex floats in the renderer's vertex layout as a new block. The float count must
+    // be a whole number of vertices.
+    pub fn apply_vertex_data(&self, vertex_data: &[f32], material_data: &[f32]) -> Result<BlockId, RendererError> {
+        let vertices = Vertex::from_raw_f32(vertex_data, self.vertex_layout)?;
+        self.apply_shader_block(ShaderBlock { vertices, material_data: material_data.to_vec(), ..Default::default() })
+    }
+
+    // Applies a single block of shader instructions
+    fn apply_shader_block(&self, block: ShaderBlock) -> Result<BlockId, RendererError> {
+        Ok(self.upload_blocks(std::iter::once(block))?[0])
+    }
+
+    // Starts recording staging copies
+    fn upload_builder(&self) -> Result<UploadBuilder, RendererError> {
+        let mut commands = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.upload_context.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+
+        // Each batch gets its own pair of queries, so concurrent uploads never share one
+        let profile = self.profiling
+            && self.buffer_strategy == BufferStrategy::DeviceLocal
+            && self.upload_context.queue.family().timestamp_valid_bits().is_some();
+        let timestamps = if profile {
+            QueryPool::new(self.device.clone(), QueryType::Timestamp, 2).ok().map(Arc::new)
+        } else {
+            None
+        };
+        if let Some(pool) = &timestamps {
+            unsafe {
+                commands
+                    .reset_query_pool(pool.clone(), 0..2)?
+                    .write_timestamp(pool.clone(), 0, PipelineStage::TopOfPipe)?;
+            }
+        }
+        Ok(UploadBuilder { commands, timestamps, copies: 0 })
+    }
+
+    // Submits recorded staging copies and waits for them
+    fn submit_uploads(&self, uploads: UploadBuilder) -> Result<(), RendererError> {
+        match self.flush_uploads(uploads)? {
+            Some(pending) => self.finish_upload(pending),
+            None => Ok(()),
+        }
+    }
+
+    // Submits recorded staging copies without waiting, None when nothing was recorded, e.g. for host-visible buffers
+    fn flush_uploads(&self, mut uploads: UploadBuilder) -> Result<Option<PendingUpload>, RendererError> {
+        if uploads.copies == 0 {
+            return Ok(None);
+        }
+        if let Some(pool) = &uploads.timestamps {
+            unsafe {
+                uploads.commands.write_timestamp(pool.clone(), 1, PipelineStage::BottomOfPipe)?;
+            }
+        }
+        let fence = self.upload_context.submit(&self.device, uploads.commands.build()?)?;
+        Ok(Some(PendingUpload { fence, timestamps: uploads.timestamps }))
+    }
+
+    // Waits for a submitted batch of uploads and records its GPU time when profiling
+    fn finish_upload(&self, pending: PendingUpload) -> Result<(), RendererError> {
+        pending.fence.wait(None)?;
+
+        if let Some(ticks) = pending.timestamps.as_ref().and_then(|pool| timestamp_delta(pool, 0)) {
+            let period = self.device.physical_device().properties().timestamp_period;
+            self.metadata.lock().unwrap().record_gpu_timing(GpuTiming {
+                pass: GpuPass::Upload,
+                frame_number: None,
+                millis: ticks_to_millis(ticks, period),
+            });
+        }
+        Ok(())
+    }
+
+    // Buffer for a chunk filled by ArrayPools::fill for strategy. Host-visible chunks are drawn from as they
+    // are, device-local storage gets a new buffer and a staging copy recorded into uploads.
+    fn place_array<T>(&self, chunk: PoolChunk<T>, strategy: BufferStrategy, usage: BufferUsage, uploads: &mut UploadBuilder) -> Result<GpuArray<T>, RendererError>
+    where
+        T: Copy + Send + Sync + 'static,
+    {
+        match strategy {
+            BufferStrategy::HostVisible => Ok(Arc::new(chunk)),
+            BufferStrategy::DeviceLocal => {
+                let buffer = DeviceLocalBuffer::<[T]>::array(
+                    self.device.clone(),
+                    chunk.len() as usize,
+                    BufferUsage { transfer_destination: true, ..usage },
+                    self.upload_context.queue_families(),
+                )?;
+                uploads.commands.copy_buffer(chunk, buffer.clone())?;
+                uploads.copies += 1;
+                Ok(buffer)
+            }
+        }
+    }
+
+    // Uploads the buffers of blocks in one submission, evicting older blocks if the memory budget asks for it,
+    // and registers them for drawing once the copies are done. A failed batch registers nothing and gives
+    // its reservations back.
+    fn upload_blocks<I>(&self, blocks: I) -> Result<Vec<BlockId>, RendererError>
+    where
+        I: IntoIterator<Item = ShaderBlock>,
+    {
+        let mut uploads = self.upload_builder()?;
+        let mut created = Vec::new();
+        for block in blocks {
+            match self.create_budgeted_block(block, &mut uploads) {
+                Ok(block) => created.push(block),
+                Err(e) => {
+                    self.cancel_blocks(created);
+                    return Err(e);
+                }
+            }
+        }
+        if let Err(e) = self.submit_uploads(uploads) {
+            self.cancel_blocks(created);
+            return Err(e);
+        }
+        Ok(created.into_iter().map(|(uploaded, bytes)| self.register_block(uploaded, bytes)).collect())
+    }
+
+    // Makes a block created by create_budgeted_block drawable and commits its memory reservation. The
+    // slots of removed blocks are reused before the list grows, evicted ones never are.
+    fn register_block(&self, uploaded: UploadedBlock, bytes: u64) -> BlockId {
+        // Keep the buffers alive so build_command_buffer can draw them every frame
+        let mut blocks = self.blocks.lock().unwrap();
+        let reused = self.removed_blocks.lock().unwrap().pop_first();
+        let block_id = reused.unwrap_or_else(|| {
+            blocks.push(None);
+            blocks.len() - 1
+        });
+        self.name_block_buffers(block_id, &uploaded);
+        self.metadata.lock().unwrap().record_block_loaded(uploaded.vertex_buffer.len() as usize);
+        blocks[block_id] = Some(uploaded);
+        self.memory_budget.lock().unwrap().commit(block_id, bytes);
+        self.metadata.lock().unwrap().record_buffer_pools(self.buffer_pool_stats());
+        block_id
+    }
+
+    // Gives back the reservations of blocks created by create_budgeted_block that will not be registered
+    fn cancel_blocks(&self, created: Vec<(UploadedBlock, u64)>) {
+        let mut budget = self.memory_budget.lock().unwrap();
+        for (_, bytes) in created {
+            budget.cancel(bytes);
+        }
+    }
+
+    // Reserves room for block in the memory budget and creates its buffers, returning them with the
+    // reservation the caller has to commit
+    fn create_budgeted_block(&self, block: ShaderBlock, uploads: &mut UploadBuilder) -> Result<(UploadedBlock, u64), RendererError> {
+        let staged = self.pools.stage(self.buffer_strategy, block)?;
+        self.create_staged_block(staged, uploads)
+    }
+
+    // Like create_budgeted_block, for a block whose arrays are already written
+    fn create_staged_block(&self, staged: StagedBlock, uploads: &mut UploadBuilder) -> Result<(UploadedBlock, u64), RendererError> {
+        let bytes = self.block_bytes(&staged.block);
+        let evicted = self.memory_budget.lock().unwrap().reserve(bytes)?;
+        if !evicted.is_empty() {
+            // Frames in flight hold on to the buffers until the GPU is done with them
+            let mut blocks = self.blocks.lock().unwrap();
+            for block_id in evicted {
+                if let Some(block) = blocks[block_id].take() {
+                    self.metadata.lock().unwrap().record_block_evicted(block.vertex_buffer.len() as usize);
+                }
+            }
+        }
+
+        match self.create_block_buffers(staged, uploads) {
+            Ok(uploaded) => Ok((uploaded, bytes)),
+            Err(e) => {
+                self.memory_budget.lock().unwrap().cancel(bytes);
+                Err(e)
+            }
+        }
+    }
+
+    // Pooled host-visible buffers are shared between blocks, only device-local ones belong to a single block
+    fn name_block_buffers(&self, block_id: BlockId, block: &UploadedBlock) {
+        if !self.debug_names || self.buffer_strategy != BufferStrategy::DeviceLocal {
+            return;
+        }
+        self.set_debug_name(block.vertex_buffer.inner().buffer, &format!("block {} vertices", block_id));
+        if let Some(index_buffer) = &block.index_buffer {
+            self.set_debug_name(index_buffer.inner().buffer, &format!("block {} indices", block_id));
+        }
+        if !block.instances.is_empty() {
+            self.set_debug_name(block.instance_buffer.inner().buffer, &format!("block {} instances", block_id));
+        }
+    }
+
+    // Device-local memory the buffers of a block will take, as counted against the memory budget. Pooled
+    // host-visible chunks, materials and model uniforms are left out, evicting the block would not give
+    // that memory back to the device.
+    fn block_bytes(&self, block: &ShaderBlock) -> u64 {
+        let vertex_bytes = block.vertices.len() as u64 * std::mem::size_of::<Vertex>() as u64;
+        let mut bytes = 0;
+        if self.buffer_strategy == BufferStrategy::DeviceLocal {
+            bytes += vertex_bytes
+                + block.indices.len() as u64 * std::mem::size_of::<u32>() as u64
+                + block.instances.len() as u64 * std::mem::size_of::<InstanceData>() as u64;
+        }
+        if self.preprocess_enabled && self.preprocess.is_some() && !block.vertices.is_empty() {
+            bytes += vertex_bytes; // The vertices the pass writes, its raw input is host-visible
+        }
+        bytes
+    }
+
+    // Creates the buffers and descriptor sets of one block
+    fn create_block_buffers(&self, staged: StagedBlock, uploads: &mut UploadBuilder) -> Result<UploadedBlock, RendererError> {
+        let StagedBlock { block, strategy, vertices: vertex_chunk, indices: index_chunk, instances: instance_chunk } = staged;
+        let ShaderBlock { vertices, material_data, texture_id, pipeline, instances, .. } = block;
+
+        // Buffers for vertex data and material properties
+        let vertex_buffer = self.place_array(vertex_chunk, strategy, BufferUsage::vertex_buffer(), uploads)?;
+
+        let preprocess = self.preprocess_input(&vertices)?;
+        let bounds = Bounds::of(&vertices).map(|bounds| bounds.instanced(&instances));
+
+        // Materials are small uniforms and always stay host-visible
+        let material_buffer = Arc::new(self.material_pool.chunk(material_uniform(&material_data))?);
+
+        // Bind the material buffer and texture the fragment shader reads
+        let texture = self.texture_for(texture_id)?;
+        let material_set = Arc::new(
+            PersistentDescriptorSet::start(self.set_layout(self.material_set)?.clone())
+                .add_buffer(material_buffer.clone())?
+                .add_sampled_image(texture.image, texture.sampler)?
+                .build()?
+        );
+
+        let index_buffer = match index_chunk {
+            Some(chunk) => Some(self.place_array(chunk, strategy, BufferUsage::index_buffer(), uploads)?),
+            None => None,
+        };
+
+        // The shared identity is a host-visible chunk, so it never depends on the copies of the block that
+        // happened to create it
+        let instance_buffer = match instance_chunk {
+            Some(chunk) => self.place_array(chunk, strategy, BufferUsage::vertex_buffer(), uploads)?,
+            None => {
+                let mut identity = self.identity_instances.lock().unwrap();
+                if identity.is_none() {
+                    let chunk = self.pools.instances.host.chunk(std::iter::once(InstanceData { instance_transform: IDENTITY_TRANSFORM }))?;
+                    *identity = Some(Arc::new(chunk) as GpuArray<InstanceData>);
+                }
+                identity.clone().unwrap()
+            }
+        };
+
+        Ok(UploadedBlock {
+            vertex_buffer,
+            material_buffer,
+            index_buffer,
+            instance_buffer,
+            material_set,
+            transform: IDENTITY_TRANSFORM,
+            pipeline,
+            preprocess,
+            bounds,
+            instances,
+            visible: true,
+        })
+    }
+
+    // Chunks and memory of the pools block buffers are allocated from
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        let host = [
+            self.material_pool.stats(),
+            self.pools.vertices.host.stats(),
+            self.pools.indices.host.stats(),
+            self.pools.instances.host.stats(),
+        ];
+        let staging = [self.pools.vertices.staging.stats(), self.pools.indices.staging.stats(), self.pools.instances.staging.stats()];
+        BufferPoolStats {
+            chunks: host.iter().map(|&(chunks, _, _)| chunks).sum(),
+            staging_chunks: staging.iter().map(|&(chunks, _, _)| chunks).sum(),
+            bytes_reserved: host.iter().chain(staging.iter()).map(|&(_, bytes, _)| bytes).sum(),
+            allocations: host.iter().chain(staging.iter()).map(|&(_, _, allocations)| allocations).sum(),
+        }
+    }
+
+    // Number of block ids handed out so far, evicted blocks and removed ids waiting for reuse included
+    pub fn block_count(&self) -> usize {
+        self.blocks.lock().unwrap().len()
+    }
+
+    // Drops a block's buffers once the frames in flight are done with them. Its id reports BlockRemoved
+    // until the next upload reuses it, so drop it from the scene graph and anything else that holds it.
+    pub fn remove_block(&self, block_id: BlockId) -> Result<(), RendererError> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let block = match blocks.get_mut(block_id) {
+            Some(slot) => slot.take().ok_or_else(|| self.missing_block(block_id))?,
+            None => return Err(RendererError::UnknownBlock(block_id)),
+        };
+        self.removed_blocks.lock().unwrap().insert(block_id);
+        self.memory_budget.lock().unwrap().release(block_id);
+        self.metadata.lock().unwrap().record_block_evicted(block.vertex_buffer.len() as usize);
+        Ok(())
+    }
+
+    // Uploads block in place of an existing one, keeping its id and transform. The old buffers are dropped
+    // once the frames in flight that draw them have finished. A failed upload leaves the old block in place.
+    pub fn replace_block(&self, block_id: BlockId, block: ShaderBlock) -> Result<(), RendererError> {
+        // Given back first, so making room for the replacement never evicts the block it replaces
+        let old_bytes = {
+            self.uploaded_block(block_id, |_| ())?;
+            let mut budget = self.memory_budget.lock().unwrap();
+            let old_bytes = budget.resident.get(&block_id).map(|&(_, size)| size);
+            budget.release(block_id);
+            old_bytes
+        };
+        let uploaded = self.upload_builder().and_then(|mut uploads| {
+            let uploaded = self.create_budgeted_block(block, &mut uploads)?;
+            self.submit_uploads(uploads)?;
+            Ok(uploaded)
+        });
+        let (mut uploaded, bytes) = match uploaded {
+            Ok(uploaded) => uploaded,
+            Err(e) => {
+                if let Some(old_bytes) = old_bytes {
+                    let mut budget = self.memory_budget.lock().unwrap();
+                    budget.used += old_bytes;
+                    budget.commit(block_id, old_bytes);
+                }
+                return Err(e);
+            }
+        };
+
+        let mut blocks = self.blocks.lock().unwrap();
+        let (transform, visible) = match &blocks[block_id] {
+            Some(old) => (old.transform, old.visible),
+            None => {
+                // Removed by another thread while the replacement was uploading
+                self.memory_budget.lock().unwrap().cancel(bytes);
+                return Err(self.missing_block(block_id));
+            }
+        };
+        uploaded.transform = transform;
+        uploaded.visible = visible;
+        self.name_block_buffers(block_id, &uploaded);
+        {
+            let mut metadata = self.metadata.lock().unwrap();
+            metadata.record_block_loaded(uploaded.vertex_buffer.len() as usize);
+            if let Some(old) = blocks[block_id].replace(uploaded) {
+                metadata.record_block_evicted(old.vertex_buffer.len() as usize);
+            }
+        }
+        self.memory_budget.lock().unwrap().commit(block_id, bytes);
+        self.metadata.lock().unwrap().record_buffer_pools(self.buffer_pool_stats());
+        Ok(())
+    }
+
+    // Moves a block by replacing its model matrix, takes effect on the next frame without re-uploading buffers
+    pub fn set_block_transform(&self, block_id: BlockId, transform: Transform) -> Result<(), RendererError> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let block = blocks.get_mut(block_id)
+            .ok_or(RendererError::UnknownBlock(block_id))?
+            .as_mut()
+            .ok_or_else(|| self.missing_block(block_id))?;
+        block.transform = transform;
+        self.memory_budget.lock().unwrap().touch(block_id);
+        Ok(())
+    }
+
+    // Draws the blocks graph references instead of every uploaded block, each with its node's world matrix
+    // applied after the block's own transform. A block can be drawn by several nodes. None draws every block
+    // again. Transforms are pushed per draw, nothing is re-uploaded.
+    pub fn set_scene_graph(&self, graph: Option<SceneGraph>) {
+        *self.scene_graph.lock().unwrap() = graph;
+    }
+
+    // Moves the scene graph node at path, and everything below it, relative to its parent from the next frame on
+    pub fn set_node_transform(&self, path: &str, transform: Transform) -> Result<(), RendererError> {
+        match self.scene_graph.lock().unwrap().as_mut() {
+            Some(graph) if graph.set_transform(path, transform) => Ok(()),
+            _ => Err(RendererError::UnknownSceneNode(path.to_string())),
+        }
+    }
+
+    // Shows or hides the scene graph node at path and everything below it from the next frame on
+    pub fn set_node_visible(&self, path: &str, visible: bool) -> Result<(), RendererError> {
+        match self.scene_graph.lock().unwrap().as_mut() {
+            Some(graph) if graph.set_visible(path, visible) => Ok(()),
+            _ => Err(RendererError::UnknownSceneNode(path.to_string())),
+        }
+    }
+
+    // Blocks of the scene graph's draw list with the transforms they are drawn with, and how many uploaded
+    // blocks with vertices hidden nodes leave out. References to blocks that were never uploaded, or were
+    // removed or evicted, are skipped, with a warning when they change if report_missing is set. Only frames
+    // report, so queries between them don't reset what was warned about.
+    fn scene_draws<'a>(&self, blocks: &'a [Option<UploadedBlock>], graph: &SceneGraph, report_missing: bool) -> (Vec<(BlockId, &'a UploadedBlock, Transform)>, usize) {
+        let (draw_list, hidden_list) = graph.resolve();
+        let hidden = hidden_list.into_iter()
+            .filter(|&block_id| blocks.get(block_id).and_then(Option::as_ref).map_or(false, |block| block.bounds.is_some()))
+            .count();
+        let mut draws = Vec::new();
+        let mut missing = Vec::new();
+        for (block_id, world) in draw_list {
+            match blocks.get(block_id).and_then(Option::as_ref) {
+                Some(block) => draws.push((block_id, block, mat4_mul(&world, &block.transform))),
+                None => missing.push(block_id),
+            }
+        }
+        if !report_missing {
+            return (draws, hidden);
+        }
+        let mut reported = self.missing_scene_blocks.lock().unwrap();
+        if missing != *reported {
+            if !missing.is_empty() {
+                log::warn!("skipping scene graph references to blocks {:?}, they are not uploaded", missing);
+            }
+            *reported = missing;
+        }
+        (draws, hidden)
+    }
+
+    // Blocks a frame draws, before visibility and culling, with the transforms they are drawn with, and how
+    // many the scene graph hides. report_missing is passed on to scene_draws.
+    fn block_draws<'a>(&self, blocks: &'a [Option<UploadedBlock>], report_missing: bool) -> (Vec<(BlockId, &'a UploadedBlock, Transform)>, usize) {
+        match self.scene_graph.lock().unwrap().as_ref() {
+            Some(graph) => self.scene_draws(blocks, graph, report_missing),
+            None => {
+                let draws = blocks
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(block_id, block)| block.as_ref().map(|block| (block_id, block, block.transform)))
+                    .collect();
+                (draws, 0)
+            }
+        }
+    }
+
+    // Block under a point of the surface, in pixels from its top left corner like winit cursor positions,
+    // nearest to the camera. The ray through the point, from the near to the far plane, is tested against
+    // the bounding box of every visible block as it is drawn. A box the ray starts in, e.g. with the camera
+    // inside it, is hit right away. None outside the viewport or when the ray hits nothing.
+    //
+    //     // Block ids are slots that removed blocks give back, so materials are looked up by id, not position
+    //     let block_ids = renderer.stream_blocks(blocks.clone(), DEFAULT_MAX_INFLIGHT_UPLOADS, |_| {})?;
+    //     let materials: HashMap<BlockId, Vec<f32>> =
+    //         block_ids.into_iter().zip(blocks.into_iter().map(|block| block.material_data)).collect();
+    //     let mut cursor = (0.0, 0.0);
+    //     let mut tinted: Option<BlockId> = None;
+    //     event_loop.run_return(|event, _, control_flow| match event {
+    //         Event::WindowEvent { event: WindowEvent::CursorMoved { position, .. }, .. } => {
+    //             cursor = (position.x as f32, position.y as f32);
+    //         }
+    //         Event::WindowEvent { event: WindowEvent::MouseInput { state: ElementState::Pressed, .. }, .. } => {
+    //             // Picked blocks are uploaded, so updating their materials only fails on device errors
+    //             if let Some(id) = tinted.take() {
+    //                 renderer.update_block_materials(id, &materials[&id]).expect("failed to restore material");
+    //             }
+    //             if let Some(id) = renderer.pick(cursor) {
+    //                 renderer.update_block_materials(id, &[1.0, 1.0, 0.0, 1.0]).expect("failed to tint block");
+    //                 tinted = Some(id);
+    //             }
+    //         }
+    //         Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => *control_flow = ControlFlow::Exit,
+    //         Event::RedrawEventsCleared => {
+    //             if let Err(e) = renderer.render_once() {
+    //                 log::error!("frame failed: {}", e);
+    //                 *control_flow = ControlFlow::Exit;
+    //             }
+    //         }
+    //         _ => {}
+    //     });
+    pub fn pick(&self, screen_xy: (f32, f32)) -> Option<BlockId> {
+        let (x, y) = screen_xy;
+        let [origin_x, origin_y] = self.viewport.origin;
+        let [width, height] = self.viewport.dimensions;
+        let (x, y) = ((x - origin_x) / width, (y - origin_y) / height);
+        // Also rejects NaN from an empty viewport
+        if !((0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y)) {
+            return None;
+        }
+
+        // Clip space y points down like the surface, depth runs from the near plane at 0 to the far plane at 1
+        let matrices = self.camera_matrices(&self.camera.lock().unwrap().source);
+        let clip_to_world = mat4_inverse(&mat4_mul(&matrices.proj, &matrices.view))?;
+        let unproject = |depth: f32| {
+            let [wx, wy, wz, w] = transform_point(&clip_to_world, [x * 2.0 - 1.0, y * 2.0 - 1.0, depth]);
+            [wx / w, wy / w, wz / w]
+        };
+        let near = unproject(0.0);
+        let ray = sub3(unproject(1.0), near);
+
+        let blocks = self.blocks.lock().unwrap();
+        self.block_draws(&blocks, false).0
+            .into_iter()
+            .filter(|(_, block, _)| block.visible)
+            .filter_map(|(block_id, block, transform)| {
+                let bounds = block.bounds.as_ref()?;
+                let corners = bounds.corners().map(|corner| {
+                    let [x, y, z, _] = transform_point(&transform, corner);
+                    [x, y, z]
+                });
+                let distance = Bounds::of_points(corners)?.ray_entry(near, ray)?;
+                Some((block_id, distance))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(block_id, _)| block_id)
+    }
+
+    // Hides or shows a block from the next frame on. Hidden blocks keep their buffers and can be updated
+    // as usual, they are only skipped when recording draws.
+    pub fn set_block_visible(&self, block_id: BlockId, visible: bool) -> Result<(), RendererError> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let block = blocks.get_mut(block_id)
+            .ok_or(RendererError::UnknownBlock(block_id))?
+            .as_mut()
+            .ok_or_else(|| self.missing_block(block_id))?;
+        block.visible = visible;
+        Ok(())
+    }
+
+    pub fn block_visible(&self, block_id: BlockId) -> Result<bool, RendererError> {
+        let blocks = self.blocks.lock().unwrap();
+        let block = blocks.get(block_id)
+            .ok_or(RendererError::UnknownBlock(block_id))?
+            .as_ref()
+            .ok_or_else(|| self.missing_block(block_id))?;
+        Ok(block.visible)
+    }
+
+    // Overwrites the first material_data.len() floats of a block's material uniform in place, without
+    // allocating a new buffer or descriptor set. Waits for the frames in flight, which may still read the
+    // old material, before writing.
+    pub fn update_block_materials(&mut self, block_id: BlockId, material_data: &[f32]) -> Result<(), RendererError> {
+        let buffer = self.uploaded_block(block_id, |block| block.material_buffer.clone())?;
+        let len = buffer.len() as usize;
+        if material_data.len() > len {
+            return Err(RendererError::UpdateOutOfRange { end: material_data.len(), len });
+        }
+        if material_data.is_empty() {
+            return Ok(());
+        }
+        let target = BufferSlice::from_typed_buffer_access(buffer).slice(0..material_data.len()).unwrap();
+        self.write_in_place(material_data.iter().copied(), target)
+    }
+
+    // Overwrites the vertices in range of a block in place. vertex_data is in the renderer's vertex layout
+    // and must hold exactly range.len() vertices. The block's bounds grow to cover the new positions.
+    // Blocks uploaded while the preprocess pass was enabled rerun it over the new vertices. Waits for the
+    // frames in flight, which may still read the old vertices, before writing.
+    pub fn update_block_vertices(&mut self, block_id: BlockId, range: Range<usize>, vertex_data: &[f32]) -> Result<(), RendererError> {
+        let vertices = Vertex::from_raw_f32(vertex_data, self.vertex_layout)?;
+        self.write_block_vertices(block_id, range, &vertices)
+    }
+
+    // update_block_vertices with vertices that are already whole
+    fn write_block_vertices(&mut self, block_id: BlockId, range: Range<usize>, vertices: &[Vertex]) -> Result<(), RendererError> {
+        let buffer = self.uploaded_block(block_id, |block| block.vertex_buffer.clone())?;
+        let len = buffer.len() as usize;
+        if range.end > len || range.start > range.end {
+            return Err(RendererError::UpdateOutOfRange { end: range.end, len });
+        }
+        if vertices.len() != range.len() {
+            return Err(RendererError::MalformedGeometry(format!(
+                "{} vertices given to replace {}", vertices.len(), range.len()
+            )));
+        }
+        if vertices.is_empty() {
+            return Ok(());
+        }
+        let target = BufferSlice::from_typed_buffer_access(buffer).slice(range.clone()).unwrap();
+        self.write_in_place(vertices.iter().copied(), target)?;
+
+        // write_in_place waited for the frames in flight, nothing reads the preprocess buffers now
+        if let Some(input) = self.uploaded_block(block_id, |block| block.preprocess.clone())? {
+            let stride = VertexLayout::PositionNormalUv.stride();
+            input.raw.write()?[range.start * stride..range.end * stride]
+                .copy_from_slice(&Vertex::to_raw_f32(vertices, VertexLayout::PositionNormalUv));
+            self.dispatch_preprocess(&[&input])?;
+        }
+
+        let mut blocks = self.blocks.lock().unwrap();
+        if let Some(Some(block)) = blocks.get_mut(block_id) {
+            let moved = Bounds::of(vertices).map(|bounds| bounds.instanced(&block.instances));
+            let corners = block.bounds.iter().chain(moved.iter()).flat_map(Bounds::corners);
+            block.bounds = Bounds::of_points(corners);
+        }
+        Ok(())
+    }
+
+    // Error for an id whose slot is empty, either removed or evicted
+    fn missing_block(&self, block_id: BlockId) -> RendererError {
+        if self.removed_blocks.lock().unwrap().contains(&block_id) {
+            RendererError::BlockRemoved(block_id)
+        } else {
+            RendererError::BlockEvicted(block_id)
+        }
+    }
+
+    // Looks up an uploaded block and marks it used for the memory budget
+    fn uploaded_block<R>(&self, block_id: BlockId, f: impl FnOnce(&UploadedBlock) -> R) -> Result<R, RendererError> {
+        let blocks = self.blocks.lock().unwrap();
+        let block = blocks.get(block_id)
+            .ok_or(RendererError::UnknownBlock(block_id))?
+            .as_ref()
+            .ok_or_else(|| self.missing_block(block_id))?;
+        self.memory_budget.lock().unwrap().touch(block_id);
+        Ok(f(block))
+    }
+
+    // Copies data into target once no frame in flight can still be reading it, and waits for the copy so
+    // the next frame sees the new contents
+    fn write_in_place<T, I, B>(&mut self, data: I, target: B) -> Result<(), RendererError>
+    where
+        T: Copy + Send + Sync + 'static,
+        I: ExactSizeIterator<Item = T>,
+        B: TypedBufferAccess<Content = [T]> + Send + Sync + 'static,
+    {
+        let staging = CpuAccessibleBuffer::from_iter(self.device.clone(), BufferUsage::transfer_source(), false, data)?;
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        builder.copy_buffer(staging, target)?;
+        let command_buffer = builder.build()?;
+
+        self.wait_idle();
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer)?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+        Ok(())
+    }
+
+    // Name, type and driver of the physical device the renderer runs on
+    pub fn device_info(&self) -> DeviceInfo {
+        let physical = self.device.physical_device();
+        let properties = physical.properties();
+        DeviceInfo {
+            name: properties.device_name.clone(),
+            device_type: properties.device_type,
+            index: physical.index(),
+            vendor_id: properties.vendor_id,
+            driver_version: properties.driver_version,
+            api_version: properties.api_version,
+        }
+    }
+
+    // Returns a handle that can stop run from another thread
+    pub fn control(&self) -> RenderControl {
+        self.control.clone()
+    }
+
+    // Copy of the renderer's Metadata, the lock is only held for the clone
+    pub fn metadata(&self) -> Metadata {
+        self.metadata.lock().unwrap().clone()
+    }
+
+    // Same copy as metadata
+    pub fn metadata_snapshot(&self) -> Metadata {
+        self.metadata()
+    }
+
+    // Handle for reading the Metadata from other threads, e.g. a UI thread while render_loop runs
+    pub fn metadata_handle(&self) -> MetadataHandle {
+        MetadataHandle(self.metadata.clone())
+    }
+
+    // Runs f with the Metadata locked, e.g. to set entries. Keep f short, frames wait for the lock.
+    //
+    //     renderer.metadata_mut(|metadata| { metadata.set_entry("scene", "lobby"); });
+    pub fn metadata_mut<R>(&self, f: impl FnOnce(&mut Metadata) -> R) -> R {
+        f(&mut self.metadata.lock().unwrap())
+    }
+
+    // Frame count and frame time statistics, copied out so the lock is never held across a frame
+    pub fn stats(&self) -> FrameStats {
+        self.metadata.lock().unwrap().stats()
+    }
+
+    // Stops presenting frames until resume, render_once still draws on demand
+    pub fn pause(&self) {
+        self.control.pause();
+    }
+
+    pub fn resume(&self) {
+        self.control.resume();
+    }
+
+    // Renders exactly one frame, also while paused
+    pub fn render_once(&mut self) -> Result<(), RendererError> {
+        self.render_and_recover()
+    }
+
+    // Renders frames until should_continue returns false or the control handle is stopped, then waits for
+    // the frames in flight so the renderer can be dropped right away. Unrecoverable errors end the loop early.
+    //
+    //     let stop = Arc::new(AtomicBool::new(false));
+    //     let flag = stop.clone();
+    //     renderer.render_loop(move || !flag.load(Ordering::Relaxed))?;
+    pub fn render_loop(&mut self, mut should_continue: impl FnMut() -> bool) -> Result<(), RendererError> {
+        let mut result = Ok(());
+        while should_continue() && !self.control.is_stopped() {
+            // Also returns once stopped while paused
+            self.control.wait_while_paused();
+            if self.control.is_stopped() {
+                break;
+            }
+            if let Err(e) = self.render_and_recover() {
+                result = Err(e);
+                break;
+            }
+        }
+        self.wait_idle();
+        result
+    }
+
+    // Handle for seeking and looping play from another thread
+    pub fn playback_control(&self) -> PlaybackControl {
+        self.playback.clone()
+    }
+
+    // Shows the frames of metrics one after another at fps, through one block drawn along with the loaded ones,
+    // until the last frame has had its time or, when looping, until the control handle is stopped. Frames
+    // are skipped when rendering falls behind, and pausing the control handle holds the current frame. With
+    // InterpolationMode::Linear frames are rendered every LINEAR_PLAYBACK_INTERVAL, each blended between the
+    // recorded frames around the playback time. The playback block is removed before returning.
+    pub fn play(&mut self, metrics: VideoMetrics, fps: f32) -> Result<(), RendererError> {
+        if !(fps > 0.0 && fps.is_finite()) {
+            return Err(RendererError::InvalidFrameRate(fps));
+        }
+        let frames = playback_frames(metrics.frame_data);
+        if frames.is_empty() {
+            return Ok(());
+        }
+
+        let mut start = Instant::now();
+        let mut shown: Option<(usize, BlockId)> = None;
+        let result = loop {
+            if self.control.is_stopped() {
+                break Ok(());
+            }
+            if self.control.is_paused() {
+                self.control.wait_while_paused();
+                // Resume from the held frame rather than catching up on the paused time
+                let index = shown.map_or(0, |(index, _)| index);
+                start = Instant::now() - playback_offset(index, fps);
+                continue;
+            }
+            if let Some(frame_number) = self.playback.take_seek() {
+                let index = frames.partition_point(|frame| frame.frame_number < frame_number).min(frames.len() - 1);
+                start = Instant::now() - playback_offset(index, fps);
+            }
+
+            // The frame due now, any between it and the one on screen are skipped
+            let position = start.elapsed().as_secs_f64() * fps as f64;
+            let mut index = position as usize;
+            let mut s = position.fract() as f32;
+            if index >= frames.len() {
+                if !self.playback.is_looping() {
+                    break Ok(());
+                }
+                index = 0;
+                s = 0.0;
+                start = Instant::now();
+            }
+            // Blended frames change on every rendered frame, the last one has nothing to blend towards
+            let blend = match self.playback.interpolation() {
+                InterpolationMode::Linear if s > 0.0 && index + 1 < frames.len() => Some(s),
+                _ => None,
+            };
+            if blend.is_some() || shown.map_or(true, |(on_screen, _)| on_screen != index) {
+                let frame = match blend {
+                    Some(s) => frames[index].lerp(&frames[index + 1], s),
+                    None => frames[index].clone(),
+                };
+                let frame_number = frame.frame_number;
+                let block_id = match self.show_playback_frame(frame, shown.map(|(_, block_id)| block_id)) {
+                    Ok(block_id) => block_id,
+                    Err(e) => break Err(e),
+                };
+                shown = Some((index, block_id));
+                self.playback.set_current(Some(frame_number));
+            }
+
+            let rendered = Instant::now();
+            if let Err(e) = self.render_and_recover() {
+                break Err(e);
+            }
+            // Blended frames are paced too, presentation does not block in every present mode
+            let next = match blend {
+                Some(_) => rendered + LINEAR_PLAYBACK_INTERVAL,
+                None => start + playback_offset(index + 1, fps),
+            };
+            if let Some(wait) = next.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+        };
+
+        if let Some((_, block_id)) = shown {
+            let _ = self.remove_block(block_id);
+        }
+        self.wait_idle();
+        result
+    }
+
+    // Puts frame on screen through the playback block, which is written in place while frames keep the
+    // same number of vertices and material floats, and replaced otherwise. Returns the playback block.
+    fn show_playback_frame(&mut self, frame: FrameData, shown: Option<BlockId>) -> Result<BlockId, RendererError> {
+        let material = material_uniform(&frame.material_data);
+        if let Some(block_id) = shown {
+            // Fails when the memory budget evicted the block, which is then uploaded again
+            let sizes = self.uploaded_block(block_id, |block| (block.vertex_buffer.len() as usize, block.material_buffer.len() as usize));
+            if sizes.ok() == Some((frame.vertices.len(), material.len())) {
+                self.write_block_vertices(block_id, 0..frame.vertices.len(), &frame.vertices)?;
+                self.update_block_materials(block_id, &material)?;
+                return Ok(block_id);
+            }
+        }
+        let block_id = self.apply_shader_block(ShaderBlock {
+            vertices: frame.vertices,
+            material_data: frame.material_data,
+            ..Default::default()
+        })?;
+        if let Some(previous) = shown {
+            let _ = self.remove_block(previous); // Already gone when the memory budget evicted it
+        }
+        Ok(block_id)
+    }
+
+    // Drives the renderer from a winit event loop until the window closes or the control handle is stopped
+    pub fn run(mut self, mut event_loop: EventLoop<()>) -> Result<(), RendererError> {
+        let mut result = Ok(());
+        let mut last_frame = Instant::now();
+
+        event_loop.run_return(|event, _, control_flow| {
+            let paused = self.control.is_paused();
+            *control_flow = if paused {
+                ControlFlow::WaitUntil(Instant::now() + PAUSED_POLL_INTERVAL)
+            } else {
+                ControlFlow::Poll
+            };
+
+            if self.control.is_stopped() {
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+
+            match event {
+                Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => {
+                    *control_flow = ControlFlow::Exit;
+                }
+                Event::WindowEvent { event: WindowEvent::Resized(_), .. } => {
+                    self.needs_recreate = true;
+                }
+                Event::WindowEvent { event, .. } => self.window_event(&event),
+                Event::MainEventsCleared if !paused => {
+                    if let Some(swapchain) = self.swapchain() {
+                        swapchain.surface().window().request_redraw();
+                    }
+                }
+                // Redraws the OS asks for while paused are dropped, the last presented frame stays on screen
+                Event::RedrawRequested(_) if !paused => {
+                    // Capped so a pause or a stall doesn't teleport a moving camera
+                    let now = Instant::now();
+                    self.update_camera_controller(now.duration_since(last_frame).as_secs_f32().min(0.1));
+                    last_frame = now;
+                    if let Err(e) = self.render_and_recover() {
+                        result = Err(e);
+                        *control_flow = ControlFlow::Exit;
+                    }
+                }
+                _ => {}
+            }
+        });
+
+        // Let the GPU finish every frame before the buffers and swapchain are dropped
+        self.wait_idle();
+        result
+    }
+
+    // Renders a frame, recreating the swapchain when it is out of date and logging other transient errors.
+    // Nothing is rendered while the window is minimized.
+    fn render_and_recover(&mut self) -> Result<(), RendererError> {
+        render_or_idle(
+            self,
+            |renderer| renderer.surface_extent(),
+            |renderer| renderer.metadata.lock().unwrap().break_interval(),
+            |renderer| {
+                if recovery(renderer.render_frame())? {
+                    renderer.needs_recreate = true;
+                }
+                Ok(())
+            },
+        )
+    }
+
+    // Renders a single frame
+    fn render_frame(&mut self) -> Result<(), RendererError> {
+        #[cfg(feature = "notify")]
+        self.poll_shader_watch();
+        self.poll_loads();
+
+        // Scheduled by a resize or by a suboptimal acquisition of the previous frame
+        if self.needs_recreate {
+            self.recreate_swapchain()?;
+        }
+
+        // Release resources of frames the GPU has already finished without blocking on the rest
+        if let Some(previous_frame_end) = self.previous_frame_end.as_mut() {
+            previous_frame_end.cleanup_finished();
+        }
+
+        let load_previous = self.frame_settings.lock().unwrap().load_previous;
+        if load_previous == self.clears_color {
+            self.rebuild_render_pass(!load_previous)?;
+        }
+
+        let (image_num, suboptimal, acquire_future) = self.acquire_image()?;
+
+        // Only block if the GPU is still using the resources of the frame that last occupied this slot
+        let slot = self.current_frame;
+        if let Some(fence) = self.frame_fences[slot].take() {
+            fence.wait(None)?;
+        }
+        self.collect_timing(slot);
+        // Waiting for the image and the fence is GPU time, only recording and submitting count as CPU time
+        let cpu_start = Instant::now();
+
+        // Chain after the most recently submitted frame so submissions stay ordered without a CPU stall
+        let previous_future = match self.previous_frame_end.take() {
+            Some(future) => future,
+            None => sync::now(self.device.clone()).boxed(),
+        };
+
+        let previous_future = match acquire_future {
+            Some(acquire_future) => previous_future.join(acquire_future).boxed(),
+            None => previous_future,
+        };
+
+        // Render the frame, presenting it only when there is a swapchain
+        let command_buffer = self.build_command_buffer(image_num, slot)?;
+        let executed = previous_future.then_execute(self.queue.clone(), command_buffer)?;
+        #[cfg(feature = "egui")]
+        let executed = self.draw_overlay(executed.boxed(), image_num);
+        let submitted = match &self.target {
+            RenderTarget::Swapchain(swapchain, _) => executed
+                .then_swapchain_present(self.queue.clone(), swapchain.clone(), image_num)
+                .boxed(),
+            RenderTarget::Offscreen(_) => executed.boxed(),
+        };
+        let future = submitted.then_signal_fence_and_flush()?;
+
+        let fence = Arc::new(future);
+        self.frame_fences[slot] = Some(fence.clone());
+        self.previous_frame_end = Some(fence.boxed());
+        self.current_frame = (slot + 1) % self.frame_fences.len();
+        self.last_image = Some(image_num);
+        self.pending_timings[slot] = Some((self.frame_number, cpu_start.elapsed().as_micros() as u64, self.profiling));
+        self.frame_number = self.frame_number.wrapping_add(1);
+        let extent = self.framebuffers[image_num].dimensions();
+        {
+            let mut metadata = self.metadata.lock().unwrap();
+            metadata.record_frame(Instant::now());
+            metadata.extent = [extent[0], extent[1]];
+        }
+
+        // The frame was still presented, the swapchain is matched to the surface before the next one
+        let (state, recreate) = self.suboptimal.after_acquire(suboptimal);
+        self.suboptimal = state;
+        self.needs_recreate |= recreate;
+
+        Ok(())
+    }
+
+    // Draws an egui overlay over every presented frame, with a panel of frame statistics, loaded blocks and
+    // the camera. Captured frames are copied before the overlay is drawn and do not include it.
+    #[cfg(feature = "egui")]
+    pub fn enable_overlay(&mut self) -> Result<(), RendererError> {
+        let swapchain = self.swapchain()
+            .ok_or_else(|| RendererError::Reconfigure("the overlay needs a window surface".into()))?;
+        if self.overlay.is_none() {
+            let gui = egui_winit_vulkano::Gui::new(swapchain.surface().clone(), self.queue.clone(), true);
+            self.overlay = Some(Overlay { gui, stats_panel: true, ui: None });
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "egui")]
+    pub fn disable_overlay(&mut self) {
+        self.overlay = None;
+    }
+
+    // Shows or hides the built-in panel, the UI of set_overlay_ui is drawn either way
+    #[cfg(feature = "egui")]
+    pub fn set_overlay_stats(&mut self, visible: bool) {
+        if let Some(overlay) = &mut self.overlay {
+            overlay.stats_panel = visible;
+        }
+    }
+
+    // Registers UI drawn every frame after the built-in panel, enabling the overlay if needed
+    #[cfg(feature = "egui")]
+    pub fn set_overlay_ui<F>(&mut self, ui: F) -> Result<(), RendererError>
+    where
+        F: FnMut(&egui::Context) + Send + 'static,
+    {
+        self.enable_overlay()?;
+        if let Some(overlay) = &mut self.overlay {
+            overlay.ui = Some(Box::new(ui));
+        }
+        Ok(())
+    }
+
+    // Routes a window event of run to the overlay, then to the camera controller unless egui wanted it.
+    // Input over an overlay panel is not meant for the camera.
+    fn window_event(&mut self, event: &WindowEvent) {
+        #[cfg(feature = "egui")]
+        if self.overlay_event(event) {
+            return;
+        }
+        if let Some(controller) = &mut self.camera_controller {
+            controller.handle_event(event);
+        }
+    }
+
+    // Passes a window event to the overlay, true when egui wants it for itself
+    #[cfg(feature = "egui")]
+    fn overlay_event(&mut self, event: &WindowEvent) -> bool {
+        match &mut self.overlay {
+            Some(overlay) => overlay.gui.update(event),
+            None => false,
+        }
+    }
+
+    // Records the overlay into the image of the frame after before. The size is taken from the image, so
+    // the overlay follows swapchain recreation.
+    #[cfg(feature = "egui")]
+    fn draw_overlay(&mut self, before: Box<dyn GpuFuture>, image_num: usize) -> Box<dyn GpuFuture> {
+        let image = match (&self.target, &self.overlay) {
+            (RenderTarget::Swapchain(_, images), Some(_)) => images.get(image_num).cloned(),
+            _ => None,
+        };
+        let image = match image {
+            Some(image) => image,
+            None => return before,
+        };
+        let stats = self.stats();
+        let metadata = self.metadata();
+        let camera = self.camera_summary();
+        let Overlay { gui, stats_panel, ui } = self.overlay.as_mut().unwrap();
+        gui.immediate_ui(|gui| {
+            let ctx = gui.context();
+            if *stats_panel {
+                egui::Window::new("Renderer").show(&ctx, |panel| {
+                    panel.label(format!("{:.1} fps, {} frames", stats.average_fps, stats.frames_rendered));
+                    if let Some(p95) = stats.p95_frame_time {
+                        panel.label(format!("p95 frame time {:.2} ms", p95.as_secs_f64() * 1000.0));
+                    }
+                    panel.label(format!(
+                        "{} blocks drawn, {} culled, {} hidden",
+                        stats.culling.drawn, stats.culling.culled, stats.culling.hidden,
+                    ));
+                    panel.label(format!("{} blocks loaded, {} vertices", metadata.loaded_blocks(), metadata.vertex_count()));
+                    if let Some(source) = metadata.source() {
+                        panel.label(format!("source {}", source));
+                    }
+                    panel.label(format!("extent {}x{}", metadata.extent()[0], metadata.extent()[1]));
+                    panel.label(camera);
+                });
+            }
+            if let Some(ui) = ui {
+                ui(&ctx);
+            }
+        });
+        gui.draw_on_image(before, image)
+    }
+
+    // One line describing the camera, for the overlay panel
+    #[cfg(feature = "egui")]
+    fn camera_summary(&self) -> String {
+        match &self.camera.lock().unwrap().source {
+            CameraSource::Clip => "camera: clip space".to_string(),
+            CameraSource::LookAt(camera) => format!(
+                "camera: {:.2?} looking at {:.2?}, {:?}", camera.position, camera.target, camera.projection
+            ),
+            CameraSource::Matrices(_) => "camera: custom matrices".to_string(),
+        }
+    }
+
+    // The swapchain being presented to, None when rendering offscreen
+    fn swapchain(&self) -> Option<Arc<Swapchain<Window>>> {
+        match &self.target {
+            RenderTarget::Swapchain(swapchain, _) => Some(swapchain.clone()),
+            RenderTarget::Offscreen(_) => None,
+        }
+    }
+
+    // Picks the image to render into, acquiring it from the swapchain and recreating the swapchain while
+    // it no longer matches the surface, up to MAX_ACQUIRE_ATTEMPTS times
+    fn acquire_image(&mut self) -> Result<(usize, bool, Option<SwapchainAcquireFuture<Window>>), RendererError> {
+        if let RenderTarget::Offscreen(images) = &self.target {
+            return Ok((self.current_frame % images.len(), false, None));
+        }
+
+        let (image_num, suboptimal, acquire_future) = acquire_with_retries(
+            self,
+            |renderer| {
+                let swapchain = renderer.swapchain().ok_or(AcquireError::OutOfDate)?;
+                vulkano::swapchain::acquire_next_image(swapchain, Some(ACQUIRE_TIMEOUT))
+            },
+            |renderer| renderer.recreate_swapchain(),
+        )?;
+        Ok((image_num, suboptimal, Some(acquire_future)))
+    }
+
+    // Builds the command buffer for rendering
+    fn build_command_buffer(&self, image_num: usize, slot: usize) -> Result<Arc<vulkano::command_buffer::PrimaryAutoCommandBuffer>, RendererError> {
+        // Framebuffers that no longer line up with the swapchain mean it has to be recreated
+        let framebuffer = framebuffer_for_image(&self.framebuffers, image_num)
+            .ok_or(RendererError::Acquire(AcquireError::OutOfDate))?;
+        // With post-processing the scene is drawn into the intermediate image and the post pass writes the target
+        let post = match &self.post {
+            Some(post) => {
+                let target = post.targets.get(image_num).ok_or(RendererError::Acquire(AcquireError::OutOfDate))?;
+                Some((post, target))
+            }
+            None => None,
+        };
+        let framebuffer = post.map_or(framebuffer, |(_, target)| target.scene_framebuffer.clone());
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+
+        // Bracket the frame's commands with timestamps in this slot's queries, the render pass gets
+        // its own pair when profiling
+        let timestamps = self.timestamp_pool.clone().map(|pool| (pool, slot as u32 * TIMESTAMPS_PER_SLOT));
+        if let Some((pool, first)) = &timestamps {
+            unsafe {
+                builder
+                    .reset_query_pool(pool.clone(), *first..*first + TIMESTAMPS_PER_SLOT)?
+                    .write_timestamp(pool.clone(), *first, PipelineStage::TopOfPipe)?;
+            }
+        }
+        let pass_timestamps = timestamps.as_ref().filter(|_| self.profiling);
+
+        // Bound for every block
+        let (camera_matrices, camera_set) = self.camera_set()?;
+
+        // The preprocess output was written when blocks were uploaded or updated, frames only draw it
+        let blocks = self.blocks.lock().unwrap();
+        let preprocess = self.preprocess.as_ref().filter(|_| self.preprocess_enabled);
+
+        if let Some((pool, first)) = pass_timestamps {
+            unsafe {
+                builder.write_timestamp(pool.clone(), *first + 2, PipelineStage::TopOfPipe)?;
+            }
+        }
+
+        // Draw every uploaded block the camera can see into the acquired image, opaque blocks first so
+        // blended ones composite over everything behind them
+        let frustum = Frustum::from_view_projection(&mat4_mul(&camera_matrices.proj, &camera_matrices.view));
+        let (draws, scene_hidden) = self.block_draws(&blocks, true);
+        let mut culling = CullingStats { hidden: scene_hidden, ..CullingStats::default() };
+        let mut ordered: Vec<(&UploadedBlock, Transform)> = Vec::new();
+        let mut drawn = Vec::new();
+        for (block_id, block, transform) in draws {
+            let bounds = match &block.bounds {
+                Some(bounds) => bounds,
+                None => continue, // Nothing to draw
+            };
+            // The preprocess shader may move vertices anywhere, the uploaded bounds say nothing about its output
+            let preprocessed = preprocess.is_some() && block.preprocess.is_some();
+            if !block.visible {
+                culling.hidden += 1;
+            } else if self.frustum_culling && !preprocessed && frustum.culls(bounds, &transform) {
+                culling.culled += 1;
+            } else {
+                ordered.push((block, transform));
+                drawn.push(block_id);
+            }
+        }
+        // Blocks the frame draws are the last ones the budget evicts
+        self.memory_budget.lock().unwrap().touch_all(&drawn);
+        culling.drawn = ordered.len();
+        ordered.sort_by_key(|(block, _)| block.pipeline.blend == BlendMode::AlphaBlend);
+        let mut active_pipelines = Vec::new();
+        for (block, _) in &ordered {
+            if !active_pipelines.contains(&block.pipeline) {
+                active_pipelines.push(block.pipeline);
+            }
+        }
+        {
+            let mut metadata = self.metadata.lock().unwrap();
+            metadata.record_culling(culling);
+            metadata.record_pipelines(active_pipelines);
+        }
+
+        // Pipelines are looked up here, the recording threads cannot create them
+        let mut pipelines = HashMap::new();
+        for (block, _) in &ordered {
+            if !pipelines.contains_key(&block.pipeline) {
+                pipelines.insert(block.pipeline, self.pipeline_for(block.pipeline)?);
+            }
+        }
+        let context = DrawContext {
+            pipelines,
+            camera_set,
+            material_set: self.material_set,
+            identity_model_set: self.identity_model_set()?,
+            model_pool: if self.push_transforms { None } else { Some(self.model_pool.clone()) },
+            preprocessing: preprocess.is_some(),
+        };
+
+        let threads = if self.parallel_recording { recording_threads(ordered.len()) } else { 1 };
+        let clear_values = self.clear_values(&self.frame_settings());
+        let (draw_subpass, subpasses) = (self.draw_subpass(), self.subpass_count());
+        if threads > 1 {
+            let subpass = Subpass::from(self.render_pass.clone(), draw_subpass).unwrap();
+            let chunk_len = (ordered.len() + threads - 1) / threads;
+            // The renderer itself is not Sync, the threads only get what they record with
+            let (device, queue, dynamic_state) = (&self.device, &self.queue, (&self.viewport, &self.scissor));
+            let secondaries = std::thread::scope(|scope| {
+                let workers: Vec<_> = ordered
+                    .chunks(chunk_len)
+                    .map(|chunk| {
+                        let (context, subpass) = (&context, subpass.clone());
+                        scope.spawn(move || record_secondary(device, queue, subpass, dynamic_state, chunk, context))
+                    })
+                    .collect();
+                // Join every worker before reporting the first error, so none outlives the frame
+                let results: Vec<_> = workers
+                    .into_iter()
+                    .map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                    .collect();
+                results.into_iter().collect::<Result<Vec<_>, RendererError>>()
+            })?;
+            // Subpasses before the draw subpass have nothing to record
+            if draw_subpass == 0 {
+                builder.begin_render_pass(framebuffer, true, clear_values)?;
+            } else {
+                builder.begin_render_pass(framebuffer, false, clear_values)?;
+                for _ in 1..draw_subpass {
+                    builder.next_subpass(false)?;
+                }
+                builder.next_subpass(true)?;
+            }
+            for secondary in secondaries {
+                builder.execute_commands(secondary)?;
+            }
+        } else {
+            builder.begin_render_pass(framebuffer, false, clear_values)?;
+            for _ in 0..draw_subpass {
+                builder.next_subpass(false)?;
+            }
+            builder
+                .set_viewport(0, std::iter::once(self.viewport.clone()))
+                .set_scissor(0, std::iter::once(self.scissor.clone()));
+            record_draws(&mut builder, &ordered, &context)?;
+        }
+        drop(blocks);
+
+        for _ in draw_subpass + 1..subpasses {
+            builder.next_subpass(false)?;
+        }
+        builder.end_render_pass()?;
+
+        if let Some((pool, first)) = pass_timestamps {
+            unsafe {
+                builder.write_timestamp(pool.clone(), *first + 3, PipelineStage::BottomOfPipe)?;
+            }
+        }
+
+        // The effect covers the whole target, the scene viewport only limits what was drawn into it
+        if let Some((post, target)) = post {
+            let viewport = ViewportRect::full(self.target.dimensions()).viewport();
+            builder
+                .begin_render_pass(target.framebuffer.clone(), false, vec![ClearValue::None])?
+                .set_viewport(0, std::iter::once(viewport))
+                .bind_pipeline_graphics(post.pipeline.clone())
+                .bind_descriptor_sets(PipelineBindPoint::Graphics, post.pipeline.layout().clone(), 0, target.set.clone())
+                .draw(3, 1, 0, 0)?
+                .end_render_pass()?;
+        }
+
+        if let Some((pool, first)) = &timestamps {
+            unsafe {
+                builder.write_timestamp(pool.clone(), *first + 1, PipelineStage::BottomOfPipe)?;
+            }
+        }
+
+        // Copy the finished image out before it is handed to the presentation engine
+        if let Some(buffer) = &self.capture_buffer {
+            match &self.target {
+                RenderTarget::Swapchain(_, images) => {
+                    let image = images.get(image_num).ok_or(RendererError::Acquire(AcquireError::OutOfDate))?;
+                    builder.copy_image_to_buffer(image.clone(), buffer.clone())?;
+                }
+                RenderTarget::Offscreen(images) => {
+                    builder.copy_image_to_buffer(images[image_num].clone(), buffer.clone())?;
+                }
+            }
+        }
+
+        Ok(Arc::new(builder.build()?))
+    }
+
+    // Clear values for every attachment of the render pass, in attachment order
+    fn clear_values(&self, settings: &FrameSettings) -> Vec<ClearValue> {
+        let mut values = attachment_clear_values(settings, self.clears_color, self.samples, self.depth_format.is_some());
+        if let Some(config) = &self.render_pass_config {
+            // Configured passes are never multisampled, so the extra attachments follow the target
+            values.splice(1..1, config.color_attachments.iter().map(|_| ClearValue::Float([0.0; 4])));
+        }
+        values
+    }
+
+    // Subpass blocks are drawn in
+    fn draw_subpass(&self) -> u32 {
+        self.render_pass_config.as_ref().map_or(0, |config| config.draw_subpass)
+    }
+
+    // Subpasses of the scene render pass
+    fn subpass_count(&self) -> u32 {
+        self.render_pass_config.as_ref().map_or(1, |config| config.resolved_subpasses().len() as u32)
+    }
+
+    // Gives the scene render pass the attachments and subpasses of config, or the single target attachment
+    // for None. Needs framebuffers the renderer built itself, and no multisampling.
+    pub fn set_render_pass_config(&mut self, config: Option<RenderPassConfig>) -> Result<(), RendererError> {
+        if let Some(config) = &config {
+            config.check()?;
+            if self.samples > 1 {
+                return Err(RendererError::InvalidRenderPassConfig("multisampled render passes cannot be configured".to_string()));
+            }
+        }
+        let previous = std::mem::replace(&mut self.render_pass_config, config);
+        let result = self.rebuild_render_pass(self.clears_color);
+        if result.is_err() {
+            self.render_pass_config = previous;
+        }
+        result
+    }
+
+    pub fn render_pass_config(&self) -> Option<&RenderPassConfig> {
+        self.render_pass_config.as_ref()
+    }
+
+    // Copies color attachment index of the most recently rendered frame into CPU memory, in the attachment's
+    // format. Attachment 0 is the target image, see read_pixels.
+    pub fn read_attachment(&mut self, index: usize) -> Result<Vec<u8>, RendererError> {
+        if index == 0 {
+            return self.read_pixels();
+        }
+        let image_num = self.last_image.ok_or(RendererError::NoFrameRendered)?;
+        let images = match &self.post {
+            Some(post) if self.post_enabled() => post.targets.get(image_num).map(|target| &target.attachments),
+            _ => self.attachment_images.get(image_num),
+        };
+        let image = images
+            .and_then(|images| images.get(index - 1))
+            .cloned()
+            .ok_or(RendererError::UnknownAttachment(index))?;
+        let [width, height] = self.target.dimensions();
+        let bytes_per_pixel = image.format().size().unwrap_or(4);
+        let buffer = CpuAccessibleBuffer::from_iter(
+            self.device.clone(),
+            BufferUsage::transfer_destination(),
+            false,
+            (0..width as usize * height as usize * bytes_per_pixel).map(|_| 0u8),
+        )?;
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        builder.copy_image_to_buffer(image, buffer.clone())?;
+        let command_buffer = builder.build()?;
+
+        // The frame must be finished before its attachments can be copied
+        self.wait_idle();
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer)?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+
+        let pixels = buffer.read()?.to_vec();
+        Ok(pixels)
+    }
+
+    // Framebuffers of the scene render pass for images, with the extra color attachments of the render
+    // pass config created for each
+    fn scene_framebuffers<I>(
+        &self,
+        render_pass: &Arc<RenderPass>,
+        images: Vec<I>,
+    ) -> Result<(Vec<Arc<dyn FramebufferAbstract + Send + Sync>>, Vec<Vec<Arc<AttachmentImage>>>), RendererInitError>
+    where
+        I: ImageViewAccess + Clone + Send + Sync + 'static,
+    {
+        let format = self.target.format();
+        let dimensions = self.target.dimensions();
+        let config = match &self.render_pass_config {
+            Some(config) => config,
+            None => {
+                let attachments = images.iter().map(|_| Vec::new()).collect();
+                let framebuffers = build_framebuffers(&self.device, render_pass, images, dimensions, format, self.depth_format, self.samples)?;
+                return Ok((framebuffers, attachments));
+            }
+        };
+        let usage = ImageUsage { color_attachment: true, input_attachment: true, transfer_source: true, ..ImageUsage::none() };
+        images.into_iter().enumerate().map(|(i, image)| {
+            let attachments = config.color_attachments.iter().enumerate().map(|(k, &attachment_format)| {
+                let attachment = AttachmentImage::with_usage(self.device.clone(), dimensions, attachment_format, usage)?;
+                self.set_debug_name(attachment.inner().image, &format!("color attachment {} of framebuffer {}", k + 1, i));
+                Ok(attachment)
+            }).collect::<Result<Vec<_>, ImageCreationError>>()?;
+            // Boxed, as the number of attachments is only known at runtime
+            let mut framebuffer = Framebuffer::start(render_pass.clone()).add(image)?.boxed();
+            for attachment in &attachments {
+                framebuffer = framebuffer.add(attachment.clone())?.boxed();
+            }
+            let framebuffer: Arc<dyn FramebufferAbstract + Send + Sync> = match self.depth_format {
+                Some(depth_format) => Arc::new(framebuffer.add(AttachmentImage::transient(self.device.clone(), dimensions, depth_format)?)?.build()?),
+                None => Arc::new(framebuffer.build()?),
+            };
+            Ok((framebuffer, attachments))
+        }).collect::<Result<Vec<_>, RendererInitError>>().map(|built| built.into_iter().unzip())
+    }
+
+    // Swaps the render pass for one that clears or loads the color attachment, along with the pipeline
+    // and framebuffers that depend on it
+    fn rebuild_render_pass(&mut self, clear_color: bool) -> Result<(), RendererError> {
+        let reconfigure = |e: RendererInitError| RendererError::Reconfigure(Box::new(e));
+        let format = self.target.format();
+        let render_pass = match &self.render_pass_config {
+            Some(config) => create_configured_render_pass(self.device.clone(), format, self.depth_format, config, clear_color),
+            None => create_render_pass(self.device.clone(), format, self.depth_format, self.samples, clear_color),
+        }
+        .map_err(|e| reconfigure(e.into()))?;
+        let (framebuffers, attachment_images) = match &self.target {
+            RenderTarget::Swapchain(_, images) if images.is_empty() => {
+                return Err(RendererError::Reconfigure("the framebuffers were built by the caller and cannot be rebuilt".into()));
+            }
+            RenderTarget::Swapchain(_, images) => self.scene_framebuffers(&render_pass, images.clone()),
+            RenderTarget::Offscreen(images) => self.scene_framebuffers(&render_pass, images.clone()),
+        }
+        .map_err(reconfigure)?;
+        self.pipeline = create_pipeline(&self.device, &render_pass, self.draw_subpass(), self.depth_format.is_some(), PipelineKey::default(), PolygonMode::Fill, self.shader_modules.as_ref(), self.target.manual_gamma())
+            .map_err(reconfigure)?;
+        self.pipelines.lock().unwrap().clear(); // Built for the old render pass, recreated on next use
+        self.render_pass = render_pass;
+        self.framebuffers = framebuffers;
+        self.attachment_images = attachment_images;
+        self.clears_color = clear_color;
+        self.rebuild_post_targets()
+    }
+
+    // Handles swapchain recreation (in case of resizing or updating)
+    // A minimized window leaves the swapchain untouched and keeps the recreation pending until it is restored
+    fn recreate_swapchain(&mut self) -> Result<(), RendererError> {
+        let swapchain = match self.swapchain() {
+            Some(swapchain) => swapchain,
+            None => {
+                self.needs_recreate = false; // Nothing to recreate
+                return Ok(());
+            }
+        };
+        if self.surface_extent().map_or(false, is_zero_extent) {
+            self.needs_recreate = true;
+            return Ok(());
+        }
+        // The surface may support different modes and image counts now, e.g. after moving to another monitor
+        let caps = swapchain.surface().capabilities(self.device.physical_device())
+            .map_err(|e| RendererError::Reconfigure(Box::new(e)))?;
+        let present_mode = if self.present_modes.is_empty() {
+            swapchain.present_mode()
+        } else {
+            choose_present_mode(&self.present_modes, |mode| caps.present_modes.supports(mode))
+        };
+        let image_count = clamp_image_count(self.desired_image_count, caps.min_image_count, caps.max_image_count);
+        let recreate = || {
+            let dimensions: [u32; 2] = swapchain.surface().window().inner_size().into();
+            swapchain.recreate().dimensions(dimensions).present_mode(present_mode).num_images(image_count).build()
+        };
+        let (new_swapchain, new_images) = match recreate() {
+            Ok(recreated) => recreated,
+            // The window was resized again while recreating, retry once with its newest size
+            Err(SwapchainCreationError::UnsupportedDimensions) => recreate()?,
+            Err(e) => return Err(e.into()),
+        };
+        self.adopt_swapchain(new_swapchain, new_images)
+    }
+
+    // Keeps the validation callback alive and, when there is one, names objects from now on along with the
+    // target images that already exist. Names need debug-utils, which create_instance only enables for validation.
+    fn enable_debug_names(&mut self, debug_callback: Option<DebugCallback>) {
+        self.debug_names = debug_callback.is_some();
+        self.debug_callback = debug_callback;
+        self.name_target_images();
+    }
+
+    // Labels a Vulkan object for validation messages and tools like RenderDoc, does nothing without debug names
+    fn set_debug_name<T: VulkanObject + DeviceOwned>(&self, object: &T, name: &str) {
+        if !self.debug_names {
+            return;
+        }
+        if let Ok(name) = CString::new(name) {
+            // A name is only a debugging aid, failing to set one is not worth reporting
+            let _ = self.device.set_object_name(object, &name);
+        }
+    }
+
+    fn name_target_images(&self) {
+        match &self.target {
+            RenderTarget::Swapchain(_, images) => {
+                for (i, image) in images.iter().enumerate() {
+                    self.set_debug_name(image.inner().image, &format!("swapchain image {}", i));
+                }
+            }
+            RenderTarget::Offscreen(images) => {
+                for (i, image) in images.iter().enumerate() {
+                    self.set_debug_name(image.inner().image, &format!("offscreen image {}", i));
+                }
+            }
+        }
+    }
+
+    // Switches to a recreated swapchain. If its framebuffers cannot be built the recreation stays pending.
+    fn adopt_swapchain(&mut self, swapchain: Arc<Swapchain<Window>>, images: Vec<Arc<SwapchainImage<Window>>>) -> Result<(), RendererError> {
+        // The new images are the source of truth for the extent, the window may have changed again since
+        self.target = RenderTarget::Swapchain(swapchain, images.clone());
+        self.name_target_images();
+        self.update_viewport();
+        self.needs_recreate = true;
+        let (framebuffers, attachment_images) = self.create_framebuffers(images)?;
+        self.framebuffers = framebuffers;
+        self.attachment_images = attachment_images;
+        self.rebuild_post_targets()?;
+        self.needs_recreate = false;
+        let mut metadata = self.metadata.lock().unwrap();
+        metadata.image_count = self.framebuffers.len();
+        metadata.break_interval(); // Recreation stalls, which is not a slow frame
+        Ok(())
+    }
+
+    // Present modes the window surface supports, for building a settings menu. Empty when rendering
+    // offscreen or when the surface cannot be queried.
+    pub fn supported_present_modes(&self) -> Vec<PresentMode> {
+        let swapchain = match self.swapchain() {
+            Some(swapchain) => swapchain,
+            None => return Vec::new(),
+        };
+        match swapchain.surface().capabilities(self.device.physical_device()) {
+            Ok(caps) => caps.present_modes.iter().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // Recreates the swapchain with mode, or with Fifo if the surface does not support mode. The mode is
+    // kept for later recreations. Does nothing when rendering offscreen.
+    pub fn set_present_mode(&mut self, mode: PresentMode) -> Result<(), SwapchainCreationError> {
+        let swapchain = match self.swapchain() {
+            Some(swapchain) => swapchain,
+            None => return Ok(()),
+        };
+        let supported = self.supported_present_modes();
+        let chosen = choose_present_mode(&[mode], |candidate| supported.contains(&candidate));
+        if chosen != mode {
+            log::warn!("Present mode {:?} is not supported by the surface, falling back to {:?}", mode, chosen);
+        }
+        self.present_modes = vec![chosen];
+
+        // A minimized window cannot back a swapchain, the mode is picked up once it is restored
+        let dimensions: [u32; 2] = swapchain.surface().window().inner_size().into();
+        if is_zero_extent(dimensions) {
+            self.needs_recreate = true;
+            return Ok(());
+        }
+        let (new_swapchain, new_images) = swapchain.recreate().dimensions(dimensions).present_mode(chosen).build()?;
+        if let Err(e) = self.adopt_swapchain(new_swapchain, new_images) {
+            log::warn!("Render error: {}", e); // Retried before the next frame
+        }
+        Ok(())
+    }
+
+    // Helper function to create framebuffers for new swapchain images
+    fn create_framebuffers(
+        &self,
+        images: Vec<Arc<SwapchainImage<Window>>>,
+    ) -> Result<(Vec<Arc<dyn FramebufferAbstract + Send + Sync>>, Vec<Vec<Arc<AttachmentImage>>>), RendererError> {
+        self.scene_framebuffers(&self.render_pass, images)
+            .map_err(|e| RendererError::Reconfigure(Box::new(e)))
+    }
+
+    // Current size of the window surface, None when rendering offscreen
+    fn surface_extent(&self) -> Option<[u32; 2]> {
+        match &self.target {
+            RenderTarget::Swapchain(swapchain, _) => Some(swapchain.surface().window().inner_size().into()),
+            RenderTarget::Offscreen(_) => None,
+        }
+    }
+
+    // Additional methods for managing shader data and database interactions can be added here
+}
+
+// Nearest-rank percentile of sorted values, None when empty
+fn percentile(sorted: &[Duration], p: f32) -> Option<Duration> {
+    let rank = (p * sorted.len() as f32).ceil() as usize;
+    sorted.get(rank.max(1) - 1).copied()
+}
+
+// A surface without area, e.g. a minimized window, cannot back a swapchain
+fn is_zero_extent(extent: [u32; 2]) -> bool {
+    extent[0] == 0 || extent[1] == 0
+}
+
+// Calls render unless extent reports a surface without area, in which case idle is called instead and
+// nothing touches the swapchain. Offscreen targets have no extent and always render.
+fn render_or_idle<S>(
+    state: &mut S,
+    extent: impl FnOnce(&S) -> Option<[u32; 2]>,
+    idle: impl FnOnce(&mut S),
+    render: impl FnOnce(&mut S) -> Result<(), RendererError>,
+) -> Result<(), RendererError> {
+    if extent(state).map_or(false, is_zero_extent) {
+        idle(state);
+        return Ok(());
+    }
+    render(state)
+}
+
+// Queues to create: one from the graphics family, then one from a transfer-only family if the device
+// has one, so uploads do not compete with rendering
+fn queue_requests(physical: PhysicalDevice, graphics: QueueFamily) -> Vec<(QueueFamily, f32)> {
+    let mut requests = vec![(graphics, 0.5)];
+    let transfer = physical.queue_families()
+        .find(|q| q.explicitly_supports_transfers() && !q.supports_graphics());
+    if let Some(transfer) = transfer {
+        requests.push((transfer, 0.5));
+    }
+    requests
+}
+
+// Budget used unless with_memory_budget is called, 80% of the largest device-local heap so images
+// and other applications keep some room
+fn default_memory_budget(physical: PhysicalDevice) -> u64 {
+    physical.memory_heaps()
+        .filter(|heap| heap.is_device_local())
+        .map(|heap| heap.size() as u64)
+        .max()
+        .map_or(u64::MAX, |size| size / 10 * 8)
+}
+
+// Everything the draws of a frame bind besides the blocks themselves
+struct DrawContext {
+    pipelines: HashMap<PipelineKey, Arc<GraphicsPipeline>>, // Every pipeline the frame's blocks use
+    camera_set: Arc<dyn DescriptorSet + Send + Sync>,
+    material_set: usize,
+    identity_model_set: Arc<dyn DescriptorSet + Send + Sync>, // Bound while transforms are pushed
+    model_pool: Option<CpuBufferPool<Transform>>, // Set when transforms do not fit in push constants
+    preprocessing: bool, // Draw the preprocess output of blocks that have one
+}
+
+// Fewest blocks worth handing to a recording thread of their own
+const MIN_BLOCKS_PER_RECORDING_THREAD: usize = 256;
+
+// Threads to record draws of block_count blocks on, one per available core at most
+fn recording_threads(block_count: usize) -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    (block_count / MIN_BLOCKS_PER_RECORDING_THREAD).clamp(1, cores)
+}
+
+// Records the draws of blocks into a command buffer, binding a pipeline whenever the key changes
+fn record_draws<L>(builder: &mut AutoCommandBufferBuilder<L>, draws: &[(&UploadedBlock, Transform)], context: &DrawContext) -> Result<(), RendererError> {
+    let mut bound: Option<(PipelineKey, Arc<GraphicsPipeline>)> = None;
+    for (block, transform) in draws {
+        let pipeline = match &bound {
+            Some((key, pipeline)) if *key == block.pipeline => pipeline.clone(),
+            _ => {
+                let pipeline = context.pipelines[&block.pipeline].clone();
+                builder
+                    .bind_pipeline_graphics(pipeline.clone())
+                    .bind_descriptor_sets(PipelineBindPoint::Graphics, pipeline.layout().clone(), CAMERA_SET as u32, context.camera_set.clone());
+                bound = Some((block.pipeline, pipeline.clone()));
+                pipeline
+            }
+        };
+        // Each draw gets its own uniform from the pool, the command buffer keeps it alive until the frame is done
+        let model_set: Arc<dyn DescriptorSet + Send + Sync> = match &context.model_pool {
+            Some(pool) => {
+                let layout = pipeline.layout().descriptor_set_layout(MODEL_SET)
+                    .ok_or_else(|| RendererError::DescriptorSet(format!("pipeline layout has no descriptor set {}", MODEL_SET).into()))?;
+                Arc::new(PersistentDescriptorSet::start(layout.clone()).add_buffer(pool.next(*transform)?)?.build()?)
+            }
+            None => context.identity_model_set.clone(),
+        };
+        builder
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                context.material_set as u32,
+                block.material_set.clone(),
+            )
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                MODEL_SET as u32,
+                model_set,
+            )
+            .push_constants(pipeline.layout().clone(), 0, vs::ty::PushConstants { transform: push_transform(context.model_pool.is_none(), transform) })
+            .bind_vertex_buffers(0, (drawn_vertices(block, context.preprocessing), block.instance_buffer.clone()));
+        let instance_count = block.instance_buffer.len() as u32;
+        match &block.index_buffer {
+            Some(index_buffer) => {
+                builder
+                    .bind_index_buffer(index_buffer.clone())
+                    .draw_indexed(index_buffer.len() as u32, instance_count, 0, 0, 0)?;
+            }
+            None => {
+                builder.draw(drawn_vertices(block, context.preprocessing).len() as u32, instance_count, 0, 0)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Records the draws of blocks into a secondary command buffer that continues subpass. Dynamic state is
+// not inherited from the primary command buffer, so the viewport and scissor are set again.
+fn record_secondary(device: &Arc<Device>, queue: &Arc<Queue>, subpass: Subpass, (viewport, scissor): (&Viewport, &Scissor),
+                    draws: &[(&UploadedBlock, Transform)], context: &DrawContext) -> Result<SecondaryAutoCommandBuffer, RendererError> {
+    let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+        device.clone(),
+        queue.family(),
+        CommandBufferUsage::OneTimeSubmit,
+        subpass,
+    )?;
+    builder
+        .set_viewport(0, std::iter::once(viewport.clone()))
+        .set_scissor(0, std::iter::once(scissor.clone()));
+    record_draws(&mut builder, draws, context)?;
+    Ok(builder.build()?)
+}
+
+// Reads the frames of the database at db_path into one block per frame. A missing database or one
+// without frames is an error rather than an empty scene.
+fn partition_database(db_path: &str) -> Result<PartitionedData, RendererError> {
+    let data = shader_partition_compressor::partition_data(db_path).map_err(|e| match e {
+        PartitionError::Open(e) => RendererError::DatabaseOpen(e),
+        e => RendererError::Partition(e),
+    })?;
+    if data.blocks.is_empty() {
+        return Err(RendererError::EmptyPartition);
+    }
+    Ok(data)
+}
+
+// Body of the load_vertex_data_async thread. Frames are read in batches and each block is passed to stage,
+// which checks it and writes its staging memory, and sent as soon as it is complete. The bounded channel
+// keeps the thread from holding more than a few blocks, and the thread stops early once the load is
+// cancelled or the renderer is gone.
+fn partition_in_background<T, S>(db_path: &str, blocks: SyncSender<Result<T, RendererError>>, cancelled: &AtomicBool, stage: S)
+where
+    S: Fn(ShaderBlock) -> Result<T, RendererError>,
+{
+    let db = match DatabaseManager::open_existing(db_path) {
+        Ok(db) => db,
+        Err(e) => {
+            let _ = blocks.send(Err(RendererError::DatabaseOpen(e)));
+            return;
+        }
+    };
+    let mut sent = false;
+    for block in shader_partition_compressor::partition_metrics_iter(&db, FrameRange::default()) {
+        if cancelled.load(Ordering::Acquire) {
+            return;
+        }
+        let staged = block.map_err(RendererError::from).and_then(&stage);
+        let failed = staged.is_err();
+        if blocks.send(staged).is_err() || failed {
+            return;
+        }
+        sent = true;
+    }
+    if !sent {
+        let _ = blocks.send(Err(RendererError::EmptyPartition));
+    }
+}
+
+// Indices past the end of the vertex buffer would make the GPU read whatever follows it
+fn validate_indices(block: &ShaderBlock) -> Result<(), RendererError> {
+    match block.indices.iter().find(|&&index| index as usize >= block.vertices.len()) {
+        Some(index) => Err(RendererError::MalformedGeometry(
+            format!("index {} is out of range for {} vertices", index, block.vertices.len())
+        )),
+        None => Ok(()),
+    }
+}
+
+// Features enabled on devices the renderer creates whenever the device supports them
+fn optional_features(physical: PhysicalDevice) -> Features {
+    Features {
+        fill_mode_non_solid: physical.supported_features().fill_mode_non_solid, // Line and Point polygon modes
+        ..Features::none()
+    }
+}
+
+// Vertex buffer a block is drawn from, the preprocess output while the pass runs
+fn drawn_vertices(block: &UploadedBlock, preprocessing: bool) -> GpuArray<Vertex> {
+    match &block.preprocess {
+        Some(input) if preprocessing => input.vertices.clone(),
+        _ => block.vertex_buffer.clone(),
+    }
+}
+
+// Workgroups needed for one invocation per vertex
+fn workgroup_count(vertex_count: u32, workgroup_size: u32) -> u32 {
+    (vertex_count + workgroup_size - 1) / workgroup_size
+}
+
+// Builds the preprocess pipeline from module, or from the default cs shader
+fn create_preprocess(device: &Arc<Device>, module: Option<Arc<ShaderModule>>, workgroup_size: u32) -> Result<Preprocess, RendererError> {
+    let reconfigure = |e: ComputePipelineCreationError| RendererError::Reconfigure(Box::new(e));
+    let cs = cs::Shader::load(device.clone()).map_err(|e| RendererError::Reconfigure(Box::new(e)))?;
+    let spec = cs::SpecializationConstants { workgroup_size };
+    let pipeline = match &module {
+        Some(module) => {
+            let entry = unsafe { compute_entry_point_like(module, cs.main_entry_point()) };
+            ComputePipeline::new(device.clone(), &entry, &spec, None).map_err(reconfigure)?
+        }
+        None => ComputePipeline::new(device.clone(), &cs.main_entry_point(), &spec, None).map_err(reconfigure)?,
+    };
+    Ok(Preprocess { module, pipeline: Arc::new(pipeline), workgroup_size })
+}
+
+// Transform pushed for a draw, identity when the block's model uniform carries it instead. Scene graph
+// transforms are lost then, which only happens on devices below the push constant size Vulkan guarantees.
+fn push_transform(push_transforms: bool, transform: &Transform) -> Transform {
+    if push_transforms {
+        *transform
+    } else {
+        IDENTITY_TRANSFORM
+    }
+}
+
+// Queries per frame slot: frame start and end, then render pass start and end when profiling
+const TIMESTAMPS_PER_SLOT: u32 = 4;
+
+// Creates a timestamp query pool for every frame slot, when the queue supports timestamps
+fn create_timestamp_pool(device: &Arc<Device>, queue: &Arc<Queue>, slots: usize) -> Option<Arc<QueryPool>> {
+    queue.family().timestamp_valid_bits()?;
+    QueryPool::new(device.clone(), QueryType::Timestamp, slots as u32 * TIMESTAMPS_PER_SLOT).ok()
+}
+
+// Ticks between the timestamps at first and first + 1, None until both have been written
+fn timestamp_delta(pool: &QueryPool, first: u32) -> Option<u64> {
+    let mut ticks = [0u64; 2];
+    let available = pool.queries_range(first..first + 2)?
+        .get_results(&mut ticks, QueryResultFlags { wait: false, with_availability: false, partial: false })
+        .ok()?;
+    if available {
+        Some(ticks[1].saturating_sub(ticks[0]))
+    } else {
+        None
+    }
+}
+
+// Converts a timestamp delta to milliseconds, period is the device's nanoseconds per tick
+fn ticks_to_millis(ticks: u64, period: f32) -> f64 {
+    ticks as f64 * period as f64 / 1_000_000.0
+}
+
+// Converts a timestamp delta to nanoseconds, period is the device's nanoseconds per tick
+fn ticks_to_nanos(ticks: u64, period: f32) -> u64 {
+    (ticks as f64 * period as f64) as u64
+}
+
+// Converts a timestamp delta to microseconds, period is the device's nanoseconds per tick
+fn ticks_to_micros(ticks: u64, period: f32) -> u64 {
+    (ticks as f64 * period as f64 / 1000.0) as u64
+}
+
+// Swapchain image usage, readable by transfers when the surface allows it so frames can be captured
+fn swapchain_usage(supported: ImageUsage) -> ImageUsage {
+    ImageUsage {
+        color_attachment: true,
+        transfer_source: supported.transfer_source,
+        ..ImageUsage::none()
+    }
+}
+
+// Prefers an 8-bit sRGB surface format so shader output is gamma corrected on present, then
+// an 8-bit UNORM one (see needs_manual_gamma), otherwise takes whatever the surface lists first
+fn choose_surface_format(supported: &[(Format, ColorSpace)]) -> Option<(Format, ColorSpace)> {
+    let find = |formats: &[Format]| supported.iter()
+        .find(|(format, color_space)| formats.contains(format) && *color_space == ColorSpace::SrgbNonLinear);
+    find(&[Format::B8G8R8A8Srgb, Format::R8G8B8A8Srgb])
+        .or_else(|| find(&[Format::B8G8R8A8Unorm, Format::R8G8B8A8Unorm]))
+        .or_else(|| supported.first())
+        .copied()
+}
+
+// Whether a presented format stores linear shader output as is, so the fragment shaders have to
+// encode it to sRGB themselves
+fn needs_manual_gamma(format: Format) -> bool {
+    matches!(format, Format::B8G8R8A8Unorm | Format::R8G8B8A8Unorm | Format::A8B8G8R8UnormPack32)
+}
+
+// Converts 8-bit color pixels to RGBA8, swizzling BGRA formats
+fn to_rgba8(format: Format, mut bytes: Vec<u8>) -> Result<Vec<u8>, CaptureError> {
+    match format {
+        Format::R8G8B8A8Unorm | Format::R8G8B8A8Srgb => Ok(bytes),
+        Format::B8G8R8A8Unorm | Format::B8G8R8A8Srgb => {
+            for pixel in bytes.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+            Ok(bytes)
+        }
+        other => Err(CaptureError::UnsupportedFormat(other)),
+    }
+}
+
+// Width over height, treating a zero height (minimized window) as square
+fn aspect_ratio(dimensions: [u32; 2]) -> f32 {
+    if dimensions[1] == 0 {
+        1.0
+    } else {
+        dimensions[0] as f32 / dimensions[1] as f32
+    }
+}
+
+// Multiplies two column-major matrices, a * b
+fn mat4_mul(a: &Transform, b: &Transform) -> Transform {
+    let mut out = [[0.0; 4]; 4];
+    for col in 0..4 {
+        for row in 0..4 {
+            out[col][row] = (0..4).map(|k| a[k][row] * b[col][k]).sum();
+        }
+    }
+    out
+}
+
+// Axis-aligned box around vertex positions
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bounds {
+    min: [f32; 3],
+    max: [f32; 3],
+}
+
+impl Bounds {
+    // None when there are no vertices
+    fn of(vertices: &[Vertex]) -> Option<Self> {
+        Self::of_points(vertices.iter().map(|vertex| vertex.position))
+    }
+
+    fn of_points(mut points: impl Iterator<Item = [f32; 3]>) -> Option<Self> {
+        let first = points.next()?;
+        let mut bounds = Bounds { min: first, max: first };
+        for point in points {
+            for axis in 0..3 {
+                bounds.min[axis] = bounds.min[axis].min(point[axis]);
+                bounds.max[axis] = bounds.max[axis].max(point[axis]);
+            }
+        }
+        Some(bounds)
+    }
+
+    // Box around the copies of self moved by every instance transform, self when there are none
+    fn instanced(self, instances: &[Transform]) -> Self {
+        let corners = instances.iter().flat_map(|instance| {
+            self.corners().map(move |corner| {
+                let [x, y, z, _] = transform_point(instance, corner);
+                [x, y, z]
+            })
+        });
+        Self::of_points(corners).unwrap_or(self)
+    }
+
+    // Fraction of ray, from origin, at which it enters the box, 0 when origin is inside. None when the
+    // segment from origin to origin + ray misses the box.
+    fn ray_entry(&self, origin: [f32; 3], ray: [f32; 3]) -> Option<f32> {
+        let (mut entry, mut exit) = (0.0f32, 1.0f32);
+        for axis in 0..3 {
+            if ray[axis] == 0.0 {
+                // Parallel to the slab, inside it everywhere or nowhere
+                if origin[axis] < self.min[axis] || origin[axis] > self.max[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let a = (self.min[axis] - origin[axis]) / ray[axis];
+            let b = (self.max[axis] - origin[axis]) / ray[axis];
+            entry = entry.max(a.min(b));
+            exit = exit.min(a.max(b));
+        }
+        if entry <= exit {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
+    fn corners(&self) -> impl Iterator<Item = [f32; 3]> + '_ {
+        (0..8).map(move |i| {
+            let pick = |axis: usize| if i & (1 << axis) == 0 { self.min[axis] } else { self.max[axis] };
+            [pick(0), pick(1), pick(2)]
+        })
+    }
+}
+
+// The six planes bounding what a view-projection matrix maps into Vulkan clip space, where x and y
+// lie in -w..w and depth in 0..w. Points p inside satisfy dot(plane, (p, 1)) >= 0 for every plane.
+struct Frustum {
+    planes: [[f32; 4]; 6],
+}
+
+impl Frustum {
+    fn from_view_projection(m: &Transform) -> Self {
+        let row = |r: usize| [m[0][r], m[1][r], m[2][r], m[3][r]];
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let add = |a: [f32; 4], b: [f32; 4]| [a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]];
+        let sub = |a: [f32; 4], b: [f32; 4]| [a[0] - b[0], a[1] - b[1], a[2] - b[2], a[3] - b[3]];
+        Frustum { planes: [add(w, x), sub(w, x), add(w, y), sub(w, y), z, sub(w, z)] }
+    }
+
+    // Whether bounds, moved by transform, lie entirely on the outer side of one of the planes
+    fn culls(&self, bounds: &Bounds, transform: &Transform) -> bool {
+        let corners: Vec<[f32; 4]> = bounds.corners().map(|corner| transform_point(transform, corner)).collect();
+        self.planes.iter().any(|plane| {
+            corners.iter().all(|c| plane[0] * c[0] + plane[1] * c[1] + plane[2] * c[2] + plane[3] * c[3] < 0.0)
+        })
+    }
+}
+
+// Point (x, y, z, 1) multiplied by a column-major transform
+fn transform_point(transform: &Transform, [x, y, z]: [f32; 3]) -> [f32; 4] {
+    let mut out = [0.0; 4];
+    for (row, value) in out.iter_mut().enumerate() {
+        *value = transform[0][row] * x + transform[1][row] * y + transform[2][row] * z + transform[3][row];
+    }
+    out
+}
+
+// Inverse of a column-major transform by cofactor expansion, None when it is singular
+fn mat4_inverse(m: &Transform) -> Option<Transform> {
+    let mut a = [0.0f32; 16];
+    for (value, &m) in a.iter_mut().zip(m.iter().flatten()) {
+        *value = m;
+    }
+    let mut inv = [0.0f32; 16];
+    inv[0] = a[5] * a[10] * a[15] - a[5] * a[11] * a[14] - a[9] * a[6] * a[15] + a[9] * a[7] * a[14] + a[13] * a[6] * a[11] - a[13] * a[7] * a[10];
+    inv[4] = -a[4] * a[10] * a[15] + a[4] * a[11] * a[14] + a[8] * a[6] * a[15] - a[8] * a[7] * a[14] - a[12] * a[6] * a[11] + a[12] * a[7] * a[10];
+    inv[8] = a[4] * a[9] * a[15] - a[4] * a[11] * a[13] - a[8] * a[5] * a[15] + a[8] * a[7] * a[13] + a[12] * a[5] * a[11] - a[12] * a[7] * a[9];
+    inv[12] = -a[4] * a[9] * a[14] + a[4] * a[10] * a[13] + a[8] * a[5] * a[14] - a[8] * a[6] * a[13] - a[12] * a[5] * a[10] + a[12] * a[6] * a[9];
+    inv[1] = -a[1] * a[10] * a[15] + a[1] * a[11] * a[14] + a[9] * a[2] * a[15] - a[9] * a[3] * a[14] - a[13] * a[2] * a[11] + a[13] * a[3] * a[10];
+    inv[5] = a[0] * a[10] * a[15] - a[0] * a[11] * a[14] - a[8] * a[2] * a[15] + a[8] * a[3] * a[14] + a[12] * a[2] * a[11] - a[12] * a[3] * a[10];
+    inv[9] = -a[0] * a[9] * a[15] + a[0] * a[11] * a[13] + a[8] * a[1] * a[15] - a[8] * a[3] * a[13] - a[12] * a[1] * a[11] + a[12] * a[3] * a[9];
+    inv[13] = a[0] * a[9] * a[14] - a[0] * a[10] * a[13] - a[8] * a[1] * a[14] + a[8] * a[2] * a[13] + a[12] * a[1] * a[10] - a[12] * a[2] * a[9];
+    inv[2] = a[1] * a[6] * a[15] - a[1] * a[7] * a[14] - a[5] * a[2] * a[15] + a[5] * a[3] * a[14] + a[13] * a[2] * a[7] - a[13] * a[3] * a[6];
+    inv[6] = -a[0] * a[6] * a[15] + a[0] * a[7] * a[14] + a[4] * a[2] * a[15] - a[4] * a[3] * a[14] - a[12] * a[2] * a[7] + a[12] * a[3] * a[6];
+    inv[10] = a[0] * a[5] * a[15] - a[0] * a[7] * a[13] - a[4] * a[1] * a[15] + a[4] * a[3] * a[13] + a[12] * a[1] * a[7] - a[12] * a[3] * a[5];
+    inv[14] = -a[0] * a[5] * a[14] + a[0] * a[6] * a[13] + a[4] * a[1] * a[14] - a[4] * a[2] * a[13] - a[12] * a[1] * a[6] + a[12] * a[2] * a[5];
+    inv[3] = -a[1] * a[6] * a[11] + a[1] * a[7] * a[10] + a[5] * a[2] * a[11] - a[5] * a[3] * a[10] - a[9] * a[2] * a[7] + a[9] * a[3] * a[6];
+    inv[7] = a[0] * a[6] * a[11] - a[0] * a[7] * a[10] - a[4] * a[2] * a[11] + a[4] * a[3] * a[10] + a[8] * a[2] * a[7] - a[8] * a[3] * a[6];
+    inv[11] = -a[0] * a[5] * a[11] + a[0] * a[7] * a[9] + a[4] * a[1] * a[11] - a[4] * a[3] * a[9] - a[8] * a[1] * a[7] + a[8] * a[3] * a[5];
+    inv[15] = a[0] * a[5] * a[10] - a[0] * a[6] * a[9] - a[4] * a[1] * a[10] + a[4] * a[2] * a[9] + a[8] * a[1] * a[6] - a[8] * a[2] * a[5];
+
+    let det = a[0] * inv[0] + a[1] * inv[4] + a[2] * inv[8] + a[3] * inv[12];
+    if det == 0.0 || !det.is_finite() {
+        return None;
+    }
+    let mut out = [[0.0; 4]; 4];
+    for (i, value) in inv.iter().enumerate() {
+        out[i / 4][i % 4] = value / det;
+    }
+    Some(out)
+}
+
+fn sub3(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot3(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross3(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize3(v: [f32; 3]) -> [f32; 3] {
+    let len = dot3(v, v).sqrt();
+    if len == 0.0 {
+        v
+    } else {
+        [v[0] / len, v[1] / len, v[2] / len]
+    }
+}
+
+// Right-handed view matrix looking from eye towards target
+fn look_at(eye: [f32; 3], target: [f32; 3], up: [f32; 3]) -> Transform {
+    let f = normalize3(sub3(target, eye));
+    let s = normalize3(cross3(f, up));
+    let u = cross3(s, f);
+    [
+        [s[0], u[0], -f[0], 0.0],
+        [s[1], u[1], -f[1], 0.0],
+        [s[2], u[2], -f[2], 0.0],
+        [-dot3(s, eye), -dot3(u, eye), dot3(f, eye), 1.0],
+    ]
+}
+
+// Perspective projection for Vulkan clip space, with y pointing down and depth in 0..1
+fn perspective(fov_y: f32, aspect_ratio: f32, near: f32, far: f32) -> Transform {
+    let f = 1.0 / (fov_y / 2.0).tan();
+    [
+        [f / aspect_ratio, 0.0, 0.0, 0.0],
+        [0.0, -f, 0.0, 0.0],
+        [0.0, 0.0, far / (near - far), -1.0],
+        [0.0, 0.0, near * far / (near - far), 0.0],
+    ]
+}
+
+// Orthographic projection for Vulkan clip space, with y pointing down and depth in 0..1
+fn orthographic(height: f32, aspect_ratio: f32, near: f32, far: f32) -> Transform {
+    let width = height * aspect_ratio;
+    [
+        [2.0 / width, 0.0, 0.0, 0.0],
+        [0.0, -2.0 / height, 0.0, 0.0],
+        [0.0, 0.0, 1.0 / (near - far), 0.0],
+        [0.0, 0.0, near / (near - far), 1.0],
+    ]
+}
+
+// Lays out material data as the material uniform, missing components take their DEFAULT_MATERIAL value
+fn material_uniform(material_data: &[f32]) -> Vec<f32> {
+    let mut uniform = DEFAULT_MATERIAL.to_vec();
+    uniform.resize(DEFAULT_MATERIAL.len().max(material_data.len()), 0.0);
+    uniform[..material_data.len()].copy_from_slice(material_data);
+    uniform
+}
+
+// Returns the framebuffer that wraps the acquired swapchain image
+fn framebuffer_for_image<F: Clone>(framebuffers: &[F], image_num: usize) -> Option<F> {
+    framebuffers.get(image_num).cloned()
+}
+
+// Creates one framebuffer per swapchain image, each with its own depth and multisampled color
+// images when depth or MSAA is enabled
+fn build_framebuffers<I>(
+    device: &Arc<Device>,
+    render_pass: &Arc<RenderPass>,
+    images: Vec<I>,
+    dimensions: [u32; 2],
+    color_format: Format,
+    depth_format: Option<Format>,
+    samples: u32,
+) -> Result<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>, RendererInitError>
+where
+    I: ImageViewAccess + Clone + Send + Sync + 'static,
+{
+    images.into_iter().map(|image| {
+        let framebuffer = match (depth_format, samples > 1) {
+            (Some(format), true) => {
+                let intermediary = AttachmentImage::transient_multisampled(device.clone(), dimensions, samples, color_format)?;
+                let depth = AttachmentImage::transient_multisampled(device.clone(), dimensions, samples, format)?;
+                Arc::new(
+                    Framebuffer::start(render_pass.clone())
+                        .add(intermediary)?
+                        .add(image.clone())?
+                        .add(depth)?
+                        .build()?
+                ) as Arc<dyn FramebufferAbstract + Send + Sync>
+            }
+            (None, true) => {
+                let intermediary = AttachmentImage::transient_multisampled(device.clone(), dimensions, samples, color_format)?;
+                Arc::new(
+                    Framebuffer::start(render_pass.clone())
+                        .add(intermediary)?
+                        .add(image.clone())?
+                        .build()?
+                ) as Arc<dyn FramebufferAbstract + Send + Sync>
+            }
+            (Some(format), false) => {
+                let depth = AttachmentImage::transient(device.clone(), dimensions, format)?;
+                Arc::new(
+                    Framebuffer::start(render_pass.clone())
+                        .add(image.clone())?
+                        .add(depth)?
+                        .build()?
+                ) as Arc<dyn FramebufferAbstract + Send + Sync>
+            }
+            (None, false) => Arc::new(
+                Framebuffer::start(render_pass.clone())
+                    .add(image.clone())?
+                    .build()?
+            ) as Arc<dyn FramebufferAbstract + Send + Sync>,
+        };
+        Ok(framebuffer)
+    }).collect()
+}
+
+// Loads the default shaders and builds the graphics pipeline for a subpass of the render pass
+#[allow(clippy::too_many_arguments)]
+fn create_pipeline(
+    device: &Arc<Device>,
+    render_pass: &Arc<RenderPass>,
+    subpass: u32,
+    depth: bool,
+    key: PipelineKey,
+    polygon_mode: PolygonMode,
+    modules: Option<&ShaderModules>,
+    manual_gamma: bool,
+) -> Result<Arc<GraphicsPipeline>, RendererInitError> {
+    let vs = vs::Shader::load(device.clone()).map_err(RendererInitError::ShaderLoad)?;
+    let vs_entry = match modules {
+        Some(modules) => unsafe { entry_point_like(&modules.vertex, vs.main_entry_point()) },
+        None => vs.main_entry_point(),
+    };
+    let depth_stencil = match (depth, key.blend) {
+        (false, _) => DepthStencil::disabled(),
+        (true, BlendMode::Opaque) => DepthStencil::simple_depth_test(),
+        // Blended blocks are hidden behind opaque ones but do not hide each other
+        (true, BlendMode::AlphaBlend) => DepthStencil { depth_write: false, ..DepthStencil::simple_depth_test() },
+    };
+
+    let subpass = Subpass::from(render_pass.clone(), subpass).unwrap();
+    let normals = subpass.num_color_attachments() > 1;
+
+    // Declared up front so the entry points can borrow them
+    let fs;
+    let fs_lit;
+    let fs_entry = match (key.shaders, modules) {
+        (ShaderKind::Unlit, Some(modules)) => {
+            fs = fs::Shader::load(device.clone()).map_err(RendererInitError::ShaderLoad)?;
+            unsafe { entry_point_like(&modules.fragment, fs.main_entry_point()) }
+        }
+        (ShaderKind::Unlit, None) => {
+            fs = fs::Shader::load(device.clone()).map_err(RendererInitError::ShaderLoad)?;
+            fs.main_entry_point()
+        }
+        (ShaderKind::Lit, _) => {
+            fs_lit = fs_lit::Shader::load(device.clone()).map_err(RendererInitError::ShaderLoad)?;
+            fs_lit.main_entry_point()
+        }
+    };
+
+    let builder = GraphicsPipeline::start()
+        .vertex_input(BuffersDefinition::new().vertex::<Vertex>().instance::<InstanceData>())
+        .vertex_shader(vs_entry, ())
+        .triangle_list()
+        .viewports_scissors_dynamic(1)
+        .depth_stencil(depth_stencil)
+        // All fragment shaders declare the same constants. Custom shaders only write the color output.
+        .fragment_shader(fs_entry, fs::SpecializationConstants {
+            manual_gamma: manual_gamma as u32,
+            write_normals: (normals && modules.is_none()) as u32,
+        })
+        .render_pass(subpass);
+    let builder = match key.cull {
+        CullMode::None => builder.cull_mode_disabled(),
+        CullMode::Back => builder.cull_mode_back(),
+        CullMode::Front => builder.cull_mode_front(),
+    };
+    let builder = match key.blend {
+        BlendMode::Opaque => builder.blend_pass_through(),
+        BlendMode::AlphaBlend => builder.blend_alpha_blending(),
+    };
+    let builder = match polygon_mode {
+        PolygonMode::Fill => builder.polygon_mode_fill(),
+        PolygonMode::Line => builder.polygon_mode_line(),
+        PolygonMode::Point => builder.polygon_mode_point(),
+    };
+    Ok(Arc::new(builder.build(device.clone())?))
+}
+
+// Builds the pipeline of the post-processing pass, running module instead of post_fs when given
+fn create_post_pipeline(
+    device: &Arc<Device>,
+    render_pass: &Arc<RenderPass>,
+    module: Option<&Arc<ShaderModule>>,
+) -> Result<Arc<GraphicsPipeline>, RendererInitError> {
+    let vs = post_vs::Shader::load(device.clone()).map_err(RendererInitError::ShaderLoad)?;
+    let fs = post_fs::Shader::load(device.clone()).map_err(RendererInitError::ShaderLoad)?;
+    let fs_entry = match module {
+        Some(module) => unsafe { entry_point_like(module, fs.main_entry_point()) },
+        None => fs.main_entry_point(),
+    };
+    let pipeline = GraphicsPipeline::start()
+        .vertex_input(BuffersDefinition::new())
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs_entry, ())
+        .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+        .build(device.clone())?;
+    Ok(Arc::new(pipeline))
+}
+
+// Entry point "main" of module, described by the reflection data of a built-in shader.
+// Unsafe because module must actually have that interface, as check_custom_shader verifies on load.
+unsafe fn entry_point_like<'a>(module: &'a ShaderModule, like: GraphicsEntryPoint) -> GraphicsEntryPoint<'a> {
+    module.graphics_entry_point(
+        CStr::from_bytes_with_nul_unchecked(b"main\0"),
+        like.descriptor_set_layout_descs().iter().cloned(),
+        *like.push_constant_range(),
+        // Constant ids the module does not declare are ignored by the driver, the ones it does have the
+        // built-in's types
+        like.spec_constants(),
+        like.input().clone(),
+        like.output().clone(),
+        like.ty(),
+    )
+}
+
+// Shader modules for source, None for the built-in shaders. Only GLSL sources use the cache.
+#[cfg_attr(not(feature = "glsl"), allow(unused_variables))]
+fn load_shader_source(device: &Arc<Device>, source: &ShaderSource, cache: &mut ShaderCache) -> Result<Option<ShaderModules>, RendererError> {
+    let spirv_module = |builtin: BuiltinShader, spirv: &[u8]| {
+        if !is_spirv(spirv) {
+            return Err(RendererError::ShaderCompile(format!("the {} shader is not a SPIR-V module", builtin.name())));
+        }
+        check_custom_shader(device, spirv, builtin)?;
+        unsafe { ShaderModule::new(device.clone(), spirv) }.map_err(|e| RendererError::Reconfigure(Box::new(e)))
+    };
+    match source {
+        ShaderSource::Builtin => Ok(None),
+        ShaderSource::Spirv { vertex, fragment } => Ok(Some(ShaderModules {
+            vertex: spirv_module(BuiltinShader::Vertex, vertex)?,
+            fragment: spirv_module(BuiltinShader::Fragment, fragment)?,
+        })),
+        #[cfg(feature = "glsl")]
+        ShaderSource::Glsl { vertex, fragment } => {
+            // Compiled modules are checked like SPIR-V ones, a GLSL shader can declare any interface
+            let bytes = |words: Vec<u32>| -> Vec<u8> { words.iter().flat_map(|word| word.to_le_bytes()).collect() };
+            Ok(Some(ShaderModules {
+                vertex: spirv_module(BuiltinShader::Vertex, &bytes(compile_glsl(cache, vertex, shaderc::ShaderKind::Vertex, "vertex.glsl")?))?,
+                fragment: spirv_module(BuiltinShader::Fragment, &bytes(compile_glsl(cache, fragment, shaderc::ShaderKind::Fragment, "fragment.glsl")?))?,
+            }))
+        }
+    }
+}
+
+// Compiles GLSL to SPIR-V words, or returns them from the cache when the same source was compiled before
+#[cfg(feature = "glsl")]
+fn compile_glsl(cache: &mut ShaderCache, source: &str, kind: shaderc::ShaderKind, file_name: &str) -> Result<Vec<u32>, RendererError> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    file_name.hash(&mut hasher); // Stands in for the stage
+    source.hash(&mut hasher);
+    let key = hasher.finish();
+    if let Some(words) = cache.compiled.get(&key) {
+        return Ok(words.clone());
+    }
+
+    let mut compiler = shaderc::Compiler::new()
+        .ok_or_else(|| RendererError::ShaderCompile("the shaderc compiler could not be initialized".to_string()))?;
+    let artifact = compiler.compile_into_spirv(source, kind, file_name, "main", None)
+        .map_err(|e| RendererError::ShaderCompile(e.to_string()))?;
+    let words = artifact.as_binary().to_vec();
+    cache.compiled.insert(key, words.clone());
+    Ok(words)
+}
+
+// Compute entry point "main" of module, described by the reflection data of the default cs shader.
+// Unsafe because module must actually have that interface, as check_custom_shader verifies on load.
+unsafe fn compute_entry_point_like<'a>(module: &'a ShaderModule, like: ComputeEntryPoint) -> ComputeEntryPoint<'a> {
+    module.compute_entry_point(
+        CStr::from_bytes_with_nul_unchecked(b"main\0"),
+        like.descriptor_set_layout_descs().iter().cloned(),
+        *like.push_constant_range(),
+        <cs::SpecializationConstants as SpecializationConstants>::descriptors(),
+    )
+}
+
+// Execution models of OpEntryPoint, the stage an entry point runs in
+const SPIRV_VERTEX: u32 = 0;
+const SPIRV_FRAGMENT: u32 = 4;
+const SPIRV_GL_COMPUTE: u32 = 5;
+
+// Storage classes of OpVariable and OpTypePointer
+const SPIRV_UNIFORM_CONSTANT: u32 = 0;
+const SPIRV_INPUT: u32 = 1;
+const SPIRV_UNIFORM: u32 = 2;
+const SPIRV_OUTPUT: u32 = 3;
+const SPIRV_PUSH_CONSTANT: u32 = 9;
+const SPIRV_STORAGE_BUFFER: u32 = 12;
+
+// Decorations of OpDecorate and OpMemberDecorate
+const SPIRV_SPEC_ID: u32 = 1;
+const SPIRV_BUFFER_BLOCK: u32 = 3;
+const SPIRV_ARRAY_STRIDE: u32 = 6;
+const SPIRV_MATRIX_STRIDE: u32 = 7;
+const SPIRV_LOCATION: u32 = 30;
+const SPIRV_BINDING: u32 = 33;
+const SPIRV_DESCRIPTOR_SET: u32 = 34;
+const SPIRV_OFFSET: u32 = 35;
+
+// Type of a specialization constant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpecConstantKind {
+    Bool,
+    Int,   // 32-bit signed
+    Uint,  // 32-bit unsigned
+    Float, // 32-bit
+    Other, // Any other width
+}
+
+// What a descriptor binding holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DescriptorKind {
+    UniformBuffer,
+    StorageBuffer,
+    SampledImage, // Combined image and sampler
+    Image,
+    Sampler,
+    Other, // Texel buffers and input attachments, which no built-in shader binds
+}
+
+// The parts of an entry point that a pipeline built from a built-in shader's reflection data relies on.
+// Locations are expanded, a mat4 input takes four vec4 locations.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct EntryInterface {
+    inputs: BTreeMap<u32, Format>,
+    outputs: BTreeMap<u32, Format>,
+    descriptors: BTreeMap<(u32, u32), DescriptorKind>, // By set and binding
+    push_constant_size: u32, // Bytes from the start of the push constant range to the end of the last member
+    spec_constants: BTreeMap<u32, SpecConstantKind>, // By constant_id
+}
+
+impl EntryInterface {
+    // Interface of a built-in graphics shader, spec_constants being the constants the renderer sets for it
+    fn graphics(like: &GraphicsEntryPoint, spec_constants: &[(u32, SpecConstantKind)]) -> Self {
+        EntryInterface {
+            inputs: interface_locations(like.input()),
+            outputs: interface_locations(like.output()),
+            descriptors: layout_descriptors(like.descriptor_set_layout_descs()),
+            push_constant_size: like.push_constant_range().as_ref().map_or(0, |range| (range.offset + range.size) as u32),
+            spec_constants: spec_constants.iter().copied().collect(),
+        }
+    }
+
+    // Interface of a built-in compute shader, which has no location inputs or outputs
+    fn compute(like: &ComputeEntryPoint, spec_constants: &[(u32, SpecConstantKind)]) -> Self {
+        EntryInterface {
+            descriptors: layout_descriptors(like.descriptor_set_layout_descs()),
+            push_constant_size: like.push_constant_range().as_ref().map_or(0, |range| (range.offset + range.size) as u32),
+            spec_constants: spec_constants.iter().copied().collect(),
+            ..EntryInterface::default()
+        }
+    }
+
+    // Reflects the "main" entry point of spirv with the given execution model. Only what check compares
+    // is read, everything else is left to the driver.
+    fn reflect(spirv: &[u8], model: u32) -> Result<Self, String> {
+        const MAGIC: u32 = 0x0723_0203;
+        if !is_spirv(spirv) {
+            return Err("not a SPIR-V module".to_string());
+        }
+        let mut words: Vec<u32> = spirv.chunks_exact(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])).collect();
+        if words[0] != MAGIC {
+            words.iter_mut().for_each(|word| *word = word.swap_bytes()); // Big-endian module
+        }
+        let mut module = SpirvDeclarations::default();
+        let mut i = 5; // Past the header
+        while i < words.len() {
+            let count = (words[i] >> 16) as usize;
+            if count == 0 || i + count > words.len() {
+                return Err(format!("malformed instruction at word {}", i));
+            }
+            module.read(words[i] & 0xffff, &words[i + 1..i + count]);
+            i += count;
+        }
+        module.entry_interface(model)
+    }
+
+    // Checks that custom, reflected from a module standing in for the shader self describes, declares
+    // every location, binding and specialization constant it uses the way self does
+    fn check(&self, custom: &EntryInterface) -> Result<(), String> {
+        for (direction, expected, declared) in [("input", &self.inputs, &custom.inputs), ("output", &self.outputs, &custom.outputs)] {
+            for (location, format) in declared {
+                match expected.get(location) {
+                    Some(expected) if expected == format => {}
+                    Some(expected) => {
+                        return Err(format!("{} location {} is {:?} instead of {:?}", direction, location, format, expected))
+                    }
+                    None => return Err(format!("{} location {} is not part of the built-in interface", direction, location)),
+                }
+            }
+        }
+        for (&(set, binding), kind) in &custom.descriptors {
+            match self.descriptors.get(&(set, binding)) {
+                Some(expected) if expected == kind => {}
+                Some(expected) => {
+                    return Err(format!("set {} binding {} is a {:?} instead of a {:?}", set, binding, kind, expected))
+                }
+                None => return Err(format!("set {} binding {} is not part of the built-in interface", set, binding)),
+            }
+        }
+        if custom.push_constant_size > self.push_constant_size {
+            return Err(format!(
+                "push constants take {} bytes, the built-in shader provides {}",
+                custom.push_constant_size, self.push_constant_size
+            ));
+        }
+        // Constants the renderer does not set keep the module's defaults
+        for (constant_id, kind) in &custom.spec_constants {
+            match self.spec_constants.get(constant_id) {
+                Some(expected) if expected != kind => {
+                    return Err(format!(
+                        "specialization constant {} is {:?}, the renderer sets it as {:?}",
+                        constant_id, kind, expected
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Formats of a shader interface by location
+fn interface_locations(interface: &ShaderInterface) -> BTreeMap<u32, Format> {
+    interface.elements().iter()
+        .flat_map(|entry| entry.location.clone().map(move |location| (location, entry.format)))
+        .collect()
+}
+
+// Kinds of the bindings of descriptor set layouts, by set and binding
+fn layout_descriptors(sets: &[DescriptorSetDesc]) -> BTreeMap<(u32, u32), DescriptorKind> {
+    let mut descriptors = BTreeMap::new();
+    for (set, layout) in sets.iter().enumerate() {
+        for (binding, desc) in layout.bindings().iter().enumerate() {
+            if let Some(desc) = desc {
+                let kind = match &desc.ty {
+                    DescriptorDescTy::Buffer(buffer) if buffer.storage => DescriptorKind::StorageBuffer,
+                    DescriptorDescTy::Buffer(_) => DescriptorKind::UniformBuffer,
+                    DescriptorDescTy::CombinedImageSampler(_) => DescriptorKind::SampledImage,
+                    DescriptorDescTy::Image(_) => DescriptorKind::Image,
+                    DescriptorDescTy::Sampler => DescriptorKind::Sampler,
+                    _ => DescriptorKind::Other,
+                };
+                descriptors.insert((set as u32, binding as u32), kind);
+            }
+        }
+    }
+    descriptors
+}
+
+// Types EntryInterface::reflect distinguishes, by result id
+#[derive(Debug, Clone)]
+enum SpirvType {
+    Bool,
+    Int { width: u32, signed: bool },
+    Float { width: u32 },
+    Vector(u32, u32),   // Component type and count
+    Matrix(u32, u32),   // Column type and count
+    Array(u32, u32),    // Element type and the constant holding the length
+    RuntimeArray(u32),  // Element type
+    Struct(Vec<u32>),   // Member types
+    Image,
+    Sampler,
+    SampledImage,
+    Pointer(u32),       // Pointee type
+}
+
+// Module-level declarations of a SPIR-V module, as far as EntryInterface::reflect needs them
+#[derive(Default)]
+struct SpirvDeclarations {
+    entry_points: Vec<(u32, Vec<u32>)>, // Execution model and interface variables of every "main"
+    types: HashMap<u32, SpirvType>,
+    constants: HashMap<u32, u32>, // Low word of every OpConstant, for array lengths
+    variables: Vec<(u32, u32, u32)>, // Result id, pointer type and storage class
+    spec_constants: Vec<(u32, u32)>, // Result id and type
+    decorations: HashMap<(u32, u32), u32>, // First operand by target and decoration, zero when there is none
+    member_decorations: HashMap<(u32, u32, u32), u32>, // First operand by struct type, member and decoration
+}
+
+impl SpirvDeclarations {
+    // Records one instruction, given its opcode and operands
+    fn read(&mut self, opcode: u32, operands: &[u32]) {
+        let op = |i: usize| operands.get(i).copied().unwrap_or(0);
+        let ty = match opcode {
+            15 => {
+                // OpEntryPoint: execution model, function, name, then the interface variables
+                let name = operands.get(2..).unwrap_or(&[]);
+                let name_words = name.iter().position(|word| word >> 24 == 0).map_or(name.len(), |i| i + 1);
+                let bytes: Vec<u8> = name[..name_words].iter().flat_map(|word| word.to_le_bytes()).collect();
+                if bytes.split(|&b| b == 0).next() == Some(&b"main"[..]) {
+                    self.entry_points.push((op(0), name[name_words..].to_vec()));
+                }
+                return;
+            }
+            43 => {
+                self.constants.insert(op(1), op(2)); // OpConstant
+                return;
+            }
+            48 | 49 | 50 => {
+                self.spec_constants.push((op(1), op(0))); // OpSpecConstantTrue, OpSpecConstantFalse and OpSpecConstant
+                return;
+            }
+            59 => {
+                self.variables.push((op(1), op(0), op(2))); // OpVariable
+                return;
+            }
+            71 => {
+                self.decorations.insert((op(0), op(1)), op(2)); // OpDecorate
+                return;
+            }
+            72 => {
+                self.member_decorations.insert((op(0), op(1), op(2)), op(3)); // OpMemberDecorate
+                return;
+            }
+            20 => SpirvType::Bool,
+            21 => SpirvType::Int { width: op(1), signed: op(2) != 0 },
+            22 => SpirvType::Float { width: op(1) },
+            23 => SpirvType::Vector(op(1), op(2)),
+            24 => SpirvType::Matrix(op(1), op(2)),
+            25 => SpirvType::Image,
+            26 => SpirvType::Sampler,
+            27 => SpirvType::SampledImage,
+            28 => SpirvType::Array(op(1), op(2)),
+            29 => SpirvType::RuntimeArray(op(1)),
+            30 => SpirvType::Struct(operands.get(1..).unwrap_or(&[]).to_vec()),
+            32 => SpirvType::Pointer(op(2)),
+            _ => return,
+        };
+        self.types.insert(op(0), ty);
+    }
+
+    fn decoration(&self, target: u32, decoration: u32) -> Option<u32> {
+        self.decorations.get(&(target, decoration)).copied()
+    }
+
+    // Interface of the "main" entry point with the given execution model
+    fn entry_interface(&self, model: u32) -> Result<EntryInterface, String> {
+        let (_, entry_variables) = self.entry_points.iter()
+            .find(|(entry_model, _)| *entry_model == model)
+            .ok_or_else(|| "no \"main\" entry point for this stage".to_string())?;
+        let mut interface = EntryInterface::default();
+        for &(id, pointer, storage) in &self.variables {
+            let pointee = match self.types.get(&pointer) {
+                Some(SpirvType::Pointer(pointee)) => *pointee,
+                _ => return Err(format!("variable {} is not declared through a pointer", id)),
+            };
+            match storage {
+                SPIRV_INPUT | SPIRV_OUTPUT if entry_variables.contains(&id) => {
+                    // Built-ins such as gl_Position have no location
+                    let location = match self.decoration(id, SPIRV_LOCATION) {
+                        Some(location) => location,
+                        None => continue,
+                    };
+                    let locations = if storage == SPIRV_INPUT { &mut interface.inputs } else { &mut interface.outputs };
+                    for (offset, format) in self.location_formats(pointee)?.into_iter().enumerate() {
+                        locations.insert(location + offset as u32, format);
+                    }
+                }
+                SPIRV_UNIFORM_CONSTANT | SPIRV_UNIFORM | SPIRV_STORAGE_BUFFER => {
+                    if let (Some(set), Some(binding)) = (self.decoration(id, SPIRV_DESCRIPTOR_SET), self.decoration(id, SPIRV_BINDING)) {
+                        interface.descriptors.insert((set, binding), self.descriptor_kind(pointee, storage));
+                    }
+                }
+                SPIRV_PUSH_CONSTANT => interface.push_constant_size = interface.push_constant_size.max(self.size_of(pointee)?),
+                _ => {}
+            }
+        }
+        for &(id, ty) in &self.spec_constants {
+            if let Some(constant_id) = self.decoration(id, SPIRV_SPEC_ID) {
+                let kind = match self.types.get(&ty) {
+                    Some(SpirvType::Bool) => SpecConstantKind::Bool,
+                    Some(SpirvType::Int { width: 32, signed: true }) => SpecConstantKind::Int,
+                    Some(SpirvType::Int { width: 32, signed: false }) => SpecConstantKind::Uint,
+                    Some(SpirvType::Float { width: 32 }) => SpecConstantKind::Float,
+                    _ => SpecConstantKind::Other,
+                };
+                interface.spec_constants.insert(constant_id, kind);
+            }
+        }
+        Ok(interface)
+    }
+
+    // Format of every location a variable of type ty takes, in order
+    fn location_formats(&self, ty: u32) -> Result<Vec<Format>, String> {
+        match self.types.get(&ty) {
+            Some(SpirvType::Array(element, length)) => {
+                let length = *self.constants.get(length).ok_or_else(|| format!("array {} has no constant length", ty))?;
+                Ok(self.location_formats(*element)?.repeat(length as usize))
+            }
+            Some(SpirvType::Matrix(column, count)) => Ok(vec![self.vector_format(*column)?; *count as usize]),
+            _ => Ok(vec![self.vector_format(ty)?]),
+        }
+    }
+
+    // Format of a 32-bit scalar or vector at one location
+    fn vector_format(&self, ty: u32) -> Result<Format, String> {
+        const FLOAT: [Format; 4] = [Format::R32Sfloat, Format::R32G32Sfloat, Format::R32G32B32Sfloat, Format::R32G32B32A32Sfloat];
+        const INT: [Format; 4] = [Format::R32Sint, Format::R32G32Sint, Format::R32G32B32Sint, Format::R32G32B32A32Sint];
+        const UINT: [Format; 4] = [Format::R32Uint, Format::R32G32Uint, Format::R32G32B32Uint, Format::R32G32B32A32Uint];
+        let (scalar, count) = match self.types.get(&ty) {
+            Some(SpirvType::Vector(component, count)) => (*component, *count as usize),
+            _ => (ty, 1),
+        };
+        let formats = match self.types.get(&scalar) {
+            Some(SpirvType::Float { width: 32 }) => &FLOAT,
+            Some(SpirvType::Int { width: 32, signed: true }) => &INT,
+            Some(SpirvType::Int { width: 32, signed: false }) => &UINT,
+            _ => return Err(format!("interface type {} is not a 32-bit scalar, vector or matrix", ty)),
+        };
+        formats.get(count.wrapping_sub(1)).copied().ok_or_else(|| format!("vector {} has {} components", ty, count))
+    }
+
+    // What a descriptor variable of type pointee in the storage class binds, arrays of descriptors included
+    fn descriptor_kind(&self, pointee: u32, storage: u32) -> DescriptorKind {
+        let mut ty = pointee;
+        while let Some(SpirvType::Array(element, _)) | Some(SpirvType::RuntimeArray(element)) = self.types.get(&ty) {
+            ty = *element;
+        }
+        match (storage, self.types.get(&ty)) {
+            (SPIRV_STORAGE_BUFFER, _) => DescriptorKind::StorageBuffer,
+            // Before SPIR-V 1.3 storage buffers are uniform blocks decorated BufferBlock
+            (SPIRV_UNIFORM, _) if self.decoration(ty, SPIRV_BUFFER_BLOCK).is_some() => DescriptorKind::StorageBuffer,
+            (SPIRV_UNIFORM, _) => DescriptorKind::UniformBuffer,
+            (_, Some(SpirvType::SampledImage)) => DescriptorKind::SampledImage,
+            (_, Some(SpirvType::Image)) => DescriptorKind::Image,
+            (_, Some(SpirvType::Sampler)) => DescriptorKind::Sampler,
+            _ => DescriptorKind::Other,
+        }
+    }
+
+    // Size in bytes of a push constant block or one of its members, laid out by its offset decorations
+    fn size_of(&self, ty: u32) -> Result<u32, String> {
+        Ok(match self.types.get(&ty) {
+            Some(SpirvType::Bool) => 4,
+            Some(SpirvType::Int { width, .. }) | Some(SpirvType::Float { width }) => width / 8,
+            Some(SpirvType::Vector(component, count)) | Some(SpirvType::Matrix(component, count)) => self.size_of(*component)? * count,
+            Some(SpirvType::Array(element, length)) => {
+                let length = *self.constants.get(length).ok_or_else(|| format!("array {} has no constant length", ty))?;
+                let stride = match self.decoration(ty, SPIRV_ARRAY_STRIDE) {
+                    Some(stride) => stride,
+                    None => self.size_of(*element)?,
+                };
+                stride * length
+            }
+            Some(SpirvType::Struct(members)) => {
+                let mut end = 0;
+                for (member, &member_ty) in members.iter().enumerate() {
+                    let member = member as u32;
+                    let offset = self.member_decorations.get(&(ty, member, SPIRV_OFFSET)).copied().unwrap_or(0);
+                    let size = match (self.types.get(&member_ty), self.member_decorations.get(&(ty, member, SPIRV_MATRIX_STRIDE))) {
+                        (Some(SpirvType::Matrix(_, columns)), Some(stride)) => stride * columns,
+                        _ => self.size_of(member_ty)?,
+                    };
+                    end = end.max(offset + size);
+                }
+                end
+            }
+            _ => return Err(format!("push constant type {} has no fixed size", ty)),
+        })
+    }
+}
+
+// Built-in shaders a custom module can stand in for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BuiltinShader {
+    Vertex,     // vs
+    Fragment,   // fs, drawn for ShaderKind::Unlit
+    Post,       // post_fs
+    Preprocess, // cs, also run by dispatch_compute
+}
+
+impl BuiltinShader {
+    fn name(self) -> &'static str {
+        match self {
+            BuiltinShader::Vertex => "vertex",
+            BuiltinShader::Fragment => "fragment",
+            BuiltinShader::Post => "post",
+            BuiltinShader::Preprocess => "compute",
+        }
+    }
+
+    fn model(self) -> u32 {
+        match self {
+            BuiltinShader::Vertex => SPIRV_VERTEX,
+            BuiltinShader::Fragment | BuiltinShader::Post => SPIRV_FRAGMENT,
+            BuiltinShader::Preprocess => SPIRV_GL_COMPUTE,
+        }
+    }
+
+    // Interface described by the built-in shader's reflection data, with the constants the renderer sets
+    fn interface(self, device: &Arc<Device>) -> Result<EntryInterface, OomError> {
+        Ok(match self {
+            BuiltinShader::Vertex => EntryInterface::graphics(&vs::Shader::load(device.clone())?.main_entry_point(), &[]),
+            BuiltinShader::Fragment => {
+                EntryInterface::graphics(&fs::Shader::load(device.clone())?.main_entry_point(), &[(0, SpecConstantKind::Bool)])
+            }
+            BuiltinShader::Post => EntryInterface::graphics(&post_fs::Shader::load(device.clone())?.main_entry_point(), &[]),
+            BuiltinShader::Preprocess => {
+                EntryInterface::compute(&cs::Shader::load(device.clone())?.main_entry_point(), &[(0, SpecConstantKind::Uint)])
+            }
+        })
+    }
+}
+
+// Checks that spirv can replace builtin, which entry_point_like and compute_entry_point_like rely on
+fn check_custom_shader(device: &Arc<Device>, spirv: &[u8], builtin: BuiltinShader) -> Result<(), ShaderInterfaceError> {
+    let expected = builtin.interface(device).map_err(ShaderInterfaceError::BuiltinLoad)?;
+    let custom = EntryInterface::reflect(spirv, builtin.model())
+        .map_err(|reason| ShaderInterfaceError::Unreadable(builtin.name(), reason))?;
+    expected.check(&custom).map_err(|reason| ShaderInterfaceError::Mismatch(builtin.name(), reason))
+}
+
+// Watch events report absolute paths, so relative shader paths are resolved against the working directory
+#[cfg(feature = "notify")]
+fn absolute_path(path: &Path) -> PathBuf {
+    std::env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Resolves the depth attachment format requested by the options against what the device supports
+fn resolve_depth_format(physical: PhysicalDevice, options: &RendererOptions) -> Result<Option<Format>, RendererInitError> {
+    if !options.depth {
+        return Ok(None);
+    }
+    let supported = |format: Format| format.properties(physical).optimal_tiling_features.depth_stencil_attachment;
+    let format = choose_depth_format(options.depth_format, supported).ok_or(
+        RendererInitError::UnsupportedDepthFormat(options.depth_format.unwrap_or(Format::D32Sfloat)),
+    )?;
+    Ok(Some(format))
+}
+
+// Resolves the MSAA sample count requested by the options, only counts usable for both color and depth
+// attachments are considered
+fn resolve_sample_count(physical: PhysicalDevice, options: &RendererOptions) -> Result<u32, RendererInitError> {
+    let limits = physical.limits();
+    let supported_samples = limits.framebuffer_color_sample_counts() & limits.framebuffer_depth_sample_counts();
+    clamp_sample_count(options.samples, supported_samples).map_err(RendererInitError::InvalidOptions)
+}
+
+// Creates the single-subpass render pass, with a depth attachment when a depth format is given
+// and a multisampled color attachment resolved into the swapchain image when samples > 1
+fn create_render_pass(
+    device: Arc<Device>,
+    color_format: Format,
+    depth_format: Option<Format>,
+    samples: u32,
+    clear_color: bool,
+) -> Result<Arc<RenderPass>, RenderPassCreationError> {
+    // Color load op of the pass, and whether the multisampled image has to keep its contents for the next Load
+    macro_rules! render_pass {
+        ($color_load:ident, $intermediary_store:ident) => {
+            match (depth_format, samples > 1) {
+                (Some(depth_format), true) => vulkano::single_pass_renderpass!(
+                    device,
+                    attachments: {
+                        intermediary: {
+                            load: $color_load,
+                            store: $intermediary_store,
+                            format: color_format,
+                            samples: samples,
+                        },
+                        color: {
+                            load: DontCare,
+                            store: Store,
+                            format: color_format,
+                            samples: 1,
+                        },
+                        depth: {
+                            load: Clear,
+                            store: DontCare,
+                            format: depth_format,
+                            samples: samples,
+                        }
+                    },
+                    pass: {
+                        color: [intermediary],
+                        depth_stencil: {depth},
+                        resolve: [color]
+                    }
+                )?,
+                (None, true) => vulkano::single_pass_renderpass!(
+                    device,
+                    attachments: {
+                        intermediary: {
+                            load: $color_load,
+                            store: $intermediary_store,
+                            format: color_format,
+                            samples: samples,
+                        },
+                        color: {
+                            load: DontCare,
+                            store: Store,
+                            format: color_format,
+                            samples: 1,
+                        }
+                    },
+                    pass: {
+                        color: [intermediary],
+                        depth_stencil: {},
+                        resolve: [color]
+                    }
+                )?,
+                (Some(depth_format), false) => vulkano::single_pass_renderpass!(
+                    device,
+                    attachments: {
+                        color: {
+                            load: $color_load,
+                            store: Store,
+                            format: color_format,
+                            samples: 1,
+                        },
+                        depth: {
+                            load: Clear,
+                            store: DontCare,
+                            format: depth_format,
+                            samples: 1,
+                        }
+                    },
+                    pass: {
+                        color: [color],
+                        depth_stencil: {depth}
+                    }
+                )?,
+                (None, false) => vulkano::single_pass_renderpass!(
+                    device,
+                    attachments: {
+                        color: {
+                            load: $color_load,
+                            store: Store,
+                            format: color_format,
+                            samples: 1,
+                        }
+                    },
+                    pass: {
+                        color: [color],
+                        depth_stencil: {}
+                    }
+                )?,
+            }
+        };
+    }
+
+    let render_pass = if clear_color {
+        render_pass!(Clear, DontCare)
+    } else {
+        render_pass!(Load, Store)
+    };
+    Ok(Arc::new(render_pass))
+}
+
+// Builds the scene render pass described by config. Multisampling is not supported, the color attachments
+// are the target and config's images, then the depth attachment when a depth format is given.
+fn create_configured_render_pass(
+    device: Arc<Device>,
+    color_format: Format,
+    depth_format: Option<Format>,
+    config: &RenderPassConfig,
+    clear_color: bool,
+) -> Result<Arc<RenderPass>, RenderPassCreationError> {
+    let color = |format: Format, load: LoadOp| AttachmentDesc {
+        format,
+        samples: 1,
+        load,
+        store: StoreOp::Store,
+        stencil_load: LoadOp::DontCare,
+        stencil_store: StoreOp::DontCare,
+        initial_layout: ImageLayout::ColorAttachmentOptimal,
+        final_layout: ImageLayout::ColorAttachmentOptimal,
+    };
+    let mut attachments = vec![color(color_format, if clear_color { LoadOp::Clear } else { LoadOp::Load })];
+    attachments.extend(config.color_attachments.iter().map(|&format| color(format, LoadOp::Clear)));
+    let depth_index = attachments.len();
+    if let Some(format) = depth_format {
+        attachments.push(AttachmentDesc {
+            format,
+            samples: 1,
+            load: LoadOp::Clear,
+            store: StoreOp::DontCare,
+            stencil_load: LoadOp::DontCare,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::DepthStencilAttachmentOptimal,
+            final_layout: ImageLayout::DepthStencilAttachmentOptimal,
+        });
+    }
+
+    let subpasses = config.resolved_subpasses().into_iter().map(|subpass| SubpassDesc {
+        color_attachments: subpass.color.iter().map(|&a| (a, ImageLayout::ColorAttachmentOptimal)).collect(),
+        depth_stencil: Some((depth_index, ImageLayout::DepthStencilAttachmentOptimal))
+            .filter(|_| subpass.depth && depth_format.is_some()),
+        input_attachments: subpass.input.iter().map(|&a| (a, ImageLayout::ShaderReadOnlyOptimal)).collect(),
+        resolve_attachments: Vec::new(),
+        preserve_attachments: Vec::new(),
+    }).collect();
+
+    // Color writes of the source finish before fragment shaders of the destination read them
+    let dependencies = config.dependencies.iter().map(|&(source, destination)| SubpassDependencyDesc {
+        source_subpass: source as usize,
+        destination_subpass: destination as usize,
+        source_stages: PipelineStages { color_attachment_output: true, late_fragment_tests: true, ..PipelineStages::none() },
+        destination_stages: PipelineStages { fragment_shader: true, early_fragment_tests: true, ..PipelineStages::none() },
+        source_access: AccessFlags { color_attachment_write: true, depth_stencil_attachment_write: true, ..AccessFlags::none() },
+        destination_access: AccessFlags { input_attachment_read: true, depth_stencil_attachment_read: true, ..AccessFlags::none() },
+        by_region: true,
+    }).collect();
+
+    let render_pass = RenderPass::new(device, RenderPassDesc::new(attachments, subpasses, dependencies))?;
+    Ok(Arc::new(render_pass))
+}
+
+// Single color attachment written by the post-processing pass. The full-screen triangle covers every
+// pixel, so the previous contents are not loaded.
+fn create_post_render_pass(device: Arc<Device>, color_format: Format) -> Result<Arc<RenderPass>, RenderPassCreationError> {
+    let render_pass = vulkano::single_pass_renderpass!(
+        device,
+        attachments: {
+            color: {
+                load: DontCare,
+                store: Store,
+                format: color_format,
+                samples: 1,
+            }
+        },
+        pass: {
+            color: [color],
+            depth_stencil: {}
+        }
+    )?;
+    Ok(Arc::new(render_pass))
+}
+
+// Clear values in attachment order: color, the MSAA resolve target, then depth.
+// Attachments that are loaded or fully overwritten take ClearValue::None.
+fn attachment_clear_values(settings: &FrameSettings, clear_color: bool, samples: u32, depth: bool) -> Vec<ClearValue> {
+    let mut values = vec![if clear_color { settings.clear_color.into() } else { ClearValue::None }];
+    if samples > 1 {
+        values.push(ClearValue::None);
+    }
+    if depth {
+        values.push(settings.clear_depth.into());
+    }
+    values
+}
+
+// Swapchain image count for desired within the surface limits, max_count None meaning no upper limit
+fn clamp_image_count(desired: Option<u32>, min_count: u32, max_count: Option<u32>) -> u32 {
+    let count = desired.unwrap_or(min_count).max(min_count);
+    max_count.map_or(count, |max_count| count.min(max_count))
+}
+
+// First preferred present mode the surface supports, FIFO is always supported
+fn choose_present_mode<F: Fn(PresentMode) -> bool>(preferred: &[PresentMode], supported: F) -> PresentMode {
+    preferred.iter().copied().find(|&mode| supported(mode)).unwrap_or(PresentMode::Fifo)
+}
+
+// Validates a requested MSAA sample count and lowers it to the highest count in the supported bitmask
+fn clamp_sample_count(requested: u32, supported_mask: u32) -> Result<u32, RendererError> {
+    if !matches!(requested, 1 | 2 | 4 | 8) {
+        return Err(RendererError::UnsupportedSampleCount(requested));
+    }
+    let mut samples = requested;
+    while samples > 1 && supported_mask & samples == 0 {
+        samples /= 2;
+    }
+    Ok(samples)
+}
+
+// Returns the requested depth format if supported, otherwise the most precise supported one when none was requested
+fn choose_depth_format<F: Fn(Format) -> bool>(requested: Option<Format>, supported: F) -> Option<Format> {
+    match requested {
+        Some(format) => Some(format).filter(|f| supported(*f)),
+        None => DEPTH_FORMATS.iter().cloned().find(|f| supported(*f)),
+    }
+}
+
+// Orders device types by preference, lower is better
+fn device_type_rank(device_type: PhysicalDeviceType) -> u32 {
+    match device_type {
+        PhysicalDeviceType::DiscreteGpu => 0,
+        PhysicalDeviceType::IntegratedGpu => 1,
+        PhysicalDeviceType::VirtualGpu => 2,
+        PhysicalDeviceType::Cpu => 3,
+        PhysicalDeviceType::Other => 4,
+    }
+}
+
+// Picks the device and graphics queue family selector asks for among the suitable ones
+fn choose_device<'a>(
+    suitable: Vec<(PhysicalDevice<'a>, QueueFamily<'a>)>,
+    selector: &DeviceSelector,
+) -> Result<(PhysicalDevice<'a>, QueueFamily<'a>), RendererInitError> {
+    let candidates: Vec<DeviceCandidate> = suitable
+        .iter()
+        .map(|(p, _)| DeviceCandidate { index: p.index(), name: p.properties().device_name.clone(), device_type: p.properties().device_type })
+        .collect();
+    let chosen = select_device(&candidates, selector)?;
+    Ok(suitable[chosen])
+}
+
+// Position in candidates of the device selector asks for
+fn select_device(candidates: &[DeviceCandidate], selector: &DeviceSelector) -> Result<usize, RendererInitError> {
+    let fallback = (0..candidates.len())
+        .min_by_key(|&i| device_type_rank(candidates[i].device_type))
+        .ok_or(RendererInitError::NoSuitableDevice)?;
+    match selector {
+        DeviceSelector::PreferDiscrete => Ok(fallback),
+        DeviceSelector::PreferIntegrated => Ok(candidates
+            .iter()
+            .position(|c| c.device_type == PhysicalDeviceType::IntegratedGpu)
+            .unwrap_or(fallback)),
+        DeviceSelector::ByIndex(index) => match candidates.iter().position(|c| c.index == *index) {
+            Some(i) => Ok(i),
+            None => {
+                log::warn!("Requested device {:?} is missing or cannot render, using {}", selector, candidates[fallback].name);
+                Ok(fallback)
+            }
+        },
+        DeviceSelector::ByName(name) => candidates.iter().position(|c| c.name == *name).ok_or_else(|| {
+            RendererInitError::DeviceNotFound {
+                name: name.clone(),
+                available: candidates.iter().map(|c| format!("{}: {}", c.index, c.name)).collect(),
+            }
+        }),
+    }
 }
 
-impl VulkanoRenderer {
-    // Constructor for the renderer
-    pub fn new(device: Arc<Device>, queue: Arc<Queue>, pipeline: Arc<GraphicsPipeline>,
-               swapchain: Arc<Swapchain<Window>>, framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
-               render_pass: Arc<RenderPass>, metadata: Metadata) -> Self {
-        Self {
-            device,
-            queue,
-            pipeline,
-            swapchain,
-            framebuffers,
-            render_pass,
-            metadata: Mutex::new(metadata),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Renderer for the tests marked #[ignore = "needs a Vulkan device"], which run with
+    // `cargo test -- --ignored`. Without a device they fail instead of passing without checking anything.
+    fn headless(size: [u32; 2], options: RendererOptions) -> VulkanoRenderer {
+        VulkanoRenderer::create_headless(size, options).expect("no Vulkan device to run the test on")
+    }
+
+    // Mean time between frames rendered back to back, each followed by after_frame. The benchmarks using it
+    // print their numbers, run them with `cargo test --release -- --ignored --nocapture bench`.
+    fn mean_frame_time(renderer: &mut VulkanoRenderer, frames: u32, mut after_frame: impl FnMut(&mut VulkanoRenderer)) -> Duration {
+        for _ in 0..5 {
+            renderer.render_once().unwrap();
+            after_frame(renderer);
+        }
+        let start = Instant::now();
+        for _ in 0..frames {
+            renderer.render_once().unwrap();
+            after_frame(renderer);
+        }
+        renderer.wait_idle();
+        start.elapsed() / frames
+    }
+
+    // Blocks covering a size x size grid of quads over the whole image, vertices_per_block apart
+    fn grid_blocks(size: usize, vertices_per_block: usize) -> Vec<ShaderBlock> {
+        let cell = 2.0 / size as f32;
+        let mut vertices = Vec::with_capacity(size * size * 6);
+        for i in 0..size * size {
+            let (x, y) = ((i % size) as f32 * cell - 1.0, (i / size) as f32 * cell - 1.0);
+            vertices.extend(positions(&[
+                x, y, 0.5, x + cell, y, 0.5, x, y + cell, 0.5,
+                x + cell, y, 0.5, x + cell, y + cell, 0.5, x, y + cell, 0.5,
+            ]));
+        }
+        vertices
+            .chunks(vertices_per_block - vertices_per_block % 3)
+            .map(|chunk| ShaderBlock { vertices: chunk.to_vec(), material_data: vec![0.2, 0.4, 0.8, 1.0], ..Default::default() })
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn bench_frame_time_without_blocking_wait() {
+        let mut renderer = headless([1920, 1080], RendererOptions::default());
+        for block in grid_blocks(300, 30_000) {
+            renderer.apply_shader_block(block).unwrap();
+        }
+        // Waiting for every frame the way frames used to serializes CPU and GPU work
+        let blocking = mean_frame_time(&mut renderer, 200, |renderer| renderer.wait_idle());
+        let chained = mean_frame_time(&mut renderer, 200, |_| {});
+        println!("waiting for each frame: {:?}, chained frames: {:?}", blocking, chained);
+        assert!(chained < blocking, "chained {:?}, blocking {:?}", chained, blocking);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn bench_buffer_strategies_with_a_million_vertices() {
+        println!("| strategy | load | frame |");
+        println!("|---|---|---|");
+        for strategy in [BufferStrategy::HostVisible, BufferStrategy::DeviceLocal] {
+            let options = RendererOptions { buffer_strategy: strategy, ..RendererOptions::default() };
+            let mut renderer = headless([1920, 1080], options);
+            let blocks = grid_blocks(409, 60_000); // 409 * 409 quads, 1.004M vertices
+            let start = Instant::now();
+            let block_ids = renderer.upload_blocks(blocks.into_iter()).unwrap();
+            let load = start.elapsed();
+            assert_eq!(block_ids.len(), 17);
+            let frame = mean_frame_time(&mut renderer, 200, |_| {});
+            println!("| {:?} | {:?} | {:?} |", strategy, load, frame);
+        }
+    }
+
+    #[test]
+    fn test_memory_budget_evicts_least_recently_used() {
+        let mut budget = MemoryBudget::new(100);
+        for block_id in 0..3 {
+            assert!(budget.reserve(30).unwrap().is_empty());
+            budget.commit(block_id, 30);
+        }
+        budget.touch(0); // Block 1 is now the least recently used
+
+        assert_eq!(budget.reserve(30).unwrap(), vec![1]);
+        budget.commit(3, 30);
+        assert_eq!(budget.used, 90);
+
+        // Needs two blocks' worth of room
+        assert_eq!(budget.reserve(60).unwrap(), vec![2, 0]);
+        assert_eq!(budget.used, 90);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_memory_budget_keeps_drawn_blocks() {
+        let options = RendererOptions { buffer_strategy: BufferStrategy::DeviceLocal, ..RendererOptions::default() };
+        let mut renderer = headless([4, 4], options);
+        let block = |x: f32| ShaderBlock {
+            vertices: positions(&[x, 0.5, 0.0, x - 0.5, -0.5, 0.0, x + 0.5, -0.5, 0.0]),
+            material_data: vec![1.0, 0.0, 0.0, 1.0],
+            ..Default::default()
+        };
+        let bytes = renderer.block_bytes(&block(0.0));
+        renderer = renderer.with_memory_budget(2 * bytes); // Room for two blocks
+        let drawn = renderer.apply_shader_block(block(0.0)).unwrap();
+        let hidden = renderer.apply_shader_block(block(0.0)).unwrap();
+        renderer.set_block_visible(hidden, false).unwrap();
+        renderer.render_once().unwrap();
+
+        // The hidden block was uploaded later, but the drawn one was used last
+        renderer.apply_shader_block(block(0.0)).unwrap();
+        assert!(renderer.block_visible(drawn).is_ok());
+        assert!(matches!(renderer.block_visible(hidden), Err(RendererError::BlockEvicted(_))));
+    }
+
+    #[test]
+    fn test_memory_budget_rejects_blocks_that_cannot_fit() {
+        let mut budget = MemoryBudget::new(100);
+        budget.reserve(40).unwrap();
+        budget.commit(0, 40);
+
+        let err = budget.reserve(101).unwrap_err();
+        assert!(matches!(err, RendererError::BudgetExceeded { requested: 101, available: 100 }));
+        assert_eq!(budget.resident.len(), 1); // Nothing was evicted for it
+
+        budget.policy = BudgetPolicy::Reject;
+        let err = budget.reserve(70).unwrap_err();
+        assert!(matches!(err, RendererError::BudgetExceeded { requested: 70, available: 60 }));
+
+        budget.reserve(60).unwrap();
+        budget.cancel(60);
+        assert_eq!(budget.used, 40);
+    }
+
+    #[test]
+    fn test_workgroup_count_covers_every_vertex() {
+        assert_eq!(workgroup_count(0, 64), 0);
+        assert_eq!(workgroup_count(1, 64), 1);
+        assert_eq!(workgroup_count(64, 64), 1);
+        assert_eq!(workgroup_count(65, 64), 2);
+    }
+
+    // Assembles a SPIR-V module from instructions given as opcode then operands, valid only as far as
+    // EntryInterface::reflect reads
+    fn assemble(instructions: &[&[u32]]) -> Vec<u8> {
+        let mut words = vec![0x0723_0203, 0x0001_0000, 0, 100, 0];
+        for instruction in instructions {
+            words.push((instruction.len() as u32) << 16 | instruction[0]);
+            words.extend_from_slice(&instruction[1..]);
+        }
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    // A fragment shader reading a vec2 at location 0, writing a vec4 and sampling set 1 binding 1 like fs,
+    // with specialization constant 0 declared as gamma
+    fn fragment_spirv(output_location: u32, gamma: SpecConstantKind) -> Vec<u8> {
+        let main = u32::from_le_bytes(*b"main");
+        let (gamma_type, gamma_constant) = match gamma {
+            SpecConstantKind::Bool => (vec![20, 7], vec![49, 7, 12]),
+            _ => (vec![22, 7, 32], vec![50, 7, 12, 0]),
+        };
+        assemble(&[
+            &[15, SPIRV_FRAGMENT, 1, main, 0, 10, 11],
+            &[71, 10, SPIRV_LOCATION, 0],
+            &[71, 11, SPIRV_LOCATION, output_location],
+            &[71, 12, SPIRV_SPEC_ID, 0],
+            &[71, 14, SPIRV_DESCRIPTOR_SET, 1],
+            &[71, 14, SPIRV_BINDING, 1],
+            &[22, 2, 32],
+            &[23, 3, 2, 2],
+            &[23, 4, 2, 4],
+            &[32, 5, SPIRV_INPUT, 3],
+            &[32, 6, SPIRV_OUTPUT, 4],
+            &gamma_type,
+            &[25, 8, 2, 1, 0, 0, 0, 1, 0],
+            &[27, 9, 8],
+            &[32, 13, SPIRV_UNIFORM_CONSTANT, 9],
+            &[59, 5, 10, SPIRV_INPUT],
+            &[59, 6, 11, SPIRV_OUTPUT],
+            &gamma_constant,
+            &[59, 13, 14, SPIRV_UNIFORM_CONSTANT],
+        ])
+    }
+
+    // The interface fs declares
+    fn fs_interface() -> EntryInterface {
+        EntryInterface {
+            inputs: vec![(0, Format::R32G32Sfloat)].into_iter().collect(),
+            outputs: vec![(0, Format::R32G32B32A32Sfloat)].into_iter().collect(),
+            descriptors: vec![((1, 0), DescriptorKind::UniformBuffer), ((1, 1), DescriptorKind::SampledImage)].into_iter().collect(),
+            push_constant_size: 0,
+            spec_constants: vec![(0, SpecConstantKind::Bool)].into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn test_reflect_reads_entry_interface() {
+        let spirv = fragment_spirv(0, SpecConstantKind::Bool);
+        let custom = EntryInterface::reflect(&spirv, SPIRV_FRAGMENT).unwrap();
+        assert_eq!(custom.inputs, fs_interface().inputs);
+        assert_eq!(custom.outputs, fs_interface().outputs);
+        assert_eq!(custom.descriptors.len(), 1);
+        assert_eq!(custom.descriptors[&(1, 1)], DescriptorKind::SampledImage);
+        assert_eq!(custom.spec_constants, fs_interface().spec_constants);
+        assert!(fs_interface().check(&custom).is_ok());
+
+        // Big-endian modules read the same
+        let swapped: Vec<u8> = spirv.chunks_exact(4).flat_map(|word| [word[3], word[2], word[1], word[0]]).collect();
+        assert_eq!(EntryInterface::reflect(&swapped, SPIRV_FRAGMENT).unwrap(), custom);
+
+        assert_eq!(EntryInterface::reflect(&spirv, SPIRV_VERTEX).unwrap_err(), "no \"main\" entry point for this stage");
+        assert!(EntryInterface::reflect(&spirv[..spirv.len() - 4], SPIRV_FRAGMENT).is_err()); // Truncated instruction
+    }
+
+    #[test]
+    fn test_check_rejects_interface_mismatches() {
+        let reflect = |output_location, gamma| EntryInterface::reflect(&fragment_spirv(output_location, gamma), SPIRV_FRAGMENT).unwrap();
+        assert_eq!(
+            fs_interface().check(&reflect(1, SpecConstantKind::Bool)).unwrap_err(),
+            "output location 1 is not part of the built-in interface"
+        );
+
+        // The renderer sets constant 0 as a bool, which a float would reinterpret
+        assert_eq!(
+            fs_interface().check(&reflect(0, SpecConstantKind::Float)).unwrap_err(),
+            "specialization constant 0 is Float, the renderer sets it as Bool"
+        );
+
+        let mut untextured = fs_interface();
+        untextured.descriptors.remove(&(1, 1));
+        assert_eq!(
+            untextured.check(&reflect(0, SpecConstantKind::Bool)).unwrap_err(),
+            "set 1 binding 1 is not part of the built-in interface"
+        );
+
+        let mut two_component = fs_interface();
+        two_component.outputs.insert(0, Format::R32G32Sfloat);
+        assert!(two_component.check(&reflect(0, SpecConstantKind::Bool)).unwrap_err().starts_with("output location 0 is R32G32B32A32Sfloat"));
+    }
+
+    #[cfg(feature = "glsl")]
+    const DOUBLE_CS: &str = "
+        #version 450
+
+        layout(constant_id = 0) const uint workgroup_size = 64;
+        layout(local_size_x_id = 0) in;
+
+        layout(set = 0, binding = 0) readonly buffer Input {
+            float input_data[];
+        };
+
+        layout(set = 0, binding = 1) writeonly buffer Output {
+            float output_data[];
+        };
+
+        layout(push_constant) uniform Counts {
+            uint count;
+        } counts;
+
+        void main() {
+            uint i = gl_GlobalInvocationID.x;
+            if (i < counts.count) {
+                output_data[i] = input_data[i] * 2.0;
+            }
+        }
+    ";
+
+    // dispatch_compute takes SPIR-V, compiled here from DOUBLE_CS
+    #[cfg(feature = "glsl")]
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_dispatch_compute_doubles_floats() {
+        let renderer = headless([4, 4], RendererOptions::default());
+        let words = compile_glsl(&mut ShaderCache::default(), DOUBLE_CS, shaderc::ShaderKind::Compute, "double.comp").unwrap();
+        let spirv: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let data: Vec<f32> = (0..100).map(|i| i as f32 * 0.5 - 7.0).collect();
+        let input = CpuAccessibleBuffer::from_iter(
+            renderer.device.clone(),
+            BufferUsage::storage_buffer(),
+            false,
+            data.iter().copied(),
+        ).unwrap();
+
+        let groups = workgroup_count(data.len() as u32, DEFAULT_PREPROCESS_WORKGROUP_SIZE);
+        let output = renderer.dispatch_compute(&spirv, input, [groups, 1, 1]).unwrap();
+        let doubled: Vec<f32> = data.iter().map(|x| x * 2.0).collect();
+        assert_eq!(*output.read().unwrap(), doubled[..]);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_preprocess_matches_cpu_vertices() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        renderer.set_preprocess_enabled(true).unwrap();
+        renderer.set_preprocess_workgroup_size(4).unwrap(); // Several workgroups, the last one partial
+        let raw: Vec<f32> = (0..80).map(|i| i as f32 * 0.25 - 3.0).collect();
+        let mut expected = Vertex::from_raw_f32(&raw, VertexLayout::PositionNormalUv).unwrap();
+        let block_id = renderer.apply_shader_block(ShaderBlock {
+            vertices: expected.clone(),
+            material_data: vec![1.0, 1.0, 1.0, 1.0],
+            ..Default::default()
+        }).unwrap();
+        // The pass ran at upload, no frame has to be drawn first
+        assert_eq!(preprocessed_vertices(&renderer, block_id), expected);
+
+        // Updated vertices go through the pass again, frames keep drawing without rerunning it
+        let moved = [Vertex { position: [9.0, 8.0, 7.0], ..Vertex::default() }; 2];
+        renderer.write_block_vertices(block_id, 3..5, &moved).unwrap();
+        expected[3..5].copy_from_slice(&moved);
+        renderer.render_once().unwrap();
+        renderer.wait_idle();
+        assert_eq!(preprocessed_vertices(&renderer, block_id), expected);
+    }
+
+    // Copies the preprocess output of a block back to the host
+    fn preprocessed_vertices(renderer: &VulkanoRenderer, block_id: BlockId) -> Vec<Vertex> {
+        let vertices = renderer.blocks.lock().unwrap()[block_id].as_ref().unwrap().preprocess.as_ref().unwrap().vertices.clone();
+        let readback = CpuAccessibleBuffer::from_iter(
+            renderer.device.clone(),
+            BufferUsage::transfer_destination(),
+            false,
+            (0..vertices.len()).map(|_| Vertex::default()),
+        ).unwrap();
+        let mut builder = AutoCommandBufferBuilder::primary(
+            renderer.device.clone(),
+            renderer.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+        builder.copy_buffer(vertices, readback.clone()).unwrap();
+        sync::now(renderer.device.clone())
+            .then_execute(renderer.queue.clone(), builder.build().unwrap()).unwrap()
+            .then_signal_fence_and_flush().unwrap()
+            .wait(None).unwrap();
+        let vertices = readback.read().unwrap().to_vec();
+        vertices
+    }
+
+    #[cfg(feature = "glsl")]
+    #[test]
+    fn test_glsl_is_compiled_once_per_source() {
+        let source = "#version 450\nlayout(location = 0) out vec4 f_color;\nvoid main() { f_color = vec4(1.0); }\n";
+        let mut cache = ShaderCache::default();
+        let first = compile_glsl(&mut cache, source, shaderc::ShaderKind::Fragment, "fragment.glsl").unwrap();
+        let second = compile_glsl(&mut cache, source, shaderc::ShaderKind::Fragment, "fragment.glsl").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(cache.compiled.len(), 1);
+        assert_eq!(first[0], 0x0723_0203); // SPIR-V magic number
+    }
+
+    #[cfg(feature = "glsl")]
+    #[test]
+    fn test_glsl_errors_carry_diagnostics() {
+        let mut cache = ShaderCache::default();
+        let err = compile_glsl(&mut cache, "#version 450\nvoid main() { undeclared = 1.0; }\n", shaderc::ShaderKind::Fragment, "fragment.glsl")
+            .unwrap_err();
+        match err {
+            RendererError::ShaderCompile(diagnostics) => assert!(diagnostics.contains("undeclared"), "{}", diagnostics),
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert!(cache.compiled.is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_repeated_blocks_share_pool_buffers() {
+        let renderer = headless([4, 4], RendererOptions::default());
+        let block = ShaderBlock {
+            vertices: positions(&[0.0, 0.5, 0.0, -0.5, -0.5, 0.0, 0.5, -0.5, 0.0]),
+            material_data: vec![1.0, 0.0, 0.0, 1.0],
+            ..Default::default()
+        };
+        for _ in 0..1000 {
+            renderer.apply_shader_block(block.clone()).unwrap();
+        }
+        let stats = renderer.buffer_pool_stats();
+        assert_eq!(stats.chunks, 2000); // A vertex and a material chunk per block
+        // The pools grow geometrically, a buffer per block would mean 2000 allocations
+        assert!(stats.allocations <= 32, "{} allocations", stats.allocations);
+        assert!(stats.bytes_reserved > 0);
+    }
+
+    // Copies a GPU array back to the host
+    fn read_back<T: Copy + Default + Send + Sync + 'static>(renderer: &VulkanoRenderer, buffer: GpuArray<T>) -> Vec<T> {
+        let readback = CpuAccessibleBuffer::from_iter(
+            renderer.device.clone(),
+            BufferUsage::transfer_destination(),
+            false,
+            (0..buffer.len()).map(|_| T::default()),
+        ).unwrap();
+        let mut builder = AutoCommandBufferBuilder::primary(
+            renderer.device.clone(),
+            renderer.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        ).unwrap();
+        builder.copy_buffer(buffer, readback.clone()).unwrap();
+        sync::now(renderer.device.clone())
+            .then_execute(renderer.queue.clone(), builder.build().unwrap()).unwrap()
+            .then_signal_fence_and_flush().unwrap()
+            .wait(None).unwrap();
+        let contents = readback.read().unwrap().to_vec();
+        contents
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_update_block_materials_in_place() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        let block_id = renderer.apply_shader_block(ShaderBlock {
+            vertices: positions(&[0.0, 0.5, 0.0, -0.5, -0.5, 0.0, 0.5, -0.5, 0.0]),
+            material_data: vec![1.0, 0.0, 0.0, 1.0],
+            ..Default::default()
+        }).unwrap();
+        renderer.render_once().unwrap(); // The old material is still read by a frame in flight
+        let buffers = |renderer: &VulkanoRenderer| {
+            let blocks = renderer.blocks.lock().unwrap();
+            let block = blocks[block_id].as_ref().unwrap();
+            (block.material_buffer.clone(), block.vertex_buffer.clone(), block.material_set.clone())
+        };
+        let (material, vertices, set) = buffers(&renderer);
+
+        renderer.update_block_materials(block_id, &[0.0, 1.0, 0.0]).unwrap();
+        let (new_material, new_vertices, new_set) = buffers(&renderer);
+        assert!(Arc::ptr_eq(&material, &new_material));
+        assert!(Arc::ptr_eq(&vertices, &new_vertices));
+        assert!(Arc::ptr_eq(&set, &new_set));
+        let uniform = read_back(&renderer, material as GpuArray<f32>);
+        assert_eq!(uniform[..4], [0.0, 1.0, 0.0, 1.0]); // Only the given floats change
+
+        let too_long = vec![0.0; uniform.len() + 1];
+        assert!(matches!(
+            renderer.update_block_materials(block_id, &too_long),
+            Err(RendererError::UpdateOutOfRange { .. })
+        ));
+        assert!(matches!(renderer.update_block_materials(7, &[1.0]), Err(RendererError::UnknownBlock(7))));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_update_block_vertices_in_place() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        renderer.set_vertex_layout(VertexLayout::Position);
+        let block_id = renderer.apply_vertex_data(&[0.0, 0.5, 0.0, -0.5, -0.5, 0.0, 0.5, -0.5, 0.0], &[1.0; 4]).unwrap();
+        renderer.render_once().unwrap();
+
+        renderer.update_block_vertices(block_id, 1..2, &[-2.0, -2.0, 0.0]).unwrap();
+        let blocks = renderer.blocks.lock().unwrap();
+        let block = blocks[block_id].as_ref().unwrap();
+        let positions: Vec<[f32; 3]> = read_back(&renderer, block.vertex_buffer.clone()).iter().map(|v| v.position).collect();
+        assert_eq!(positions, vec![[0.0, 0.5, 0.0], [-2.0, -2.0, 0.0], [0.5, -0.5, 0.0]]);
+        assert_eq!(block.bounds.unwrap().min, [-2.0, -2.0, 0.0]);
+        drop(blocks);
+
+        assert!(matches!(
+            renderer.update_block_vertices(block_id, 2..4, &[0.0; 6]),
+            Err(RendererError::UpdateOutOfRange { end: 4, len: 3 })
+        ));
+        assert!(matches!(
+            renderer.update_block_vertices(block_id, 0..2, &[0.0; 3]),
+            Err(RendererError::MalformedGeometry(_))
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_reloading_partitions_keeps_pool_memory_stable() {
+        let block = ShaderBlock {
+            vertices: positions(&[0.0, 0.5, 0.0, -0.5, -0.5, 0.0, 0.5, -0.5, 0.0]),
+            material_data: vec![1.0, 0.0, 0.0, 1.0],
+            ..Default::default()
+        };
+        // Pooled host-visible chunks are shared, evicting their blocks would give the device nothing back
+        assert_eq!(headless([4, 4], RendererOptions::default()).block_bytes(&block), 0);
+
+        let options = RendererOptions { buffer_strategy: BufferStrategy::DeviceLocal, ..RendererOptions::default() };
+        let renderer = headless([4, 4], options);
+        assert_eq!(renderer.block_bytes(&block), 3 * std::mem::size_of::<Vertex>() as u64);
+        // Room for one load, each reload evicts the previous one and its staging chunks return to the pools
+        let renderer = renderer.with_memory_budget(8 * renderer.block_bytes(&block));
+
+        let mut reserved = Vec::new();
+        for _ in 0..50 {
+            renderer.apply_partitions(PartitionedData { blocks: vec![block.clone(); 8] }, |_, _| {}).unwrap();
+            reserved.push(renderer.buffer_pool_stats().bytes_reserved);
+        }
+        assert_eq!(reserved[24], reserved[49]);
+        let stats = renderer.metadata().buffer_pools();
+        assert_eq!(stats, renderer.buffer_pool_stats());
+        assert!(stats.reuse_rate() > 0.9, "reuse rate {}", stats.reuse_rate());
+        assert_eq!(renderer.metadata().loaded_blocks(), 8);
+    }
+
+    #[test]
+    fn test_buffer_pool_reuse_rate() {
+        assert_eq!(BufferPoolStats::default().reuse_rate(), 0.0);
+        let stats = BufferPoolStats { chunks: 90, staging_chunks: 10, bytes_reserved: 4096, allocations: 5 };
+        assert!((stats.reuse_rate() - 0.95).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_load_progress_fraction() {
+        assert_eq!(LoadProgress { uploaded: 1, total: Some(4) }.fraction(), Some(0.25));
+        assert_eq!(LoadProgress { uploaded: 4, total: Some(4) }.fraction(), Some(1.0));
+        assert_eq!(LoadProgress { uploaded: 0, total: Some(0) }.fraction(), Some(1.0));
+        assert_eq!(LoadProgress { uploaded: 3, total: None }.fraction(), None);
+    }
+
+    #[test]
+    fn test_background_partition_stops_when_cancelled() {
+        let path = std::env::temp_dir().join(format!("zeta_dom_background_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let (sender, blocks) = std::sync::mpsc::sync_channel(ASYNC_LOAD_CHANNEL_BOUND);
+        partition_in_background(path_str, sender, &AtomicBool::new(false), Ok);
+        assert!(matches!(blocks.recv(), Ok(Err(RendererError::DatabaseOpen(_)))));
+        assert!(blocks.recv().is_err());
+
+        let frames = (0..3).map(|frame_number| FrameData { frame_number, vertices: vec![Vertex::default(); 3], material_data: vec![] });
+        DatabaseManager::new(path_str).unwrap().store_video_metrics(&VideoMetrics { frame_data: frames.collect() }).unwrap();
+        let (sender, blocks) = std::sync::mpsc::sync_channel(ASYNC_LOAD_CHANNEL_BOUND);
+        partition_in_background(path_str, sender, &AtomicBool::new(false), Ok);
+        assert_eq!(blocks.iter().filter(|block| block.is_ok()).count(), 3);
+
+        let (sender, blocks) = std::sync::mpsc::sync_channel(ASYNC_LOAD_CHANNEL_BOUND);
+        partition_in_background(path_str, sender, &AtomicBool::new(true), Ok);
+        assert_eq!(blocks.iter().count(), 0);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_load_vertex_data_async_uploads_between_frames() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        let path = std::env::temp_dir().join(format!("zeta_dom_async_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let count = ASYNC_LOAD_BLOCKS_PER_FRAME as u32 * 3;
+        let frames = (0..count).map(|frame_number| FrameData { frame_number, vertices: vec![Vertex::default(); 3], material_data: vec![] });
+        DatabaseManager::new(path_str).unwrap().store_video_metrics(&VideoMetrics { frame_data: frames.collect() }).unwrap();
+
+        let (sender, results) = std::sync::mpsc::channel();
+        let load = renderer.load_vertex_data_async(path_str, move |result| sender.send(result).unwrap());
+        let mut rendered = 0;
+        while !load.is_finished() {
+            renderer.render_once().unwrap();
+            rendered += 1;
+            assert!(rendered < 1000, "the load never finished");
+        }
+        // Never more than ASYNC_LOAD_BLOCKS_PER_FRAME blocks between two frames
+        assert!(rendered > 3);
+        let report = results.try_recv().unwrap().unwrap();
+        assert_eq!((report.blocks, report.vertices), (count as usize, count as usize * 3));
+        assert!(renderer.loads.is_empty());
+
+        // A dropped handle aborts the load without calling back
+        let (sender, results) = std::sync::mpsc::channel();
+        drop(renderer.load_vertex_data_async(path_str, move |result| sender.send(result).unwrap()));
+        renderer.render_once().unwrap();
+        assert!(renderer.loads.is_empty());
+        assert!(results.try_recv().is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_load_vertex_data_async_registers_after_the_copies() {
+        let options = RendererOptions { buffer_strategy: BufferStrategy::DeviceLocal, ..RendererOptions::default() };
+        let mut renderer = headless([4, 4], options);
+        let path = std::env::temp_dir().join(format!("zeta_dom_async_fence_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let frames = (0..2).map(|frame_number| FrameData { frame_number, vertices: vec![Vertex::default(); 3], material_data: vec![] });
+        DatabaseManager::new(path_str).unwrap().store_video_metrics(&VideoMetrics { frame_data: frames.collect() }).unwrap();
+
+        let load = renderer.load_vertex_data_async(path_str, |_| {});
+        let mut rendered = 0;
+        while renderer.loads[0].pending.is_none() {
+            renderer.render_once().unwrap();
+            rendered += 1;
+            assert!(rendered < 1000, "nothing was ever submitted");
+        }
+        // Submitted during the last frame, registered by a later one
+        assert_eq!(renderer.block_count(), 0);
+        while !load.is_finished() {
+            renderer.render_once().unwrap();
+            rendered += 1;
+            assert!(rendered < 1000, "the load never finished");
+        }
+        assert_eq!(renderer.block_count(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_partition_database_rejects_missing_and_empty_databases() {
+        let path = std::env::temp_dir().join(format!("zeta_dom_partition_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(partition_database(path_str), Err(RendererError::DatabaseOpen(_))));
+
+        let db = DatabaseManager::new(path_str).unwrap();
+        db.ensure_schema().unwrap();
+        assert!(matches!(partition_database(path_str), Err(RendererError::EmptyPartition)));
+
+        let frame = |frame_number, count| FrameData { frame_number, vertices: vec![Vertex::default(); count], material_data: vec![1.0] };
+        db.store_video_metrics(&VideoMetrics { frame_data: vec![frame(0, 3), frame(1, 6)] }).unwrap();
+        assert_eq!(partition_database(path_str).unwrap().blocks.len(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_load_vertex_data_reports_what_was_uploaded() {
+        let renderer = headless([4, 4], RendererOptions::default());
+        let path = std::env::temp_dir().join(format!("zeta_dom_load_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let frame = |frame_number, count| FrameData { frame_number, vertices: vec![Vertex::default(); count], material_data: vec![1.0] };
+        DatabaseManager::new(path_str).unwrap().store_video_metrics(&VideoMetrics { frame_data: vec![frame(0, 3), frame(1, 6)] }).unwrap();
+
+        let report = renderer.load_vertex_data(path_str).unwrap();
+        assert_eq!((report.blocks, report.vertices), (2, 9));
+        assert_eq!(report.bytes, renderer.memory_budget.lock().unwrap().used);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_load_vertex_data_keeps_normals_and_uvs() {
+        let renderer = headless([4, 4], RendererOptions::default());
+        let path = std::env::temp_dir().join(format!("zeta_dom_normals_{}.db", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let vertices: Vec<Vertex> = (0..3)
+            .map(|i| Vertex { position: [i as f32, 0.0, 0.0], normal: [0.0, 0.0, 1.0], uv: [i as f32 / 2.0, 1.0] })
+            .collect();
+        let db = DatabaseManager::new(path_str).unwrap().with_vertex_layout(VertexLayout::PositionNormalUv);
+        db.store_video_metrics(&VideoMetrics { frame_data: vec![FrameData { frame_number: 0, vertices: vertices.clone(), material_data: vec![1.0] }] }).unwrap();
+        drop(db);
+
+        // 24 floats would be 8 position-only vertices
+        let report = renderer.load_vertex_data(path_str).unwrap();
+        assert_eq!((report.blocks, report.vertices), (1, 3));
+        let vertex_buffer = renderer.blocks.lock().unwrap()[0].as_ref().unwrap().vertex_buffer.clone();
+        assert_eq!(read_back(&renderer, vertex_buffer), vertices);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    // Stand-in for an upload that runs out of device memory
+    fn failing_upload() -> Result<(), RendererError> {
+        Err(DeviceMemoryAllocError::OomError(OomError::OutOfDeviceMemory))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_allocation_error_propagates() {
+        let err = failing_upload().unwrap_err();
+        assert!(matches!(err, RendererError::BufferAllocation(_)));
+        assert!(!err.is_recoverable());
+        assert!(!err.is_out_of_date());
+    }
+
+    // Vertices with only positions, three floats each
+    fn positions(data: &[f32]) -> Vec<Vertex> {
+        Vertex::from_raw_f32(data, VertexLayout::Position).unwrap()
+    }
+
+    #[test]
+    fn test_vertices_from_shader_block() {
+        let json = r#"{"vertices":[{"position":[0.0,0.5,0.0]},{"position":[-0.5,-0.5,0.0],"uv":[1.0,1.0]}],"material_data":[1.0,0.0,0.0,1.0]}"#;
+        let block: ShaderBlock = serde_json::from_str(json).unwrap();
+        assert!(block.indices.is_empty());
+        assert_eq!(block.vertices.len(), 2);
+        assert_eq!(block.vertices[1].position, [-0.5, -0.5, 0.0]);
+        assert_eq!(block.vertices[0].uv, [0.0, 0.0]);
+        assert_eq!(block.vertices[1].uv, [1.0, 1.0]);
+        assert_eq!(block.vertices[1].normal, [0.0; 3]);
+        assert_eq!(block.texture_id, None);
+    }
+
+    #[test]
+    fn test_validate_indices() {
+        let block = ShaderBlock { vertices: positions(&[0.0; 9]), indices: vec![0, 1, 2], ..Default::default() };
+        assert!(validate_indices(&block).is_ok());
+        let block = ShaderBlock { indices: vec![0, 1, 3], ..block };
+        assert!(matches!(validate_indices(&block), Err(RendererError::MalformedGeometry(_))));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_apply_vertex_data_checks_stride() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        renderer.set_vertex_layout(VertexLayout::Position);
+        let triangle = [-1.0, -1.0, 0.5, 1.0, -1.0, 0.5, -1.0, 1.0, 0.5];
+        let block_id = renderer.apply_vertex_data(&triangle, &[1.0, 1.0, 1.0, 1.0]).unwrap();
+        assert_eq!(renderer.blocks.lock().unwrap()[block_id].as_ref().unwrap().vertex_buffer.len(), 3);
+
+        // A trailing float is not a vertex and nothing is uploaded
+        let err = renderer.apply_vertex_data(&triangle[..8], &[1.0, 1.0, 1.0, 1.0]).unwrap_err();
+        assert!(matches!(err, RendererError::MalformedGeometry(_)));
+        assert_eq!(renderer.block_count(), 1);
+
+        // The same floats are two and a half vertices with normals and UVs
+        renderer.set_vertex_layout(VertexLayout::PositionNormalUv);
+        assert!(renderer.apply_vertex_data(&triangle, &[1.0, 1.0, 1.0, 1.0]).is_err());
+    }
+
+    #[test]
+    fn test_material_uniform_keeps_color() {
+        assert_eq!(material_uniform(&[1.0, 0.0, 0.0, 1.0]), vec![1.0, 0.0, 0.0, 1.0, 1.0, 0.0]);
+        assert_eq!(material_uniform(&[0.2, 0.4]), vec![0.2, 0.4, 1.0, 1.0, 1.0, 0.0]);
+        assert_eq!(material_uniform(&[0.1, 0.2, 0.3, 0.5, 0.25, 1.0]), vec![0.1, 0.2, 0.3, 0.5, 0.25, 1.0]);
+        assert_eq!(material_uniform(&[]), DEFAULT_MATERIAL.to_vec());
+    }
+
+    #[test]
+    fn test_camera_centers_target() {
+        let view_proj = Camera::default().view_projection(16.0 / 9.0);
+        let clip: Vec<f32> = (0..4).map(|row| view_proj[3][row]).collect();
+        let (x, y, z) = (clip[0] / clip[3], clip[1] / clip[3], clip[2] / clip[3]);
+        assert!(x.abs() < 1e-6 && y.abs() < 1e-6);
+        assert!(z > 0.0 && z < 1.0);
+    }
+
+    #[test]
+    fn test_orthographic_maps_near_and_far_to_depth_range() {
+        let proj = orthographic(4.0, 2.0, 1.0, 11.0);
+        let project = |p: [f32; 4]| -> Vec<f32> {
+            (0..4).map(|row| (0..4).map(|col| proj[col][row] * p[col]).sum()).collect()
+        };
+        assert!(project([0.0, 0.0, -1.0, 1.0])[2].abs() < 1e-6);
+        assert!((project([0.0, 0.0, -11.0, 1.0])[2] - 1.0).abs() < 1e-6);
+        // The corner of the view volume lands on the corner of clip space, y pointing down
+        let corner = project([4.0, 2.0, -5.0, 1.0]);
+        assert!((corner[0] - 1.0).abs() < 1e-6 && (corner[1] + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_orbit_controller_clamps_pitch_and_distance() {
+        let mut orbit = OrbitController::new([0.0; 3], 5.0);
+        orbit.rotate(0.0, 1.0e6);
+        assert_eq!(orbit.pitch, MAX_PITCH);
+        orbit.rotate(0.0, -1.0e6);
+        assert_eq!(orbit.pitch, -MAX_PITCH);
+
+        orbit.min_distance = 0.5;
+        orbit.zoom(1000.0);
+        assert_eq!(orbit.distance, 0.5);
+
+        let mut camera = Camera::default();
+        orbit.apply(&mut camera);
+        assert!(camera.view().iter().flatten().all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn test_orbit_controller_round_trips_camera() {
+        let camera = Camera { position: [3.0, 4.0, -2.0], target: [1.0, 1.0, 1.0], ..Camera::default() };
+        let mut applied = Camera::default();
+        OrbitController::from_camera(&camera).apply(&mut applied);
+        for i in 0..3 {
+            assert!((applied.position[i] - camera.position[i]).abs() < 1e-4);
+        }
+        assert_eq!(applied.target, camera.target);
+    }
+
+    #[test]
+    fn test_fly_controller_moves_along_view_direction() {
+        let mut fly = FlyController::from_camera(&Camera::default());
+        fly.translate(1.0, 0.0, 0.0, 1.0);
+        assert!((fly.position[2] - 1.0).abs() < 1e-5);
+
+        fly.look(1.0e6, 1.0e6);
+        assert_eq!(fly.pitch, -MAX_PITCH);
+
+        let mut camera = Camera::default();
+        fly.apply(&mut camera);
+        let forward = normalize3(sub3(camera.target, camera.position));
+        assert!(cross3(forward, camera.up).iter().any(|v| v.abs() > 1e-3));
+    }
+
+    // Counts the window events that reach it
+    #[cfg(feature = "egui")]
+    struct CountingController(Arc<AtomicUsize>);
+
+    #[cfg(feature = "egui")]
+    impl CameraController for CountingController {
+        fn handle_event(&mut self, _event: &WindowEvent) -> bool {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            true
         }
+
+        fn apply(&self, _camera: &mut Camera) {}
     }
 
-    // Load vertex data from the database
-    pub fn load_vertex_data(&self, db_path: &str) {
-        let partitioned_data = shader_partition_compressor::partition_data(db_path);
-        self.apply_partitions(partitioned_data);
+    #[cfg(feature = "egui")]
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_overlay_needs_a_window_and_routes_events() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        // egui draws into swapchain images, a headless renderer has none
+        assert!(renderer.enable_overlay().is_err());
+        assert!(renderer.set_overlay_ui(|_| {}).is_err());
+        assert!(renderer.overlay.is_none());
+
+        // Without an overlay nothing holds events back from the camera controller
+        let events = Arc::new(AtomicUsize::new(0));
+        renderer.set_camera_controller(Some(Box::new(CountingController(events.clone()))));
+        assert!(!renderer.overlay_event(&WindowEvent::Focused(true)));
+        renderer.window_event(&WindowEvent::Focused(true));
+        renderer.window_event(&WindowEvent::Focused(false));
+        assert_eq!(events.load(Ordering::Relaxed), 2);
     }
 
-    // Applies partitioned shader data to the vertex pipeline
-    fn apply_partitions(&self, data: PartitionedData) {
-        for block in data.blocks {
-            self.apply_shader_block(block);
+    #[test]
+    fn test_camera_matrices_compose_to_view_projection() {
+        let camera = Camera { position: [1.0, 2.0, 3.0], ..Camera::default() };
+        let matrices = camera.matrices(4.0 / 3.0);
+        assert_eq!(mat4_mul(&matrices.proj, &matrices.view), camera.view_projection(4.0 / 3.0));
+        assert_eq!(CameraMatrices::default().view, IDENTITY_TRANSFORM);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_device_local_uploads_reach_graphics_queue() {
+        let options = RendererOptions { buffer_strategy: BufferStrategy::DeviceLocal, ..RendererOptions::default() };
+        let mut renderer = headless([8, 8], options);
+        let has_transfer_family = renderer.device.physical_device().queue_families()
+            .any(|q| q.explicitly_supports_transfers() && !q.supports_graphics());
+        assert_eq!(renderer.upload_context.dedicated(), has_transfer_family);
+
+        // A full-screen quad uploaded through the transfer queue when there is one
+        let corners = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]];
+        renderer.apply_shader_block(ShaderBlock {
+            vertices: positions(&corners.iter().flat_map(|&[x, y]| vec![x, y, 0.5]).collect::<Vec<_>>()),
+            material_data: vec![0.0, 1.0, 0.0, 1.0],
+            ..Default::default()
+        }).unwrap();
+        renderer.render_once().unwrap();
+        let pixels = renderer.read_pixels().unwrap();
+        assert_eq!(&pixels[..4], &[0, 255, 0, 255]);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_render_loop_returns_when_flag_flips() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut checks = 0;
+        let flag = stop.clone();
+        renderer.render_loop(|| {
+            checks += 1;
+            if checks > 3 {
+                flag.store(true, Ordering::SeqCst);
+            }
+            !flag.load(Ordering::SeqCst)
+        }).unwrap();
+
+        assert!(stop.load(Ordering::SeqCst));
+        assert_eq!(renderer.stats().frames_rendered, 3);
+        assert!(renderer.frame_fences.iter().all(Option::is_none));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_metadata_snapshot_advances_with_frames() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        assert_eq!(renderer.metadata_snapshot().frames_rendered(), 0);
+        renderer.apply_shader_block(ShaderBlock {
+            vertices: positions(&[-1.0, -1.0, 0.5, 1.0, -1.0, 0.5, 0.0, 1.0, 0.5]),
+            ..Default::default()
+        }).unwrap();
+        for _ in 0..3 {
+            renderer.render_once().unwrap();
         }
+
+        let snapshot = renderer.metadata_snapshot();
+        assert_eq!(snapshot.frames_rendered(), 3);
+        assert!(snapshot.last_present().is_some());
+        assert_eq!(snapshot.extent(), [4, 4]);
+        assert_eq!(snapshot.active_pipelines(), &[PipelineKey::default()]);
+        assert_eq!((snapshot.loaded_blocks(), snapshot.vertex_count()), (1, 3));
     }
 
-    // Applies a single block of shader instructions
-    fn apply_shader_block(&self, block: ShaderBlock) {
-        let (vertex_transform, material_properties) = (block.vertex_transform, block.material_properties);
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_apply_partitions_reports_progress_without_presenting() {
+        let renderer = headless([4, 4], RendererOptions::default());
+        let blocks = (0..5)
+            .map(|_| ShaderBlock { vertices: positions(&[0.0, 0.0, 0.5, 1.0, 0.0, 0.5, 0.0, 1.0, 0.5]), ..Default::default() })
+            .collect();
+        let mut calls = Vec::new();
+        renderer.apply_partitions(PartitionedData { blocks }, |loaded, total| calls.push((loaded, total))).unwrap();
 
-        // Allocate buffers for vertex data and material properties
-        let vertex_buffer = CpuAccessibleBuffer::from_iter(
-            self.device.clone(),
-            vulkano::buffer::BufferUsage::all(),
-            false,
-            vertex_transform.iter().cloned()
-        ).expect("failed to create buffer");
+        assert_eq!(calls, (1..=5).map(|loaded| (loaded, 5)).collect::<Vec<_>>());
+        assert_eq!(renderer.block_count(), 5);
+        assert_eq!(renderer.stats().frames_rendered, 0);
+    }
 
-        let material_buffer = CpuAccessibleBuffer::from_iter(
-            self.device.clone(),
-            vulkano::buffer::BufferUsage::all(),
-            false,
-            material_properties.iter().cloned()
-        ).expect("failed to create material buffer");
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_render_loop_honours_control_stop() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        renderer.control().stop();
+        renderer.render_loop(|| true).unwrap();
+        assert_eq!(renderer.stats().frames_rendered, 0);
+    }
 
-        // Create the command buffer to execute the drawing commands
-        let mut builder = AutoCommandBufferBuilder::primary(
-            self.device.clone(),
-            self.queue.family(),
-            CommandBufferUsage::OneTimeSubmit,
-        ).unwrap();
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_render_loop_stops_while_paused() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        let control = renderer.control();
+        control.pause();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            control.stop();
+        });
+        // Stopping wakes the parked loop, which must return without rendering the frame it was waiting for
+        renderer.render_loop(|| true).unwrap();
+        stopper.join().unwrap();
+        assert_eq!(renderer.stats().frames_rendered, 0);
+    }
 
-        // Bind vertex data and material properties to the shader pipeline
-        builder
-            .bind_pipeline_graphics(self.pipeline.clone())
-            .bind_vertex_buffers(0, vertex_buffer.clone())
-            .draw(self.pipeline.clone(), &self.framebuffers[0])
-            .unwrap();
-        
-        let command_buffer = builder.build().unwrap();
-
-        // Execute the command buffer on the GPU
-        let future = sync::now(self.device.clone())
-            .then_execute(self.queue.clone(), command_buffer)
-            .unwrap()
-            .then_swapchain_present(self.queue.clone(), self.swapchain.clone(), 0)
-            .then_signal_fence_and_flush()
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_camera_matrices_reach_vertex_shader() {
+        let mut renderer = headless([8, 8], RendererOptions::default());
+        // A red quad over the left half of clip space
+        let corners = [[-1.0, -1.0], [0.0, -1.0], [0.0, 1.0], [-1.0, -1.0], [0.0, 1.0], [-1.0, 1.0]];
+        renderer.apply_shader_block(ShaderBlock {
+            vertices: positions(&corners.iter().flat_map(|&[x, y]| vec![x, y, 0.5]).collect::<Vec<_>>()),
+            material_data: vec![1.0, 0.0, 0.0, 1.0],
+            ..Default::default()
+        }).unwrap();
+
+        // The view moves it to the right half, the projection leaves it there
+        let mut view = IDENTITY_TRANSFORM;
+        view[3][0] = 1.0;
+        renderer.set_camera_matrices(CameraMatrices { view, proj: IDENTITY_TRANSFORM });
+        renderer.render_once().unwrap();
+        let pixels = renderer.read_pixels().unwrap();
+
+        let pixel = |x: usize, y: usize| &pixels[(y * 8 + x) * 4..(y * 8 + x) * 4 + 4];
+        assert_eq!(pixel(6, 4), &[255, 0, 0, 255]);
+        assert_eq!(pixel(1, 4), &[0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_recording_threads_scale_with_blocks() {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        assert_eq!(recording_threads(0), 1);
+        assert_eq!(recording_threads(MIN_BLOCKS_PER_RECORDING_THREAD - 1), 1);
+        assert_eq!(recording_threads(MIN_BLOCKS_PER_RECORDING_THREAD * 2), cores.min(2));
+        assert_eq!(recording_threads(usize::MAX), cores);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_parallel_recording_matches_inline() {
+        let mut renderer = headless([16, 16], RendererOptions::default());
+        // 10k small triangles tiling the image, alternating red and green
+        for i in 0..10_000 {
+            let (x, y) = ((i % 100) as f32 / 50.0 - 1.0, (i / 100) as f32 / 50.0 - 1.0);
+            renderer.apply_shader_block(ShaderBlock {
+                vertices: positions(&[x, y, 0.5, x + 0.02, y, 0.5, x, y + 0.02, 0.5]),
+                material_data: if i % 2 == 0 { vec![1.0, 0.0, 0.0, 1.0] } else { vec![0.0, 1.0, 0.0, 1.0] },
+                ..Default::default()
+            }).unwrap();
+        }
+        renderer.render_once().unwrap();
+        let inline = renderer.read_pixels().unwrap();
+
+        renderer.set_parallel_recording(true);
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.read_pixels().unwrap(), inline);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn bench_parallel_recording_with_10k_draws() {
+        let mut renderer = headless([256, 256], RendererOptions::default());
+        for block in grid_blocks(71, 3) { // Two draws per quad, 10082 draws
+            renderer.apply_shader_block(block).unwrap();
+        }
+        let inline = mean_frame_time(&mut renderer, 100, |_| {});
+        renderer.set_parallel_recording(true);
+        let parallel = mean_frame_time(&mut renderer, 100, |_| {});
+        println!("10k draws recorded inline: {:?}, on {} threads: {:?}", inline, recording_threads(10_082), parallel);
+        if recording_threads(10_082) > 1 {
+            assert!(parallel < inline, "parallel {:?}, inline {:?}", parallel, inline);
+        }
+    }
+
+    #[test]
+    fn test_playback_frames_sorted_and_deduplicated() {
+        let frame = |frame_number: u32, red: f32| FrameData { frame_number, vertices: Vec::new(), material_data: vec![red] };
+        let frames = playback_frames(vec![frame(2, 0.2), frame(0, 0.0), frame(2, 0.5), frame(1, 0.1)]);
+        assert_eq!(frames.iter().map(|f| f.frame_number).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(frames[2].material_data, vec![0.2]); // The first frame 2 wins
+        assert_eq!(playback_offset(30, 60.0), Duration::from_millis(500));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_play_shows_frames_and_cleans_up() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        assert!(matches!(renderer.play(VideoMetrics { frame_data: Vec::new() }, 0.0), Err(RendererError::InvalidFrameRate(_))));
+
+        let quad = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]];
+        let vertices = positions(&quad.iter().flat_map(|&[x, y]| vec![x, y, 0.5]).collect::<Vec<_>>());
+        let frame = |frame_number: u32, color: [f32; 4]| FrameData { frame_number, vertices: vertices.clone(), material_data: color.to_vec() };
+        let metrics = VideoMetrics { frame_data: vec![frame(1, [0.0, 1.0, 0.0, 1.0]), frame(0, [1.0, 0.0, 0.0, 1.0])] };
+
+        renderer.play(metrics.clone(), 50.0).unwrap();
+        let control = renderer.playback_control();
+        assert_eq!(control.current_frame(), Some(1));
+        assert_eq!(&renderer.read_pixels().unwrap()[..4], &[0, 255, 0, 255]);
+        assert!(renderer.blocks.lock().unwrap().iter().all(|block| block.is_none()));
+
+        // Seeking past the end shows the last frame
+        control.seek(100);
+        renderer.play(metrics.clone(), 50.0).unwrap();
+        assert_eq!(control.current_frame(), Some(1));
+
+        // Blended frames end on the last recorded one, unblended
+        control.set_interpolation(InterpolationMode::Linear);
+        renderer.play(metrics, 50.0).unwrap();
+        assert_eq!(control.current_frame(), Some(1));
+        assert_eq!(&renderer.read_pixels().unwrap()[..4], &[0, 255, 0, 255]);
+        assert!(renderer.blocks.lock().unwrap().iter().all(|block| block.is_none()));
+        // Frames of the same size are written into one block per play, however many were rendered
+        assert_eq!(renderer.blocks.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_clear_color_is_honored() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        renderer.render_once().unwrap();
+        assert!(renderer.read_pixels().unwrap().chunks(4).all(|pixel| pixel == [0, 0, 0, 255]));
+
+        renderer.set_clear_color([1.0, 0.0, 0.0, 1.0]);
+        renderer.render_once().unwrap();
+        assert!(renderer.read_pixels().unwrap().chunks(4).all(|pixel| pixel == [255, 0, 0, 255]));
+
+        // Transparent backgrounds for compositing
+        renderer.set_clear_color([0.0, 0.0, 0.0, 0.0]);
+        renderer.render_once().unwrap();
+        assert!(renderer.read_pixels().unwrap().chunks(4).all(|pixel| pixel == [0, 0, 0, 0]));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_draws_known_triangle() {
+        let mut renderer = headless([8, 8], RendererOptions::default());
+        renderer.set_clear_color([0.0, 0.0, 1.0, 1.0]);
+        // Covers the top left half of the image, the diagonal itself is left out of the checks
+        renderer.apply_shader_block(ShaderBlock {
+            vertices: positions(&[-1.0, -1.0, 0.5, 1.0, -1.0, 0.5, -1.0, 1.0, 0.5]),
+            material_data: vec![1.0, 0.0, 0.0, 1.0],
+            ..Default::default()
+        }).unwrap();
+
+        // Every image of the target gets the triangle, not just the first one
+        for _ in 0..DEFAULT_FRAMES_IN_FLIGHT {
+            renderer.render_once().unwrap();
+            let pixels = renderer.read_pixels().unwrap();
+            let pixel = |x: usize, y: usize| &pixels[(y * 8 + x) * 4..(y * 8 + x) * 4 + 4];
+            assert_eq!(pixel(0, 0), &[255, 0, 0, 255]);
+            assert_eq!(pixel(5, 1), &[255, 0, 0, 255]);
+            assert_eq!(pixel(1, 5), &[255, 0, 0, 255]);
+            assert_eq!(pixel(7, 7), &[0, 0, 255, 255]);
+            assert_eq!(pixel(6, 3), &[0, 0, 255, 255]);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_load_previous_keeps_the_last_frame_with_one_image_in_use() {
+        let mut renderer = headless([4, 4], RendererOptions::default()).frames_in_flight(1);
+        let half = |x0: f32, x1: f32, rgba: Vec<f32>| ShaderBlock {
+            vertices: positions(&[x0, -1.0, 0.5, x1, -1.0, 0.5, x0, 1.0, 0.5, x1, -1.0, 0.5, x1, 1.0, 0.5, x0, 1.0, 0.5]),
+            material_data: rgba,
+            ..Default::default()
+        };
+        renderer.set_load_previous(true).unwrap();
+        let left = renderer.apply_shader_block(half(-1.0, 0.0, vec![1.0, 0.0, 0.0, 1.0])).unwrap();
+        renderer.render_once().unwrap();
+        renderer.remove_block(left).unwrap();
+        renderer.apply_shader_block(half(0.0, 1.0, vec![0.0, 1.0, 0.0, 1.0])).unwrap();
+        renderer.render_once().unwrap();
+
+        // The left half was drawn by the first frame only
+        let pixels = renderer.read_pixels().unwrap();
+        assert_eq!(&pixels[0..4], &[255, 0, 0, 255]);
+        assert_eq!(&pixels[12..16], &[0, 255, 0, 255]);
+
+        renderer.set_load_previous(false).unwrap();
+        renderer.render_once().unwrap();
+        assert_eq!(&renderer.read_pixels().unwrap()[0..4], &[0, 0, 0, 255]);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_post_passthrough_matches_direct() {
+        let mut renderer = headless([8, 8], RendererOptions::default());
+        renderer.apply_shader_block(ShaderBlock {
+            vertices: positions(&[-1.0, -1.0, 0.5, 1.0, -1.0, 0.5, -1.0, 1.0, 0.5]),
+            material_data: vec![0.0, 1.0, 0.0, 1.0],
+            ..Default::default()
+        }).unwrap();
+        renderer.render_once().unwrap();
+        let direct = renderer.read_pixels().unwrap();
+
+        renderer.set_post_enabled(true).unwrap();
+        assert!(renderer.post_enabled());
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.read_pixels().unwrap(), direct);
+
+        // Rebuilding the scene render pass rebuilds the intermediate images with it
+        renderer.set_load_previous(true).unwrap();
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.post.as_ref().unwrap().targets.len(), renderer.framebuffers.len());
+        renderer.set_load_previous(false).unwrap();
+
+        renderer.set_post_enabled(false).unwrap();
+        assert!(!renderer.post_enabled());
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.read_pixels().unwrap(), direct);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_post_shader_rejects_non_spirv() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        assert!(matches!(renderer.set_post_shader(b"void main() {}"), Err(RendererError::ShaderCompile(_))));
+        assert!(!renderer.post_enabled());
+    }
+
+    #[test]
+    fn test_viewport_follows_resize() {
+        let before = ViewportRect::full([800, 600]).viewport();
+        let after = ViewportRect::full([1280, 720]).viewport();
+        assert_eq!(before.dimensions, [800.0, 600.0]);
+        assert_eq!(after.dimensions, [1280.0, 720.0]);
+        assert_eq!(after.origin, [0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_scene_graph_composes_transforms() {
+        let tree = ConicTree::from_json(r#"{
+            "car": {
+                "transform": [1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 2, 0, 0, 1],
+                "block_id": 0,
+                "wheel": {"transform": "2,0,0,0, 0,2,0,0, 0,0,2,0, 0,1,0,1", "block_id": "1"},
+                "label": "sedan"
+            },
+            "ground": {"block_id": 1}
+        }"#).unwrap();
+        let mut graph = SceneGraph::from_tree(&tree).unwrap();
+        let translation = |x: f32, y: f32| {
+            let mut transform = IDENTITY_TRANSFORM;
+            transform[3][0] = x;
+            transform[3][1] = y;
+            transform
+        };
+        let mut wheel = translation(2.0, 1.0);
+        for i in 0..3 {
+            wheel[i][i] = 2.0;
+        }
+        assert_eq!(graph.draw_list(), vec![(0, translation(2.0, 0.0)), (1, wheel), (1, IDENTITY_TRANSFORM)]);
+        assert_eq!(graph.transform("root/ground"), Some(IDENTITY_TRANSFORM));
+        assert_eq!(graph.transform("root/car/transform"), None);
+
+        // Moving the parent moves the child with it
+        assert!(graph.set_transform("root/car/", translation(-1.0, 0.0)));
+        wheel[3][0] = -1.0;
+        assert_eq!(graph.draw_list()[1], (1, wheel));
+        assert!(!graph.set_transform("root/bike", IDENTITY_TRANSFORM));
+
+        // Hiding the parent hides the child, the other nodes keep drawing
+        assert!(graph.set_visible("root/car", false));
+        assert_eq!(graph.draw_list(), vec![(1, IDENTITY_TRANSFORM)]);
+        let hidden = ConicTree::from_json(r#"{"car": {"block_id": 0, "visible": "false", "wheel": {"block_id": 1}}, "ground": {"block_id": 1, "visible": 1}}"#).unwrap();
+        assert_eq!(SceneGraph::from_tree(&hidden).unwrap().draw_list(), vec![(1, IDENTITY_TRANSFORM)]);
+        assert_eq!(SceneGraph::from_tree(&hidden).unwrap().resolve().1, vec![0, 1]);
+        let maybe = ConicTree::from_json(r#"{"car": {"visible": "maybe"}}"#).unwrap();
+        assert_eq!(SceneGraph::from_tree(&maybe), Err(SceneGraphError::MalformedVisibility("root/car".to_string())));
+
+        let short = ConicTree::from_json(r#"{"car": {"transform": "1,0,0"}}"#).unwrap();
+        assert_eq!(SceneGraph::from_tree(&short), Err(SceneGraphError::MalformedTransform("root/car".to_string())));
+        let negative = ConicTree::from_json(r#"{"car": {"block_id": -1}}"#).unwrap();
+        assert_eq!(SceneGraph::from_tree(&negative), Err(SceneGraphError::MalformedBlockId("root/car".to_string())));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_uniform_transforms_move_blocks_with_frames_in_flight() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        // Conformant devices always push transforms, force the uniform fallback
+        renderer.push_transforms = false;
+        let quad = [[-1.0, -1.0], [0.0, -1.0], [0.0, 1.0], [-1.0, -1.0], [0.0, 1.0], [-1.0, 1.0]];
+        let vertices = positions(&quad.iter().flat_map(|&[x, y]| vec![x, y, 0.5]).collect::<Vec<_>>());
+        let id = renderer.apply_shader_block(ShaderBlock { vertices, material_data: vec![1.0, 0.0, 0.0, 1.0], ..Default::default() }).unwrap();
+        let red = |pixels: &[u8], x: usize| pixels[x * 4..x * 4 + 4] == [255, 0, 0, 255];
+
+        // Moving the block while the previous frame may still read its transform must not fail
+        let mut moved = IDENTITY_TRANSFORM;
+        moved[3][0] = 1.0;
+        renderer.render_once().unwrap();
+        renderer.set_block_transform(id, moved).unwrap();
+        renderer.render_once().unwrap();
+        let pixels = renderer.read_pixels().unwrap();
+        assert!(!red(&pixels, 0) && red(&pixels, 3));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_scene_graph_moves_blocks_without_reupload() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        // Covers the left half of the image
+        let quad = [[-1.0, -1.0], [0.0, -1.0], [0.0, 1.0], [-1.0, -1.0], [0.0, 1.0], [-1.0, 1.0]];
+        let vertices = positions(&quad.iter().flat_map(|&[x, y]| vec![x, y, 0.5]).collect::<Vec<_>>());
+        let id = renderer.apply_shader_block(ShaderBlock { vertices, material_data: vec![1.0, 0.0, 0.0, 1.0], ..Default::default() }).unwrap();
+        let tree = ConicTree::from_json(&format!(r#"{{"parent": {{"child": {{"block_id": {}}}, "ghost": {{"block_id": 7}}}}}}"#, id)).unwrap();
+        renderer.set_scene_graph(Some(SceneGraph::from_tree(&tree).unwrap()));
+        let vertex_buffer = renderer.blocks.lock().unwrap()[id].as_ref().unwrap().vertex_buffer.clone();
+
+        renderer.render_once().unwrap();
+        let pixels = renderer.read_pixels().unwrap();
+        let red = |pixels: &[u8], x: usize| pixels[x * 4..x * 4 + 4] == [255, 0, 0, 255];
+        assert!(red(&pixels, 0) && !red(&pixels, 3));
+
+        // Moving the parent right by half the clip space moves the block to the right half
+        let mut moved = IDENTITY_TRANSFORM;
+        moved[3][0] = 1.0;
+        renderer.set_node_transform("root/parent", moved).unwrap();
+        renderer.render_once().unwrap();
+        let pixels = renderer.read_pixels().unwrap();
+        assert!(!red(&pixels, 0) && red(&pixels, 3));
+        assert!(Arc::ptr_eq(&renderer.blocks.lock().unwrap()[id].as_ref().unwrap().vertex_buffer, &vertex_buffer));
+        assert_eq!(*renderer.missing_scene_blocks.lock().unwrap(), vec![7]);
+
+        // Picking walks the same draw list without touching what frames warned about
+        renderer.missing_scene_blocks.lock().unwrap().clear();
+        assert_eq!(renderer.pick((3.5, 2.0)), Some(id));
+        assert!(renderer.missing_scene_blocks.lock().unwrap().is_empty());
+        assert!(matches!(renderer.set_node_transform("root/nowhere", moved), Err(RendererError::UnknownSceneNode(_))));
+    }
+
+    #[test]
+    fn test_frustum_culls_blocks_outside_clip_space() {
+        let frustum = Frustum::from_view_projection(&IDENTITY_TRANSFORM); // x and y in -1..1, depth in 0..1
+        let bounds = |min: [f32; 3], max: [f32; 3]| Bounds { min, max };
+        assert!(!frustum.culls(&bounds([-0.5, -0.5, 0.5], [0.5, 0.5, 0.5]), &IDENTITY_TRANSFORM));
+        assert!(!frustum.culls(&bounds([0.5, 0.0, 0.5], [3.0, 0.1, 0.5]), &IDENTITY_TRANSFORM)); // Straddles the right edge
+        assert!(frustum.culls(&bounds([2.0, 0.0, 0.5], [3.0, 0.1, 0.5]), &IDENTITY_TRANSFORM));
+        assert!(frustum.culls(&bounds([0.0, 0.0, -1.0], [0.1, 0.1, -0.5]), &IDENTITY_TRANSFORM)); // Behind the near plane
+
+        // The block transform moves the bounds before testing
+        let mut moved = IDENTITY_TRANSFORM;
+        moved[3][1] = -5.0;
+        assert!(frustum.culls(&bounds([-0.5, -0.5, 0.5], [0.5, 0.5, 0.5]), &moved));
+
+        // A perspective camera sees what is in front of it but not what is behind
+        let camera = Camera { position: [0.0, 0.0, 5.0], target: [0.0; 3], ..Camera::default() };
+        let perspective = Frustum::from_view_projection(&camera.view_projection(1.0));
+        assert!(!perspective.culls(&bounds([-1.0; 3], [1.0; 3]), &IDENTITY_TRANSFORM));
+        assert!(perspective.culls(&bounds([-1.0, -1.0, 8.0], [1.0, 1.0, 9.0]), &IDENTITY_TRANSFORM));
+    }
+
+    #[test]
+    fn test_bounds_of_vertices() {
+        assert_eq!(Bounds::of(&[]), None);
+        let vertices = positions(&[1.0, -2.0, 0.0, -1.0, 3.0, 0.5]);
+        assert_eq!(Bounds::of(&vertices), Some(Bounds { min: [-1.0, -2.0, 0.0], max: [1.0, 3.0, 0.5] }));
+        assert_eq!(Bounds::of(&vertices).unwrap().corners().count(), 8);
+    }
+
+    #[test]
+    fn test_bounds_cover_instances() {
+        let unit = Bounds { min: [0.0; 3], max: [1.0; 3] };
+        assert_eq!(unit.instanced(&[]), unit);
+        let mut moved = IDENTITY_TRANSFORM;
+        moved[3] = [10.0, 0.0, -5.0, 1.0];
+        assert_eq!(unit.instanced(&[IDENTITY_TRANSFORM, moved]), Bounds { min: [0.0, 0.0, -5.0], max: [11.0, 1.0, 1.0] });
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_instanced_matches_separate_draws() {
+        let mut renderer = headless([16, 16], RendererOptions::default());
+        // A small triangle repeated along the diagonal
+        let triangle = positions(&[-1.0, -1.0, 0.5, -0.75, -1.0, 0.5, -1.0, -0.75, 0.5]);
+        let transforms: Vec<Transform> = (0..6)
+            .map(|i| {
+                let mut transform = IDENTITY_TRANSFORM;
+                transform[3] = [i as f32 * 0.3, i as f32 * 0.3, 0.0, 1.0];
+                transform
+            })
+            .collect();
+
+        let separate: Vec<BlockId> = transforms.iter().map(|&transform| {
+            let block_id = renderer.apply_shader_block(ShaderBlock {
+                vertices: triangle.clone(),
+                material_data: vec![1.0, 0.5, 0.0, 1.0],
+                ..Default::default()
+            }).unwrap();
+            renderer.set_block_transform(block_id, transform).unwrap();
+            block_id
+        }).collect();
+        renderer.render_once().unwrap();
+        let expected = renderer.read_pixels().unwrap();
+        for block_id in separate {
+            renderer.remove_block(block_id).unwrap();
+        }
+
+        let before = renderer.buffer_pool_stats();
+        renderer.apply_shader_block(ShaderBlock {
+            vertices: triangle,
+            material_data: vec![1.0, 0.5, 0.0, 1.0],
+            instances: transforms,
+            ..Default::default()
+        }).unwrap();
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.read_pixels().unwrap(), expected);
+        assert_eq!(renderer.stats().culling.drawn, 1);
+
+        // Vertices, material and instances all come from the pools
+        let after = renderer.buffer_pool_stats();
+        assert_eq!(after.chunks + after.staging_chunks, before.chunks + before.staging_chunks + 3);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_replace_and_remove_blocks_keep_counts() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        let triangle = positions(&[0.0, 0.5, 0.5, -0.5, -0.5, 0.5, 0.5, -0.5, 0.5]);
+        let block = |vertices: Vec<Vertex>| ShaderBlock { vertices, material_data: vec![1.0; 4], ..Default::default() };
+        let first = renderer.apply_shader_block(block(triangle.clone())).unwrap();
+        let second = renderer.apply_shader_block(block(triangle.clone())).unwrap();
+        let mut moved = IDENTITY_TRANSFORM;
+        moved[3][0] = 0.25;
+        renderer.set_block_transform(first, moved).unwrap();
+        renderer.render_once().unwrap(); // The frame in flight still draws the old buffers
+
+        let two_triangles = [triangle.clone(), triangle.clone()].concat();
+        renderer.replace_block(first, block(two_triangles)).unwrap();
+        let metadata = renderer.metadata();
+        assert_eq!((metadata.loaded_blocks(), metadata.vertex_count()), (2, 9));
+        assert_eq!(renderer.block_count(), 2);
+        assert_eq!(renderer.blocks.lock().unwrap()[first].as_ref().unwrap().transform, moved);
+        renderer.render_once().unwrap();
+
+        renderer.remove_block(second).unwrap();
+        let metadata = renderer.metadata();
+        assert_eq!((metadata.loaded_blocks(), metadata.vertex_count()), (1, 6));
+        assert!(matches!(renderer.replace_block(second, block(triangle.clone())), Err(RendererError::BlockRemoved(_))));
+        assert!(matches!(renderer.remove_block(second), Err(RendererError::BlockRemoved(_))));
+        assert!(matches!(renderer.replace_block(9, block(triangle.clone())), Err(RendererError::UnknownBlock(9))));
+
+        // The next upload takes the removed slot instead of growing the list
+        let reused = renderer.apply_shader_block(block(triangle.clone())).unwrap();
+        assert_eq!((reused, renderer.block_count()), (second, 2));
+        renderer.remove_block(reused).unwrap();
+
+        // A rejected replacement leaves the block and its budget entry alone
+        let used = renderer.memory_budget.lock().unwrap().used;
+        let bad = ShaderBlock { indices: vec![0, 1, 7], ..block(triangle) };
+        assert!(matches!(renderer.replace_block(first, bad), Err(RendererError::MalformedGeometry(_))));
+        assert_eq!(renderer.memory_budget.lock().unwrap().used, used);
+        assert_eq!(renderer.metadata().vertex_count(), 6);
+        renderer.render_once().unwrap();
+    }
+
+    #[test]
+    fn test_render_pass_config_check() {
+        let config = RenderPassConfig::with_color_attachments(vec![Format::R8G8B8A8Unorm]);
+        assert!(config.check().is_ok());
+
+        let deferred = RenderPassConfig {
+            subpasses: vec![
+                SubpassConfig { color: vec![0, 1], input: Vec::new(), depth: true },
+                SubpassConfig { color: vec![0], input: vec![1], depth: false },
+            ],
+            dependencies: vec![(0, 1)],
+            ..config.clone()
+        };
+        assert!(deferred.check().is_ok());
+
+        let invalid = |config: RenderPassConfig| matches!(config.check(), Err(RendererError::InvalidRenderPassConfig(_)));
+        assert!(invalid(RenderPassConfig { draw_subpass: 1, ..config.clone() }));
+        assert!(invalid(RenderPassConfig { dependencies: vec![(1, 0)], ..deferred.clone() }));
+        assert!(invalid(RenderPassConfig {
+            subpasses: vec![SubpassConfig { color: vec![0, 2], input: Vec::new(), depth: true }],
+            ..config.clone()
+        }));
+        assert!(invalid(RenderPassConfig {
+            subpasses: vec![SubpassConfig { color: vec![0, 1], input: vec![1], depth: true }],
+            ..config.clone()
+        }));
+        // Blocks are drawn into the target
+        assert!(invalid(RenderPassConfig {
+            subpasses: vec![SubpassConfig { color: vec![1, 0], input: Vec::new(), depth: true }],
+            ..config
+        }));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_render_pass_config_writes_every_attachment() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        renderer
+            .set_render_pass_config(Some(RenderPassConfig::with_color_attachments(vec![Format::R8G8B8A8Unorm])))
             .unwrap();
-        
-        future.wait(None).unwrap();
+        let quad = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]];
+        let vertices = quad.iter()
+            .map(|&[x, y]| Vertex { position: [x, y, 0.5], normal: [0.0, 0.0, 1.0], uv: [0.0, 0.0] })
+            .collect();
+        renderer.apply_shader_block(ShaderBlock { vertices, material_data: vec![1.0, 0.0, 0.0, 1.0], ..Default::default() }).unwrap();
+        renderer.render_once().unwrap();
+
+        assert!(renderer.read_attachment(0).unwrap().chunks(4).all(|pixel| pixel == [255, 0, 0, 255]));
+        // The normal (0, 0, 1) mapped to 0..1
+        let normals = renderer.read_attachment(1).unwrap();
+        assert_eq!(normals.len(), 4 * 4 * 4);
+        assert!(normals.chunks(4).all(|pixel| (127..=128).contains(&pixel[0]) && (127..=128).contains(&pixel[1]) && pixel[2..] == [255, 255]));
+        assert!(matches!(renderer.read_attachment(2), Err(RendererError::UnknownAttachment(2))));
+
+        // Back to the single target attachment
+        renderer.set_render_pass_config(None).unwrap();
+        renderer.render_once().unwrap();
+        assert!(matches!(renderer.read_attachment(1), Err(RendererError::UnknownAttachment(1))));
     }
 
-    // Main rendering loop
-    pub fn render_loop(&mut self) {
-        // Begin the rendering loop
-        loop {
-            // Handle swapchain recreation errors
-            match self.render_frame() {
-                Ok(_) => {}
-                Err(SwapchainCreationError::OutOfDate) => {
-                    self.recreate_swapchain();
-                }
-                Err(e) => println!("Render error: {:?}", e),
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_culling_stats_reach_metadata() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        for x in [0.0, 5.0] {
+            renderer.apply_shader_block(ShaderBlock {
+                vertices: positions(&[x, 0.0, 0.5, x + 0.5, 0.0, 0.5, x, 0.5, 0.5]),
+                material_data: vec![1.0, 1.0, 1.0, 1.0],
+                ..Default::default()
+            }).unwrap();
+        }
+
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.stats().culling, CullingStats { drawn: 1, culled: 1, hidden: 0 });
+        renderer.set_frustum_culling(false);
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.stats().culling, CullingStats { drawn: 2, culled: 0, hidden: 0 });
+
+        // The preprocess shader decides where a preprocessed block ends up, its uploaded bounds do not
+        renderer.set_frustum_culling(true);
+        renderer.set_preprocess_enabled(true).unwrap();
+        renderer.apply_shader_block(ShaderBlock {
+            vertices: positions(&[5.0, 0.0, 0.5, 5.5, 0.0, 0.5, 5.0, 0.5, 0.5]),
+            material_data: vec![1.0, 1.0, 1.0, 1.0],
+            ..Default::default()
+        }).unwrap();
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.stats().culling, CullingStats { drawn: 2, culled: 1, hidden: 0 });
+    }
+
+    #[test]
+    fn test_mat4_inverse() {
+        let camera = Camera { position: [1.0, 2.0, 3.0], ..Camera::default() };
+        let m = mat4_mul(&camera.projection(1.5), &camera.view());
+        let product = mat4_mul(&mat4_inverse(&m).unwrap(), &m);
+        for (col, column) in product.iter().enumerate() {
+            for (row, value) in column.iter().enumerate() {
+                let expected = if col == row { 1.0 } else { 0.0 };
+                assert!((value - expected).abs() < 1e-4, "{:?}", product);
             }
         }
+        assert_eq!(mat4_inverse(&IDENTITY_TRANSFORM), Some(IDENTITY_TRANSFORM));
+        assert_eq!(mat4_inverse(&[[0.0; 4]; 4]), None);
     }
 
-    // Renders a single frame
-    fn render_frame(&mut self) -> Result<(), SwapchainCreationError> {
-        // Get the next image from the swapchain
-        let (image_num, suboptimal, acquire_future) = vulkano::swapchain::acquire_next_image(self.swapchain.clone(), None).unwrap();
+    #[test]
+    fn test_ray_entry() {
+        let bounds = Bounds { min: [-1.0; 3], max: [1.0; 3] };
+        assert_eq!(bounds.ray_entry([-3.0, 0.0, 0.0], [6.0, 0.0, 0.0]), Some(1.0 / 3.0));
+        assert_eq!(bounds.ray_entry([0.0, 0.5, 0.0], [0.0, 0.0, 4.0]), Some(0.0)); // Starting inside
+        assert_eq!(bounds.ray_entry([-3.0, 2.0, 0.0], [6.0, 0.0, 0.0]), None); // Parallel, above the box
+        assert_eq!(bounds.ray_entry([-3.0, 0.0, 0.0], [1.0, 0.0, 0.0]), None); // Ends before the box
+        assert_eq!(bounds.ray_entry([3.0, 0.0, 0.0], [1.0, 0.0, 0.0]), None); // Behind the start
+        assert_eq!(bounds.ray_entry([-3.0, -3.0, 0.0], [6.0, 6.0, 0.0]), Some(1.0 / 3.0));
+    }
 
-        // Render the frame
-        let command_buffer = self.build_command_buffer(image_num)?;
-        let future = acquire_future
-            .then_execute(self.queue.clone(), command_buffer)?
-            .then_swapchain_present(self.queue.clone(), self.swapchain.clone(), image_num)
-            .then_signal_fence_and_flush();
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_pick_returns_nearest_visible_block() {
+        let renderer = headless([4, 4], RendererOptions::default());
+        assert_eq!(renderer.pick((1.0, 1.0)), None); // Empty scene
+
+        let quad = |x0: f32, x1: f32, z: f32| {
+            let corners = [[x0, -1.0], [x1, -1.0], [x1, 1.0], [x0, -1.0], [x1, 1.0], [x0, 1.0]];
+            let vertices = positions(&corners.iter().flat_map(|&[x, y]| vec![x, y, z]).collect::<Vec<_>>());
+            ShaderBlock { vertices, material_data: vec![1.0; 4], ..Default::default() }
+        };
+        // Without a camera clip space is drawn as is, so lower depths are nearer
+        let back = renderer.apply_shader_block(quad(-1.0, 1.0, 0.5)).unwrap();
+        let front = renderer.apply_shader_block(quad(0.0, 1.0, 0.25)).unwrap();
+        assert_eq!(renderer.pick((0.5, 2.0)), Some(back));
+        assert_eq!(renderer.pick((3.5, 2.0)), Some(front));
+
+        renderer.set_block_visible(front, false).unwrap();
+        assert_eq!(renderer.pick((3.5, 2.0)), Some(back));
+        renderer.set_block_visible(front, true).unwrap();
+
+        // Transforms move the boxes along with the blocks
+        let mut moved = IDENTITY_TRANSFORM;
+        moved[3][0] = -1.0;
+        renderer.set_block_transform(front, moved).unwrap();
+        assert_eq!(renderer.pick((0.5, 2.0)), Some(front));
+        assert_eq!(renderer.pick((3.5, 2.0)), Some(back));
 
-        future.unwrap().wait(None).unwrap();
+        assert_eq!(renderer.pick((4.5, 2.0)), None);
+        assert_eq!(renderer.pick((-0.5, 2.0)), None);
+        assert_eq!(renderer.pick((f32::NAN, 2.0)), None);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_hidden_blocks_are_not_drawn() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        let quad = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]];
+        let vertices = positions(&quad.iter().flat_map(|&[x, y]| vec![x, y, 0.5]).collect::<Vec<_>>());
+        let block = renderer.apply_shader_block(ShaderBlock { vertices, material_data: vec![1.0, 0.0, 0.0, 1.0], ..Default::default() }).unwrap();
+
+        renderer.set_block_visible(block, false).unwrap();
+        assert!(!renderer.block_visible(block).unwrap());
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.stats().culling, CullingStats { drawn: 0, culled: 0, hidden: 1 });
+        assert!(renderer.read_pixels().unwrap().chunks(4).all(|pixel| pixel != [255, 0, 0, 255]));
+
+        renderer.set_block_visible(block, true).unwrap();
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.stats().culling, CullingStats { drawn: 1, culled: 0, hidden: 0 });
+        assert!(renderer.read_pixels().unwrap().chunks(4).all(|pixel| pixel == [255, 0, 0, 255]));
+        assert!(matches!(renderer.set_block_visible(9, false), Err(RendererError::UnknownBlock(9))));
+
+        // Blocks below a hidden scene graph node count as hidden too
+        let tree = ConicTree::from_json(&format!(r#"{{"parent": {{"visible": "false", "child": {{"block_id": {}}}}}}}"#, block)).unwrap();
+        renderer.set_scene_graph(Some(SceneGraph::from_tree(&tree).unwrap()));
+        renderer.render_once().unwrap();
+        assert_eq!(renderer.stats().culling, CullingStats { drawn: 0, culled: 0, hidden: 1 });
+    }
+
+    #[test]
+    fn test_viewport_rect_clamps_to_image() {
+        let inside = ViewportRect { x: 100, y: 50, width: 200, height: 100 };
+        assert_eq!(inside.clamped([800, 600]), inside);
+        assert_eq!(
+            ViewportRect { x: 700, y: 0, width: 400, height: 600 }.clamped([800, 600]),
+            ViewportRect { x: 700, y: 0, width: 100, height: 600 }
+        );
+        // Entirely outside still leaves a valid one pixel viewport
+        assert_eq!(
+            ViewportRect { x: 900, y: 900, width: 10, height: 10 }.clamped([800, 600]),
+            ViewportRect { x: 799, y: 599, width: 1, height: 1 }
+        );
+        let scissor = inside.scissor();
+        assert_eq!((scissor.origin, scissor.dimensions), ([100, 50], [200, 100]));
+    }
 
-        if suboptimal {
-            return Err(SwapchainCreationError::Suboptimal);
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_polygon_mode_needs_non_solid_fill() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        let supported = renderer.device.physical_device().supported_features().fill_mode_non_solid;
+        match renderer.set_polygon_mode(PolygonMode::Line) {
+            Ok(()) => assert!(supported),
+            Err(e) => assert!(!supported && matches!(e, RendererError::UnsupportedFeature(_))),
         }
+        renderer.set_polygon_mode(PolygonMode::Fill).unwrap();
+        assert_eq!(renderer.polygon_mode(), PolygonMode::Fill);
+    }
 
-        Ok(())
+    // Suitable devices of a machine with a software renderer at index 0, whose index 2 cannot render
+    fn mock_devices() -> Vec<DeviceCandidate> {
+        vec![
+            DeviceCandidate { index: 0, name: "llvmpipe".to_string(), device_type: PhysicalDeviceType::Cpu },
+            DeviceCandidate { index: 1, name: "Intel(R) UHD Graphics 630".to_string(), device_type: PhysicalDeviceType::IntegratedGpu },
+            DeviceCandidate { index: 3, name: "NVIDIA GeForce RTX 3060".to_string(), device_type: PhysicalDeviceType::DiscreteGpu },
+        ]
     }
 
-    // Builds the command buffer for rendering
-    fn build_command_buffer(&self, image_num: usize) -> Result<Arc<vulkano::command_buffer::PrimaryAutoCommandBuffer>, SwapchainCreationError> {
-        let framebuffer = self.framebuffers[image_num].clone();
-        let mut builder = AutoCommandBufferBuilder::primary(
-            self.device.clone(),
-            self.queue.family(),
-            CommandBufferUsage::OneTimeSubmit,
-        ).unwrap();
+    #[test]
+    fn test_instance_config_validation_follows_build_profile() {
+        let config = InstanceConfig::default();
+        assert_eq!(config.validation, cfg!(debug_assertions));
+        assert_eq!(RendererOptions::default().instance, config);
+    }
 
-        builder
-            .begin_render_pass(framebuffer.clone(), false, vec![[0.0, 0.0, 0.0, 1.0].into()])
-            .unwrap()
-            .draw(self.pipeline.clone(), &self.framebuffers[0])
-            .unwrap()
-            .end_render_pass()
+    #[test]
+    fn test_validation_log_level() {
+        let none = MessageSeverity::none();
+        assert_eq!(validation_log_level(MessageSeverity { error: true, ..none }), log::Level::Error);
+        assert_eq!(validation_log_level(MessageSeverity { warning: true, ..none }), log::Level::Warn);
+        assert_eq!(validation_log_level(MessageSeverity { information: true, ..none }), log::Level::Debug);
+        assert_eq!(validation_log_level(MessageSeverity { verbose: true, ..none }), log::Level::Trace);
+    }
+
+    #[test]
+    fn test_suboptimal_recreates_once_between_frames() {
+        // A suboptimal image is used as is, it does not recreate the swapchain mid-frame
+        let acquired = acquire_with_retries(&mut (), |_| Ok((3, true)), |_| panic!("no recreation"));
+        assert_eq!(acquired.unwrap(), (3, true));
+
+        // A driver reporting every acquisition as suboptimal gets one recreation, not one per frame
+        let mut state = Suboptimal::No;
+        let mut recreations = 0;
+        for suboptimal in [false, true, true, true, true, false, true] {
+            let (next, recreate) = state.after_acquire(suboptimal);
+            state = next;
+            recreations += recreate as usize;
+            if suboptimal && state == Suboptimal::Persistent {
+                assert!(!recreate);
+            }
+        }
+        assert_eq!(recreations, 2); // Once for the first run, again once it was optimal in between
+        assert_eq!(state, Suboptimal::Recreated);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_pending_recreate_without_swapchain_keeps_rendering() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        // As left behind by a suboptimal frame
+        renderer.suboptimal = Suboptimal::Recreated;
+        renderer.needs_recreate = true;
+        for _ in 0..3 {
+            renderer.render_once().unwrap();
+        }
+        assert!(!renderer.needs_recreate);
+        assert_eq!(renderer.suboptimal, Suboptimal::No);
+        assert_eq!(renderer.stats().frames_rendered, 3);
+    }
+
+    #[test]
+    fn test_acquire_survives_resize_storm() {
+        // Every resize leaves the swapchain out of date for a couple of acquisitions
+        let mut out_of_date = 2;
+        let mut recreations = 0;
+        let acquired = acquire_with_retries(
+            &mut recreations,
+            |_| if out_of_date > 0 { out_of_date -= 1; Err(AcquireError::OutOfDate) } else { Ok(7) },
+            |recreations| { *recreations += 1; Ok(()) },
+        );
+        assert_eq!(acquired.unwrap(), 7);
+        assert_eq!(recreations, 2);
+
+        // A storm that outlasts the attempts ends the frame with an error the render loop recovers from
+        let mut recreations = 0;
+        let error = acquire_with_retries(&mut recreations, |_| Err::<(), _>(AcquireError::OutOfDate), |r| { *r += 1; Ok(()) })
+            .unwrap_err();
+        assert!(error.is_out_of_date());
+        assert_eq!(recreations, MAX_ACQUIRE_ATTEMPTS - 1);
+
+        let error = acquire_with_retries(&mut (), |_| Err::<(), _>(AcquireError::Timeout), |_| panic!("no recreation"))
+            .unwrap_err();
+        assert!(error.is_recoverable() && !error.is_out_of_date());
+    }
+
+    #[test]
+    fn test_select_device() {
+        let devices = mock_devices();
+        assert_eq!(select_device(&devices, &DeviceSelector::PreferDiscrete).unwrap(), 2);
+        assert_eq!(select_device(&devices, &DeviceSelector::PreferIntegrated).unwrap(), 1);
+        assert_eq!(select_device(&devices, &DeviceSelector::ByIndex(1)).unwrap(), 1);
+        assert_eq!(select_device(&devices, &DeviceSelector::ByIndex(3)).unwrap(), 2);
+        assert_eq!(select_device(&devices, &DeviceSelector::ByName("llvmpipe".to_string())).unwrap(), 0);
+        // Without an integrated GPU the PreferDiscrete order applies
+        assert_eq!(select_device(&devices[..1], &DeviceSelector::PreferIntegrated).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_select_device_falls_back_to_discrete() {
+        let devices = mock_devices();
+        assert_eq!(select_device(&devices, &DeviceSelector::ByIndex(2)).unwrap(), 2); // Present but cannot render
+        assert_eq!(select_device(&devices, &DeviceSelector::ByIndex(7)).unwrap(), 2);
+        assert!(matches!(select_device(&[], &DeviceSelector::ByIndex(0)), Err(RendererInitError::NoSuitableDevice)));
+    }
+
+    #[test]
+    fn test_select_device_by_unknown_name_lists_devices() {
+        let error = select_device(&mock_devices(), &DeviceSelector::ByName("Radeon".to_string())).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("\"Radeon\""));
+        assert!(message.contains("0: llvmpipe") && message.contains("3: NVIDIA GeForce RTX 3060"));
+    }
+
+    #[test]
+    fn test_driver_version_string() {
+        let mut info = DeviceInfo {
+            name: "NVIDIA GeForce RTX 3060".to_string(),
+            device_type: PhysicalDeviceType::DiscreteGpu,
+            index: 0,
+            vendor_id: 0x10de,
+            driver_version: (535 << 22) | (104 << 14) | (5 << 6),
+            api_version: Version::V1_1,
+        };
+        assert_eq!(info.driver_version_string(), "535.104.5.0");
+        info.vendor_id = 0x1002;
+        info.driver_version = (2 << 22) | (0 << 12) | 279;
+        assert_eq!(info.driver_version_string(), "2.0.279");
+    }
+
+    #[test]
+    fn test_choose_surface_format() {
+        let formats = [
+            (Format::B8G8R8A8Unorm, ColorSpace::SrgbNonLinear),
+            (Format::B8G8R8A8Srgb, ColorSpace::SrgbNonLinear),
+        ];
+        assert_eq!(choose_surface_format(&formats), Some((Format::B8G8R8A8Srgb, ColorSpace::SrgbNonLinear)));
+        assert_eq!(choose_surface_format(&formats[..1]), Some((Format::B8G8R8A8Unorm, ColorSpace::SrgbNonLinear)));
+        assert_eq!(choose_surface_format(&[]), None);
+
+        // 8-bit UNORM is preferred over other formats listed first
+        let formats = [
+            (Format::A2B10G10R10UnormPack32, ColorSpace::SrgbNonLinear),
+            (Format::R8G8B8A8Unorm, ColorSpace::SrgbNonLinear),
+        ];
+        assert_eq!(choose_surface_format(&formats), Some((Format::R8G8B8A8Unorm, ColorSpace::SrgbNonLinear)));
+        assert_eq!(choose_surface_format(&formats[..1]), Some((Format::A2B10G10R10UnormPack32, ColorSpace::SrgbNonLinear)));
+    }
+
+    #[test]
+    fn test_needs_manual_gamma() {
+        assert!(needs_manual_gamma(Format::B8G8R8A8Unorm));
+        assert!(needs_manual_gamma(Format::R8G8B8A8Unorm));
+        assert!(!needs_manual_gamma(Format::B8G8R8A8Srgb));
+        assert!(!needs_manual_gamma(Format::R8G8B8A8Srgb));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_headless_no_manual_gamma() {
+        let renderer = headless([4, 4], RendererOptions::default());
+        assert_eq!(renderer.surface_format(), None);
+        assert!(!renderer.manual_gamma());
+    }
+
+    #[test]
+    fn test_capture_out_of_date_is_retriable() {
+        assert!(CaptureError::from(RendererError::Acquire(AcquireError::OutOfDate)).is_retriable());
+        assert!(!CaptureError::Unsupported.is_retriable());
+        assert!(!CaptureError::UnsupportedFormat(Format::R16G16B16A16Sfloat).is_retriable());
+    }
+
+    #[test]
+    fn test_attachment_clear_values() {
+        let settings = FrameSettings { clear_color: [0.2, 0.3, 0.4, 1.0], load_previous: false, clear_depth: 0.5 };
+
+        let values = attachment_clear_values(&settings, true, 4, true);
+        assert_eq!(values.len(), 3);
+        assert!(matches!(values[0], ClearValue::Float(c) if c == [0.2, 0.3, 0.4, 1.0]));
+        assert!(matches!(values[1], ClearValue::None));
+        assert!(matches!(values[2], ClearValue::Depth(d) if d == 0.5));
+
+        let loaded = attachment_clear_values(&settings, false, 1, false);
+        assert_eq!(loaded.len(), 1);
+        assert!(matches!(loaded[0], ClearValue::None));
+    }
+
+    #[test]
+    fn test_to_rgba8() {
+        let bgra = vec![10, 20, 30, 255, 40, 50, 60, 128];
+        assert_eq!(to_rgba8(Format::B8G8R8A8Unorm, bgra).unwrap(), vec![30, 20, 10, 255, 60, 50, 40, 128]);
+        assert_eq!(to_rgba8(Format::R8G8B8A8Srgb, vec![1, 2, 3, 4]).unwrap(), vec![1, 2, 3, 4]);
+        assert!(matches!(
+            to_rgba8(Format::R16G16B16A16Sfloat, vec![0; 8]),
+            Err(CaptureError::UnsupportedFormat(Format::R16G16B16A16Sfloat))
+        ));
+    }
+
+    #[test]
+    fn test_ticks_to_micros() {
+        assert_eq!(ticks_to_micros(1_000_000, 1.0), 1000);
+        assert_eq!(ticks_to_micros(1_000, 52.08), 52);
+        assert_eq!(ticks_to_micros(0, 1.0), 0);
+    }
+
+    #[test]
+    fn test_ticks_to_nanos() {
+        assert_eq!(ticks_to_nanos(1_000, 1.0), 1000);
+        assert_eq!(ticks_to_nanos(1_000, 52.08), 52080);
+        assert_eq!(ticks_to_nanos(0, 1.0), 0);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_last_gpu_frame_nanos() {
+        let mut renderer = headless([64, 64], RendererOptions::default());
+        assert_eq!(renderer.last_gpu_frame_nanos(), None);
+        let triangles: Vec<f32> = (0..3000).flat_map(|i| {
+            let z = i as f32 * 0.001;
+            vec![0.0, 0.9, z, -0.9, -0.9, z, 0.9, -0.9, z]
+        }).collect();
+        renderer.apply_shader_block(ShaderBlock {
+            vertices: positions(&triangles),
+            material_data: vec![1.0, 0.5, 0.0, 1.0],
+            ..Default::default()
+        }).unwrap();
+        renderer.render_once().unwrap();
+        renderer.wait_idle();
+
+        match renderer.timestamp_pool {
+            Some(_) => assert!(renderer.last_gpu_frame_nanos().unwrap() > 0),
+            None => assert_eq!(renderer.last_gpu_frame_nanos(), None), // The queue cannot write timestamps
+        }
+    }
+
+    #[test]
+    fn test_aspect_ratio() {
+        assert_eq!(aspect_ratio([1920, 1080]), 1920.0 / 1080.0);
+        assert_eq!(aspect_ratio([800, 0]), 1.0);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_blocks_are_drawn_into_each_image() {
+        // Three images, each rendered into in turn, stand in for a triple-buffered swapchain
+        let options = RendererOptions { desired_image_count: Some(3), ..Default::default() };
+        let mut renderer = headless([4, 4], options).frames_in_flight(3);
+        let quad = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]];
+        let vertices = positions(&quad.iter().flat_map(|&[x, y]| vec![x, y, 0.5]).collect::<Vec<_>>());
+        renderer.apply_shader_block(ShaderBlock { vertices, material_data: vec![1.0, 0.0, 0.0, 1.0], ..Default::default() }).unwrap();
+
+        let mut images = Vec::new();
+        for _ in 0..3 {
+            renderer.render_once().unwrap();
+            // Copies the image this frame was rendered into
+            assert!(renderer.read_pixels().unwrap().chunks(4).all(|pixel| pixel == [255, 0, 0, 255]));
+            images.push(renderer.last_image.unwrap());
+        }
+        assert_eq!(images, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_render_control_stop_from_another_thread() {
+        let control = RenderControl::new();
+        let remote = control.clone();
+        std::thread::spawn(move || remote.stop()).join().unwrap();
+        assert!(control.is_stopped());
+    }
+
+    #[test]
+    fn test_render_control_pause_parks_until_resumed() {
+        let control = RenderControl::new();
+        control.pause();
+        assert!(control.is_paused());
+
+        let remote = control.clone();
+        let waiter = std::thread::spawn(move || {
+            remote.wait_while_paused();
+            remote.is_paused()
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        control.resume();
+        assert!(!waiter.join().unwrap());
+
+        // Stopping wakes a paused loop so it can exit
+        control.pause();
+        let remote = control.clone();
+        let waiter = std::thread::spawn(move || remote.wait_while_paused());
+        control.stop();
+        waiter.join().unwrap();
+        assert!(control.is_paused());
+    }
+
+    #[test]
+    fn test_choose_depth_format() {
+        let only_d16 = |format: Format| format == Format::D16Unorm;
+        assert_eq!(choose_depth_format(None, only_d16), Some(Format::D16Unorm));
+        assert_eq!(choose_depth_format(Some(Format::D16Unorm), only_d16), Some(Format::D16Unorm));
+        assert_eq!(choose_depth_format(Some(Format::D32Sfloat), only_d16), None);
+        assert_eq!(choose_depth_format(None, |_| true), Some(Format::D32Sfloat));
+    }
+
+    #[test]
+    fn test_metadata_frame_stats() {
+        let mut metadata = Metadata::with_window(100);
+        let start = Instant::now();
+        metadata.record_frame(start);
+        assert_eq!(metadata.stats().last_frame_time, None);
+
+        // 99 frames of 10ms and one of 50ms
+        let mut end = start;
+        for i in 0..100 {
+            end += Duration::from_millis(if i == 50 { 50 } else { 10 });
+            metadata.record_frame(end);
+        }
+        let stats = metadata.stats();
+        assert_eq!(stats.frames_rendered, 101);
+        assert_eq!(stats.last_frame_time, Some(Duration::from_millis(10)));
+        assert_eq!(stats.p95_frame_time, Some(Duration::from_millis(10)));
+        assert_eq!(stats.p99_frame_time, Some(Duration::from_millis(10)));
+        assert!((stats.average_fps - 100.0 / 1.04).abs() < 0.01);
+
+        // A gap after break_interval is not a frame, and the window drops the oldest times
+        metadata.break_interval();
+        metadata.record_frame(end + Duration::from_secs(5));
+        metadata.record_frame(end + Duration::from_secs(5) + Duration::from_millis(20));
+        let stats = metadata.stats();
+        assert_eq!(stats.frames_rendered, 103);
+        assert_eq!(stats.last_frame_time, Some(Duration::from_millis(20)));
+        assert_eq!(stats.p99_frame_time, Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn test_ticks_to_millis() {
+        assert_eq!(ticks_to_millis(2_000_000, 1.0), 2.0);
+        assert!((ticks_to_millis(1_000, 52.08) - 0.05208).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_metadata_last_present_survives_pauses() {
+        let mut metadata = Metadata::default();
+        assert_eq!(metadata.last_present(), None);
+        let end = Instant::now();
+        metadata.record_frame(end);
+        metadata.break_interval();
+        assert_eq!(metadata.last_present(), Some(end));
+        assert_eq!(metadata.frames_rendered(), 1);
+    }
+
+    #[test]
+    fn test_metadata_builder_and_entries() {
+        let mut metadata = Metadata::default().with_source("metrics.db").with_entry("scene", "lobby");
+        assert_eq!(metadata.source(), Some("metrics.db"));
+        assert_eq!(metadata.entry("scene"), Some("lobby"));
+        assert_eq!(metadata.set_entry("scene", "hall"), Some("lobby".to_string()));
+        assert_eq!(metadata.remove_entry("scene"), Some("hall".to_string()));
+        assert!(metadata.entries().is_empty());
+
+        metadata.record_block_loaded(30);
+        metadata.record_block_loaded(12);
+        metadata.record_block_evicted(30);
+        assert_eq!((metadata.loaded_blocks(), metadata.vertex_count()), (1, 12));
+    }
+
+    #[test]
+    fn test_metadata_concurrent_loads_and_frames() {
+        let metadata = Arc::new(Mutex::new(Metadata::default()));
+        let loader = {
+            let metadata = metadata.clone();
+            std::thread::spawn(move || {
+                for i in 0..500 {
+                    let mut metadata = metadata.lock().unwrap();
+                    metadata.record_block_loaded(3);
+                    metadata.set_entry("last_block", &i.to_string());
+                }
+            })
+        };
+        for _ in 0..500 {
+            metadata.lock().unwrap().record_frame(Instant::now());
+            // Readers copy out and never see a block without its vertices
+            let snapshot = metadata.lock().unwrap().clone();
+            assert_eq!(snapshot.vertex_count(), snapshot.loaded_blocks() * 3);
+        }
+        loader.join().unwrap();
+
+        let metadata = metadata.lock().unwrap();
+        assert_eq!(metadata.frames_rendered(), 500);
+        assert_eq!((metadata.loaded_blocks(), metadata.vertex_count()), (500, 1500));
+        assert_eq!(metadata.entry("last_block"), Some("499"));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_metadata_handle_snapshots_while_rendering() {
+        let mut renderer = headless([4, 4], RendererOptions::default());
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (metadata, done) = (renderer.metadata_handle(), done.clone());
+            std::thread::spawn(move || {
+                let (mut snapshots, mut frames) = (0, 0);
+                loop {
+                    // Blocks are recorded along with their vertices, and frames only ever advance
+                    let snapshot = metadata.snapshot();
+                    assert_eq!(snapshot.vertex_count(), snapshot.loaded_blocks() * 3);
+                    assert!(snapshot.frames_rendered() >= frames);
+                    frames = snapshot.frames_rendered();
+                    snapshots += 1;
+                    if done.load(Ordering::Acquire) {
+                        return snapshots;
+                    }
+                }
+            })
+        };
+        for i in 0..20 {
+            renderer.apply_shader_block(ShaderBlock {
+                vertices: positions(&[-1.0, -1.0, 0.5, 1.0, -1.0, 0.5, 0.0, 1.0, 0.5]),
+                ..Default::default()
+            }).unwrap();
+            renderer.render_once().unwrap();
+            renderer.metadata_mut(|metadata| metadata.set_entry("frame", &i.to_string()));
+        }
+        done.store(true, Ordering::Release);
+        assert!(reader.join().unwrap() > 0);
+
+        let metadata = renderer.metadata_handle().snapshot();
+        assert_eq!((metadata.frames_rendered(), metadata.loaded_blocks()), (20, 20));
+        assert_eq!(metadata.entry("frame"), Some("19"));
+    }
+
+    #[test]
+    fn test_metadata_keeps_recent_gpu_timings() {
+        let mut metadata = Metadata::with_window(2);
+        for frame in 0..3 {
+            metadata.record_gpu_timing(GpuTiming { pass: GpuPass::RenderPass, frame_number: Some(frame), millis: 1.5 });
+        }
+        let timings = metadata.gpu_timings();
+        assert_eq!(timings.len(), 2);
+        assert_eq!(timings[0].frame_number, Some(1));
+        assert_eq!(timings[1].frame_number, Some(2));
+    }
+
+    #[test]
+    fn test_percentile() {
+        let sorted: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 0.95), Some(Duration::from_millis(10)));
+        assert_eq!(percentile(&sorted, 0.5), Some(Duration::from_millis(5)));
+        assert_eq!(percentile(&[], 0.99), None);
+    }
+
+    #[test]
+    fn test_minimized_window_idles() {
+        // A minimized window reports 0x0, which skips rendering instead of recreating the swapchain
+        assert!(is_zero_extent([0, 0]));
+        assert!(is_zero_extent([800, 0]));
+        assert!(!is_zero_extent([800, 600]));
+
+        // Minimizing and restoring a window: the frames in between idle instead of touching the swapchain
+        let (mut rendered, mut idled) = (0, 0);
+        for extent in [[800, 600], [0, 0], [0, 0], [800, 600]] {
+            render_or_idle(
+                &mut (&mut rendered, &mut idled),
+                |_| Some(extent),
+                |(_, idled)| **idled += 1,
+                |(rendered, _)| {
+                    assert!(!is_zero_extent(extent), "rendered into a minimized window");
+                    **rendered += 1;
+                    Ok(())
+                },
+            )
             .unwrap();
+        }
+        assert_eq!((rendered, idled), (2, 2));
 
-        Ok(builder.build().unwrap())
+        // A recreation that fails is logged by the render loop and retried on the next frame
+        let err = RendererError::from(SwapchainCreationError::UnsupportedDimensions);
+        assert!(err.is_recoverable());
+        assert!(!err.is_out_of_date());
     }
 
-    // Handles swapchain recreation (in case of resizing or updating)
-    fn recreate_swapchain(&mut self) {
-        let (new_swapchain, new_images) = self.swapchain.recreate().unwrap();
-        self.swapchain = new_swapchain;
-        self.framebuffers = self.create_framebuffers(new_images);
+    #[test]
+    fn test_choose_present_mode() {
+        let fifo_only = |mode: PresentMode| mode == PresentMode::Fifo;
+        assert_eq!(choose_present_mode(&[PresentMode::Mailbox, PresentMode::Immediate], fifo_only), PresentMode::Fifo);
+        assert_eq!(choose_present_mode(&[], fifo_only), PresentMode::Fifo);
+
+        let no_mailbox = |mode: PresentMode| mode != PresentMode::Mailbox;
+        assert_eq!(choose_present_mode(&[PresentMode::Mailbox, PresentMode::Immediate], no_mailbox), PresentMode::Immediate);
     }
 
-    // Helper function to create framebuffers for new swapchain images
-    fn create_framebuffers(&self, images: Vec<Arc<SwapchainImage<Window>>>) -> Vec<Arc<dyn FramebufferAbstract + Send + Sync>> {
-        images.into_iter().map(|image| {
-            Arc::new(
-                Framebuffer::start(self.render_pass.clone())
-                    .add(image.clone())
-                    .unwrap()
-                    .build()
-                    .unwrap()
-            ) as Arc<dyn FramebufferAbstract + Send + Sync>
-        }).collect::<Vec<_>>()
+    #[test]
+    fn test_clamp_image_count() {
+        assert_eq!(clamp_image_count(None, 2, Some(8)), 2);
+        assert_eq!(clamp_image_count(Some(3), 2, Some(8)), 3);
+        assert_eq!(clamp_image_count(Some(3), 2, Some(2)), 2);
+        assert_eq!(clamp_image_count(Some(1), 2, None), 2);
+        assert_eq!(clamp_image_count(Some(16), 2, None), 16);
     }
 
-    // Additional methods for managing shader data and database interactions can be added here
-}
\ No newline at end of file
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn test_desired_image_count_sets_framebuffers() {
+        let options = RendererOptions { desired_image_count: Some(3), ..Default::default() };
+        let mut renderer = headless([4, 4], options);
+        assert_eq!(renderer.framebuffers.len(), 3);
+        assert_eq!(renderer.metadata_snapshot().image_count(), renderer.framebuffers.len());
+        assert_eq!(renderer.frame_fences.len(), 3);
+        let mut renderer = renderer.frames_in_flight(5);
+        assert_eq!(renderer.frame_fences.len(), 3);
+        for _ in 0..3 {
+            renderer.render_once().unwrap();
+        }
+
+        // Fewer images than frames in flight would let two frames draw into the same image
+        let options = RendererOptions { desired_image_count: Some(1), ..Default::default() };
+        let renderer = headless([4, 4], options);
+        assert_eq!(renderer.framebuffers.len(), DEFAULT_FRAMES_IN_FLIGHT);
+    }
+
+    #[test]
+    fn test_clamp_sample_count() {
+        // Device supporting 1, 2 and 4 samples
+        let supported = 0b0111;
+        assert_eq!(clamp_sample_count(1, supported).unwrap(), 1);
+        assert_eq!(clamp_sample_count(4, supported).unwrap(), 4);
+        assert_eq!(clamp_sample_count(8, supported).unwrap(), 4);
+        assert!(matches!(clamp_sample_count(3, supported), Err(RendererError::UnsupportedSampleCount(3))));
+    }
+
+    #[test]
+    fn test_frame_errors_reach_recovery() {
+        // An acquisition still out of date after every attempt schedules a recreation instead of failing
+        let out_of_date = acquire_with_retries(&mut (), |_| Err::<(), _>(AcquireError::OutOfDate), |_| Ok(()));
+        assert!(recovery(out_of_date).unwrap());
+
+        // A suboptimal image is drawn into and schedules the recreation itself, it is never an error
+        assert_eq!(Suboptimal::No.after_acquire(true), (Suboptimal::Recreated, true));
+
+        let timed_out = acquire_with_retries(&mut (), |_| Err::<(), _>(AcquireError::Timeout), |_| panic!("no recreation"));
+        assert!(!recovery(timed_out).unwrap());
+        let lost = acquire_with_retries(&mut (), |_| Err::<(), _>(AcquireError::DeviceLost), |_| panic!("no recreation"));
+        assert!(matches!(recovery(lost), Err(RendererError::Acquire(AcquireError::DeviceLost))));
+        assert!(matches!(recovery(failing_upload()), Err(RendererError::BufferAllocation(_))));
+    }
+}